@@ -0,0 +1,116 @@
+//! Receive throughput for 100 point scans, the size the LiDAR task sends
+//!
+//! This bench is newer than the receive path it replaced, so that path is copied here as the
+//! `drain_and_decode` arm rather than measured by checking out an older revision. To compare a
+//! later change with this one, save a baseline here and compare against it after the change:
+//! cargo bench --bench receive -- --save-baseline before
+//! cargo bench --bench receive -- --baseline before
+
+use std::collections::VecDeque;
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use mote_api::messages::mote_to_host;
+use mote_api::{HostLink, MoteLink};
+
+const MESSAGES: usize = 100;
+
+fn scan() -> mote_to_host::Message {
+    mote_to_host::Message::Scan(mote_to_host::Scan {
+        timestamp_us: 0,
+        points: (0..100u8)
+            .map(|i| mote_to_host::Point {
+                quality: i,
+                angle_rad: i as f32 * 0.0628,
+                distance_mm: 500.0 + i as f32,
+            })
+            .collect(),
+    })
+}
+
+// Packets for MESSAGES scans, each of which fits in a single UDP packet
+fn scan_packets() -> Vec<Vec<u8>> {
+    let mut mote = HostLink::new();
+    (0..MESSAGES)
+        .map(|_| {
+            mote.send(scan()).unwrap();
+            mote.poll_transmit().unwrap()
+        })
+        .collect()
+}
+
+/// The receive path poll_receive used before poll_receive_in: each frame is drained from the
+/// receive buffer into a Vec, COBS decoded into another Vec, and its payload copied into a third
+/// before being deserialized. Only single fragment frames are handled, which is all this bench
+/// sends, and the link's sequence and duplicate bookkeeping is skipped, which flatters this arm.
+#[derive(Default)]
+struct DrainAndDecode {
+    deserialization_buffer: VecDeque<u8>,
+}
+
+impl DrainAndDecode {
+    // The layout of a fragment, see the frame module
+    const HEADER_LENGTH: usize = 5;
+    const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+
+    fn handle_receive(&mut self, packet: &[u8]) {
+        self.deserialization_buffer.extend(packet);
+    }
+
+    fn poll_receive(&mut self) -> Option<mote_to_host::Message> {
+        let end = self.deserialization_buffer.iter().position(|&x| x == 0)?;
+        let linear_buf: Vec<u8> = self.deserialization_buffer.drain(0..=end).collect();
+        let mut cobs_buff: Vec<u8> = vec![10; linear_buf.len()];
+        let decoded_size = corncobs::decode_buf(&linear_buf, &mut cobs_buff).ok()?;
+
+        let (checked, checksum) = cobs_buff[..decoded_size].split_at(decoded_size - 2);
+        if Self::CRC.checksum(checked).to_le_bytes() != checksum {
+            return None;
+        }
+        let chunk = Vec::from(&checked[Self::HEADER_LENGTH..]);
+        postcard::from_bytes(&chunk).ok()
+    }
+}
+
+fn receive(c: &mut Criterion) {
+    let packets = scan_packets();
+    let mut group = c.benchmark_group("receive_100_point_scans");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+
+    group.bench_function("drain_and_decode", |b| {
+        let mut link = DrainAndDecode::default();
+        b.iter(|| {
+            for packet in &packets {
+                link.handle_receive(packet);
+                black_box(link.poll_receive().unwrap());
+            }
+        })
+    });
+
+    group.bench_function("poll_receive", |b| {
+        let mut link = MoteLink::new();
+        b.iter(|| {
+            for packet in &packets {
+                link.handle_receive(packet);
+                black_box(link.poll_receive().unwrap());
+            }
+        })
+    });
+
+    group.bench_function("poll_receive_in", |b| {
+        let mut link = MoteLink::new();
+        let mut buffer = [0; 1400];
+        b.iter(|| {
+            for packet in &packets {
+                link.handle_receive(packet);
+                let msg: Option<mote_to_host::Message> = link.poll_receive_in(&mut buffer).unwrap();
+                black_box(msg);
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, receive);
+criterion_main!(benches);
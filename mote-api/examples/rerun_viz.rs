@@ -3,18 +3,27 @@
 
 use anyhow::anyhow;
 use color_space::{Hsv, Rgb};
-use futures_util::{StreamExt, pin_mut};
+use futures_util::{SinkExt, StreamExt, pin_mut};
 use mdns::RecordKind;
 use rerun::external::glam;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::net::Ipv4Addr;
 use std::time::Duration;
+use tokio::net::UdpSocket;
 
 use mote_api::MoteLink;
+use mote_api::mapping::{GridConfig, OccupancyGrid};
 use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::odometry::{Odometry, OdometryConfig};
+use mote_api::scan::{ScanAssembler, ScanFilter};
+use mote_api::transport::{TokioClock, TokioLink, TransportError};
 
 const MDNS_SERVICE: &str = "_mote-api._udp.local";
 const MDNS_TIMEOUT: Duration = Duration::from_secs(15);
 
+// Mote's drive base, for odometry
+const WHEEL_RADIUS_M: f32 = 0.033;
+const TRACK_WIDTH_M: f32 = 0.14;
+
 fn discover_mote() -> anyhow::Result<Ipv4Addr> {
     println!("Scanning for Motes...");
     async_std::task::block_on(async {
@@ -62,76 +71,114 @@ fn main() -> anyhow::Result<()> {
     .unwrap()
     .detach();
 
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    loop {
+        if let Err(err) = runtime.block_on(visualize(ip, &rec)) {
+            println!("Lost connection to Mote: {err}");
+        }
+    }
+}
+
+async fn visualize(ip: Ipv4Addr, rec: &rerun::RecordingStream) -> anyhow::Result<()> {
     // Both commands and data use the same UDP socket
-    'socket_error: loop {
-        if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
-            if let Err(_) = socket.connect((ip, 7475)) {
-                println!("Failed to connect to Mote");
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((ip, 7475)).await?;
+
+    // Reading messages from the link also sends acknowledgements and retransmissions
+    let mut link = TokioLink::new(MoteLink::new(), socket, TokioClock::new());
+
+    // Scans arrive in arcs of up to 100 points, so collect them into whole revolutions
+    let mut assembler = ScanAssembler::new(ScanFilter::default());
+
+    // Map the sweeps at the pose odometry estimates
+    let mut odometry = Odometry::new(OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M));
+    let mut last_imu_us: Option<u64> = None;
+    let mut grid = OccupancyGrid::new(GridConfig::new(20.0, 20.0, 0.05));
+
+    // Ping the robot
+    println!("Pinging Mote");
+    link.send(host_to_mote::Message::Ping).await?;
+
+    while let Some(message) = link.next().await {
+        let message = match message {
+            Ok(message) => message,
+            // Only the message in a corrupt frame is lost
+            Err(TransportError::Link(err)) => {
+                eprintln!("Dropped a message: {err}");
                 continue;
             }
-
-            let mut link = MoteLink::new();
-
-            // Ping the robot
-            println!("Pinging Mote");
-            link.send(host_to_mote::Message::Ping).unwrap();
-
-            loop {
-                // Retrieve and transmit all messages queued to be sent
-                while let Some(payload) = link.poll_transmit() {
-                    if let Err(_) = socket.send(&payload) {
-                        continue 'socket_error;
-                    }
-                    continue;
+            Err(TransportError::Transport(err)) => {
+                eprintln!("Lost the socket to Mote: {err}");
+                break;
+            }
+        };
+        // Mote packs lidar points in its own units, unpack them into a Scan
+        let Ok(message) = message.unpacked() else {
+            continue;
+        };
+        match message {
+            mote_to_host::Message::Pong => {
+                println!("Got pong from Mote.");
+            }
+            mote_to_host::Message::Ping => {
+                println!("Mote pinged host.");
+                link.send(host_to_mote::Message::Pong).await?;
+            }
+            mote_to_host::Message::DriveBaseState(state) => {
+                odometry.update_drive_base(&state);
+            }
+            mote_to_host::Message::IMUMeasurement(measurement) => {
+                // Mote's timestamps don't suffer from the network's jitter
+                if let Some(last_us) = last_imu_us {
+                    let dt_s = measurement.timestamp_us.saturating_sub(last_us) as f32 / 1e6;
+                    odometry.update_imu(&measurement, dt_s);
                 }
-
-                // Read a message from the socket
-                let mut buf = vec![0u8; 2000];
-                let num_read = socket.recv(&mut buf).unwrap();
-                link.handle_receive(&mut buf[..num_read]);
-
-                while let Ok(Some(message)) = link.poll_receive() {
-                    match message {
-                        mote_to_host::Message::Pong => {
-                            println!("Got pong from Mote.");
-                        }
-                        mote_to_host::Message::Ping => {
-                            println!("Mote pinged host.");
-                            link.send(host_to_mote::Message::Pong).unwrap();
-                        }
-                        mote_to_host::Message::Scan(scan_data) => {
-                            // We got a LiDAR scan message, lets push the points to rerun for visualization
-                            let points: Vec<glam::Vec2> = scan_data
-                                .iter()
-                                .map(|point| {
-                                    glam::Vec2::from_angle(point.angle_rad) * point.distance_mm
-                                })
-                                .collect();
-
-                            let colors: Vec<rerun::Color> = scan_data
-                                .iter()
-                                .map(|point| {
-                                    let rgb = Rgb::from(Hsv::new(
-                                        point.distance_mm as f64 / 20.0,
-                                        1.0,
-                                        1.0,
-                                    ));
-                                    rerun::Color::from_rgb(rgb.r as u8, rgb.g as u8, rgb.b as u8)
-                                })
-                                .collect();
-
-                            rec.log(
-                                "lidar_scan",
-                                &rerun::Points2D::new(points)
-                                    .with_colors(colors)
-                                    .with_radii([10.0]),
-                            )
-                            .unwrap();
-                        }
-                        _ => todo!(),
-                    }
+                last_imu_us = Some(measurement.timestamp_us);
+            }
+            mote_to_host::Message::Scan(scan) => {
+                assembler.push(&scan.points, scan.timestamp_us);
+                // We got a full LiDAR sweep, lets push the points to rerun for visualization
+                while let Some(sweep) = assembler.poll_sweep() {
+                    let points: Vec<glam::Vec2> = sweep
+                        .points
+                        .iter()
+                        .map(|point| glam::Vec2::from_angle(point.angle_rad) * point.distance_mm)
+                        .collect();
+
+                    let colors: Vec<rerun::Color> = sweep
+                        .points
+                        .iter()
+                        .map(|point| {
+                            let rgb =
+                                Rgb::from(Hsv::new(point.distance_mm as f64 / 20.0, 1.0, 1.0));
+                            rerun::Color::from_rgb(rgb.r as u8, rgb.g as u8, rgb.b as u8)
+                        })
+                        .collect();
+
+                    rec.log(
+                        "lidar_scan",
+                        &rerun::Points2D::new(points)
+                            .with_colors(colors)
+                            .with_radii([10.0]),
+                    )
+                    .unwrap();
+
+                    grid.insert_scan(&sweep.points, odometry.pose());
+                    let config = grid.config();
+                    rec.log(
+                        "map",
+                        &rerun::Image::from_l8(
+                            grid.image(),
+                            [config.width as u32, config.height as u32],
+                        ),
+                    )
+                    .unwrap();
                 }
             }
+            _ => {}
         }
     }
+    Ok(())
 }
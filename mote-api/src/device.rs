@@ -0,0 +1,93 @@
+//! Helpers for serving the Mote API, shared by the firmware and the simulator
+
+use alloc::boxed::Box;
+
+use crate::PROTOCOL_VERSION;
+use crate::messages::host_to_mote;
+use crate::messages::mote_to_host::{self, HelloAck, NackReason};
+
+/// Answer to a host's version handshake, from a device running `firmware_version` built from
+/// `git_hash`, with the given capabilities flags
+pub fn hello_ack(firmware_version: &str, git_hash: &str, capabilities: u32) -> HelloAck {
+    HelloAck {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: firmware_version.into(),
+        git_hash: git_hash.into(),
+        capabilities,
+    }
+}
+
+/// Unwrap a host message, returning the request id if it was sent as a request
+pub fn unwrap_request(message: host_to_mote::Message) -> (Option<u32>, host_to_mote::Message) {
+    match message {
+        host_to_mote::Message::Request(request) => (Some(request.id), *request.message),
+        message => (None, message),
+    }
+}
+
+/// Reply to a host message given the outcome of carrying it out.
+///
+/// Requests are always answered with a Response or Nack carrying their id. Bare commands are only
+/// answered if they have a reply.
+pub fn reply(
+    id: Option<u32>,
+    outcome: Result<Option<mote_to_host::Message>, NackReason>,
+) -> Option<mote_to_host::Message> {
+    match (id, outcome) {
+        (Some(id), Ok(result)) => Some(mote_to_host::Message::Response(mote_to_host::Response {
+            id,
+            result: result.map(Box::new),
+        })),
+        (Some(id), Err(reason)) => Some(mote_to_host::Message::Nack(mote_to_host::Nack {
+            id,
+            reason,
+        })),
+        (None, outcome) => outcome.ok().flatten(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_are_always_answered() {
+        let request = host_to_mote::Message::Request(host_to_mote::Request {
+            id: 7,
+            message: Box::new(host_to_mote::Message::Ping),
+        });
+        let (id, command) = unwrap_request(request);
+        assert_eq!((id, &command), (Some(7), &host_to_mote::Message::Ping));
+        assert_eq!(
+            reply(id, Ok(Some(mote_to_host::Message::Pong))),
+            Some(mote_to_host::Message::Response(mote_to_host::Response {
+                id: 7,
+                result: Some(Box::new(mote_to_host::Message::Pong)),
+            }))
+        );
+        assert_eq!(
+            reply(id, Ok(None)),
+            Some(mote_to_host::Message::Response(mote_to_host::Response {
+                id: 7,
+                result: None,
+            }))
+        );
+        assert_eq!(
+            reply(id, Err(NackReason::Unsupported)),
+            Some(mote_to_host::Message::Nack(mote_to_host::Nack {
+                id: 7,
+                reason: NackReason::Unsupported,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_bare_commands_are_answered_only_with_a_reply() {
+        let (id, command) = unwrap_request(host_to_mote::Message::Ping);
+        assert_eq!((id, command), (None, host_to_mote::Message::Ping));
+        let pong = mote_to_host::Message::Pong;
+        assert_eq!(reply(None, Ok(Some(pong.clone()))), Some(pong));
+        assert_eq!(reply(None, Ok(None)), None);
+        assert_eq!(reply(None, Err(NackReason::InvalidArgument)), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! Wire framing shared by MoteComms and StaticMoteComms
+//!
+//! A serialized message is split into fragments small enough that each fits in one MTU sized
+//! packet. Each fragment is laid out (before COBS encoding) as its header, its slice of the
+//! payload, then a little endian CRC-16 of everything before it. Every packet is therefore a
+//! complete COBS frame, so a lost or reordered packet can't be spliced into another message.
+
+use crate::Error;
+
+/// CRC appended to every frame, so that corrupted bytes which still happen to decode as a valid
+/// message are rejected instead of being delivered as the wrong message.
+const FRAME_CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+pub(crate) const FRAME_CRC_LENGTH: usize = 2;
+
+/// Fragment flag: the receiver must acknowledge this frame
+pub(crate) const FLAG_RELIABLE: u8 = 1 << 0;
+/// Fragment flag: acknowledges the reliable frame with this sequence number, carries no payload
+pub(crate) const FLAG_ACK: u8 = 1 << 1;
+
+/// Header at the start of every fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FragmentHeader {
+    /// Sequence number of the frame, shared by all of its fragments
+    pub sequence: u16,
+    pub fragment_index: u8,
+    pub fragment_count: u8,
+    pub flags: u8,
+}
+
+pub(crate) const FRAGMENT_HEADER_LENGTH: usize = 5;
+
+impl FragmentHeader {
+    /// Header of the acknowledgement for the reliable frame with the given sequence number
+    pub fn ack(sequence: u16) -> Self {
+        Self {
+            sequence,
+            fragment_index: 0,
+            fragment_count: 1,
+            flags: FLAG_ACK,
+        }
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.flags & FLAG_RELIABLE != 0
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.flags & FLAG_ACK != 0
+    }
+
+    fn to_bytes(self) -> [u8; FRAGMENT_HEADER_LENGTH] {
+        let [sequence_low, sequence_high] = self.sequence.to_le_bytes();
+        [
+            sequence_low,
+            sequence_high,
+            self.fragment_index,
+            self.fragment_count,
+            self.flags,
+        ]
+    }
+
+    fn from_bytes(bytes: [u8; FRAGMENT_HEADER_LENGTH]) -> Self {
+        Self {
+            sequence: u16::from_le_bytes([bytes[0], bytes[1]]),
+            fragment_index: bytes[2],
+            fragment_count: bytes[3],
+            flags: bytes[4],
+        }
+    }
+}
+
+/// Largest fragment payload which still fits in a single MTU sized packet once the header and
+/// checksum are added and the result is COBS encoded.
+pub(crate) fn fragment_capacity(mtu: usize) -> usize {
+    let mut packet_size = mtu;
+    while corncobs::max_encoded_len(packet_size) > mtu {
+        packet_size -= 1;
+    }
+    packet_size
+        .checked_sub(FRAGMENT_HEADER_LENGTH + FRAME_CRC_LENGTH)
+        .filter(|&capacity| capacity > 0)
+        .expect("MTU too small to carry a fragment")
+}
+
+/// Number of fragments needed to carry a payload of the given length
+pub(crate) fn fragment_count(payload_length: usize, mtu: usize) -> Result<u8, Error> {
+    // An empty payload still needs a fragment to carry its header
+    let count = payload_length.div_ceil(fragment_capacity(mtu)).max(1);
+    u8::try_from(count).map_err(|_| Error::MessageTooLarge)
+}
+
+/// Lay out a fragment in `out`, ready for COBS encoding, returning its length.
+///
+/// `out` must have room for the header, chunk and checksum.
+pub(crate) fn write_fragment(header: FragmentHeader, chunk: &[u8], out: &mut [u8]) -> usize {
+    let checked_length = FRAGMENT_HEADER_LENGTH + chunk.len();
+    out[..FRAGMENT_HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+    out[FRAGMENT_HEADER_LENGTH..checked_length].copy_from_slice(chunk);
+    let checksum = FRAME_CRC.checksum(&out[..checked_length]);
+    out[checked_length..checked_length + FRAME_CRC_LENGTH].copy_from_slice(&checksum.to_le_bytes());
+    checked_length + FRAME_CRC_LENGTH
+}
+
+/// Check a COBS decoded fragment, returning its header and slice of the payload.
+pub(crate) fn read_fragment(decoded: &[u8]) -> Result<(FragmentHeader, &[u8]), Error> {
+    // Fragments too short to carry a header and checksum can't be valid
+    let checked_size = decoded
+        .len()
+        .checked_sub(FRAME_CRC_LENGTH)
+        .filter(|&size| size >= FRAGMENT_HEADER_LENGTH)
+        .ok_or(Error::ChecksumMismatch)?;
+    let (checked, checksum) = decoded.split_at(checked_size);
+    if FRAME_CRC.checksum(checked).to_le_bytes() != checksum {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    let (header, chunk) = checked.split_at(FRAGMENT_HEADER_LENGTH);
+    let header =
+        FragmentHeader::from_bytes([header[0], header[1], header[2], header[3], header[4]]);
+    if header.fragment_index >= header.fragment_count {
+        return Err(Error::InvalidFragment);
+    }
+
+    Ok((header, chunk))
+}
@@ -0,0 +1,400 @@
+//! Scan matching with the iterative closest point algorithm
+//!
+//! Wheel odometry drifts, especially when the wheels slip. `align` finds the pose of a lidar sweep
+//! relative to reference points: either the previous sweep, to measure how far Mote moved between
+//! the two, or the occupied cells of a saved OccupancyGrid, to localise Mote in the map.
+//!
+//! Each iteration pairs every point of the sweep with the closest reference point, then solves for
+//! the rigid transform minimising the squared distances between the pairs in closed form, see Besl
+//! and McKay, "A Method for Registration of 3-D Shapes". Pairs further apart than
+//! `IcpConfig::max_correspondence_m` are ignored, so the initial guess, e.g. from wheel odometry,
+//! has to be within about that distance of the true pose.
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use libm::{atan2f, ceilf, floorf, hypotf, sqrtf};
+
+use crate::mapping::{OCCUPIED_THRESHOLD, OccupancyGrid};
+use crate::messages::mote_to_host::Point;
+use crate::odometry::{Pose, wrap_angle};
+use crate::scan::point_position;
+
+/// Side of the square buckets reference points are sorted into, to find the closest quickly
+const BUCKET_M: f32 = 0.25;
+
+/// A point of the sweep in Mote's frame, and the reference point it was paired with
+type Pair = ((f32, f32), (f32, f32));
+
+/// When to pair points and when to stop iterating
+#[derive(Debug, Clone, PartialEq)]
+pub struct IcpConfig {
+    /// Points further than this from every reference point are left unpaired
+    pub max_correspondence_m: f32,
+    pub max_iterations: usize,
+    /// Iteration stops once the pose moves less than both of these in an iteration
+    pub translation_tolerance_m: f32,
+    pub rotation_tolerance_rad: f32,
+    /// Matching fails if fewer points than this are paired
+    pub min_correspondences: usize,
+}
+
+impl Default for IcpConfig {
+    fn default() -> Self {
+        Self {
+            max_correspondence_m: 0.5,
+            max_iterations: 50,
+            translation_tolerance_m: 1e-4,
+            rotation_tolerance_rad: 1e-4,
+            min_correspondences: 20,
+        }
+    }
+}
+
+/// The outcome of aligning a sweep with a reference
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanMatch {
+    /// Pose the sweep was measured from, in the reference's frame
+    pub pose: Pose,
+    /// Whether the pose settled within the tolerances before `IcpConfig::max_iterations`
+    pub converged: bool,
+    pub iterations: usize,
+    /// Number of points paired in the last iteration
+    pub correspondences: usize,
+    /// Root mean square distance between paired points in the last iteration, in meters
+    pub rms_error_m: f32,
+}
+
+/// Points to align sweeps with, see the module documentation
+#[derive(Debug, Clone, Default)]
+pub struct Reference {
+    points: Vec<(f32, f32)>,
+    /// Indices of the points in each bucket
+    buckets: BTreeMap<(i32, i32), Vec<usize>>,
+}
+
+impl Reference {
+    /// Reference points in meters, in the frame matched poses should be in
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        let mut buckets: BTreeMap<(i32, i32), Vec<usize>> = BTreeMap::new();
+        for (i, &(x, y)) in points.iter().enumerate() {
+            buckets.entry(bucket(x, y)).or_default().push(i);
+        }
+        Self { points, buckets }
+    }
+
+    /// The points of a sweep, in the frame of the pose it was measured from
+    pub fn from_sweep(points: &[Point]) -> Self {
+        Self::new(points.iter().map(point_position).collect())
+    }
+
+    /// The centres of a grid's occupied cells, in the map frame
+    pub fn from_grid(grid: &OccupancyGrid) -> Self {
+        let config = grid.config();
+        let mut points = Vec::new();
+        for row in 0..config.height {
+            for column in 0..config.width {
+                if grid.probability(column, row) >= OCCUPIED_THRESHOLD {
+                    points.push((
+                        config.origin.0 + (column as f32 + 0.5) * config.resolution_m,
+                        config.origin.1 + (row as f32 + 0.5) * config.resolution_m,
+                    ));
+                }
+            }
+        }
+        Self::new(points)
+    }
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// The reference point closest to `x`, `y` within `max_distance`, and its squared distance
+    fn closest(&self, x: f32, y: f32, max_distance: f32) -> Option<((f32, f32), f32)> {
+        let (column, row) = bucket(x, y);
+        let reach = ceilf(max_distance / BUCKET_M) as i32;
+        let mut closest = None;
+        let mut closest_squared = max_distance * max_distance;
+        for bucket_row in row - reach..=row + reach {
+            for bucket_column in column - reach..=column + reach {
+                let Some(indices) = self.buckets.get(&(bucket_column, bucket_row)) else {
+                    continue;
+                };
+                for &i in indices {
+                    let point = self.points[i];
+                    let squared = (point.0 - x) * (point.0 - x) + (point.1 - y) * (point.1 - y);
+                    if squared <= closest_squared {
+                        closest = Some(point);
+                        closest_squared = squared;
+                    }
+                }
+            }
+        }
+        closest.map(|point| (point, closest_squared))
+    }
+}
+
+/// Find the pose `points` were measured from in `reference`'s frame, starting from `initial`
+///
+/// To measure the motion between consecutive sweeps, use `Reference::from_sweep` of the earlier
+/// sweep, and the motion wheel odometry measured between them as the initial guess. Returns None
+/// if too few points could be paired with reference points.
+pub fn align(
+    reference: &Reference,
+    points: &[Point],
+    initial: Pose,
+    config: &IcpConfig,
+) -> Option<ScanMatch> {
+    let scan: Vec<(f32, f32)> = points.iter().map(point_position).collect();
+    let mut pose = initial;
+    let mut pairs: Vec<Pair> = Vec::with_capacity(scan.len());
+    for iteration in 1..=config.max_iterations {
+        pairs.clear();
+        let mut squared_error = 0.0;
+        for &(x, y) in &scan {
+            let (moved_x, moved_y) = pose.transform_point(x, y);
+            if let Some((closest, squared)) =
+                reference.closest(moved_x, moved_y, config.max_correspondence_m)
+            {
+                pairs.push(((x, y), closest));
+                squared_error += squared;
+            }
+        }
+        if pairs.len() < config.min_correspondences.max(2) {
+            return None;
+        }
+
+        let next = solve(&pairs);
+        let converged = hypotf(next.x - pose.x, next.y - pose.y) < config.translation_tolerance_m
+            && wrap_angle(next.theta - pose.theta).abs() < config.rotation_tolerance_rad;
+        pose = next;
+        if converged || iteration == config.max_iterations {
+            return Some(ScanMatch {
+                pose,
+                converged,
+                iterations: iteration,
+                correspondences: pairs.len(),
+                rms_error_m: sqrtf(squared_error / pairs.len() as f32),
+            });
+        }
+    }
+    None
+}
+
+/// The pose which best maps the first point of each pair onto the second, in the least squares
+/// sense
+fn solve(pairs: &[Pair]) -> Pose {
+    let count = pairs.len() as f32;
+    let (mut source, mut target) = ((0.0, 0.0), (0.0, 0.0));
+    for &(from, to) in pairs {
+        source = (source.0 + from.0, source.1 + from.1);
+        target = (target.0 + to.0, target.1 + to.1);
+    }
+    let source = (source.0 / count, source.1 / count);
+    let target = (target.0 / count, target.1 / count);
+
+    // Cross-covariance of the centred points
+    let (mut xx, mut xy, mut yx, mut yy) = (0.0, 0.0, 0.0, 0.0);
+    for &(from, to) in pairs {
+        let from = (from.0 - source.0, from.1 - source.1);
+        let to = (to.0 - target.0, to.1 - target.1);
+        xx += from.0 * to.0;
+        xy += from.0 * to.1;
+        yx += from.1 * to.0;
+        yy += from.1 * to.1;
+    }
+    let theta = atan2f(xy - yx, xx + yy);
+
+    let rotation = Pose {
+        theta,
+        ..Default::default()
+    };
+    let (rotated_x, rotated_y) = rotation.transform_point(source.0, source.1);
+    Pose {
+        x: target.0 - rotated_x,
+        y: target.1 - rotated_y,
+        theta,
+    }
+}
+
+fn bucket(x: f32, y: f32) -> (i32, i32) {
+    (floorf(x / BUCKET_M) as i32, floorf(y / BUCKET_M) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use crate::mapping::GridConfig;
+    use crate::messages::mote_to_host;
+
+    /// Walls of a 5m by 3m room with a box in one corner, so no two poses see the same sweep
+    const ROOM: [((f32, f32), (f32, f32)); 8] = [
+        ((-2.5, -1.5), (2.5, -1.5)),
+        ((2.5, -1.5), (2.5, 1.5)),
+        ((2.5, 1.5), (-2.5, 1.5)),
+        ((-2.5, 1.5), (-2.5, -1.5)),
+        ((1.2, 0.4), (1.8, 0.4)),
+        ((1.8, 0.4), (1.8, 1.0)),
+        ((1.8, 1.0), (1.2, 1.0)),
+        ((1.2, 1.0), (1.2, 0.4)),
+    ];
+
+    /// A sweep of `ROOM` measured from `pose`, one point per degree
+    fn room_scan(pose: Pose) -> Vec<mote_to_host::Point> {
+        (0..360)
+            .map(|i| {
+                let angle_rad = (i as f32).to_radians();
+                // Bearings are clockwise from forward
+                let heading = pose.theta - angle_rad;
+                let (dx, dy) = (libm::cosf(heading), libm::sinf(heading));
+                let distance_m = ROOM
+                    .iter()
+                    .filter_map(|&(a, b)| {
+                        let (ex, ey) = (b.0 - a.0, b.1 - a.1);
+                        let (wx, wy) = (a.0 - pose.x, a.1 - pose.y);
+                        let denominator = dx * ey - dy * ex;
+                        let along_ray = (wx * ey - wy * ex) / denominator;
+                        let along_wall = (wx * dy - wy * dx) / denominator;
+                        (denominator.abs() > 1e-6
+                            && along_ray > 0.0
+                            && (0.0..=1.0).contains(&along_wall))
+                        .then_some(along_ray)
+                    })
+                    .fold(f32::MAX, f32::min);
+                mote_to_host::Point {
+                    quality: 40,
+                    angle_rad,
+                    distance_mm: distance_m * 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    fn assert_pose_near(pose: Pose, expected: Pose, tolerance_m: f32) {
+        let distance = libm::hypotf(pose.x - expected.x, pose.y - expected.y);
+        let rotation = wrap_angle(pose.theta - expected.theta).abs();
+        assert!(
+            distance < tolerance_m && rotation < tolerance_m / 2.0,
+            "{pose:?} isn't {expected:?}"
+        );
+    }
+
+    #[test]
+    fn test_pose_compose_relative() {
+        let from = Pose {
+            x: 1.0,
+            y: -0.5,
+            theta: 3.0,
+        };
+        let to = Pose {
+            x: -0.2,
+            y: 0.7,
+            theta: -2.9,
+        };
+        let motion = from.relative(to);
+        assert_pose_near(from.compose(motion), to, 1e-5);
+        assert_pose_near(Pose::default().relative(to), to, 1e-6);
+        // Turning 0.38 rad the short way round, across ±π
+        assert!((motion.theta - (core::f32::consts::TAU - 5.9)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_icp_consecutive_sweeps() {
+        let start = Pose {
+            x: 0.3,
+            y: -0.2,
+            theta: 0.4,
+        };
+        let end = Pose {
+            x: 0.55,
+            y: -0.1,
+            theta: 0.55,
+        };
+        let reference = Reference::from_sweep(&room_scan(start));
+        let config = IcpConfig::default();
+
+        // From no guess at all, and from a guess with odometry's drift
+        let motion = start.relative(end);
+        let guess = Pose {
+            x: motion.x * 0.8,
+            y: motion.y + 0.05,
+            theta: motion.theta - 0.05,
+        };
+        for initial in [Pose::default(), guess] {
+            let result = align(&reference, &room_scan(end), initial, &config).unwrap();
+            assert!(result.converged);
+            assert!(result.correspondences > 300);
+            assert!(result.rms_error_m < 0.02);
+            assert_pose_near(result.pose, motion, 0.01);
+        }
+    }
+
+    #[test]
+    fn test_icp_localises_in_map() {
+        let mut grid = OccupancyGrid::new(GridConfig::new(6.0, 4.0, 0.05));
+        for (x, y, theta) in [(0.0, 0.0, 0.0), (-1.5, 0.5, 2.0), (1.0, -0.8, -1.0)] {
+            let pose = Pose { x, y, theta };
+            grid.insert_scan(&room_scan(pose), pose);
+        }
+        let reference = Reference::from_grid(&grid);
+        assert!(!reference.points().is_empty());
+
+        let actual = Pose {
+            x: -1.0,
+            y: 0.3,
+            theta: -0.6,
+        };
+        let guess = Pose {
+            x: -0.85,
+            y: 0.4,
+            theta: -0.45,
+        };
+        let config = IcpConfig::default();
+        let result = align(&reference, &room_scan(actual), guess, &config).unwrap();
+        assert!(result.converged);
+        assert_pose_near(result.pose, actual, 0.04);
+    }
+
+    #[test]
+    fn test_icp_localises_in_loaded_map() {
+        let mut grid = OccupancyGrid::new(GridConfig::new(6.0, 4.0, 0.05));
+        for (x, y, theta) in [(0.0, 0.0, 0.0), (-1.5, 0.5, 2.0), (1.0, -0.8, -1.0)] {
+            let pose = Pose { x, y, theta };
+            grid.insert_scan(&room_scan(pose), pose);
+        }
+        let loaded = OccupancyGrid::from_pgm(&grid.to_pgm(), &grid.to_yaml("room.pgm")).unwrap();
+        assert_eq!(loaded.config().resolution_m, 0.05);
+        assert_eq!((loaded.config().width, loaded.config().height), (120, 80));
+        assert_eq!(loaded.config().origin, grid.config().origin);
+        assert_eq!(loaded.image(), grid.image());
+
+        let actual = Pose {
+            x: 0.8,
+            y: 0.2,
+            theta: 2.5,
+        };
+        let guess = Pose {
+            x: 0.65,
+            y: 0.3,
+            theta: 2.35,
+        };
+        let reference = Reference::from_grid(&loaded);
+        let config = IcpConfig::default();
+        let result = align(&reference, &room_scan(actual), guess, &config).unwrap();
+        assert!(result.converged);
+        assert_pose_near(result.pose, actual, 0.04);
+    }
+
+    #[test]
+    fn test_icp_needs_correspondences() {
+        let config = IcpConfig::default();
+        let scan = room_scan(Pose::default());
+        let empty = Reference::default();
+        assert!(align(&empty, &scan, Pose::default(), &config).is_none());
+
+        // A reference nowhere near the sweep
+        let far = Reference::new(vec![(100.0, 100.0), (100.0, 101.0), (101.0, 100.0)]);
+        assert!(align(&far, &scan, Pose::default(), &config).is_none());
+    }
+}
@@ -4,24 +4,99 @@
 
 // I'd prefer to move away from alloc, but it's here for now.
 extern crate alloc;
+#[cfg(feature = "tokio")]
+extern crate std;
 use core::marker::PhantomData;
+use core::ops::Range;
 
-use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use alloc::{collections::vec_deque::VecDeque, vec, vec::Vec};
 
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use thiserror::Error;
 
+pub mod device;
+mod frame;
+pub mod icp;
+pub mod mapping;
 pub mod messages;
+pub mod motion;
+pub mod odometry;
+pub mod packed_scan;
+pub mod scan;
+mod state;
+mod static_comms;
+pub mod timesync;
+#[cfg(any(feature = "tokio", feature = "embassy"))]
+pub mod transport;
 
+use crate::frame::{
+    FLAG_RELIABLE, FRAGMENT_HEADER_LENGTH, FRAME_CRC_LENGTH, FragmentHeader, fragment_capacity,
+    fragment_count, read_fragment, write_fragment,
+};
 use crate::messages::{host_to_mote, mote_to_host};
+use crate::state::LinkState;
+pub use crate::static_comms::*;
+
+/// Version of the wire protocol (framing and message definitions) spoken by this crate.
+///
+/// Bump this whenever a change would cause an older peer to mis-decode frames, e.g. when message
+/// variants are added or reordered.
+pub const PROTOCOL_VERSION: u16 = 11;
+
+/// Implemented by message types so that MoteComms can inspect the version handshake and pick a
+/// delivery mode.
+pub trait ProtocolMessage {
+    /// Protocol version advertised by the peer, if this message is part of the handshake
+    fn protocol_version(&self) -> Option<u16>;
+
+    /// Whether MoteComms::send should deliver this message reliably, see MoteComms::send_reliable
+    fn reliable(&self) -> bool {
+        false
+    }
+
+    /// Transmit queue this message joins, see MoteComms::poll_transmit
+    fn priority(&self) -> Priority {
+        Priority::Control
+    }
+}
+
+/// Transmit priority classes. Each class has its own queue, and control traffic is always sent
+/// before telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Commands and replies. Never dropped once queued, a send is refused instead if the queue
+    /// is full.
+    Control,
+    /// Data streamed continuously, which is stale once newer data exists. The oldest queued
+    /// messages are dropped to make room for new ones.
+    Telemetry,
+}
 
 /// Error type
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("Bitcode ser/de failed")]
-    BitCodeError(#[from] bitcode::Error),
+    #[error("Postcard ser/de failed")]
+    PostcardError(#[from] postcard::Error),
     #[error("Cobs pack/unpack failed")]
     CobsError(corncobs::CobsError),
+    #[error("Frame checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Message too large to fragment")]
+    MessageTooLarge,
+    #[error("Fragment header is inconsistent")]
+    InvalidFragment,
+    #[error("Transmit queue is full")]
+    TransmitQueueFull,
+    #[error("Received frame is longer than the MTU")]
+    FrameTooLong,
+    #[error("Receive buffer is too small for the frame")]
+    BufferTooSmall,
+    #[error("Peer speaks protocol version {peer}, but this link speaks version {local}")]
+    IncompatibleProtocol { local: u16, peer: u16 },
+    #[error("Packed scan data is truncated or malformed")]
+    MalformedScan,
+    #[error("Map image or metadata is truncated or malformed")]
+    MalformedMap,
 }
 
 impl From<corncobs::CobsError> for Error {
@@ -30,135 +105,567 @@ impl From<corncobs::CobsError> for Error {
     }
 }
 
-/// Implements encoding of message types.
-fn to_slice<M>(message: &M) -> Result<Vec<u8>, Error>
+/// Link quality counters, see MoteComms::stats
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Frames queued for transmission
+    pub frames_sent: u32,
+    /// Frames received and decoded
+    pub frames_received: u32,
+    /// Frames inferred lost from gaps in the received sequence numbers
+    pub frames_dropped: u32,
+    /// Frames rejected while decoding (checksum, COBS or postcard failures)
+    pub frames_corrupt: u32,
+    /// Frames received after a frame with a newer sequence number
+    pub frames_out_of_order: u32,
+    /// Fragments discarded because the rest of their frame never arrived
+    pub fragments_expired: u32,
+    /// Reliable frames sent again after going unacknowledged
+    pub retransmissions: u32,
+    /// Reliable frames given up on after MAX_RETRANSMISSIONS
+    pub delivery_failures: u32,
+    /// Reliable frames received more than once, and not delivered again
+    pub duplicates_suppressed: u32,
+    /// Telemetry frames dropped from a full transmit queue before being sent
+    pub frames_discarded: u32,
+    /// Times the receive buffer filled up and a partially received frame was discarded
+    pub receive_overflows: u32,
+}
+
+/// Partially reassembled frames older than this are discarded, see MoteComms::handle_time
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 1000;
+
+/// Number of frames which may be partially reassembled at once. When full, the oldest is discarded.
+const MAX_PARTIAL_FRAMES: usize = 4;
+
+/// Reliable frames are sent again if they haven't been acknowledged after this long
+pub const RETRANSMIT_TIMEOUT_MS: u64 = 250;
+
+/// Reliable frames are given up on after being sent again this many times
+pub const MAX_RETRANSMISSIONS: u8 = 8;
+
+/// Number of reliable frames which may await acknowledgement at once. When full, the oldest is
+/// given up on.
+const MAX_UNACKNOWLEDGED: usize = 16;
+
+/// Serialize a message and split it into COBS encoded fragments, see the frame module.
+fn to_fragments<M>(
+    sequence: u16,
+    flags: u8,
+    mtu: usize,
+    max_message: usize,
+    message: &M,
+) -> Result<Vec<Vec<u8>>, Error>
 where
     M: Serialize + ?Sized,
 {
-    let ser_buff = bitcode::serialize(message)?;
+    let payload = postcard::to_allocvec(message)?;
+    if payload.len() > max_message {
+        return Err(Error::MessageTooLarge);
+    }
+    let fragment_count = fragment_count(payload.len(), mtu)?;
+
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![&payload[..]]
+    } else {
+        payload.chunks(fragment_capacity(mtu)).collect()
+    };
+    Ok(chunks
+        .into_iter()
+        .zip(0..)
+        .map(|(chunk, fragment_index)| {
+            let header = FragmentHeader {
+                sequence,
+                fragment_index,
+                fragment_count,
+                flags,
+            };
+            encode_fragment(header, chunk)
+        })
+        .collect())
+}
+
+/// COBS encode a single fragment
+fn encode_fragment(header: FragmentHeader, chunk: &[u8]) -> Vec<u8> {
+    let mut ser_buff = vec![0; FRAGMENT_HEADER_LENGTH + chunk.len() + FRAME_CRC_LENGTH];
+    write_fragment(header, chunk, &mut ser_buff);
+
     let encoded_size = corncobs::max_encoded_len(ser_buff.len());
     let mut cobs_buff: Vec<u8> = Vec::with_capacity(encoded_size);
     cobs_buff.resize(encoded_size, 10);
     let encoded_size = corncobs::encode_buf(&ser_buff, &mut cobs_buff);
     cobs_buff.truncate(encoded_size);
 
-    Ok(cobs_buff)
+    cobs_buff
 }
 
-/// Implements decoding of message types.
-fn from_bytes<M>(bytes: &[u8]) -> Result<M, Error>
-where
-    M: DeserializeOwned,
-{
-    let mut cobs_buff: Vec<u8> = Vec::with_capacity(bytes.len());
-    cobs_buff.resize(bytes.len(), 10);
-    let decoded_size = corncobs::decode_buf(bytes, &mut cobs_buff)?;
-    cobs_buff.truncate(decoded_size);
+/// A frame which has received some, but not all, of its fragments
+struct PartialFrame {
+    sequence: u16,
+    fragments: Vec<Option<Vec<u8>>>,
+    fragments_received: usize,
+    length: usize,
+    started_ms: u64,
+}
 
-    Ok(bitcode::deserialize::<M>(&cobs_buff)?)
+/// A reliable frame which has been sent, but not yet acknowledged
+struct Unacknowledged {
+    sequence: u16,
+    fragments: Vec<Vec<u8>>,
+    last_sent_ms: u64,
+    retransmissions: u8,
 }
 
-// Sets the capacity for the deserialization ringbuffer
-const MAX_MESSAGE_LENGTH: usize = 5000;
+/// Default number of packets each transmit queue holds, see MoteComms::with_transmit_capacity
+pub const DEFAULT_TRANSMIT_CAPACITY: usize = 64;
 
 /// Bidirectional SansIO communication link betweek mote and the host.
 ///
+/// MAX_MESSAGE caps the serialized length of a message, and the number of received bytes
+/// buffered while waiting for poll_receive.
+///
 /// You probably do not want to directly construct this. Instead, use the type aliases:
 /// MoteLink (use on host)
 /// HostLink (use on mote)
 /// MoteConfigLink
 /// HostConfigLink
-pub struct MoteComms<const MTU: usize, I, O>
+pub struct MoteComms<const MTU: usize, const MAX_MESSAGE: usize, I, O>
 where
     I: DeserializeOwned, // Input type
     O: Serialize,        // Output type
 {
-    buffered_transmits: VecDeque<Vec<u8>>,
+    control_transmits: VecDeque<Vec<u8>>,
+    /// Telemetry packets, tagged with their frame's sequence number
+    telemetry_transmits: VecDeque<(u16, Vec<u8>)>,
+    transmit_capacity: usize,
     deserialization_buffer: VecDeque<u8>,
+    /// Dropping received bytes up to the next zero delimiter, after the buffer overflowed
+    resynchronising: bool,
+    receive_scratch: Vec<u8>,
+    partial_frames: VecDeque<PartialFrame>,
+    unacknowledged: VecDeque<Unacknowledged>,
+    state: LinkState,
 
     in_type: PhantomData<I>,
     out_type: PhantomData<O>,
 }
-impl<const MTU: usize, I, O> Default for MoteComms<MTU, I, O>
+impl<const MTU: usize, const MAX_MESSAGE: usize, I, O> Default for MoteComms<MTU, MAX_MESSAGE, I, O>
 where
-    I: for<'de> Deserialize<'de>, // Input type
-    O: Serialize,
+    I: for<'de> Deserialize<'de> + ProtocolMessage, // Input type
+    O: Serialize + ProtocolMessage,
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<const MTU: usize, I, O> MoteComms<MTU, I, O>
+impl<const MTU: usize, const MAX_MESSAGE: usize, I, O> MoteComms<MTU, MAX_MESSAGE, I, O>
 where
-    I: for<'de> Deserialize<'de>, // Input type
-    O: Serialize,                 // Output type
+    I: for<'de> Deserialize<'de> + ProtocolMessage, // Input type
+    O: Serialize + ProtocolMessage,                 // Output type
 {
     /// Generate a new link
     pub fn new() -> Self {
+        Self::with_transmit_capacity(DEFAULT_TRANSMIT_CAPACITY)
+    }
+
+    /// Generate a new link whose transmit queues each hold at most `capacity` packets
+    ///
+    /// Acknowledgements and retransmissions of reliable messages don't count towards the limit.
+    pub fn with_transmit_capacity(capacity: usize) -> Self {
         Self {
-            buffered_transmits: VecDeque::new(),
+            control_transmits: VecDeque::new(),
+            telemetry_transmits: VecDeque::new(),
+            transmit_capacity: capacity,
             deserialization_buffer: VecDeque::new(),
+            resynchronising: false,
+            receive_scratch: Vec::new(),
+            partial_frames: VecDeque::new(),
+            unacknowledged: VecDeque::new(),
+            state: LinkState::new(),
             in_type: PhantomData,
             out_type: PhantomData,
         }
     }
 
     /// Queue a message to be sent
+    ///
+    /// Messages larger than the MTU are split across several packets, each carrying a fragment
+    /// header so the receiver can reassemble them in any order. Messages which serialize to more
+    /// than MAX_MESSAGE bytes are refused with `Error::MessageTooLarge`.
+    ///
+    /// Messages are sent reliably if ProtocolMessage::reliable says so, otherwise best-effort.
+    ///
+    /// Messages are queued by ProtocolMessage::priority. Queueing telemetry drops the oldest
+    /// queued telemetry messages if there isn't room for it. Control messages are refused with
+    /// `Error::TransmitQueueFull` instead.
     pub fn send(&mut self, message: O) -> Result<(), Error> {
-        let encoded_bytes: Vec<u8> = to_slice(&message)?;
+        let reliable = message.reliable();
+        self.queue(&message, reliable)
+    }
+
+    /// Queue a message to be sent reliably, regardless of ProtocolMessage::reliable
+    ///
+    /// The peer acknowledges reliable messages, and suppresses any duplicates it receives. Until
+    /// then, poll_transmit sends the message again every RETRANSMIT_TIMEOUT_MS, giving up after
+    /// MAX_RETRANSMISSIONS. Retransmission is timed by handle_time, so it must be called
+    /// periodically for reliable messages to be retried.
+    ///
+    /// Reliable messages are always queued as control traffic.
+    pub fn send_reliable(&mut self, message: O) -> Result<(), Error> {
+        self.queue(&message, true)
+    }
+
+    fn queue(&mut self, message: &O, reliable: bool) -> Result<(), Error> {
+        let sequence = self.state.transmit_sequence();
+        let flags = if reliable { FLAG_RELIABLE } else { 0 };
+        let fragments = to_fragments(sequence, flags, MTU, MAX_MESSAGE, message)?;
+        let priority = if reliable {
+            Priority::Control
+        } else {
+            message.priority()
+        };
+        self.make_room(priority, fragments.len())?;
+        self.state.advance_transmit_sequence();
 
-        // Break message into packets given the MTU
-        for chunk in encoded_bytes.chunks(MTU) {
-            self.buffered_transmits.push_back(Vec::from(chunk));
+        if reliable {
+            if self.unacknowledged.len() >= MAX_UNACKNOWLEDGED {
+                self.unacknowledged.pop_front();
+                self.state.stats.delivery_failures =
+                    self.state.stats.delivery_failures.wrapping_add(1);
+            }
+            self.unacknowledged.push_back(Unacknowledged {
+                sequence,
+                fragments: fragments.clone(),
+                last_sent_ms: self.state.now_ms,
+                retransmissions: 0,
+            });
         }
+        match priority {
+            Priority::Control => self.control_transmits.extend(fragments),
+            Priority::Telemetry => self
+                .telemetry_transmits
+                .extend(fragments.into_iter().map(|packet| (sequence, packet))),
+        }
+
+        Ok(())
+    }
 
+    /// Ensure the queue for `priority` can take another `packets` packets
+    fn make_room(&mut self, priority: Priority, packets: usize) -> Result<(), Error> {
+        if packets > self.transmit_capacity {
+            return Err(Error::TransmitQueueFull);
+        }
+        match priority {
+            Priority::Control => {
+                if self.control_transmits.len() + packets > self.transmit_capacity {
+                    return Err(Error::TransmitQueueFull);
+                }
+            }
+            Priority::Telemetry => {
+                while self.telemetry_transmits.len() + packets > self.transmit_capacity {
+                    // Drop every remaining packet of the oldest message, a partial one is useless
+                    let Some(&(oldest, _)) = self.telemetry_transmits.front() else {
+                        break;
+                    };
+                    while self
+                        .telemetry_transmits
+                        .front()
+                        .is_some_and(|&(sequence, _)| sequence == oldest)
+                    {
+                        self.telemetry_transmits.pop_front();
+                    }
+                    self.state.stats.frames_discarded =
+                        self.state.stats.frames_discarded.wrapping_add(1);
+                }
+            }
+        }
         Ok(())
     }
 
     /// Get the next packet to be sent
+    ///
+    /// Control packets are sent before any telemetry. Once all control traffic has been sent,
+    /// reliable messages whose acknowledgement is overdue are queued again, ahead of telemetry.
     pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
-        self.buffered_transmits.pop_front()
+        if self.control_transmits.is_empty() {
+            self.queue_retransmissions();
+        }
+        self.control_transmits.pop_front().or_else(|| {
+            self.telemetry_transmits
+                .pop_front()
+                .map(|(_, packet)| packet)
+        })
+    }
+
+    fn queue_retransmissions(&mut self) {
+        let now_ms = self.state.now_ms;
+        let stats = &mut self.state.stats;
+        let control_transmits = &mut self.control_transmits;
+        self.unacknowledged.retain_mut(|pending| {
+            if now_ms.saturating_sub(pending.last_sent_ms) < RETRANSMIT_TIMEOUT_MS {
+                return true;
+            }
+            if pending.retransmissions >= MAX_RETRANSMISSIONS {
+                stats.delivery_failures = stats.delivery_failures.wrapping_add(1);
+                return false;
+            }
+            pending.retransmissions += 1;
+            pending.last_sent_ms = now_ms;
+            stats.retransmissions = stats.retransmissions.wrapping_add(1);
+            control_transmits.extend(pending.fragments.iter().cloned());
+            true
+        });
+    }
+
+    /// Number of reliable messages which have not been acknowledged by the peer yet
+    pub fn unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
     }
 
     /// Receive a message from raw bytes
+    ///
+    /// At most MAX_MESSAGE bytes are buffered. If the buffer overflows, the oldest frame is
+    /// discarded, or the frame being received if it is the only one, and
+    /// LinkStats::receive_overflows is incremented.
     pub fn handle_receive(&mut self, packet: &[u8]) {
-        // Push the recieved bytes into the serialization buffer, potentially dropping the first
-        // value if the buffer is full
-        packet.iter().for_each(|byte| {
-            self.deserialization_buffer.push_back(*byte);
-            if self.deserialization_buffer.len() > MAX_MESSAGE_LENGTH {
-                self.deserialization_buffer.pop_front();
+        for &byte in packet {
+            if self.resynchronising {
+                // Skip the rest of the discarded frame
+                self.resynchronising = byte != 0;
+                continue;
+            }
+            if self.deserialization_buffer.len() >= MAX_MESSAGE {
+                self.state.stats.receive_overflows =
+                    self.state.stats.receive_overflows.wrapping_add(1);
+                // Drop bytes up to and including the first delimiter, so the buffer starts on a
+                // frame boundary again
+                match self.deserialization_buffer.iter().position(|&b| b == 0) {
+                    Some(end) => {
+                        self.deserialization_buffer.drain(..=end);
+                    }
+                    None => {
+                        self.deserialization_buffer.clear();
+                        self.resynchronising = byte != 0;
+                        continue;
+                    }
+                }
+            }
+            self.deserialization_buffer.push_back(byte);
+        }
+    }
+
+    /// Advance the link's clock, in milliseconds since any fixed point.
+    ///
+    /// Frames which are still missing fragments REASSEMBLY_TIMEOUT_MS after their first fragment
+    /// arrived are discarded. Links which are never given the time only discard incomplete frames
+    /// once too many are waiting, and never retransmit reliable messages.
+    pub fn handle_time(&mut self, now_ms: u64) {
+        self.state.now_ms = now_ms;
+
+        let stats = &mut self.state.stats;
+        self.partial_frames.retain(|partial| {
+            let expired = now_ms.saturating_sub(partial.started_ms) > REASSEMBLY_TIMEOUT_MS;
+            if expired {
+                stats.fragments_expired = stats
+                    .fragments_expired
+                    .wrapping_add(partial.fragments_received as u32);
             }
+            !expired
         });
     }
 
     /// Poll for new messages in the recv buffer
+    ///
+    /// Fragments that fail their checksum are dropped and reported as `Error::ChecksumMismatch`.
+    ///
+    /// Handshake messages are always returned, so that they can be answered and inspected. Once
+    /// the peer has advertised an incompatible protocol version, all other messages are refused
+    /// with `Error::IncompatibleProtocol`.
+    ///
+    /// Receiving a reliable message queues an acknowledgement for poll_transmit.
     pub fn poll_receive(&mut self) -> Result<Option<I>, Error> {
-        if let Some(end) = self.deserialization_buffer.iter().position(|&x| x == 0) {
-            let linear_buf: Vec<u8> = self.deserialization_buffer.drain(0..=end).collect();
-            match from_bytes::<I>(&linear_buf) {
-                Ok(msg) => Ok(Some(msg)),
-                Err(Error::BitCodeError(err)) => Err(err.into()),
-                Err(Error::CobsError(corncobs::CobsError::Corrupt)) => {
-                    Err(Error::CobsError(corncobs::CobsError::Corrupt))
-                }
+        let mut buffer = core::mem::take(&mut self.receive_scratch);
+        // The deserialization buffer caps both frames and reassembled messages at this length
+        buffer.resize(MAX_MESSAGE, 0);
+        let received = self.poll_receive_in(&mut buffer);
+        self.receive_scratch = buffer;
+        received
+    }
+
+    /// Poll for new messages in the recv buffer, decoding them in `buffer`
+    ///
+    /// Behaves like poll_receive, but each frame is COBS decoded in place in the caller's buffer
+    /// and the message deserialized straight from it. M is usually I, but may instead be a type
+    /// with the same serialized layout which borrows strings and byte slices from `buffer`.
+    ///
+    /// Frames or reassembled messages too long for `buffer` are dropped and reported as
+    /// `Error::BufferTooSmall`.
+    pub fn poll_receive_in<'b, M>(&mut self, buffer: &'b mut [u8]) -> Result<Option<M>, Error>
+    where
+        M: Deserialize<'b> + ProtocolMessage,
+    {
+        let Some((header, payload)) = self.receive_payload(buffer)? else {
+            return Ok(None);
+        };
+        let buffer: &'b [u8] = buffer;
+
+        let msg = self.state.decode::<M>(header, &buffer[payload])?;
+        if header.is_reliable() {
+            self.queue_ack(header.sequence);
+        }
+        self.state.accept(msg)
+    }
+
+    /// Decode fragments into `buffer` until one completes a new frame, returning its header and
+    /// where its payload lies in `buffer`
+    fn receive_payload(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Result<Option<(FragmentHeader, Range<usize>)>, Error> {
+        while let Some(end) = self.deserialization_buffer.iter().position(|&x| x == 0) {
+            let length = end + 1;
+            if length > buffer.len() {
+                self.deserialization_buffer.drain(..length);
+                self.state.stats.frames_corrupt = self.state.stats.frames_corrupt.wrapping_add(1);
+                return Err(Error::BufferTooSmall);
+            }
+            for (slot, byte) in buffer
+                .iter_mut()
+                .zip(self.deserialization_buffer.drain(..length))
+            {
+                *slot = byte;
+            }
+
+            let fragment = corncobs::decode_in_place(&mut buffer[..length])
+                .map_err(Error::from)
+                .and_then(|decoded_size| read_fragment(&buffer[..decoded_size]));
+            let (header, chunk) = match fragment {
+                Ok(fragment) => fragment,
                 Err(Error::CobsError(corncobs::CobsError::Truncated)) => {
-                    // We checked for this in the if above, so it shouldn't happen.
+                    // We checked for the end byte above, so it shouldn't happen.
                     // But it isn't an error.
-                    Ok(None)
+                    continue;
+                }
+                Err(err) => {
+                    self.state.stats.frames_corrupt =
+                        self.state.stats.frames_corrupt.wrapping_add(1);
+                    return Err(err);
                 }
+            };
+
+            if header.is_ack() {
+                self.unacknowledged
+                    .retain(|pending| pending.sequence != header.sequence);
+                continue;
             }
-        } else {
-            // No end byte = no message
-            Ok(None)
+
+            let payload = if header.fragment_count == 1 {
+                // The chunk directly follows the header
+                FRAGMENT_HEADER_LENGTH..FRAGMENT_HEADER_LENGTH + chunk.len()
+            } else {
+                let Some(payload) = self.reassemble(header, chunk) else {
+                    continue;
+                };
+                let Some(destination) = buffer.get_mut(..payload.len()) else {
+                    self.state.stats.frames_corrupt =
+                        self.state.stats.frames_corrupt.wrapping_add(1);
+                    return Err(Error::BufferTooSmall);
+                };
+                destination.copy_from_slice(&payload);
+                0..payload.len()
+            };
+            if self.state.is_duplicate(header) {
+                // Our acknowledgement was lost, so send it again
+                self.queue_ack(header.sequence);
+                continue;
+            }
+            return Ok(Some((header, payload)));
+        }
+
+        // No end byte = no message
+        Ok(None)
+    }
+
+    /// Queue an acknowledgement of the reliable frame with the given sequence number
+    fn queue_ack(&mut self, sequence: u16) {
+        self.control_transmits
+            .push_back(encode_fragment(FragmentHeader::ack(sequence), &[]));
+    }
+
+    /// Store a received fragment, returning the frame's payload once every fragment has arrived
+    fn reassemble(&mut self, header: FragmentHeader, chunk: &[u8]) -> Option<Vec<u8>> {
+        let fragment_count = header.fragment_count as usize;
+        let position = self.partial_frames.iter().position(|partial| {
+            partial.sequence == header.sequence && partial.fragments.len() == fragment_count
+        });
+        let position = match position {
+            Some(position) => position,
+            None => {
+                if self.partial_frames.len() >= MAX_PARTIAL_FRAMES
+                    && let Some(oldest) = self.partial_frames.pop_front()
+                {
+                    self.state.stats.fragments_expired = self
+                        .state
+                        .stats
+                        .fragments_expired
+                        .wrapping_add(oldest.fragments_received as u32);
+                }
+                self.partial_frames.push_back(PartialFrame {
+                    sequence: header.sequence,
+                    fragments: vec![None; fragment_count],
+                    fragments_received: 0,
+                    length: 0,
+                    started_ms: self.state.now_ms,
+                });
+                self.partial_frames.len() - 1
+            }
+        };
+
+        let partial = &mut self.partial_frames[position];
+        let slot = &mut partial.fragments[header.fragment_index as usize];
+        if slot.is_some() {
+            // Duplicate fragment
+            return None;
         }
+        partial.length += chunk.len();
+        partial.fragments_received += 1;
+        *slot = Some(Vec::from(chunk));
+
+        if partial.length > MAX_MESSAGE {
+            // Larger than we would ever accept, stop buffering it
+            let oversized = self.partial_frames.remove(position)?;
+            self.state.stats.frames_corrupt = self.state.stats.frames_corrupt.wrapping_add(1);
+            self.state.stats.fragments_expired = self
+                .state
+                .stats
+                .fragments_expired
+                .wrapping_add(oversized.fragments_received as u32);
+            return None;
+        }
+        if partial.fragments_received < fragment_count {
+            return None;
+        }
+
+        let complete = self.partial_frames.remove(position)?;
+        Some(complete.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    /// Link quality counters
+    pub fn stats(&self) -> LinkStats {
+        self.state.stats
+    }
+
+    /// Protocol version advertised by the peer during the handshake, if one has been received
+    pub fn peer_protocol_version(&self) -> Option<u16> {
+        self.state.peer_protocol_version
     }
 }
 
 /// Used by the host to send commands to and receive data from Mote
 pub type MoteLink = MoteComms<
     1400, // UDP MTU(ish)
+    5000, // Max message
     mote_to_host::Message,
     host_to_mote::Message,
 >;
@@ -166,20 +673,23 @@ pub type MoteLink = MoteComms<
 /// Used by Mote to send data to and receive commands from the host
 pub type HostLink = MoteComms<
     1400, // UDP MTU(ish)
+    5000, // Max message
     host_to_mote::Message,
     mote_to_host::Message,
 >;
 
 /// Used by the host to send commands to and receive data from Mote
 pub type MoteConfigLink = MoteComms<
-    64, // Serial MTU
+    64,   // Serial MTU
+    2048, // Max message
     mote_to_host::Message,
     host_to_mote::Message,
 >;
 
 /// Used by Mote to send data to and receive commands from the host
 pub type HostConfigLink = MoteComms<
-    64, // Serial MTU
+    64,   // Serial MTU
+    2048, // Max message
     host_to_mote::Message,
     mote_to_host::Message,
 >;
@@ -189,23 +699,79 @@ mod tests {
     use super::*;
     use alloc::{boxed::Box, string::String, vec};
 
+    use crate::packed_scan::tests::raw_lidar_points;
+
+    // Max message length of MoteLink and HostLink
+    const UDP_MAX_MESSAGE: usize = 5000;
+
+    fn hello_ack(protocol_version: u16) -> mote_to_host::HelloAck {
+        mote_to_host::HelloAck {
+            protocol_version,
+            firmware_version: String::from("0.1.0"),
+            git_hash: String::from("c8c9062"),
+            capabilities: mote_to_host::capabilities::LIDAR | mote_to_host::capabilities::IMU,
+        }
+    }
+
     // Returns all mote_to_host message variants including heap-allocated ones.
     fn all_mote_messages() -> Vec<mote_to_host::Message> {
         vec![
             mote_to_host::Message::Ping,
             mote_to_host::Message::Pong,
-            mote_to_host::Message::Scan(vec![
-                mote_to_host::Point {
-                    quality: 255,
-                    angle_rad: 1.5707,
-                    distance_mm: 500.0,
+            mote_to_host::Message::HelloAck(hello_ack(PROTOCOL_VERSION)),
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 7,
+                result: Some(Box::new(mote_to_host::Message::Pong)),
+            }),
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 8,
+                result: None,
+            }),
+            mote_to_host::Message::Nack(mote_to_host::Nack {
+                id: 9,
+                reason: mote_to_host::NackReason::Unsupported,
+            }),
+            mote_to_host::Message::Scan(mote_to_host::Scan {
+                timestamp_us: 12_345_678,
+                points: vec![
+                    mote_to_host::Point {
+                        quality: 255,
+                        angle_rad: 1.5707,
+                        distance_mm: 500.0,
+                    },
+                    mote_to_host::Point {
+                        quality: 0,
+                        angle_rad: 0.0,
+                        distance_mm: 0.0,
+                    },
+                ],
+            }),
+            mote_to_host::Message::DriveBaseState(mote_to_host::DriveBaseState {
+                timestamp_us: 12_400_000,
+                left: mote_to_host::WheelJointState {
+                    effort_percent: 40.0,
+                    velocity_rad_per_s: 5.0,
+                    postition_rad: 12.5,
+                },
+                right: mote_to_host::WheelJointState {
+                    effort_percent: -40.0,
+                    velocity_rad_per_s: -5.0,
+                    postition_rad: -12.5,
+                },
+            }),
+            mote_to_host::Message::IMUMeasurement(mote_to_host::IMUMeasurement {
+                timestamp_us: u64::MAX,
+                accel: mote_to_host::IMUAxisTriple {
+                    x: 0.1,
+                    y: -0.2,
+                    z: 9.8,
                 },
-                mote_to_host::Point {
-                    quality: 0,
-                    angle_rad: 0.0,
-                    distance_mm: 0.0,
+                gyro: mote_to_host::IMUAxisTriple {
+                    x: 0.0,
+                    y: 0.01,
+                    z: -1.5,
                 },
-            ]),
+            }),
             mote_to_host::Message::State(Box::new(mote_to_host::State {
                 uid: String::from("mote-test"),
                 ip: Some(String::from("192.168.1.100")),
@@ -232,6 +798,23 @@ mod tests {
                     }],
                 },
             })),
+            mote_to_host::Message::TimeSyncReply(mote_to_host::TimeSyncReply {
+                host_t0: 1_700_000_000_000_000,
+                device_t1: 12_500_000,
+                device_t2: 12_500_150,
+            }),
+            mote_to_host::Message::PackedScan(mote_to_host::PackedScan::new(
+                12_600_000,
+                mote_to_host::PointEncoding::DeltaAngle,
+                raw_lidar_points(0, 10),
+            )),
+            mote_to_host::Message::MoveFinished(mote_to_host::MoveFinished {
+                id: 3,
+                timestamp_us: 12_700_000,
+                outcome: mote_to_host::MoveOutcome::Completed,
+                left_error_rad: 0.01,
+                right_error_rad: -0.02,
+            }),
         ]
     }
 
@@ -240,6 +823,11 @@ mod tests {
         vec![
             host_to_mote::Message::Ping,
             host_to_mote::Message::Pong,
+            host_to_mote::Message::Hello(host_to_mote::Hello::default()),
+            host_to_mote::Message::Request(host_to_mote::Request {
+                id: 7,
+                message: Box::new(host_to_mote::Message::Ping),
+            }),
             host_to_mote::Message::RequestNetworkScan,
             host_to_mote::Message::SetNetworkConnectionConfig(
                 host_to_mote::SetNetworkConnectionConfig {
@@ -250,22 +838,67 @@ mod tests {
             host_to_mote::Message::SetUID(host_to_mote::SetUID {
                 uid: String::from("mote-abc"),
             }),
+            host_to_mote::Message::TimeSyncRequest(host_to_mote::TimeSyncRequest {
+                host_t0: 1_700_000_000_000_000,
+            }),
+            host_to_mote::Message::ConfigureTelemetry(host_to_mote::ConfigureTelemetry {
+                scan: host_to_mote::ScanTelemetry {
+                    enabled: false,
+                    decimation: 4,
+                },
+                imu: host_to_mote::PeriodicTelemetry {
+                    enabled: true,
+                    period_ms: 50,
+                },
+                ..Default::default()
+            }),
+            host_to_mote::Message::MoveWheels(host_to_mote::MoveWheels {
+                id: 3,
+                left_rad: 10.0,
+                right_rad: -10.0,
+                max_velocity_rad_per_s: 8.0,
+                max_acceleration_rad_per_s2: 20.0,
+            }),
         ]
     }
 
     // --- encode / decode ---
 
+    // Implements decoding of a single fragment, returning its header and slice of the payload.
+    fn from_bytes(bytes: &[u8]) -> Result<(FragmentHeader, Vec<u8>), Error> {
+        let mut cobs_buff: Vec<u8> = Vec::with_capacity(bytes.len());
+        cobs_buff.resize(bytes.len(), 10);
+        let decoded_size = corncobs::decode_buf(bytes, &mut cobs_buff)?;
+
+        let (header, chunk) = read_fragment(&cobs_buff[..decoded_size])?;
+        Ok((header, Vec::from(chunk)))
+    }
+
+    // Encode a message which fits in a single fragment
+    fn to_slice<M: Serialize>(sequence: u16, message: &M) -> Result<Vec<u8>, Error> {
+        let mut fragments = to_fragments(sequence, 0, 1400, UDP_MAX_MESSAGE, message)?;
+        assert_eq!(fragments.len(), 1);
+        Ok(fragments.remove(0))
+    }
+
+    // Decode a single fragment message
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<(u16, M), Error> {
+        let (header, chunk) = from_bytes(bytes)?;
+        assert_eq!((header.fragment_index, header.fragment_count), (0, 1));
+        Ok((header.sequence, postcard::from_bytes(&chunk)?))
+    }
+
     #[test]
     fn test_encode_decode_all_variants() -> Result<(), Error> {
-        for msg in all_mote_messages() {
-            let bytes = to_slice(&msg)?;
-            let recv: mote_to_host::Message = from_bytes(&bytes)?;
-            assert_eq!(msg, recv);
+        for (sequence, msg) in all_mote_messages().into_iter().enumerate() {
+            let bytes = to_slice(sequence as u16, &msg)?;
+            let recv: (u16, mote_to_host::Message) = decode(&bytes)?;
+            assert_eq!((sequence as u16, msg), recv);
         }
-        for msg in all_host_messages() {
-            let bytes = to_slice(&msg)?;
-            let recv: host_to_mote::Message = from_bytes(&bytes)?;
-            assert_eq!(msg, recv);
+        for (sequence, msg) in all_host_messages().into_iter().enumerate() {
+            let bytes = to_slice(sequence as u16, &msg)?;
+            let recv: (u16, host_to_mote::Message) = decode(&bytes)?;
+            assert_eq!((sequence as u16, msg), recv);
         }
         Ok(())
     }
@@ -351,15 +984,16 @@ mod tests {
 
     #[test]
     fn test_fragmentation() -> Result<(), Error> {
-        let scan = mote_to_host::Message::Scan(
-            (0..100u8)
+        let scan = mote_to_host::Message::Scan(mote_to_host::Scan {
+            timestamp_us: 0,
+            points: (0..100u8)
                 .map(|i| mote_to_host::Point {
                     quality: i,
                     angle_rad: i as f32 * 0.01,
                     distance_mm: i as f32 * 10.0,
                 })
                 .collect(),
-        );
+        });
 
         let mut host_l = HostConfigLink::new(); // MTU = 64
         host_l.send(scan.clone())?;
@@ -421,22 +1055,1758 @@ mod tests {
     fn test_empty_cobs_payload_returns_error() {
         let mut link = MoteLink::new();
         // [0x01, 0x00] is a valid COBS frame (overhead byte 0x01 = no data, then
-        // terminator), but the empty decoded payload cannot be deserialized as a
-        // message — bitcode returns an error.
+        // terminator), but the empty decoded payload is too short to carry a
+        // checksum.
         link.handle_receive(&[0x01, 0x00]);
-        assert!(link.poll_receive().is_err());
+        assert!(matches!(link.poll_receive(), Err(Error::ChecksumMismatch)));
+    }
+
+    // --- Frame checksum ---
+
+    // Decode a COBS frame, flip a single bit of the decoded bytes, then re-encode it so the
+    // corruption is only detectable by the checksum.
+    fn flip_decoded_bit(frame: &[u8], bit: usize) -> Vec<u8> {
+        let mut decoded = vec![0u8; frame.len()];
+        let decoded_size = corncobs::decode_buf(frame, &mut decoded).unwrap();
+        decoded.truncate(decoded_size);
+        decoded[bit / 8] ^= 1 << (bit % 8);
+
+        let mut encoded = vec![0u8; corncobs::max_encoded_len(decoded.len())];
+        let encoded_size = corncobs::encode_buf(&decoded, &mut encoded);
+        encoded.truncate(encoded_size);
+        encoded
     }
 
-    // --- Receive buffer is capped at MAX_MESSAGE_LENGTH ---
+    #[test]
+    fn test_checksum_rejects_single_bit_flips() -> Result<(), Error> {
+        let command = host_to_mote::Message::DriveBaseCommand(host_to_mote::SetDriveBaseVelocity {
+            left_velocity_rad: 1.0,
+            right_velocity_rad: -1.0,
+        });
+        let frame = to_slice(0, &command)?;
+        let decoded_size = corncobs::decode_buf(&frame, &mut vec![0u8; frame.len()])?;
+
+        for bit in 0..decoded_size * 8 {
+            let mut link = HostLink::new();
+            link.handle_receive(&flip_decoded_bit(&frame, bit));
+            assert!(
+                matches!(link.poll_receive(), Err(Error::ChecksumMismatch)),
+                "bit {bit} flip was not rejected"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_bit_flips_never_deliver_a_message() -> Result<(), Error> {
+        let command = host_to_mote::Message::DriveBaseCommand(host_to_mote::SetDriveBaseVelocity {
+            left_velocity_rad: 0.5,
+            right_velocity_rad: 0.5,
+        });
+        let frame = to_slice(0, &command)?;
+
+        // Flip every bit of the encoded frame, including the COBS overhead bytes, but not
+        // the terminator
+        for bit in 0..(frame.len() - 1) * 8 {
+            let mut corrupted = frame.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+
+            let mut link = HostLink::new();
+            link.handle_receive(&corrupted);
+            // A flip may split the frame in two, so drain everything that was received
+            for _ in 0..frame.len() {
+                if let Ok(Some(message)) = link.poll_receive() {
+                    panic!("bit {bit} flip delivered {message:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_frame_after_corrupt_frame() -> Result<(), Error> {
+        let frame = to_slice(0, &host_to_mote::Message::Ping)?;
+        let mut link = HostLink::new();
+        link.handle_receive(&flip_decoded_bit(&frame, 0));
+        link.handle_receive(&frame);
+
+        assert!(matches!(link.poll_receive(), Err(Error::ChecksumMismatch)));
+        assert_eq!(link.poll_receive()?, Some(host_to_mote::Message::Ping));
+        Ok(())
+    }
+
+    // --- Receive buffer is capped at MAX_MESSAGE ---
 
     #[test]
     fn test_receive_buffer_overflow() -> Result<(), Error> {
         let mut link = MoteLink::new();
-        // Feed more bytes than MAX_MESSAGE_LENGTH with no terminator.
-        let data = vec![0xABu8; MAX_MESSAGE_LENGTH + 500];
+        // Feed more bytes than MAX_MESSAGE with no terminator.
+        let data = vec![0xABu8; UDP_MAX_MESSAGE + 500];
         link.handle_receive(&data);
         // No zero byte in the buffer so poll_receive returns None, not an error.
         assert!(link.poll_receive()?.is_none());
+        assert_eq!(link.stats().receive_overflows, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_overflow_resynchronises() -> Result<(), Error> {
+        let mut link = MoteLink::new();
+        // The rest of the overlong frame is skipped, up to and including its delimiter
+        link.handle_receive(&vec![0xABu8; UDP_MAX_MESSAGE + 500]);
+        link.handle_receive(&[0xAB; 10]);
+        link.handle_receive(&[0]);
+        link.handle_receive(&to_slice(0, &mote_to_host::Message::Pong)?);
+        assert_eq!(link.poll_receive()?, Some(mote_to_host::Message::Pong));
+        assert!(link.poll_receive()?.is_none());
+        let stats = link.stats();
+        assert_eq!((stats.receive_overflows, stats.frames_corrupt), (1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_overflow_discards_oldest_frame() -> Result<(), Error> {
+        let mut link = MoteConfigLink::new();
+        let mut host_l = HostConfigLink::new();
+        // Queue more scans than the config link buffers, without polling in between
+        for tag in 0..4 {
+            host_l.send(medium_scan(tag))?;
+            for packet in transmits(&mut host_l) {
+                link.handle_receive(&packet);
+            }
+        }
+        assert!(link.stats().receive_overflows > 0);
+
+        // Whatever is left starts on a frame boundary, so the newest scan still arrives intact
+        let mut received = Vec::new();
+        while let Some(msg) = link.poll_receive()? {
+            received.push(msg);
+        }
+        assert_eq!(received.last(), Some(&medium_scan(3)));
+        assert_eq!(link.stats().frames_corrupt, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_message_is_per_link() -> Result<(), Error> {
+        // Fits within the UDP link's limit, but not the serial link's
+        let mut config_l = HostConfigLink::new();
+        assert!(matches!(
+            config_l.send(large_scan(0)),
+            Err(Error::MessageTooLarge)
+        ));
+        assert_eq!(config_l.stats().frames_sent, 0);
+
+        let mut udp_l = HostLink::new();
+        udp_l.send(large_scan(0))?;
+        Ok(())
+    }
+
+    // --- Version handshake ---
+
+    // Deliver a message from a HostLink to a MoteLink
+    fn deliver(
+        from: &mut HostLink,
+        to: &mut MoteLink,
+        msg: mote_to_host::Message,
+    ) -> Result<(), Error> {
+        from.send(msg)?;
+        while let Some(payload) = from.poll_transmit() {
+            to.handle_receive(&payload);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_compatible_peer() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        host_l.send(host_to_mote::Message::Hello(host_to_mote::Hello::default()))?;
+        while let Some(payload) = host_l.poll_transmit() {
+            mote_l.handle_receive(&payload);
+        }
+        assert_eq!(
+            mote_l.poll_receive()?,
+            Some(host_to_mote::Message::Hello(host_to_mote::Hello {
+                protocol_version: PROTOCOL_VERSION
+            }))
+        );
+        assert_eq!(mote_l.peer_protocol_version(), Some(PROTOCOL_VERSION));
+
+        let ack = mote_to_host::Message::HelloAck(hello_ack(PROTOCOL_VERSION));
+        deliver(&mut mote_l, &mut host_l, ack.clone())?;
+        assert_eq!(host_l.poll_receive()?, Some(ack));
+        assert_eq!(host_l.peer_protocol_version(), Some(PROTOCOL_VERSION));
+
+        deliver(&mut mote_l, &mut host_l, mote_to_host::Message::Pong)?;
+        assert_eq!(host_l.poll_receive()?, Some(mote_to_host::Message::Pong));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_refuses_incompatible_peer() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+        let peer_version = PROTOCOL_VERSION + 1;
+
+        // The ack itself is still delivered so its firmware version can be reported
+        let ack = mote_to_host::Message::HelloAck(hello_ack(peer_version));
+        deliver(&mut mote_l, &mut host_l, ack.clone())?;
+        assert_eq!(host_l.poll_receive()?, Some(ack));
+        assert_eq!(host_l.peer_protocol_version(), Some(peer_version));
+
+        deliver(&mut mote_l, &mut host_l, mote_to_host::Message::Pong)?;
+        match host_l.poll_receive() {
+            Err(Error::IncompatibleProtocol { local, peer }) => {
+                assert_eq!(local, PROTOCOL_VERSION);
+                assert_eq!(peer, peer_version);
+            }
+            other => panic!("expected IncompatibleProtocol, got {other:?}"),
+        }
+
+        // A later compatible handshake (e.g. after a firmware update) restores the link
+        deliver(
+            &mut mote_l,
+            &mut host_l,
+            mote_to_host::Message::HelloAck(hello_ack(PROTOCOL_VERSION)),
+        )?;
+        deliver(&mut mote_l, &mut host_l, mote_to_host::Message::Pong)?;
+        assert!(host_l.poll_receive()?.is_some());
+        assert_eq!(host_l.poll_receive()?, Some(mote_to_host::Message::Pong));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_handshake_accepts_messages() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+        deliver(&mut mote_l, &mut host_l, mote_to_host::Message::Ping)?;
+        assert_eq!(host_l.poll_receive()?, Some(mote_to_host::Message::Ping));
+        assert_eq!(host_l.peer_protocol_version(), None);
+        Ok(())
+    }
+
+    // --- Sequence numbers and link statistics ---
+
+    // Queue one Ping per entry and return the packet for each frame
+    fn ping_packets(link: &mut HostLink, count: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let mut packets = Vec::new();
+        for _ in 0..count {
+            link.send(mote_to_host::Message::Ping)?;
+            packets.push(link.poll_transmit().unwrap());
+        }
+        Ok(packets)
+    }
+
+    // Receive every queued message, returning how many were decoded
+    fn drain(link: &mut MoteLink) -> usize {
+        let mut count = 0;
+        while let Ok(Some(_)) = link.poll_receive() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn test_sequence_numbers_increment() -> Result<(), Error> {
+        let mut link = HostLink::new();
+        for (expected, packet) in ping_packets(&mut link, 3)?.iter().enumerate() {
+            let (sequence, _): (u16, mote_to_host::Message) = decode(packet)?;
+            assert_eq!(sequence, expected as u16);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_lossless_link() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+        for packet in ping_packets(&mut mote_l, 10)? {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(drain(&mut host_l), 10);
+
+        assert_eq!(mote_l.stats().frames_sent, 10);
+        assert_eq!(
+            host_l.stats(),
+            LinkStats {
+                frames_received: 10,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_dropped_frames() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+        let packets = ping_packets(&mut mote_l, 10)?;
+
+        // Lose frames 3, 6 and 7
+        for (i, packet) in packets.iter().enumerate() {
+            if ![3, 6, 7].contains(&i) {
+                host_l.handle_receive(packet);
+            }
+        }
+        assert_eq!(drain(&mut host_l), 7);
+
+        let stats = host_l.stats();
+        assert_eq!(stats.frames_received, 7);
+        assert_eq!(stats.frames_dropped, 3);
+        assert_eq!(stats.frames_out_of_order, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_out_of_order_frames() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+        let packets = ping_packets(&mut mote_l, 4)?;
+
+        for i in [0, 2, 1, 3] {
+            host_l.handle_receive(&packets[i]);
+        }
+        assert_eq!(drain(&mut host_l), 4);
+
+        let stats = host_l.stats();
+        assert_eq!(stats.frames_received, 4);
+        // Frame 1 was briefly presumed lost, but turned up late
+        assert_eq!(stats.frames_dropped, 0);
+        assert_eq!(stats.frames_out_of_order, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_corrupt_frames() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+        let packets = ping_packets(&mut mote_l, 2)?;
+
+        host_l.handle_receive(&[0x01, 0x00]);
+        host_l.handle_receive(&packets[0]);
+        host_l.handle_receive(&packets[1]);
+
+        assert!(host_l.poll_receive().is_err());
+        assert_eq!(drain(&mut host_l), 2);
+
+        let stats = host_l.stats();
+        assert_eq!(stats.frames_corrupt, 1);
+        assert_eq!(stats.frames_received, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_resync_after_peer_restart() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        for packet in ping_packets(&mut HostLink::new(), 200)? {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(drain(&mut host_l), 200);
+
+        // A restarted peer starts counting from zero again
+        for packet in ping_packets(&mut HostLink::new(), 3)? {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(drain(&mut host_l), 3);
+
+        let stats = host_l.stats();
+        assert_eq!(stats.frames_dropped, 0);
+        assert_eq!(stats.frames_out_of_order, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_wraps_around() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        for sequence in [u16::MAX - 1, u16::MAX, 0, 1] {
+            host_l.handle_receive(&to_slice(sequence, &mote_to_host::Message::Ping)?);
+        }
+        assert_eq!(drain(&mut host_l), 4);
+        assert_eq!(host_l.stats().frames_dropped, 0);
         Ok(())
     }
+
+    // --- Fragment reassembly over a lossy, reordering link ---
+
+    // A scan large enough to need several UDP packets, tagged so it can be identified on arrival
+    fn large_scan(tag: u8) -> mote_to_host::Message {
+        mote_to_host::Message::Scan(mote_to_host::Scan {
+            timestamp_us: 0,
+            points: (0..400u16)
+                .map(|i| mote_to_host::Point {
+                    quality: tag,
+                    angle_rad: i as f32 * 0.01,
+                    distance_mm: tag as f32 * 1000.0 + i as f32,
+                })
+                .collect(),
+        })
+    }
+
+    // Queue a message and return its packets
+    fn packets(link: &mut HostLink, msg: mote_to_host::Message) -> Result<Vec<Vec<u8>>, Error> {
+        link.send(msg)?;
+        Ok(core::iter::from_fn(|| link.poll_transmit()).collect())
+    }
+
+    // Receive every queued message, ignoring errors
+    fn receive_all(link: &mut MoteLink) -> Vec<mote_to_host::Message> {
+        let mut messages = Vec::new();
+        loop {
+            match link.poll_receive() {
+                Ok(Some(msg)) => messages.push(msg),
+                Ok(None) => return messages,
+                Err(_) => {}
+            }
+        }
+    }
+
+    // Receive packets one datagram at a time, like the UDP tasks, returning every message
+    fn receive_datagrams<'a>(
+        link: &mut MoteLink,
+        packets: impl IntoIterator<Item = &'a Vec<u8>>,
+    ) -> Vec<mote_to_host::Message> {
+        let mut messages = Vec::new();
+        for packet in packets {
+            link.handle_receive(packet);
+            messages.extend(receive_all(link));
+        }
+        messages
+    }
+
+    // Small deterministic xorshift generator, so the lossy link tests are reproducible
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            self.next() as usize % bound
+        }
+    }
+
+    // Send `count` tagged scans between two links, shuffling packets within windows of `window`
+    // packets and dropping one packet in `drop_one_in`. Returns the messages which should arrive
+    // (those which lost no packets) and the messages which did.
+    fn lossy_transfer(
+        seed: u32,
+        count: u8,
+        window: usize,
+        drop_one_in: usize,
+    ) -> Result<(Vec<mote_to_host::Message>, Vec<mote_to_host::Message>), Error> {
+        let mut rng = Rng(seed);
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+
+        let mut in_flight = Vec::new();
+        for tag in 0..count {
+            for packet in packets(&mut mote_l, large_scan(tag))? {
+                in_flight.push((tag, packet));
+            }
+        }
+
+        let mut lost_tags = Vec::new();
+        let mut received = Vec::new();
+        for (window_index, window) in in_flight.chunks_mut(window).enumerate() {
+            for i in (1..window.len()).rev() {
+                window.swap(i, rng.below(i + 1));
+            }
+            host_l.handle_time(window_index as u64 * 100);
+            for (tag, packet) in window.iter() {
+                if drop_one_in > 0 && rng.below(drop_one_in) == 0 {
+                    lost_tags.push(*tag);
+                } else {
+                    received.extend(receive_datagrams(&mut host_l, [packet]));
+                }
+            }
+        }
+
+        let expected = (0..count)
+            .filter(|tag| !lost_tags.contains(tag))
+            .map(large_scan)
+            .collect();
+        Ok((expected, received))
+    }
+
+    fn scan_tag(msg: &mote_to_host::Message) -> u8 {
+        match msg {
+            mote_to_host::Message::Scan(scan) => scan.points[0].quality,
+            other => panic!("expected a scan, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_fragments_carry_headers() -> Result<(), Error> {
+        let packets = packets(&mut HostLink::new(), large_scan(0))?;
+        assert!(packets.len() > 1, "expected fragmentation");
+
+        for (index, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= 1400, "packet exceeded MTU");
+            let (header, _) = from_bytes(packet)?;
+            assert_eq!(header.sequence, 0);
+            assert_eq!(header.fragment_index as usize, index);
+            assert_eq!(header.fragment_count as usize, packets.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassembly_survives_reordering() -> Result<(), Error> {
+        for seed in 1..20 {
+            let (expected, mut received) = lossy_transfer(seed, 20, 6, 0)?;
+            received.sort_by_key(scan_tag);
+            assert_eq!(received, expected, "seed {seed}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassembly_survives_reordering_and_loss() -> Result<(), Error> {
+        for seed in 1..20 {
+            let (expected, mut received) = lossy_transfer(seed, 20, 6, 8)?;
+            // Every delivered message is intact, none are duplicated, and every message which
+            // lost no packets made it through
+            received.sort_by_key(scan_tag);
+            assert_eq!(received, expected, "seed {seed}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_incomplete_message_is_not_spliced() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+        let first = packets(&mut mote_l, large_scan(1))?;
+        let second = packets(&mut mote_l, large_scan(2))?;
+
+        // Lose the middle of the first message
+        let delivered = first[..1].iter().chain(&first[2..]).chain(&second);
+        assert_eq!(
+            receive_datagrams(&mut host_l, delivered),
+            vec![large_scan(2)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_fragments_are_ignored() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let packets = packets(&mut HostLink::new(), large_scan(3))?;
+        let delivered = packets.iter().chain(&packets[..1]);
+        assert_eq!(
+            receive_datagrams(&mut host_l, delivered),
+            vec![large_scan(3)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassembly_timeout() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let packets = packets(&mut HostLink::new(), large_scan(4))?;
+        let (last, rest) = packets.split_last().unwrap();
+
+        host_l.handle_time(0);
+        assert!(receive_datagrams(&mut host_l, rest).is_empty());
+
+        // The straggler arrives too late to complete the message
+        host_l.handle_time(REASSEMBLY_TIMEOUT_MS + 1);
+        assert!(receive_datagrams(&mut host_l, [last]).is_empty());
+        assert_eq!(host_l.stats().fragments_expired, rest.len() as u32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassembly_within_timeout() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let packets = packets(&mut HostLink::new(), large_scan(5))?;
+        let (last, rest) = packets.split_last().unwrap();
+
+        host_l.handle_time(0);
+        assert!(receive_datagrams(&mut host_l, rest).is_empty());
+        host_l.handle_time(REASSEMBLY_TIMEOUT_MS);
+        assert_eq!(receive_datagrams(&mut host_l, [last]), vec![large_scan(5)]);
+        assert_eq!(host_l.stats().fragments_expired, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_oldest_partial_message_is_evicted() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let mut host_l = MoteLink::new();
+
+        // Only the first fragment of more messages than can be buffered arrives
+        let mut last_fragments = Vec::new();
+        for tag in 0..=MAX_PARTIAL_FRAMES as u8 {
+            let packets = packets(&mut mote_l, large_scan(tag))?;
+            assert!(receive_datagrams(&mut host_l, &packets[..1]).is_empty());
+            last_fragments.push(packets[1..].to_vec());
+        }
+        assert_eq!(host_l.stats().fragments_expired, 1);
+
+        // The rest of the newer messages complete them
+        let received: Vec<u8> =
+            receive_datagrams(&mut host_l, last_fragments[1..].iter().flatten())
+                .iter()
+                .map(scan_tag)
+                .collect();
+        assert_eq!(received, (1..=MAX_PARTIAL_FRAMES as u8).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inconsistent_fragment_header_is_rejected() {
+        let header = FragmentHeader {
+            sequence: 0,
+            fragment_index: 2,
+            fragment_count: 2,
+            flags: 0,
+        };
+        let mut link = MoteLink::new();
+        link.handle_receive(&encode_fragment(header, &[0]));
+        assert!(matches!(link.poll_receive(), Err(Error::InvalidFragment)));
+    }
+
+    #[test]
+    fn test_fragment_capacity_fits_mtu() {
+        for mtu in [16, 64, 255, 256, 1400] {
+            let capacity = fragment_capacity(mtu);
+            let packet = FRAGMENT_HEADER_LENGTH + capacity + FRAME_CRC_LENGTH;
+            assert!(corncobs::max_encoded_len(packet) <= mtu);
+            assert!(corncobs::max_encoded_len(packet + 1) > mtu);
+        }
+    }
+
+    // --- Reliable delivery ---
+
+    fn set_uid(uid: &str) -> host_to_mote::Message {
+        host_to_mote::Message::SetUID(host_to_mote::SetUID {
+            uid: String::from(uid),
+        })
+    }
+
+    // Everything the link has queued for transmission
+    fn transmits<const MTU: usize, const MAX_MESSAGE: usize, I, O>(
+        link: &mut MoteComms<MTU, MAX_MESSAGE, I, O>,
+    ) -> Vec<Vec<u8>>
+    where
+        I: for<'de> Deserialize<'de> + ProtocolMessage,
+        O: Serialize + ProtocolMessage,
+    {
+        core::iter::from_fn(|| link.poll_transmit()).collect()
+    }
+
+    // Receive every queued message on the mote side, ignoring errors
+    fn receive_commands(link: &mut HostLink) -> Vec<host_to_mote::Message> {
+        let mut messages = Vec::new();
+        loop {
+            match link.poll_receive() {
+                Ok(Some(msg)) => messages.push(msg),
+                Ok(None) => return messages,
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_reliable_message_is_acknowledged() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        host_l.send(set_uid("mote-a"))?;
+        assert_eq!(host_l.unacknowledged(), 1);
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l), vec![set_uid("mote-a")]);
+
+        // The ack is consumed by the link, not delivered
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+        }
+        assert!(host_l.poll_receive()?.is_none());
+        assert_eq!(host_l.unacknowledged(), 0);
+
+        host_l.handle_time(10 * RETRANSMIT_TIMEOUT_MS);
+        assert!(host_l.poll_transmit().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unacknowledged_message_is_retransmitted() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        host_l.handle_time(0);
+        host_l.send(set_uid("mote-b"))?;
+        // Lost in transit
+        assert_eq!(transmits(&mut host_l).len(), 1);
+
+        host_l.handle_time(RETRANSMIT_TIMEOUT_MS - 1);
+        assert!(host_l.poll_transmit().is_none());
+
+        host_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l), vec![set_uid("mote-b")]);
+        assert_eq!(host_l.stats().retransmissions, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_reliable_message_is_suppressed() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        host_l.send(set_uid("mote-c"))?;
+        let packet = host_l.poll_transmit().unwrap();
+        mote_l.handle_receive(&packet);
+        mote_l.handle_receive(&packet);
+
+        assert_eq!(receive_commands(&mut mote_l), vec![set_uid("mote-c")]);
+        // Both copies are acknowledged, in case the first ack is lost
+        assert_eq!(transmits(&mut mote_l).len(), 2);
+        assert_eq!(mote_l.stats().duplicates_suppressed, 1);
+        assert_eq!(mote_l.stats().frames_received, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reliable_delivery_gives_up() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        host_l.send(set_uid("mote-d"))?;
+
+        let mut sent = 0;
+        for step in 0..=2 * MAX_RETRANSMISSIONS as u64 {
+            host_l.handle_time(step * RETRANSMIT_TIMEOUT_MS);
+            sent += transmits(&mut host_l).len();
+        }
+        assert_eq!(sent, 1 + MAX_RETRANSMISSIONS as usize);
+        assert_eq!(host_l.unacknowledged(), 0);
+
+        let stats = host_l.stats();
+        assert_eq!(stats.retransmissions, MAX_RETRANSMISSIONS as u32);
+        assert_eq!(stats.delivery_failures, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_effort_messages_are_not_retransmitted() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        host_l.send(host_to_mote::Message::DriveBaseCommand(
+            host_to_mote::SetDriveBaseVelocity {
+                left_velocity_rad: 1.0,
+                right_velocity_rad: 1.0,
+            },
+        ))?;
+        mote_l.send(large_scan(0))?;
+        assert_eq!(host_l.unacknowledged(), 0);
+        assert_eq!(mote_l.unacknowledged(), 0);
+
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l).len(), 1);
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_all(&mut host_l), vec![large_scan(0)]);
+
+        // No acks are owed in either direction
+        host_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        mote_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        assert!(host_l.poll_transmit().is_none());
+        assert!(mote_l.poll_transmit().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_reliable_overrides_message_default() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        mote_l.send_reliable(mote_to_host::Message::Pong)?;
+        assert_eq!(mote_l.unacknowledged(), 1);
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_all(&mut host_l), vec![mote_to_host::Message::Pong]);
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert!(mote_l.poll_receive()?.is_none());
+        assert_eq!(mote_l.unacknowledged(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_forgets_delivered_sequences() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+
+        let mut first_session = MoteLink::new();
+        first_session.send(host_to_mote::Message::Hello(host_to_mote::Hello::default()))?;
+        first_session.send(set_uid("mote-e"))?;
+        for packet in transmits(&mut first_session) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l).len(), 2);
+
+        // A restarted host reuses the same sequence numbers for different messages
+        let mut second_session = MoteLink::new();
+        second_session.send(host_to_mote::Message::Hello(host_to_mote::Hello::default()))?;
+        second_session.send(set_uid("mote-f"))?;
+        for packet in transmits(&mut second_session) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l)[1], set_uid("mote-f"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reliable_delivery_over_lossy_link() -> Result<(), Error> {
+        for seed in 1..20 {
+            let mut rng = Rng(seed);
+            let mut host_l = MoteLink::new();
+            let mut mote_l = HostLink::new();
+
+            let sent: Vec<_> = (0..10)
+                .map(|i| set_uid(&alloc::format!("mote-{i}")))
+                .collect();
+            for msg in &sent {
+                host_l.send(msg.clone())?;
+            }
+
+            // Lose a third of the packets in each direction until everything is acknowledged
+            let mut received = Vec::new();
+            let mut now_ms = 0;
+            while host_l.unacknowledged() > 0 {
+                host_l.handle_time(now_ms);
+                for packet in transmits(&mut host_l) {
+                    if rng.below(3) != 0 {
+                        mote_l.handle_receive(&packet);
+                        received.extend(receive_commands(&mut mote_l));
+                    }
+                }
+                for packet in transmits(&mut mote_l) {
+                    if rng.below(3) != 0 {
+                        host_l.handle_receive(&packet);
+                        assert!(host_l.poll_receive()?.is_none());
+                    }
+                }
+                now_ms += RETRANSMIT_TIMEOUT_MS;
+            }
+
+            // Each message arrives exactly once, though not necessarily in order
+            assert_eq!(host_l.stats().delivery_failures, 0, "seed {seed}");
+            received.sort_by_key(|msg| alloc::format!("{msg:?}"));
+            assert_eq!(received, sent, "seed {seed}");
+        }
+        Ok(())
+    }
+
+    // --- Request envelope ---
+
+    fn request(id: u32, message: host_to_mote::Message) -> host_to_mote::Message {
+        host_to_mote::Message::Request(host_to_mote::Request {
+            id,
+            message: Box::new(message),
+        })
+    }
+
+    #[test]
+    fn test_request_delivery_follows_wrapped_message() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        host_l.send(request(1, host_to_mote::Message::Ping))?;
+        assert_eq!(host_l.unacknowledged(), 0);
+        host_l.send(request(2, set_uid("mote-g")))?;
+        assert_eq!(host_l.unacknowledged(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrapped_handshake_is_recognised() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        let hello = request(
+            3,
+            host_to_mote::Message::Hello(host_to_mote::Hello::default()),
+        );
+        host_l.send(hello.clone())?;
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(mote_l.poll_receive()?, Some(hello));
+        assert_eq!(mote_l.peer_protocol_version(), Some(PROTOCOL_VERSION));
+
+        let answer = mote_to_host::Message::Response(mote_to_host::Response {
+            id: 3,
+            result: Some(Box::new(mote_to_host::Message::HelloAck(hello_ack(
+                PROTOCOL_VERSION + 1,
+            )))),
+        });
+        deliver(&mut mote_l, &mut host_l, answer.clone())?;
+        assert_eq!(host_l.poll_receive()?, Some(answer));
+        assert_eq!(host_l.peer_protocol_version(), Some(PROTOCOL_VERSION + 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_answer_to_reliable_request_is_retransmitted() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+
+        let command = request(4, set_uid("mote-h"));
+        host_l.send(command.clone())?;
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(receive_commands(&mut mote_l), vec![command]);
+
+        // The answer is lost, along with the request's acknowledgement
+        let answer = mote_to_host::Message::Response(mote_to_host::Response {
+            id: 4,
+            result: None,
+        });
+        mote_l.send_reliable(answer.clone())?;
+        transmits(&mut mote_l);
+
+        // The retransmitted request is only acknowledged, but the answer is sent again too
+        host_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        mote_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert!(receive_commands(&mut mote_l).is_empty());
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+        }
+        assert_eq!(host_l.poll_receive()?, Some(answer));
+        assert_eq!(host_l.unacknowledged(), 0);
+        Ok(())
+    }
+
+    // --- Transmit priorities ---
+
+    // A control message as large as a scan
+    fn scan_response(tag: u8) -> mote_to_host::Message {
+        mote_to_host::Message::Response(mote_to_host::Response {
+            id: tag as u32,
+            result: Some(Box::new(medium_scan(tag))),
+        })
+    }
+
+    #[test]
+    fn test_control_jumps_ahead_of_telemetry() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        for tag in 0..5 {
+            mote_l.send(medium_scan(tag))?;
+        }
+        mote_l.send(mote_to_host::Message::Pong)?;
+
+        let mut host_l = MoteLink::new();
+        let received = receive_datagrams(&mut host_l, &transmits(&mut mote_l));
+        assert_eq!(received[0], mote_to_host::Message::Pong);
+        assert_eq!(received[1..], (0..5).map(medium_scan).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_telemetry_drops_oldest() -> Result<(), Error> {
+        let mut mote_l = HostLink::with_transmit_capacity(4);
+        for tag in 0..6 {
+            mote_l.send(medium_scan(tag))?;
+        }
+        assert_eq!(mote_l.stats().frames_discarded, 2);
+
+        let mut host_l = MoteLink::new();
+        let received = receive_datagrams(&mut host_l, &transmits(&mut mote_l));
+        assert_eq!(received, (2..6).map(medium_scan).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_telemetry_drops_whole_messages() -> Result<(), Error> {
+        // Each scan needs many serial packets, so only two fit
+        let mut mote_l = HostConfigLink::with_transmit_capacity(40);
+        for tag in 0..3 {
+            mote_l.send(medium_scan(tag))?;
+        }
+        assert_eq!(mote_l.stats().frames_discarded, 1);
+
+        let mut host_l = MoteConfigLink::new();
+        let mut received = Vec::new();
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+            received.extend(host_l.poll_receive()?);
+        }
+        assert_eq!(received, vec![medium_scan(1), medium_scan(2)]);
+        assert_eq!(host_l.stats().fragments_expired, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_is_never_dropped() -> Result<(), Error> {
+        let mut mote_l = HostLink::with_transmit_capacity(2);
+        mote_l.send(mote_to_host::Message::Pong)?;
+        mote_l.send(mote_to_host::Message::Ping)?;
+        assert!(matches!(
+            mote_l.send(mote_to_host::Message::Pong),
+            Err(Error::TransmitQueueFull)
+        ));
+        // Telemetry has its own queue
+        mote_l.send(medium_scan(0))?;
+
+        let mut host_l = MoteLink::new();
+        let received = receive_datagrams(&mut host_l, &transmits(&mut mote_l));
+        assert_eq!(
+            received,
+            vec![
+                mote_to_host::Message::Pong,
+                mote_to_host::Message::Ping,
+                medium_scan(0)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_retransmissions_jump_ahead_of_telemetry() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        host_l.send(set_uid("mote-a"))?;
+        transmits(&mut host_l);
+
+        host_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        host_l.send(host_to_mote::Message::Ping)?;
+        let mut mote_l = HostLink::new();
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert_eq!(
+            receive_commands(&mut mote_l),
+            vec![host_to_mote::Message::Ping, set_uid("mote-a")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_control_jumps_ahead_of_telemetry() -> Result<(), Error> {
+        let mut mote_l = StaticHostLink::new();
+        for tag in 0..3 {
+            mote_l.send(medium_scan(tag))?;
+        }
+        mote_l.send(mote_to_host::Message::Pong)?;
+
+        let mut host_l = StaticMoteLink::new();
+        let mut received = Vec::new();
+        while let Some(packet) = mote_l.poll_transmit() {
+            host_l.handle_receive(&packet);
+            received.extend(host_l.poll_receive()?);
+        }
+        assert_eq!(received[0], mote_to_host::Message::Pong);
+        assert_eq!(received[1..], (0..3).map(medium_scan).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_telemetry_drops_oldest() -> Result<(), Error> {
+        // Each scan takes over half of the telemetry buffer over serial, so only one fits
+        let mut mote_l = StaticHostConfigLink::new();
+        for tag in 0..5 {
+            mote_l.send(medium_scan(tag))?;
+        }
+        assert_eq!(mote_l.stats().frames_discarded, 4);
+
+        let mut host_l = StaticMoteConfigLink::new();
+        let mut received = Vec::new();
+        while let Some(packet) = mote_l.poll_transmit() {
+            host_l.handle_receive(&packet);
+            received.extend(host_l.poll_receive()?);
+        }
+        assert_eq!(received, vec![medium_scan(4)]);
+        assert_eq!(host_l.stats().fragments_expired, 0);
+        Ok(())
+    }
+
+    // --- Receiving into a caller's buffer ---
+
+    // Layout compatible view of the first host_to_mote variants, borrowing its strings
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum BorrowedCommand<'a> {
+        Ping,
+        Pong,
+        RequestNetworkScan,
+        SetNetworkConnectionConfig { ssid: &'a str, password: &'a str },
+        SetUID { uid: &'a str },
+    }
+
+    impl ProtocolMessage for BorrowedCommand<'_> {
+        fn protocol_version(&self) -> Option<u16> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_poll_receive_in_all_variants() -> Result<(), Error> {
+        let mut buffer = [0; 1400];
+        for msg in all_mote_messages() {
+            let mut mote_l = HostLink::new();
+            mote_l.send(msg.clone())?;
+            let mut host_l = MoteLink::new();
+            while let Some(payload) = mote_l.poll_transmit() {
+                host_l.handle_receive(&payload);
+            }
+            let received: Option<mote_to_host::Message> = host_l.poll_receive_in(&mut buffer)?;
+            assert_eq!(received, Some(msg));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_receive_in_reassembles_fragments() -> Result<(), Error> {
+        let mut buffer = vec![0; UDP_MAX_MESSAGE];
+        let mut host_l = MoteLink::new();
+        let mut received = Vec::new();
+        for packet in packets(&mut HostLink::new(), large_scan(3))? {
+            host_l.handle_receive(&packet);
+            received.extend(host_l.poll_receive_in::<mote_to_host::Message>(&mut buffer)?);
+        }
+        assert_eq!(received, vec![large_scan(3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_receive_in_borrows_from_buffer() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = HostLink::new();
+        host_l.send(set_uid("mote-borrowed"))?;
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+
+        let mut buffer = [0; 64];
+        let buffer_range = buffer.as_ptr_range();
+        let received = mote_l.poll_receive_in::<BorrowedCommand>(&mut buffer)?;
+        let Some(BorrowedCommand::SetUID { uid }) = received else {
+            panic!("expected SetUID, got {received:?}");
+        };
+        assert_eq!(uid, "mote-borrowed");
+        assert!(buffer_range.contains(&uid.as_ptr()));
+
+        // Reliable messages are still acknowledged
+        assert!(mote_l.poll_transmit().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_receive_in_buffer_too_small() -> Result<(), Error> {
+        let mut mote_l = HostLink::new();
+        let hello = mote_to_host::Message::HelloAck(hello_ack(PROTOCOL_VERSION));
+        mote_l.send(hello)?;
+        mote_l.send(mote_to_host::Message::Pong)?;
+        let mut host_l = MoteLink::new();
+        for packet in transmits(&mut mote_l) {
+            host_l.handle_receive(&packet);
+        }
+
+        let mut buffer = [0; 16];
+        assert!(matches!(
+            host_l.poll_receive_in::<mote_to_host::Message>(&mut buffer),
+            Err(Error::BufferTooSmall)
+        ));
+        assert_eq!(
+            host_l.poll_receive_in(&mut buffer)?,
+            Some(mote_to_host::Message::Pong)
+        );
+        assert_eq!(host_l.stats().frames_corrupt, 1);
+        Ok(())
+    }
+
+    // --- Fixed capacity link ---
+
+    // A 100 point scan, the size the LiDAR task sends, which needs many serial packets
+    fn medium_scan(tag: u8) -> mote_to_host::Message {
+        mote_to_host::Message::Scan(mote_to_host::Scan {
+            timestamp_us: 0,
+            points: (0..100u8)
+                .map(|i| mote_to_host::Point {
+                    quality: tag,
+                    angle_rad: i as f32 * 0.01,
+                    distance_mm: i as f32 * 10.0,
+                })
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn test_static_config_links() -> Result<(), Error> {
+        for msg in all_mote_messages() {
+            let mut host_l = StaticHostConfigLink::new();
+            host_l.send(msg.clone())?;
+            let mut mote_l = StaticMoteConfigLink::new();
+            while let Some(payload) = host_l.poll_transmit() {
+                mote_l.handle_receive(&payload);
+            }
+            assert_eq!(mote_l.poll_receive()?.unwrap(), msg);
+        }
+
+        for msg in all_host_messages() {
+            let mut mote_l = StaticMoteConfigLink::new();
+            mote_l.send(msg.clone())?;
+            let mut host_l = StaticHostConfigLink::new();
+            while let Some(payload) = mote_l.poll_transmit() {
+                host_l.handle_receive(&payload);
+            }
+            assert_eq!(host_l.poll_receive()?.unwrap(), msg);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_udp_links() -> Result<(), Error> {
+        for msg in all_mote_messages() {
+            let mut host_l = StaticHostLink::new();
+            host_l.send(msg.clone())?;
+            let mut mote_l = StaticMoteLink::new();
+            while let Some(payload) = host_l.poll_transmit() {
+                mote_l.handle_receive(&payload);
+            }
+            assert_eq!(mote_l.poll_receive()?.unwrap(), msg);
+        }
+
+        for msg in all_host_messages() {
+            let mut mote_l = StaticMoteLink::new();
+            mote_l.send(msg.clone())?;
+            let mut host_l = StaticHostLink::new();
+            while let Some(payload) = mote_l.poll_transmit() {
+                host_l.handle_receive(&payload);
+            }
+            assert_eq!(host_l.poll_receive()?.unwrap(), msg);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_link_interoperates_with_alloc_link() -> Result<(), Error> {
+        let mut messages = all_mote_messages();
+        messages.push(medium_scan(1));
+        for msg in messages {
+            let mut static_l = StaticHostConfigLink::new();
+            static_l.send(msg.clone())?;
+            let mut alloc_l = MoteConfigLink::new();
+            while let Some(payload) = static_l.poll_transmit() {
+                alloc_l.handle_receive(&payload);
+            }
+            assert_eq!(alloc_l.poll_receive()?.unwrap(), msg);
+        }
+
+        for msg in all_host_messages() {
+            let mut alloc_l = MoteConfigLink::new();
+            alloc_l.send(msg.clone())?;
+            let mut static_l = StaticHostConfigLink::new();
+            while let Some(payload) = alloc_l.poll_transmit() {
+                static_l.handle_receive(&payload);
+            }
+            assert_eq!(static_l.poll_receive()?.unwrap(), msg);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_fragmentation() -> Result<(), Error> {
+        let scan = medium_scan(0);
+
+        let mut host_l = StaticHostConfigLink::new(); // MTU = 64
+        host_l.send(scan.clone())?;
+
+        let mut packet_count = 0usize;
+        let mut mote_l = StaticMoteConfigLink::new();
+        while let Some(payload) = host_l.poll_transmit() {
+            assert!(payload.len() <= 64, "packet exceeded MTU");
+            mote_l.handle_receive(&payload);
+            // The receive buffer only holds a few packets, so receive as they arrive
+            if let Some(msg) = mote_l.poll_receive()? {
+                assert_eq!(msg, scan);
+            }
+            packet_count += 1;
+        }
+        assert!(
+            packet_count > 1,
+            "expected fragmentation into multiple packets"
+        );
+        assert_eq!(mote_l.stats().frames_received, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_reassembly_survives_reordering() -> Result<(), Error> {
+        let scan = medium_scan(0);
+        let mut host_l = StaticHostConfigLink::new();
+        host_l.send(scan.clone())?;
+        let mut packets: Vec<_> = core::iter::from_fn(|| host_l.poll_transmit()).collect();
+        packets.reverse();
+
+        let mut mote_l = StaticMoteConfigLink::new();
+        let mut received = Vec::new();
+        for packet in &packets {
+            mote_l.handle_receive(packet);
+            received.extend(mote_l.poll_receive()?);
+        }
+        assert_eq!(received, vec![scan]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_reassembly_timeout() -> Result<(), Error> {
+        let mut host_l = StaticHostConfigLink::new();
+        host_l.send(medium_scan(0))?;
+        let packets: Vec<_> = core::iter::from_fn(|| host_l.poll_transmit()).collect();
+
+        let mut mote_l = StaticMoteConfigLink::new();
+        mote_l.handle_time(0);
+        for packet in &packets[..packets.len() - 1] {
+            mote_l.handle_receive(packet);
+            assert!(mote_l.poll_receive()?.is_none());
+        }
+        mote_l.handle_time(REASSEMBLY_TIMEOUT_MS + 1);
+        assert_eq!(mote_l.stats().fragments_expired as usize, packets.len() - 1);
+
+        mote_l.handle_receive(&packets[packets.len() - 1]);
+        assert!(mote_l.poll_receive()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_multiple_messages_in_order() -> Result<(), Error> {
+        let messages = [
+            host_to_mote::Message::Ping,
+            host_to_mote::Message::RequestNetworkScan,
+            host_to_mote::Message::Pong,
+        ];
+        let mut mote_l = StaticMoteConfigLink::new();
+        let mut host_l = StaticHostConfigLink::new();
+
+        for msg in &messages {
+            mote_l.send(msg.clone())?;
+        }
+        while let Some(payload) = mote_l.poll_transmit() {
+            host_l.handle_receive(&payload);
+        }
+        for expected in &messages {
+            assert_eq!(&host_l.poll_receive()?.unwrap(), expected);
+        }
+        assert!(host_l.poll_receive()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_message_too_large() {
+        let mut link = StaticHostLink::new();
+        assert!(matches!(
+            link.send(large_scan(0)),
+            Err(Error::MessageTooLarge)
+        ));
+        assert!(link.poll_transmit().is_none());
+        assert_eq!(link.stats().frames_sent, 0);
+    }
+
+    #[test]
+    fn test_static_transmit_queue_full_queues_nothing() -> Result<(), Error> {
+        let mut host_l = StaticHostConfigLink::new();
+        let mut sent = 0;
+        loop {
+            match host_l.send(scan_response(sent)) {
+                Ok(()) => sent += 1,
+                Err(Error::TransmitQueueFull) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        assert!(sent > 0);
+
+        // Every message which was accepted arrives whole, and the refused one left no packets
+        let mut mote_l = StaticMoteConfigLink::new();
+        let mut received = Vec::new();
+        while let Some(payload) = host_l.poll_transmit() {
+            mote_l.handle_receive(&payload);
+            received.extend(mote_l.poll_receive()?);
+        }
+        assert_eq!(received, (0..sent).map(scan_response).collect::<Vec<_>>());
+        assert_eq!(mote_l.stats().fragments_expired, 0);
+
+        host_l.send(scan_response(sent))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_truncated_cobs_produces_no_message() -> Result<(), Error> {
+        let mut link = StaticMoteLink::new();
+        link.handle_receive(&[0xFF, 0xFE, 0xFD, 0x00]);
+        assert!(link.poll_receive()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_empty_cobs_payload_returns_error() {
+        let mut link = StaticMoteLink::new();
+        link.handle_receive(&[0x01, 0x00]);
+        assert!(matches!(link.poll_receive(), Err(Error::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn test_static_frame_longer_than_mtu_is_dropped() -> Result<(), Error> {
+        let mut host_l = StaticHostConfigLink::new();
+        host_l.send(mote_to_host::Message::Pong)?;
+        let pong = host_l.poll_transmit().unwrap();
+
+        let mut mote_l = StaticMoteConfigLink::new();
+        mote_l.handle_receive(&[0xAB; 100]);
+        mote_l.handle_receive(&[0x00]);
+        mote_l.handle_receive(&pong);
+        assert!(matches!(mote_l.poll_receive(), Err(Error::FrameTooLong)));
+        assert_eq!(mote_l.poll_receive()?, Some(mote_to_host::Message::Pong));
+        assert_eq!(mote_l.stats().frames_corrupt, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_receive_buffer_overflow() -> Result<(), Error> {
+        let mut link = StaticMoteLink::new();
+        link.handle_receive(&[0xABu8; 5000]);
+        assert!(link.poll_receive()?.is_none());
+        assert_eq!(link.stats().receive_overflows, 1);
+
+        // The rest of the overlong frame is skipped, and the next frame arrives intact
+        link.handle_receive(&[0xAB, 0]);
+        link.handle_receive(&to_slice(0, &mote_to_host::Message::Pong)?);
+        assert_eq!(link.poll_receive()?, Some(mote_to_host::Message::Pong));
+        assert_eq!(link.stats().frames_corrupt, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_link_acknowledges_reliable_messages() -> Result<(), Error> {
+        let mut host_l = MoteLink::new();
+        let mut mote_l = StaticHostLink::new();
+
+        host_l.send(set_uid("mote-a"))?;
+        let packets = transmits(&mut host_l);
+        for packet in &packets {
+            mote_l.handle_receive(packet);
+        }
+        assert_eq!(mote_l.poll_receive()?, Some(set_uid("mote-a")));
+        while let Some(payload) = mote_l.poll_transmit() {
+            host_l.handle_receive(&payload);
+        }
+        assert!(host_l.poll_receive()?.is_none());
+        assert_eq!(host_l.unacknowledged(), 0);
+
+        // A retransmission of a delivered message is acknowledged again, but not delivered
+        for packet in &packets {
+            mote_l.handle_receive(packet);
+        }
+        assert!(mote_l.poll_receive()?.is_none());
+        assert!(mote_l.poll_transmit().is_some());
+        assert_eq!(mote_l.stats().duplicates_suppressed, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_link_retransmits_reliable_messages() -> Result<(), Error> {
+        let finished = mote_to_host::Message::MoveFinished(mote_to_host::MoveFinished {
+            id: 7,
+            timestamp_us: 1_000_000,
+            outcome: mote_to_host::MoveOutcome::Completed,
+            left_error_rad: 0.01,
+            right_error_rad: -0.02,
+        });
+        let mut mote_l = StaticHostLink::new();
+        let mut host_l = MoteLink::new();
+
+        mote_l.handle_time(0);
+        mote_l.send(finished.clone())?;
+        assert_eq!(mote_l.unacknowledged(), 1);
+        // Lost in transit
+        assert!(mote_l.poll_transmit().is_some());
+        assert!(mote_l.poll_transmit().is_none());
+
+        mote_l.handle_time(RETRANSMIT_TIMEOUT_MS - 1);
+        assert!(mote_l.poll_transmit().is_none());
+
+        mote_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        while let Some(payload) = mote_l.poll_transmit() {
+            host_l.handle_receive(&payload);
+        }
+        assert_eq!(host_l.poll_receive()?, Some(finished));
+        assert_eq!(mote_l.stats().retransmissions, 1);
+
+        // Once acknowledged, it isn't sent again
+        for packet in transmits(&mut host_l) {
+            mote_l.handle_receive(&packet);
+        }
+        assert!(mote_l.poll_receive()?.is_none());
+        assert_eq!(mote_l.unacknowledged(), 0);
+        mote_l.handle_time(10 * RETRANSMIT_TIMEOUT_MS);
+        assert!(mote_l.poll_transmit().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_retransmissions_wait_for_room() -> Result<(), Error> {
+        // Each scan takes over half of the control buffer over serial, so only one fits at a time
+        let mut mote_l = StaticHostConfigLink::new();
+        mote_l.handle_time(0);
+        let mut sent = 0;
+        for tag in 0..2 {
+            mote_l.send_reliable(medium_scan(tag))?;
+            // Lost in transit
+            sent += core::iter::from_fn(|| mote_l.poll_transmit()).count();
+        }
+
+        // Both are sent again whole, one after the other
+        mote_l.handle_time(RETRANSMIT_TIMEOUT_MS);
+        let mut host_l = StaticMoteConfigLink::new();
+        let mut received = Vec::new();
+        let mut resent = 0;
+        while let Some(packet) = mote_l.poll_transmit() {
+            resent += 1;
+            host_l.handle_receive(&packet);
+            received.extend(host_l.poll_receive()?);
+        }
+        assert_eq!(received, vec![medium_scan(0), medium_scan(1)]);
+        assert_eq!(resent, sent);
+        assert_eq!(mote_l.stats().retransmissions, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_reliable_delivery_gives_up() -> Result<(), Error> {
+        let mut host_l = StaticMoteLink::new();
+        host_l.send(set_uid("mote-d"))?;
+
+        let mut sent = 0;
+        for step in 0..=2 * MAX_RETRANSMISSIONS as u64 {
+            host_l.handle_time(step * RETRANSMIT_TIMEOUT_MS);
+            sent += core::iter::from_fn(|| host_l.poll_transmit()).count();
+        }
+        assert_eq!(sent, 1 + MAX_RETRANSMISSIONS as usize);
+        assert_eq!(host_l.unacknowledged(), 0);
+        assert_eq!(host_l.stats().delivery_failures, 1);
+        Ok(())
+    }
+
+    // --- Async adapters ---
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_link_over_udp() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio::net::UdpSocket;
+        use transport::{TokioClock, TokioLink};
+
+        let host_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mote_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        host_socket
+            .connect(mote_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        mote_socket
+            .connect(host_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut host_l = TokioLink::new(MoteLink::new(), host_socket, TokioClock::new());
+        let mut mote_l = TokioLink::new(HostLink::new(), mote_socket, TokioClock::new());
+
+        host_l.send(set_uid("mote-a")).await.unwrap();
+        assert_eq!(mote_l.next().await.unwrap().unwrap(), set_uid("mote-a"));
+
+        // Sending the scan also sends the acknowledgement, which arrives first
+        mote_l.send(large_scan(1)).await.unwrap();
+        assert_eq!(host_l.next().await.unwrap().unwrap(), large_scan(1));
+        assert_eq!(host_l.link().unacknowledged(), 0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_link_over_byte_stream() {
+        use futures_util::{SinkExt, StreamExt};
+        use transport::{ByteStream, TokioClock, TokioLink};
+
+        // A pipe much narrower than a message, like a serial port
+        let (host_end, mote_end) = tokio::io::duplex(16);
+        let mut host_l = TokioLink::new(
+            MoteConfigLink::new(),
+            ByteStream::new(host_end),
+            TokioClock::new(),
+        );
+        let mut mote_l = TokioLink::new(
+            HostConfigLink::new(),
+            ByteStream::new(mote_end),
+            TokioClock::new(),
+        );
+
+        let (sent, received) = tokio::join!(mote_l.send(medium_scan(2)), host_l.next());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap(), medium_scan(2));
+
+        // The stream ends once the other end is gone
+        drop(mote_l);
+        assert!(host_l.next().await.is_none());
+    }
+
+    /// An embassy-net driver which receives every packet it transmits, so a stack's sockets can
+    /// talk to each other through its own address
+    #[cfg(feature = "embassy")]
+    #[derive(Default)]
+    struct Loopback {
+        packets: VecDeque<Vec<u8>>,
+        waker: Option<core::task::Waker>,
+    }
+
+    #[cfg(feature = "embassy")]
+    struct LoopbackRx(Vec<u8>);
+
+    #[cfg(feature = "embassy")]
+    struct LoopbackTx<'a>(&'a mut Loopback);
+
+    #[cfg(feature = "embassy")]
+    impl embassy_net_driver::RxToken for LoopbackRx {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(mut self, f: F) -> R {
+            f(&mut self.0)
+        }
+    }
+
+    #[cfg(feature = "embassy")]
+    impl embassy_net_driver::TxToken for LoopbackTx<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            let mut packet = vec![0; len];
+            let result = f(&mut packet);
+            self.0.packets.push_back(packet);
+            if let Some(waker) = self.0.waker.take() {
+                waker.wake();
+            }
+            result
+        }
+    }
+
+    #[cfg(feature = "embassy")]
+    impl embassy_net_driver::Driver for Loopback {
+        type RxToken<'a> = LoopbackRx;
+        type TxToken<'a> = LoopbackTx<'a>;
+
+        fn receive(
+            &mut self,
+            cx: &mut core::task::Context,
+        ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+            match self.packets.pop_front() {
+                Some(packet) => Some((LoopbackRx(packet), LoopbackTx(self))),
+                None => {
+                    self.waker = Some(cx.waker().clone());
+                    None
+                }
+            }
+        }
+
+        fn transmit(&mut self, _cx: &mut core::task::Context) -> Option<Self::TxToken<'_>> {
+            Some(LoopbackTx(self))
+        }
+
+        fn link_state(&mut self, _cx: &mut core::task::Context) -> embassy_net_driver::LinkState {
+            embassy_net_driver::LinkState::Up
+        }
+
+        fn capabilities(&self) -> embassy_net_driver::Capabilities {
+            let mut capabilities = embassy_net_driver::Capabilities::default();
+            capabilities.max_transmission_unit = 1500;
+            capabilities
+        }
+
+        fn hardware_address(&self) -> embassy_net_driver::HardwareAddress {
+            embassy_net_driver::HardwareAddress::Ip
+        }
+    }
+
+    #[cfg(feature = "embassy")]
+    #[tokio::test]
+    async fn test_async_link_over_embassy_net() {
+        use embassy_net::udp::{PacketMetadata, UdpSocket};
+        use embassy_net::{Ipv4Address, Ipv4Cidr, StackResources, StaticConfigV4};
+        use futures_util::{SinkExt, StreamExt};
+        use transport::{EmbassyClock, EmbassyLink, EmbassyUdp};
+
+        let address = Ipv4Address::new(192, 168, 4, 2);
+        let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(address, 24),
+            gateway: None,
+            dns_servers: Default::default(),
+        });
+        let resources = Box::leak(Box::new(StackResources::<2>::new()));
+        let (stack, mut runner) = embassy_net::new(Loopback::default(), config, resources, 0);
+
+        let (mut host_meta, mut host_rx, mut host_tx_meta, mut host_tx) = (
+            [PacketMetadata::EMPTY; 16],
+            [0; 8192],
+            [PacketMetadata::EMPTY; 16],
+            [0; 8192],
+        );
+        let mut host_socket = UdpSocket::new(
+            stack,
+            &mut host_meta,
+            &mut host_rx,
+            &mut host_tx_meta,
+            &mut host_tx,
+        );
+        host_socket.bind(7001).unwrap();
+        let (mut mote_meta, mut mote_rx, mut mote_tx_meta, mut mote_tx) = (
+            [PacketMetadata::EMPTY; 16],
+            [0; 8192],
+            [PacketMetadata::EMPTY; 16],
+            [0; 8192],
+        );
+        let mut mote_socket = UdpSocket::new(
+            stack,
+            &mut mote_meta,
+            &mut mote_rx,
+            &mut mote_tx_meta,
+            &mut mote_tx,
+        );
+        mote_socket.bind(7475).unwrap();
+
+        let test = async {
+            stack.wait_config_up().await;
+            let mote_endpoint = (embassy_net::IpAddress::Ipv4(address), 7475);
+            let mut host_l = EmbassyLink::new(
+                MoteLink::new(),
+                EmbassyUdp::with_peer(host_socket, mote_endpoint),
+                EmbassyClock::new(),
+            );
+            // Mote answers whoever spoke to it last
+            let mut mote_l = EmbassyLink::new(
+                HostLink::new(),
+                EmbassyUdp::new(mote_socket),
+                EmbassyClock::new(),
+            );
+
+            host_l.send(set_uid("mote-a")).await.unwrap();
+            assert_eq!(mote_l.next().await.unwrap().unwrap(), set_uid("mote-a"));
+            assert_eq!(mote_l.transport().peer().unwrap().endpoint.port, 7001);
+
+            // Sending the scan also sends the acknowledgement, which arrives first
+            mote_l.send(large_scan(1)).await.unwrap();
+            assert_eq!(host_l.next().await.unwrap().unwrap(), large_scan(1));
+            assert_eq!(host_l.link().unacknowledged(), 0);
+        };
+        tokio::select! {
+            _ = runner.run() => unreachable!("the runner never returns"),
+            _ = test => {}
+        }
+    }
 }
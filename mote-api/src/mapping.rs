@@ -0,0 +1,577 @@
+//! Occupancy grid mapping from lidar sweeps
+//!
+//! OccupancyGrid keeps the log-odds of each cell being occupied. Each lidar point inserted marks
+//! the cells its beam passed through as more likely free, and the cell it hit as more likely
+//! occupied, see Thrun et al., "Probabilistic Robotics", chapter 9.
+//!
+//! Grids can be exported as PGM or PNG images with YAML metadata, in the layout used by ROS's
+//! map_server, so they open in most robotics tools, and loaded back from a PGM and its YAML.
+
+use alloc::{format, string::String, vec, vec::Vec};
+
+use libm::{expf, floorf};
+
+use crate::Error;
+use crate::messages::mote_to_host::Point;
+use crate::odometry::Pose;
+use crate::scan::point_position;
+
+/// Cells at least this likely to be occupied are exported as occupied
+pub const OCCUPIED_THRESHOLD: f32 = 0.65;
+/// Cells at most this likely to be occupied are exported as free
+pub const FREE_THRESHOLD: f32 = 0.196;
+
+// Exported pixel values, as map_server's trinary mode expects
+const PIXEL_OCCUPIED: u8 = 0;
+const PIXEL_FREE: u8 = 254;
+const PIXEL_UNKNOWN: u8 = 205;
+
+/// Extent of a grid and how strongly each measurement updates it
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridConfig {
+    /// Size of a cell in meters
+    pub resolution_m: f32,
+    /// Size of the grid in cells
+    pub width: usize,
+    pub height: usize,
+    /// Position of the grid's bottom left corner in the map frame
+    pub origin: (f32, f32),
+    /// Added to a cell's log-odds when a beam ends in it
+    pub log_odds_hit: f32,
+    /// Added to a cell's log-odds when a beam passes through it
+    pub log_odds_miss: f32,
+    /// Log-odds are clamped to this range, so cells can change their state again
+    pub log_odds_min: f32,
+    pub log_odds_max: f32,
+    /// Beams longer than this only clear cells up to this distance
+    pub max_range_m: f32,
+}
+
+impl GridConfig {
+    /// A grid `width_m` by `height_m` centered on the map frame's origin
+    pub fn new(width_m: f32, height_m: f32, resolution_m: f32) -> Self {
+        let width = (width_m / resolution_m) as usize;
+        let height = (height_m / resolution_m) as usize;
+        Self {
+            resolution_m,
+            width,
+            height,
+            origin: (
+                -(width as f32) * resolution_m / 2.0,
+                -(height as f32) * resolution_m / 2.0,
+            ),
+            log_odds_hit: 0.85,
+            log_odds_miss: -0.4,
+            log_odds_min: -2.0,
+            log_odds_max: 3.5,
+            max_range_m: 8.0,
+        }
+    }
+}
+
+/// A 2D occupancy grid, see the module documentation
+#[derive(Debug, Clone)]
+pub struct OccupancyGrid {
+    config: GridConfig,
+    /// Row major, starting at the bottom row
+    log_odds: Vec<f32>,
+}
+
+impl OccupancyGrid {
+    /// A grid where every cell is unknown
+    pub fn new(config: GridConfig) -> Self {
+        Self {
+            log_odds: vec![0.0; config.width * config.height],
+            config,
+        }
+    }
+
+    /// A grid from a map_server PGM image and its YAML metadata, e.g. as written by to_pgm and
+    /// to_yaml. Occupied and free cells are as certain as the config allows, and other cells
+    /// unknown. The origin's yaw is ignored, as map_server does.
+    pub fn from_pgm(pgm: &[u8], yaml: &str) -> Result<Self, Error> {
+        let metadata = MapMetadata::parse(yaml)?;
+
+        // Header fields are separated by whitespace, and may be interleaved with comments
+        let mut rest = pgm;
+        let mut fields = [0usize; 3];
+        let magic = next_pgm_field(&mut rest)?;
+        if magic != b"P5" {
+            return Err(Error::MalformedMap);
+        }
+        for field in &mut fields {
+            *field = core::str::from_utf8(next_pgm_field(&mut rest)?)
+                .ok()
+                .and_then(|field| field.parse().ok())
+                .ok_or(Error::MalformedMap)?;
+        }
+        let [width, height, max_value] = fields;
+        // A single whitespace byte separates the header from the pixels
+        let pixels = rest.get(1..).ok_or(Error::MalformedMap)?;
+        if !(1..=255).contains(&max_value) || pixels.len() != width * height {
+            return Err(Error::MalformedMap);
+        }
+
+        let mut grid = Self::new(GridConfig {
+            width,
+            height,
+            origin: metadata.origin,
+            ..GridConfig::new(0.0, 0.0, metadata.resolution_m)
+        });
+        // Image rows are top first, grid rows bottom first
+        for (row, line) in pixels.chunks(width.max(1)).rev().enumerate() {
+            for (column, &pixel) in line.iter().enumerate() {
+                let mut occupied = 1.0 - pixel as f32 / max_value as f32;
+                if metadata.negate {
+                    occupied = 1.0 - occupied;
+                }
+                grid.log_odds[row * width + column] = if occupied > metadata.occupied_threshold {
+                    grid.config.log_odds_max
+                } else if occupied < metadata.free_threshold {
+                    grid.config.log_odds_min
+                } else {
+                    0.0
+                };
+            }
+        }
+        Ok(grid)
+    }
+
+    pub fn config(&self) -> &GridConfig {
+        &self.config
+    }
+
+    /// Column and row of the cell holding the point, if it is in the grid
+    pub fn cell(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let (column, row) = self.cell_unbounded(x, y);
+        self.index(column, row)
+            .map(|_| (column as usize, row as usize))
+    }
+
+    /// Probability that the cell at `column`, `row` is occupied, 0.5 if it has never been seen
+    pub fn probability(&self, column: usize, row: usize) -> f32 {
+        let log_odds = self.log_odds[row * self.config.width + column];
+        1.0 - 1.0 / (1.0 + expf(log_odds))
+    }
+
+    /// Update the grid with lidar points measured from `pose`, e.g. a Sweep's points and the
+    /// pose Mote was at when it was measured
+    pub fn insert_scan(&mut self, points: &[Point], pose: Pose) {
+        let start = self.cell_unbounded(pose.x, pose.y);
+        for point in points {
+            let (x, y) = point_position(point);
+            let distance = point.distance_mm / 1000.0;
+            let hit = distance <= self.config.max_range_m;
+            let scale = if hit {
+                1.0
+            } else {
+                self.config.max_range_m / distance
+            };
+            let (x, y) = pose.transform_point(x * scale, y * scale);
+            let end = self.cell_unbounded(x, y);
+
+            self.trace(start, end, |grid, column, row| {
+                grid.update(column, row, grid.config.log_odds_miss);
+            });
+            let update = if hit {
+                self.config.log_odds_hit
+            } else {
+                self.config.log_odds_miss
+            };
+            self.update(end.0, end.1, update);
+        }
+    }
+
+    /// Grayscale image of the grid, top row first: occupied cells are black, free cells white,
+    /// and unknown cells grey
+    pub fn image(&self) -> Vec<u8> {
+        let mut image = Vec::with_capacity(self.log_odds.len());
+        for row in (0..self.config.height).rev() {
+            for column in 0..self.config.width {
+                let probability = self.probability(column, row);
+                image.push(if probability >= OCCUPIED_THRESHOLD {
+                    PIXEL_OCCUPIED
+                } else if probability <= FREE_THRESHOLD {
+                    PIXEL_FREE
+                } else {
+                    PIXEL_UNKNOWN
+                });
+            }
+        }
+        image
+    }
+
+    /// The image as a binary PGM file
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut pgm =
+            format!("P5\n{} {}\n255\n", self.config.width, self.config.height).into_bytes();
+        pgm.extend_from_slice(&self.image());
+        pgm
+    }
+
+    /// The image as a PNG file. The image data isn't compressed, so it's about as large as a PGM.
+    pub fn to_png(&self) -> Vec<u8> {
+        let (width, height) = (self.config.width, self.config.height);
+        let image = self.image();
+
+        // Each row starts with its filter type, 0 for none
+        let mut raw = Vec::with_capacity((width + 1) * height);
+        for row in image.chunks(width.max(1)) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        // A zlib stream of stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+        }
+        while let Some(block) = blocks.next() {
+            zlib.push(blocks.peek().is_none() as u8);
+            let length = block.len() as u16;
+            zlib.extend_from_slice(&length.to_le_bytes());
+            zlib.extend_from_slice(&(!length).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bit greyscale, default compression and filtering, not interlaced
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib);
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// map_server metadata for the grid exported to the image file `image`
+    pub fn to_yaml(&self, image: &str) -> String {
+        format!(
+            "image: {image}\nmode: trinary\nresolution: {}\norigin: [{}, {}, 0.0]\nnegate: 0\noccupied_thresh: {}\nfree_thresh: {}\n",
+            self.config.resolution_m,
+            self.config.origin.0,
+            self.config.origin.1,
+            OCCUPIED_THRESHOLD,
+            FREE_THRESHOLD,
+        )
+    }
+
+    fn cell_unbounded(&self, x: f32, y: f32) -> (i64, i64) {
+        (
+            floorf((x - self.config.origin.0) / self.config.resolution_m) as i64,
+            floorf((y - self.config.origin.1) / self.config.resolution_m) as i64,
+        )
+    }
+
+    fn index(&self, column: i64, row: i64) -> Option<usize> {
+        let (width, height) = (self.config.width as i64, self.config.height as i64);
+        if (0..width).contains(&column) && (0..height).contains(&row) {
+            Some((row * width + column) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, column: i64, row: i64, change: f32) {
+        if let Some(index) = self.index(column, row) {
+            let (min, max) = (self.config.log_odds_min, self.config.log_odds_max);
+            self.log_odds[index] = (self.log_odds[index] + change).clamp(min, max);
+        }
+    }
+
+    /// Visit the cells on the line from `start` up to, but not including, `end`, with
+    /// Bresenham's algorithm
+    fn trace(
+        &mut self,
+        start: (i64, i64),
+        end: (i64, i64),
+        mut visit: impl FnMut(&mut Self, i64, i64),
+    ) {
+        let (dx, dy) = ((end.0 - start.0).abs(), -(end.1 - start.1).abs());
+        let (step_x, step_y) = ((end.0 - start.0).signum(), (end.1 - start.1).signum());
+        let mut error = dx + dy;
+        let (mut column, mut row) = start;
+        while (column, row) != end {
+            visit(self, column, row);
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                column += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                row += step_y;
+            }
+        }
+    }
+}
+
+/// The fields of map_server's YAML which from_pgm uses
+struct MapMetadata {
+    resolution_m: f32,
+    origin: (f32, f32),
+    negate: bool,
+    occupied_threshold: f32,
+    free_threshold: f32,
+}
+
+impl MapMetadata {
+    /// Only the flat `key: value` lines map_server writes are understood, not YAML in general
+    fn parse(yaml: &str) -> Result<Self, Error> {
+        let number = |value: &str| value.trim().parse::<f32>().map_err(|_| Error::MalformedMap);
+        let (mut resolution_m, mut origin) = (None, None);
+        let mut negate = false;
+        let (mut occupied_threshold, mut free_threshold) = (OCCUPIED_THRESHOLD, FREE_THRESHOLD);
+        for line in yaml.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "resolution" => resolution_m = Some(number(value)?),
+                "origin" => {
+                    let mut coordinates = value
+                        .strip_prefix('[')
+                        .and_then(|value| value.strip_suffix(']'))
+                        .ok_or(Error::MalformedMap)?
+                        .split(',');
+                    let mut coordinate = || number(coordinates.next().ok_or(Error::MalformedMap)?);
+                    origin = Some((coordinate()?, coordinate()?));
+                }
+                "negate" => negate = number(value)? != 0.0,
+                "occupied_thresh" => occupied_threshold = number(value)?,
+                "free_thresh" => free_threshold = number(value)?,
+                _ => {}
+            }
+        }
+        match (resolution_m, origin) {
+            (Some(resolution_m), Some(origin)) if resolution_m > 0.0 => Ok(Self {
+                resolution_m,
+                origin,
+                negate,
+                occupied_threshold,
+                free_threshold,
+            }),
+            _ => Err(Error::MalformedMap),
+        }
+    }
+}
+
+/// The next whitespace separated field of a PGM header, skipping comments, leaving `rest` at the
+/// whitespace after it
+fn next_pgm_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    loop {
+        match rest.first() {
+            Some(byte) if byte.is_ascii_whitespace() => *rest = &rest[1..],
+            Some(b'#') => {
+                let end = rest.iter().position(|&byte| byte == b'\n');
+                *rest = &rest[end.ok_or(Error::MalformedMap)?..];
+            }
+            Some(_) => break,
+            None => return Err(Error::MalformedMap),
+        }
+    }
+    let end = rest
+        .iter()
+        .position(|byte| byte.is_ascii_whitespace())
+        .unwrap_or(rest.len());
+    let (field, after) = rest.split_at(end);
+    *rest = after;
+    Ok(field)
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut digest = CRC.digest();
+    digest.update(kind);
+    digest.update(data);
+    png.extend_from_slice(&digest.finalize().to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % MODULUS;
+        b = (b + a) % MODULUS;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::mote_to_host;
+
+    // A sweep from the centre of a square room with walls `half_width` meters away
+    fn room_sweep(half_width: f32, count: usize) -> Vec<mote_to_host::Point> {
+        (0..count)
+            .map(|i| {
+                let angle_rad = i as f32 * core::f32::consts::TAU / count as f32;
+                let (sin, cos) = (libm::sinf(angle_rad), libm::cosf(angle_rad));
+                mote_to_host::Point {
+                    quality: 40,
+                    angle_rad,
+                    distance_mm: half_width / cos.abs().max(sin.abs()) * 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_occupancy_grid_maps_room() {
+        let config = GridConfig::new(4.0, 4.0, 0.05);
+        assert_eq!((config.width, config.height), (80, 80));
+        let mut grid = OccupancyGrid::new(config);
+        for _ in 0..5 {
+            grid.insert_scan(&room_sweep(1.0, 720), Pose::default());
+        }
+
+        let occupied = |x, y| {
+            let (column, row) = grid.cell(x, y).unwrap();
+            grid.probability(column, row)
+        };
+        // Walls, the open room, and outside the room
+        for (x, y) in [(1.01, 0.0), (-0.99, 0.3), (0.5, 1.01), (0.2, -0.99)] {
+            assert!(occupied(x, y) > OCCUPIED_THRESHOLD, "({x}, {y})");
+        }
+        for (x, y) in [(0.0, 0.0), (0.5, 0.5), (-0.9, -0.2)] {
+            assert!(occupied(x, y) < FREE_THRESHOLD, "({x}, {y})");
+        }
+        assert_eq!(occupied(1.5, 1.5), 0.5);
+        assert!(grid.cell(2.5, 0.0).is_none());
+
+        // The same room seen from elsewhere, facing another way, lines up
+        let mut moved = OccupancyGrid::new(grid.config().clone());
+        let pose = Pose {
+            x: 0.5,
+            y: -0.25,
+            theta: core::f32::consts::FRAC_PI_2,
+        };
+        let points: Vec<mote_to_host::Point> = room_sweep(1.0, 720)
+            .into_iter()
+            .filter_map(|point| {
+                // Rays from the room's centre to its walls, measured from `pose` instead
+                let (x, y) = point_position(&point);
+                let (dx, dy) = (x - pose.x, y - pose.y);
+                let bearing = pose.theta - libm::atan2f(dy, dx);
+                (libm::sqrtf(dx * dx + dy * dy) > 0.1).then(|| mote_to_host::Point {
+                    quality: 40,
+                    angle_rad: bearing.rem_euclid(core::f32::consts::TAU),
+                    distance_mm: libm::sqrtf(dx * dx + dy * dy) * 1000.0,
+                })
+            })
+            .collect();
+        moved.insert_scan(&points, pose);
+        let (column, row) = moved.cell(-1.01, 0.0).unwrap();
+        assert!(moved.probability(column, row) > 0.5);
+    }
+
+    #[test]
+    fn test_occupancy_grid_max_range() {
+        let mut config = GridConfig::new(4.0, 4.0, 0.1);
+        config.max_range_m = 1.0;
+        let mut grid = OccupancyGrid::new(config);
+        // Straight ahead, beyond the maximum range
+        let point = mote_to_host::Point {
+            quality: 40,
+            angle_rad: 0.0,
+            distance_mm: 1500.0,
+        };
+        grid.insert_scan(&[point], Pose::default());
+        let probability = |x| {
+            let (column, row) = grid.cell(x, 0.05).unwrap();
+            grid.probability(column, row)
+        };
+        assert!(probability(0.55) < 0.5);
+        assert!(probability(1.05) < 0.5);
+        assert_eq!(probability(1.25), 0.5);
+        assert_eq!(probability(1.55), 0.5);
+    }
+
+    #[test]
+    fn test_occupancy_grid_export() {
+        let mut grid = OccupancyGrid::new(GridConfig::new(3.0, 2.0, 0.1));
+        grid.insert_scan(&room_sweep(0.5, 360), Pose::default());
+        let image = grid.image();
+        assert_eq!(image.len(), 30 * 20);
+        assert!(image.contains(&0) && image.contains(&254) && image.contains(&205));
+
+        let pgm = grid.to_pgm();
+        let header = b"P5\n30 20\n255\n";
+        assert_eq!(&pgm[..header.len()], header);
+        assert_eq!(&pgm[header.len()..], image.as_slice());
+
+        let yaml = grid.to_yaml("room.pgm");
+        assert!(yaml.contains("image: room.pgm\n"));
+        assert!(yaml.contains("resolution: 0.1\n"));
+        assert!(yaml.contains("origin: [-1.5, -1, 0.0]\n"));
+
+        // map_server's own output has comments, and a loaded grid exports the same image
+        let mut commented = b"P5\n# CREATOR: map_saver\n30 20\n255\n".to_vec();
+        commented.extend_from_slice(&image);
+        let loaded = OccupancyGrid::from_pgm(&commented, &yaml).unwrap();
+        assert_eq!(loaded.image(), image);
+        let negated = yaml.replace("negate: 0", "negate: 1");
+        let inverted: Vec<u8> = pgm[..header.len()]
+            .iter()
+            .copied()
+            .chain(image.iter().map(|pixel| 255 - pixel))
+            .collect();
+        let loaded = OccupancyGrid::from_pgm(&inverted, &negated).unwrap();
+        assert_eq!(loaded.image(), image);
+        for (pgm, yaml) in [
+            (&pgm[..pgm.len() - 1], yaml.as_str()),
+            (b"P2\n30 20\n255\n".as_slice(), yaml.as_str()),
+            (&pgm[..], "image: room.pgm\nresolution: 0.1\n"),
+            (&pgm[..], "origin: [-1.5, -1, 0.0]\nresolution: none\n"),
+        ] {
+            assert!(matches!(
+                OccupancyGrid::from_pgm(pgm, yaml),
+                Err(Error::MalformedMap)
+            ));
+        }
+
+        // Walk the PNG's chunks, checking their CRCs
+        let png = grid.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + length]);
+            let mut digest = crc.digest();
+            digest.update(kind);
+            digest.update(data);
+            assert_eq!(
+                digest.finalize().to_be_bytes(),
+                rest[8 + length..12 + length]
+            );
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + length..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| kind.as_slice()).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(&chunks[0].1[..8], &[0, 0, 0, 30, 0, 0, 0, 20]);
+
+        // One stored block holding each row behind a filter byte
+        let idat = &chunks[1].1;
+        assert_eq!(&idat[..3], &[0x78, 0x01, 1]);
+        let stored = &idat[7..idat.len() - 4];
+        assert_eq!(stored.len(), 31 * 20);
+        for (row, pixels) in stored.chunks(31).zip(image.chunks(30)) {
+            assert_eq!(row[0], 0);
+            assert_eq!(&row[1..], pixels);
+        }
+    }
+}
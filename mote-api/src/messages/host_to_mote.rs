@@ -1,11 +1,30 @@
 //!  Command messages sent to Mote
 
-use alloc::string::String;
+use alloc::{boxed::Box, string::String};
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "schemars")]
 use schemars::JsonSchema;
 
+use crate::{PROTOCOL_VERSION, ProtocolMessage};
+
+// HANDSHAKE MESSAGES
+
+/// Opens the version handshake, Mote answers with mote_to_host::Message::HelloAck
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u16,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
 // CONFIGURATION MESSAGES
 
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
@@ -21,6 +40,55 @@ pub struct SetUID {
     pub uid: String,
 }
 
+/// Which telemetry streams Mote sends, and how often, e.g. to turn the lidar off on a congested
+/// network when only odometry is needed. Applies until Mote restarts.
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConfigureTelemetry {
+    pub scan: ScanTelemetry,
+    pub imu: PeriodicTelemetry,
+    pub drive_base: PeriodicTelemetry,
+}
+
+impl Default for ConfigureTelemetry {
+    /// The rates Mote starts with
+    fn default() -> Self {
+        Self {
+            scan: ScanTelemetry {
+                enabled: true,
+                decimation: 1,
+            },
+            imu: PeriodicTelemetry {
+                enabled: true,
+                period_ms: 20,
+            },
+            drive_base: PeriodicTelemetry {
+                enabled: true,
+                period_ms: 100,
+            },
+        }
+    }
+}
+
+/// A telemetry stream sent at a fixed rate
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeriodicTelemetry {
+    pub enabled: bool,
+    /// Time between messages. Periods shorter than Mote samples the stream at are raised to it.
+    pub period_ms: u32,
+}
+
+/// Lidar scans, sent as fast as the lidar measures them
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScanTelemetry {
+    pub enabled: bool,
+    /// Only one in this many points is sent, thinning every sweep evenly. 0 is taken as 1, which
+    /// sends every point.
+    pub decimation: u8,
+}
+
 // RUNTIME MESSAGES
 
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
@@ -30,6 +98,47 @@ pub struct SetDriveBaseVelocity {
     pub right_velocity_rad: f32,
 }
 
+/// Starts a clock synchronisation exchange, Mote answers with mote_to_host::Message::TimeSyncReply,
+/// see the timesync module
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TimeSyncRequest {
+    /// When the request was sent, in µs on the host's monotonic clock
+    pub host_t0: u64,
+}
+
+/// Turns each wheel through an angle from where it is, along a trapezoidal velocity profile, see
+/// the motion module. Mote answers with mote_to_host::Message::MoveFinished, carrying the same id,
+/// once the wheels arrive or another drive command interrupts the move. The watchdog doesn't stop
+/// a move, so it needn't be followed by further commands.
+///
+/// Moves with limits that aren't positive are refused, with a Nack if sent as a Request.
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoveWheels {
+    /// Chosen by the host, e.g. a counter, to match the MoveFinished to the move
+    pub id: u32,
+    pub left_rad: f32,
+    pub right_rad: f32,
+    /// Neither wheel turns faster than this, in rad/s
+    pub max_velocity_rad_per_s: f32,
+    /// Neither wheel speeds up or slows down faster than this, in rad/s²
+    pub max_acceleration_rad_per_s2: f32,
+}
+
+// REQUEST MESSAGES
+
+/// Wraps a command so that Mote answers it with mote_to_host::Message::Response, or
+/// mote_to_host::Message::Nack if it refuses, carrying the same id
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Request {
+    /// Chosen by the host, e.g. a counter, to match the answer to the request
+    pub id: u32,
+    pub message: Box<Message>,
+}
+
+// Variants are encoded by index, so only ever append new variants
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Message {
@@ -39,4 +148,35 @@ pub enum Message {
     SetNetworkConnectionConfig(SetNetworkConnectionConfig),
     SetUID(SetUID),
     DriveBaseCommand(SetDriveBaseVelocity),
+    Hello(Hello),
+    Request(Request),
+    TimeSyncRequest(TimeSyncRequest),
+    ConfigureTelemetry(ConfigureTelemetry),
+    MoveWheels(MoveWheels),
+}
+
+impl ProtocolMessage for Message {
+    fn protocol_version(&self) -> Option<u16> {
+        match self {
+            Message::Hello(hello) => Some(hello.protocol_version),
+            Message::Request(request) => request.message.protocol_version(),
+            _ => None,
+        }
+    }
+
+    fn reliable(&self) -> bool {
+        // Configuration writes must not be lost. Drive commands are streamed, so a retransmitted
+        // velocity would arrive stale and be worse than a lost one. Likewise a retransmitted time
+        // sync request would carry the wrong send time. Moves are relative to where the wheels are
+        // when they arrive, so a late one still goes the right distance.
+        match self {
+            Message::RequestNetworkScan
+            | Message::SetNetworkConnectionConfig(_)
+            | Message::SetUID(_)
+            | Message::ConfigureTelemetry(_)
+            | Message::MoveWheels(_) => true,
+            Message::Request(request) => request.message.reliable(),
+            _ => false,
+        }
+    }
 }
@@ -6,8 +6,33 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "schemars")]
 use schemars::JsonSchema;
 
+use crate::{Priority, ProtocolMessage};
+
+// HANDSHAKE MESSAGES
+
+/// Bit flags reported in HelloAck::capabilities
+pub mod capabilities {
+    pub const LIDAR: u32 = 1 << 0;
+    pub const IMU: u32 = 1 << 1;
+    pub const DRIVE_BASE: u32 = 1 << 2;
+    pub const WIFI: u32 = 1 << 3;
+}
+
+/// Answer to host_to_mote::Message::Hello
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelloAck {
+    pub protocol_version: u16,
+    /// Semver of the running firmware
+    pub firmware_version: String,
+    pub git_hash: String,
+    pub capabilities: u32,
+}
+
 // RUNTIME MESSEGES
 
+// Telemetry timestamps are µs since Mote booted, from its monotonic clock
+
 // Lidar Data
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -17,6 +42,36 @@ pub struct Point {
     pub distance_mm: f32,
 }
 
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Scan {
+    /// When the last point was received from the lidar
+    pub timestamp_us: u64,
+    pub points: Vec<Point>,
+}
+
+/// How PackedScan::data is laid out, see the packed_scan module
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointEncoding {
+    /// 5 bytes per point: the quality, then the angle and distance as little endian u16s
+    Raw,
+    /// Like Raw, but each angle is the zigzag varint change from the previous point's angle,
+    /// usually a single byte
+    DeltaAngle,
+}
+
+/// Lidar points in the lidar's own fixed point units, about half the size of Scan on the wire.
+/// Convert it with PackedScan::to_scan, see the packed_scan module.
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PackedScan {
+    /// When the last point was received from the lidar
+    pub timestamp_us: u64,
+    pub encoding: PointEncoding,
+    pub data: Vec<u8>,
+}
+
 // Encoder / Drive Base Data
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -29,6 +84,8 @@ pub struct WheelJointState {
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DriveBaseState {
+    /// When the wheel joint states were sampled
+    pub timestamp_us: u64,
     pub left: WheelJointState,
     pub right: WheelJointState,
 }
@@ -45,6 +102,8 @@ pub struct IMUAxisTriple {
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct IMUMeasurement {
+    /// When the IMU was read
+    pub timestamp_us: u64,
     pub accel: IMUAxisTriple,
     pub gyro: IMUAxisTriple,
 }
@@ -97,13 +156,120 @@ pub struct State {
     pub built_in_test: BITCollection,
 }
 
+/// Answer to host_to_mote::Message::TimeSyncRequest, see the timesync module
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeSyncReply {
+    /// Copied from the request, in µs on the host's clock
+    pub host_t0: u64,
+    /// When Mote received the request, in µs since it booted
+    pub device_t1: u64,
+    /// When Mote sent this reply, in µs since it booted
+    pub device_t2: u64,
+}
+
+/// How a host_to_mote::MoveWheels ended
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveOutcome {
+    /// Both wheels came to rest at their targets
+    Completed,
+    /// The wheels didn't settle at their targets in time, e.g. because Mote was blocked
+    TimedOut,
+    /// Another drive command replaced the move before it finished
+    Interrupted,
+}
+
+/// Answer to host_to_mote::Message::MoveWheels once the move is over
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MoveFinished {
+    /// Id of the move
+    pub id: u32,
+    /// When the move ended
+    pub timestamp_us: u64,
+    pub outcome: MoveOutcome,
+    /// How far each wheel stopped short of its target, negative if it overshot
+    pub left_error_rad: f32,
+    pub right_error_rad: f32,
+}
+
+// REQUEST ANSWERS
+
+/// Answer to a host_to_mote::Request which Mote carried out
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    /// Id of the request being answered
+    pub id: u32,
+    /// Reply to the command, if it has one (e.g. Pong for Ping)
+    pub result: Option<Box<Message>>,
+}
+
+/// Why Mote refused a host_to_mote::Request
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NackReason {
+    /// The command is not handled over the link it was sent on
+    Unsupported,
+    /// The request itself is malformed, e.g. it wraps another request
+    InvalidRequest,
+    /// A field of the command is out of range, e.g. a limit which isn't positive
+    InvalidArgument,
+}
+
+/// Answer to a host_to_mote::Request which Mote refused
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Nack {
+    /// Id of the request being answered
+    pub id: u32,
+    pub reason: NackReason,
+}
+
+// Variants are encoded by index, so only ever append new variants
 #[cfg_attr(feature = "schemars", derive(JsonSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Message {
     Ping,
     Pong,
-    Scan(Vec<Point>),
+    Scan(Scan),
     DriveBaseState(DriveBaseState),
     IMUMeasurement(IMUMeasurement),
     State(Box<State>),
+    HelloAck(HelloAck),
+    Response(Response),
+    Nack(Nack),
+    TimeSyncReply(TimeSyncReply),
+    PackedScan(PackedScan),
+    MoveFinished(MoveFinished),
+}
+
+impl ProtocolMessage for Message {
+    fn protocol_version(&self) -> Option<u16> {
+        match self {
+            Message::HelloAck(hello_ack) => Some(hello_ack.protocol_version),
+            Message::Response(Response {
+                result: Some(result),
+                ..
+            }) => result.protocol_version(),
+            _ => None,
+        }
+    }
+    fn reliable(&self) -> bool {
+        // The host may be waiting on a move to finish, and won't hear about it again
+        matches!(self, Message::MoveFinished(_))
+    }
+
+    fn priority(&self) -> Priority {
+        // Sensor data and state are resent periodically, so newer data replaces anything dropped
+        match self {
+            Message::Scan(_)
+            | Message::PackedScan(_)
+            | Message::DriveBaseState(_)
+            | Message::IMUMeasurement(_)
+            | Message::State(_) => Priority::Telemetry,
+            _ => Priority::Control,
+        }
+    }
 }
@@ -0,0 +1,435 @@
+//! Closed-loop wheel moves
+//!
+//! MoveWheels turns the wheels through set angles rather than at set velocities, so that Mote can
+//! drive a distance or turn on the spot without the host closing the loop over Wi-Fi. The wheel
+//! turning furthest follows a trapezoidal velocity profile: it accelerates at the limit up to the
+//! velocity limit, cruises, then decelerates to stop on its target. Short moves never reach the
+//! velocity limit, and decelerate as soon as they're halfway. The other wheel follows in
+//! proportion, so both wheels arrive together and Mote traces a straight line or an arc.
+//!
+//! WheelMove turns the profile into setpoints for the drive base's velocity PID, adding a
+//! correction proportional to how far each wheel lags its profiled position.
+
+use libm::{fabsf, sqrtf};
+
+use crate::messages::host_to_mote::MoveWheels;
+use crate::messages::mote_to_host::{MoveFinished, MoveOutcome};
+use crate::odometry::OdometryConfig;
+
+/// Velocity added per radian a wheel lags its profiled position, in rad/s per rad
+pub const POSITION_GAIN: f32 = 4.0;
+/// A move completes once both wheels are this close to their targets, in rad
+pub const POSITION_TOLERANCE_RAD: f32 = 0.05;
+/// A move times out if the wheels haven't settled this long after the profile ends, in s
+pub const SETTLE_TIMEOUT_S: f32 = 1.0;
+/// Longest a move's profile may take, in s, so that no command can leave Mote driving unattended
+/// for long
+pub const MAX_MOVE_S: f32 = 60.0;
+
+/// Where the profile is at an instant
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ProfilePoint {
+    pub position: f32,
+    pub velocity: f32,
+}
+
+/// Moves a distance from rest to rest within velocity and acceleration limits, see the module
+/// documentation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrapezoidalProfile {
+    distance: f32,
+    acceleration: f32,
+    /// Highest speed reached, the velocity limit unless the move is short
+    peak_velocity: f32,
+    accelerating_s: f32,
+    cruising_s: f32,
+}
+
+impl TrapezoidalProfile {
+    /// Profile moving `distance`, backwards if negative, with limits in units of the distance per
+    /// second and per second². None if a limit isn't positive or a value isn't finite.
+    pub fn new(distance: f32, max_velocity: f32, max_acceleration: f32) -> Option<Self> {
+        let valid = |limit: f32| limit.is_finite() && limit > 0.0;
+        if !distance.is_finite() || !valid(max_velocity) || !valid(max_acceleration) {
+            return None;
+        }
+        let length = fabsf(distance);
+        let peak_velocity = max_velocity.min(sqrtf(length * max_acceleration));
+        let accelerating_s = peak_velocity / max_acceleration;
+        // Speeding up and slowing down cover peak_velocity * accelerating_s between them
+        let cruising_s = if peak_velocity > 0.0 {
+            (length / peak_velocity - accelerating_s).max(0.0)
+        } else {
+            0.0
+        };
+        Some(Self {
+            distance,
+            acceleration: max_acceleration,
+            peak_velocity,
+            accelerating_s,
+            cruising_s,
+        })
+    }
+
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+
+    /// Time from start to stop, in s
+    pub fn duration_s(&self) -> f32 {
+        2.0 * self.accelerating_s + self.cruising_s
+    }
+
+    /// Where the profile is `t` seconds after it starts. Before the start it is at rest at 0, and
+    /// after the end at rest at the distance.
+    pub fn sample(&self, t: f32) -> ProfilePoint {
+        let duration = self.duration_s();
+        let t = t.clamp(0.0, duration);
+        let (position, velocity) = if t < self.accelerating_s {
+            (0.5 * self.acceleration * t * t, self.acceleration * t)
+        } else if t < self.accelerating_s + self.cruising_s {
+            let ramp = 0.5 * self.peak_velocity * self.accelerating_s;
+            (
+                ramp + self.peak_velocity * (t - self.accelerating_s),
+                self.peak_velocity,
+            )
+        } else {
+            let remaining = duration - t;
+            (
+                fabsf(self.distance) - 0.5 * self.acceleration * remaining * remaining,
+                self.acceleration * remaining,
+            )
+        };
+        let sign = if self.distance < 0.0 { -1.0 } else { 1.0 };
+        ProfilePoint {
+            position: sign * position,
+            velocity: sign * velocity,
+        }
+    }
+}
+
+impl MoveWheels {
+    /// Drive `distance_m` straight ahead, backwards if negative, with Mote's speed limited to
+    /// `max_speed` in m/s and its acceleration to `max_acceleration` in m/s²
+    pub fn drive_distance(
+        id: u32,
+        config: &OdometryConfig,
+        distance_m: f32,
+        max_speed: f32,
+        max_acceleration: f32,
+    ) -> Self {
+        let wheel_rad = distance_m / config.wheel_radius_m;
+        Self {
+            id,
+            left_rad: wheel_rad,
+            right_rad: wheel_rad,
+            max_velocity_rad_per_s: max_speed / config.wheel_radius_m,
+            max_acceleration_rad_per_s2: max_acceleration / config.wheel_radius_m,
+        }
+    }
+
+    /// Turn on the spot through `angle_rad`, counterclockwise if positive, with Mote's angular
+    /// velocity limited to `max_angular_velocity` in rad/s and its angular acceleration to
+    /// `max_angular_acceleration` in rad/s²
+    pub fn rotate_angle(
+        id: u32,
+        config: &OdometryConfig,
+        angle_rad: f32,
+        max_angular_velocity: f32,
+        max_angular_acceleration: f32,
+    ) -> Self {
+        // Wheel radians per radian Mote turns
+        let ratio = config.track_width_m / 2.0 / config.wheel_radius_m;
+        Self {
+            id,
+            left_rad: -angle_rad * ratio,
+            right_rad: angle_rad * ratio,
+            max_velocity_rad_per_s: max_angular_velocity * ratio,
+            max_acceleration_rad_per_s2: max_angular_acceleration * ratio,
+        }
+    }
+
+    /// Profile of the wheel turning furthest, which the other follows in proportion. None if the
+    /// move is invalid, see TrapezoidalProfile::new, or would take longer than MAX_MOVE_S.
+    pub fn profile(&self) -> Option<TrapezoidalProfile> {
+        if !self.left_rad.is_finite() || !self.right_rad.is_finite() {
+            return None;
+        }
+        TrapezoidalProfile::new(
+            fabsf(self.left_rad).max(fabsf(self.right_rad)),
+            self.max_velocity_rad_per_s,
+            self.max_acceleration_rad_per_s2,
+        )
+        .filter(|profile| profile.duration_s() <= MAX_MOVE_S)
+    }
+}
+
+/// A MoveWheels in progress, see the module documentation
+///
+/// Wheel positions passed in are the drive base's, as in WheelJointState::postition_rad, and
+/// elapsed times are seconds since the move started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WheelMove {
+    id: u32,
+    profile: TrapezoidalProfile,
+    /// Wheel positions when the move started, in rad
+    start: (f32, f32),
+    /// Each wheel's turn as a fraction of the profile's distance, negative if it turns backwards
+    share: (f32, f32),
+}
+
+impl WheelMove {
+    /// Start `command` from the wheels' current positions. None if it is invalid, see
+    /// MoveWheels::profile.
+    pub fn new(command: &MoveWheels, left_rad: f32, right_rad: f32) -> Option<Self> {
+        let profile = command.profile()?;
+        let distance = profile.distance();
+        let share = if distance > 0.0 {
+            (command.left_rad / distance, command.right_rad / distance)
+        } else {
+            (0.0, 0.0)
+        };
+        Some(Self {
+            id: command.id,
+            profile,
+            start: (left_rad, right_rad),
+            share,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn profile(&self) -> &TrapezoidalProfile {
+        &self.profile
+    }
+
+    /// Velocity setpoints for the wheels, in rad/s
+    pub fn setpoints(&self, elapsed_s: f32, left_rad: f32, right_rad: f32) -> (f32, f32) {
+        let point = self.profile.sample(elapsed_s);
+        let setpoint = |start: f32, share: f32, position: f32| {
+            let lag = start + share * point.position - position;
+            share * point.velocity + POSITION_GAIN * lag
+        };
+        (
+            setpoint(self.start.0, self.share.0, left_rad),
+            setpoint(self.start.1, self.share.1, right_rad),
+        )
+    }
+
+    /// How far each wheel is from its target, in rad
+    pub fn errors(&self, left_rad: f32, right_rad: f32) -> (f32, f32) {
+        let distance = self.profile.distance();
+        (
+            self.start.0 + self.share.0 * distance - left_rad,
+            self.start.1 + self.share.1 * distance - right_rad,
+        )
+    }
+
+    /// How the move ended, if it has: Completed once the profile has ended with both wheels within
+    /// POSITION_TOLERANCE_RAD of their targets, or TimedOut if they aren't SETTLE_TIMEOUT_S later
+    pub fn outcome(&self, elapsed_s: f32, left_rad: f32, right_rad: f32) -> Option<MoveOutcome> {
+        let overtime = elapsed_s - self.profile.duration_s();
+        let (left_error, right_error) = self.errors(left_rad, right_rad);
+        if overtime < 0.0 {
+            None
+        } else if fabsf(left_error) <= POSITION_TOLERANCE_RAD
+            && fabsf(right_error) <= POSITION_TOLERANCE_RAD
+        {
+            Some(MoveOutcome::Completed)
+        } else if overtime >= SETTLE_TIMEOUT_S {
+            Some(MoveOutcome::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    /// Report the move ending with `outcome` at `timestamp_us`, e.g. MoveOutcome::Interrupted when
+    /// another command replaces it
+    pub fn finished(
+        &self,
+        outcome: MoveOutcome,
+        timestamp_us: u64,
+        left_rad: f32,
+        right_rad: f32,
+    ) -> MoveFinished {
+        let (left_error_rad, right_error_rad) = self.errors(left_rad, right_rad);
+        MoveFinished {
+            id: self.id,
+            timestamp_us,
+            outcome,
+            left_error_rad,
+            right_error_rad,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trapezoidal_profile() {
+        // Accelerates for 2s over 4m, cruises for 1s over 4m, then decelerates like it accelerated
+        let profile = TrapezoidalProfile::new(12.0, 4.0, 2.0).unwrap();
+        assert!((profile.duration_s() - 5.0).abs() < 1e-6);
+
+        let samples = [
+            (-1.0, 0.0, 0.0),
+            (1.0, 1.0, 2.0),
+            (2.0, 4.0, 4.0),
+            (2.5, 6.0, 4.0),
+            (4.0, 11.0, 2.0),
+            (5.0, 12.0, 0.0),
+            (6.0, 12.0, 0.0),
+        ];
+        for (t, position, velocity) in samples {
+            let point = profile.sample(t);
+            assert!((point.position - position).abs() < 1e-4, "{t}s: {point:?}");
+            assert!((point.velocity - velocity).abs() < 1e-4, "{t}s: {point:?}");
+        }
+
+        // Backwards is the mirror image
+        let backwards = TrapezoidalProfile::new(-12.0, 4.0, 2.0).unwrap();
+        assert_eq!(backwards.sample(1.0).position, -1.0);
+        assert_eq!(backwards.sample(4.0).velocity, -2.0);
+    }
+
+    #[test]
+    fn test_short_profile_is_triangular() {
+        // Never reaches the velocity limit, peaking at 2 halfway through
+        let profile = TrapezoidalProfile::new(2.0, 4.0, 2.0).unwrap();
+        assert!((profile.duration_s() - 2.0).abs() < 1e-6);
+        let peak = profile.sample(1.0);
+        assert!((peak.position - 1.0).abs() < 1e-6);
+        assert!((peak.velocity - 2.0).abs() < 1e-6);
+        assert_eq!(profile.sample(2.0).position, 2.0);
+
+        // Moving nowhere takes no time
+        let still = TrapezoidalProfile::new(0.0, 4.0, 2.0).unwrap();
+        assert_eq!(still.duration_s(), 0.0);
+        assert_eq!(still.sample(1.0), Default::default());
+    }
+
+    #[test]
+    fn test_trapezoidal_profile_rejects_invalid_limits() {
+        assert!(TrapezoidalProfile::new(1.0, 0.0, 2.0).is_none());
+        assert!(TrapezoidalProfile::new(1.0, 4.0, -2.0).is_none());
+        assert!(TrapezoidalProfile::new(f32::NAN, 4.0, 2.0).is_none());
+        assert!(TrapezoidalProfile::new(1.0, f32::INFINITY, 2.0).is_none());
+
+        let command = MoveWheels {
+            id: 1,
+            left_rad: f32::NAN,
+            right_rad: 1.0,
+            max_velocity_rad_per_s: 4.0,
+            max_acceleration_rad_per_s2: 2.0,
+        };
+        assert!(command.profile().is_none());
+        assert!(WheelMove::new(&command, 0.0, 0.0).is_none());
+
+        // Far enough and slow enough to drive for days
+        let command = MoveWheels {
+            id: 2,
+            left_rad: 1e6,
+            right_rad: 1e6,
+            max_velocity_rad_per_s: 1e-3,
+            max_acceleration_rad_per_s2: 2.0,
+        };
+        assert!(command.profile().is_none());
+    }
+
+    #[test]
+    fn test_move_wheels_from_distance_and_angle() {
+        let config = OdometryConfig::new(0.05, 0.2);
+        let drive = MoveWheels::drive_distance(1, &config, -0.5, 0.25, 0.5);
+        assert_eq!(drive.left_rad, -10.0);
+        assert_eq!(drive.right_rad, -10.0);
+        assert_eq!(drive.max_velocity_rad_per_s, 5.0);
+        assert_eq!(drive.max_acceleration_rad_per_s2, 10.0);
+
+        // Each wheel travels along a 0.1m radius circle, twice the wheel radius
+        let rotate = MoveWheels::rotate_angle(2, &config, 1.0, 2.0, 4.0);
+        assert!((rotate.left_rad + 2.0).abs() < 1e-6);
+        assert!((rotate.right_rad - 2.0).abs() < 1e-6);
+        assert!((rotate.max_velocity_rad_per_s - 4.0).abs() < 1e-6);
+        assert!((rotate.max_acceleration_rad_per_s2 - 8.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_wheel_move_wheels_arrive_together() {
+        // An arc, the left wheel turning half as far as the right, starting from arbitrary positions
+        let command = MoveWheels {
+            id: 4,
+            left_rad: 6.0,
+            right_rad: 12.0,
+            max_velocity_rad_per_s: 4.0,
+            max_acceleration_rad_per_s2: 2.0,
+        };
+        let wheel_move = WheelMove::new(&command, 1.0, -3.0).unwrap();
+        assert_eq!(wheel_move.id(), 4);
+
+        // On profile, the setpoints are the profile's velocity, in proportion for the left wheel
+        let (left, right) = wheel_move.setpoints(1.0, 1.5, -2.0);
+        assert!((left - 1.0).abs() < 1e-5);
+        assert!((right - 2.0).abs() < 1e-5);
+
+        // A lagging wheel is sped up, a leading one slowed down
+        let (left, right) = wheel_move.setpoints(1.0, 1.4, -1.9);
+        assert!((left - (1.0 + 0.1 * POSITION_GAIN)).abs() < 1e-5);
+        assert!((right - (2.0 - 0.1 * POSITION_GAIN)).abs() < 1e-5);
+
+        // Simulate wheels which follow their setpoints perfectly
+        let (mut left, mut right) = (1.0, -3.0);
+        let dt = 0.02;
+        let mut elapsed = 0.0;
+        let outcome = loop {
+            if let Some(outcome) = wheel_move.outcome(elapsed, left, right) {
+                break outcome;
+            }
+            let (left_velocity, right_velocity) = wheel_move.setpoints(elapsed, left, right);
+            left += left_velocity * dt;
+            right += right_velocity * dt;
+            elapsed += dt;
+            // Both wheels are always the same fraction of the way there
+            assert!(((left - 1.0) / 6.0 - (right + 3.0) / 12.0).abs() < 0.01);
+        };
+        assert_eq!(outcome, MoveOutcome::Completed);
+        assert!(elapsed < wheel_move.profile().duration_s() + 0.1);
+
+        let finished = wheel_move.finished(outcome, 42, left, right);
+        assert_eq!(finished.id, 4);
+        assert_eq!(finished.timestamp_us, 42);
+        assert!(finished.left_error_rad.abs() <= POSITION_TOLERANCE_RAD);
+        assert!(finished.right_error_rad.abs() <= POSITION_TOLERANCE_RAD);
+    }
+
+    #[test]
+    fn test_wheel_move_times_out() {
+        let command = MoveWheels {
+            id: 5,
+            left_rad: 2.0,
+            right_rad: 2.0,
+            max_velocity_rad_per_s: 4.0,
+            max_acceleration_rad_per_s2: 2.0,
+        };
+        let wheel_move = WheelMove::new(&command, 0.0, 0.0).unwrap();
+        let end = wheel_move.profile().duration_s();
+
+        // Not over before the profile ends, even with the wheels at their targets
+        assert_eq!(wheel_move.outcome(end - 0.1, 2.0, 2.0), None);
+        assert_eq!(
+            wheel_move.outcome(end, 2.0, 2.0),
+            Some(MoveOutcome::Completed)
+        );
+
+        // Wheels blocked short of their targets are given time to settle, then given up on
+        assert_eq!(wheel_move.outcome(end, 1.0, 2.0), None);
+        assert_eq!(
+            wheel_move.outcome(end + SETTLE_TIMEOUT_S, 1.0, 2.0),
+            Some(MoveOutcome::TimedOut)
+        );
+        assert_eq!(wheel_move.errors(1.0, 2.5), (1.0, -0.5));
+    }
+}
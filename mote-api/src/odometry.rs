@@ -0,0 +1,374 @@
+//! Wheel odometry: estimating Mote's pose from its drive base state
+//!
+//! Odometry integrates the wheel positions reported in `DriveBaseState` into a pose in the frame
+//! Mote started in, with +x forward, +y to the left and θ counterclockwise. Gyro measurements can
+//! be fused in for heading, which doesn't suffer from wheel slip.
+//!
+//! Alongside the pose, Odometry tracks its covariance by propagating each wheel's travel
+//! uncertainty through the motion model, see Siegwart et al., "Introduction to Autonomous Mobile
+//! Robots", section 5.2.4.
+
+use libm::{cosf, remainderf, sinf};
+
+use crate::messages::mote_to_host::{DriveBaseState, IMUMeasurement};
+
+/// A 2D pose: meters, and radians counterclockwise from +x
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+}
+
+impl Pose {
+    /// Map a point in this pose's frame into the frame the pose is in
+    pub fn transform_point(&self, x: f32, y: f32) -> (f32, f32) {
+        let (sin, cos) = (sinf(self.theta), cosf(self.theta));
+        (self.x + x * cos - y * sin, self.y + x * sin + y * cos)
+    }
+
+    /// `other`, a pose in this pose's frame, in the frame this pose is in
+    pub fn compose(&self, other: Pose) -> Pose {
+        let (x, y) = self.transform_point(other.x, other.y);
+        Pose {
+            x,
+            y,
+            theta: wrap_angle(self.theta + other.theta),
+        }
+    }
+
+    /// `other`, a pose in the same frame as this one, in this pose's frame. The inverse of
+    /// compose, e.g. to find how far Mote moved between two odometry poses.
+    pub fn relative(&self, other: Pose) -> Pose {
+        let (sin, cos) = (sinf(self.theta), cosf(self.theta));
+        let (dx, dy) = (other.x - self.x, other.y - self.y);
+        Pose {
+            x: dx * cos + dy * sin,
+            y: -dx * sin + dy * cos,
+            theta: wrap_angle(other.theta - self.theta),
+        }
+    }
+}
+
+/// Covariance of a pose, ordered x, y, θ
+pub type Covariance = [[f32; 3]; 3];
+
+/// Dimensions and noise model of the drive base
+#[derive(Debug, Clone, PartialEq)]
+pub struct OdometryConfig {
+    pub wheel_radius_m: f32,
+    /// Distance between the wheels' contact points
+    pub track_width_m: f32,
+    /// Variance of a wheel's travel per meter it travels, in m²/m
+    pub wheel_variance: f32,
+    /// How much heading changes are taken from the gyro rather than the wheels, from 0 (wheels
+    /// only) to 1 (gyro only)
+    pub gyro_weight: f32,
+    /// Variance of heading integrated from the gyro per second, in rad²/s
+    pub gyro_variance: f32,
+}
+
+impl OdometryConfig {
+    /// A drive base with the given dimensions, and noise typical of Mote's TT motors and IMU
+    pub fn new(wheel_radius_m: f32, track_width_m: f32) -> Self {
+        Self {
+            wheel_radius_m,
+            track_width_m,
+            wheel_variance: 1e-3,
+            gyro_weight: 0.9,
+            gyro_variance: 1e-5,
+        }
+    }
+}
+
+/// Integrates wheel joint states, and optionally gyro measurements, into a pose
+///
+/// Call update_drive_base with every DriveBaseState received. The first only sets the reference
+/// wheel positions. If update_imu is called between drive base states, the heading change is
+/// blended with the gyro's according to `OdometryConfig::gyro_weight`.
+#[derive(Debug, Clone)]
+pub struct Odometry {
+    config: OdometryConfig,
+    pose: Pose,
+    covariance: Covariance,
+    /// Wheel positions at the last update, in rad
+    wheels: Option<(f32, f32)>,
+    /// Heading change integrated from the gyro since the last update, and for how long
+    gyro: Option<(f32, f32)>,
+}
+
+impl Odometry {
+    /// Start at the origin, with no uncertainty
+    pub fn new(config: OdometryConfig) -> Self {
+        Self::with_pose(config, Pose::default())
+    }
+
+    /// Start at a known pose, with no uncertainty
+    pub fn with_pose(config: OdometryConfig, pose: Pose) -> Self {
+        Self {
+            config,
+            pose,
+            covariance: [[0.0; 3]; 3],
+            wheels: None,
+            gyro: None,
+        }
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    pub fn covariance(&self) -> Covariance {
+        self.covariance
+    }
+
+    pub fn config(&self) -> &OdometryConfig {
+        &self.config
+    }
+
+    /// Move the estimate to a known pose, with no uncertainty, e.g. after localising against a map
+    pub fn reset(&mut self, pose: Pose) {
+        self.pose = pose;
+        self.covariance = [[0.0; 3]; 3];
+        self.gyro = None;
+    }
+
+    /// Integrate the gyro's yaw rate over `dt_s` seconds. The IMU is taken to be mounted with z
+    /// up, so a positive z rate turns Mote counterclockwise.
+    pub fn update_imu(&mut self, measurement: &IMUMeasurement, dt_s: f32) {
+        let (angle, duration) = self.gyro.get_or_insert((0.0, 0.0));
+        *angle += measurement.gyro.z * dt_s;
+        *duration += dt_s;
+    }
+
+    /// Integrate the wheels' travel since the last drive base state
+    pub fn update_drive_base(&mut self, state: &DriveBaseState) {
+        let wheels = (state.left.postition_rad, state.right.postition_rad);
+        let Some(last) = self.wheels.replace(wheels) else {
+            self.gyro = None;
+            return;
+        };
+        let radius = self.config.wheel_radius_m;
+        let track = self.config.track_width_m;
+        let left = (wheels.0 - last.0) * radius;
+        let right = (wheels.1 - last.1) * radius;
+
+        let distance = (right + left) / 2.0;
+        let variance_left = self.config.wheel_variance * left.abs();
+        let variance_right = self.config.wheel_variance * right.abs();
+        let variance_distance = (variance_right + variance_left) / 4.0;
+        let mut rotation = (right - left) / track;
+        let mut variance_rotation = (variance_right + variance_left) / (track * track);
+        let mut covariance_distance_rotation = (variance_right - variance_left) / (2.0 * track);
+
+        if let Some((gyro_rotation, duration)) = self.gyro.take() {
+            let weight = self.config.gyro_weight;
+            rotation = (1.0 - weight) * rotation + weight * gyro_rotation;
+            variance_rotation = (1.0 - weight) * (1.0 - weight) * variance_rotation
+                + weight * weight * self.config.gyro_variance * duration;
+            covariance_distance_rotation *= 1.0 - weight;
+        }
+
+        // Move along the arc's mean heading
+        let heading = self.pose.theta + rotation / 2.0;
+        let (sin, cos) = (sinf(heading), cosf(heading));
+        self.pose.x += distance * cos;
+        self.pose.y += distance * sin;
+        self.pose.theta = wrap_angle(self.pose.theta + rotation);
+
+        // Σ' = F Σ Fᵀ + G Q Gᵀ, with F the Jacobian with respect to the pose and G with respect
+        // to the distance and rotation
+        let f = [
+            [1.0, 0.0, -distance * sin],
+            [0.0, 1.0, distance * cos],
+            [0.0, 0.0, 1.0],
+        ];
+        let g = [
+            [cos, -distance / 2.0 * sin],
+            [sin, distance / 2.0 * cos],
+            [0.0, 1.0],
+        ];
+        let q = [
+            [variance_distance, covariance_distance_rotation],
+            [covariance_distance_rotation, variance_rotation],
+        ];
+        let mut covariance = [[0.0; 3]; 3];
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                for k in 0..3 {
+                    for l in 0..3 {
+                        *value += f[i][k] * self.covariance[k][l] * f[j][l];
+                    }
+                }
+                for k in 0..2 {
+                    for l in 0..2 {
+                        *value += g[i][k] * q[k][l] * g[j][l];
+                    }
+                }
+            }
+        }
+        self.covariance = covariance;
+    }
+}
+
+/// Wrap an angle into (-π, π]
+pub fn wrap_angle(angle: f32) -> f32 {
+    use core::f32::consts::{PI, TAU};
+
+    let wrapped = remainderf(angle, TAU);
+    if wrapped <= -PI { PI } else { wrapped }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::mote_to_host;
+
+    const WHEEL_RADIUS_M: f32 = 0.03;
+    const TRACK_WIDTH_M: f32 = 0.15;
+
+    // Drive base state with the wheels at the given positions
+    fn wheels_at(left: f32, right: f32) -> mote_to_host::DriveBaseState {
+        let wheel = |position| mote_to_host::WheelJointState {
+            effort_percent: 0.0,
+            velocity_rad_per_s: 0.0,
+            postition_rad: position,
+        };
+        mote_to_host::DriveBaseState {
+            timestamp_us: 0,
+            left: wheel(left),
+            right: wheel(right),
+        }
+    }
+
+    // Drive `steps` equal wheel increments from the origin, returning the odometry
+    fn drive(config: OdometryConfig, left: f32, right: f32, steps: u32) -> Odometry {
+        let mut odometry = Odometry::new(config);
+        for step in 0..=steps {
+            let fraction = step as f32 / steps as f32;
+            odometry.update_drive_base(&wheels_at(left * fraction, right * fraction));
+        }
+        odometry
+    }
+
+    #[test]
+    fn test_odometry_straight_line() {
+        let config = OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M);
+        // One metre forward
+        let wheel_travel = 1.0 / WHEEL_RADIUS_M;
+        let odometry = drive(config, wheel_travel, wheel_travel, 50);
+        let pose = odometry.pose();
+        assert!((pose.x - 1.0).abs() < 1e-4, "{pose:?}");
+        assert!(pose.y.abs() < 1e-4, "{pose:?}");
+        assert!(pose.theta.abs() < 1e-6, "{pose:?}");
+
+        // Uncertainty grows along and across the direction of travel, but not in heading order
+        let covariance = odometry.covariance();
+        assert!(covariance[0][0] > 0.0);
+        assert!(covariance[1][1] > 0.0);
+        assert!(covariance[2][2] > 0.0);
+        for (i, row) in covariance.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                assert!((value - covariance[j][i]).abs() < 1e-9, "Not symmetric");
+            }
+        }
+
+        // And backwards again
+        let odometry = drive(odometry.config().clone(), -wheel_travel, -wheel_travel, 50);
+        assert!((odometry.pose().x + 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_odometry_rotation_in_place() {
+        let config = OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M);
+        // A quarter turn counterclockwise: each wheel travels a quarter of the track's circle
+        let wheel_travel = core::f32::consts::FRAC_PI_2 * TRACK_WIDTH_M / 2.0 / WHEEL_RADIUS_M;
+        let odometry = drive(config.clone(), -wheel_travel, wheel_travel, 20);
+        let pose = odometry.pose();
+        assert!(pose.x.abs() < 1e-5 && pose.y.abs() < 1e-5, "{pose:?}");
+        assert!(
+            (pose.theta - core::f32::consts::FRAC_PI_2).abs() < 1e-5,
+            "{pose:?}"
+        );
+        assert!(odometry.covariance()[2][2] > 0.0);
+
+        // Three quarter turns clockwise wrap around to the same heading
+        let odometry = drive(config, 3.0 * wheel_travel, -3.0 * wheel_travel, 60);
+        assert!((odometry.pose().theta - core::f32::consts::FRAC_PI_2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_odometry_arc() {
+        let config = OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M);
+        // Half a circle of radius 0.5m to the left
+        let radius = 0.5;
+        let left = core::f32::consts::PI * (radius - TRACK_WIDTH_M / 2.0) / WHEEL_RADIUS_M;
+        let right = core::f32::consts::PI * (radius + TRACK_WIDTH_M / 2.0) / WHEEL_RADIUS_M;
+        let pose = drive(config, left, right, 100).pose();
+        assert!(pose.x.abs() < 1e-3, "{pose:?}");
+        assert!((pose.y - 2.0 * radius).abs() < 1e-3, "{pose:?}");
+        assert!((wrap_angle(pose.theta - core::f32::consts::PI)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_odometry_fuses_gyro_heading() {
+        let mut config = OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M);
+        config.gyro_weight = 1.0;
+        let mut odometry = Odometry::new(config.clone());
+        odometry.update_drive_base(&wheels_at(0.0, 0.0));
+        // The wheels claim a quarter turn, but slipped: the gyro saw only half of it
+        let wheel_travel = core::f32::consts::FRAC_PI_2 * TRACK_WIDTH_M / 2.0 / WHEEL_RADIUS_M;
+        let gyro = mote_to_host::IMUMeasurement {
+            timestamp_us: 0,
+            accel: mote_to_host::IMUAxisTriple {
+                x: 0.0,
+                y: 0.0,
+                z: 9.8,
+            },
+            gyro: mote_to_host::IMUAxisTriple {
+                x: 0.0,
+                y: 0.0,
+                z: core::f32::consts::FRAC_PI_4,
+            },
+        };
+        for _ in 0..50 {
+            odometry.update_imu(&gyro, 0.02);
+        }
+        odometry.update_drive_base(&wheels_at(-wheel_travel, wheel_travel));
+        assert!((odometry.pose().theta - core::f32::consts::FRAC_PI_4).abs() < 1e-4);
+
+        // Without gyro measurements, the wheels are trusted, and turn a whole quarter back
+        odometry.update_drive_base(&wheels_at(0.0, 0.0));
+        assert!((odometry.pose().theta + core::f32::consts::FRAC_PI_4).abs() < 1e-4);
+
+        // Blending gives a heading between the two, and less uncertain than the wheels alone
+        config.gyro_weight = 0.5;
+        let mut fused = Odometry::new(config);
+        fused.update_drive_base(&wheels_at(0.0, 0.0));
+        for _ in 0..50 {
+            fused.update_imu(&gyro, 0.02);
+        }
+        fused.update_drive_base(&wheels_at(-wheel_travel, wheel_travel));
+        let theta = fused.pose().theta;
+        assert!(
+            (theta - 3.0 * core::f32::consts::FRAC_PI_8).abs() < 1e-4,
+            "{theta}"
+        );
+        let wheels_only = drive(
+            OdometryConfig::new(WHEEL_RADIUS_M, TRACK_WIDTH_M),
+            -wheel_travel,
+            wheel_travel,
+            1,
+        );
+        assert!(fused.covariance()[2][2] < wheels_only.covariance()[2][2]);
+    }
+
+    #[test]
+    fn test_wrap_angle() {
+        use core::f32::consts::PI;
+        assert_eq!(wrap_angle(0.0), 0.0);
+        assert!((wrap_angle(3.0 * PI) - PI).abs() < 1e-5);
+        assert!((wrap_angle(-PI / 2.0 - 2.0 * PI) + PI / 2.0).abs() < 1e-5);
+        assert_eq!(wrap_angle(-PI), PI);
+    }
+}
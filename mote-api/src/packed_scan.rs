@@ -0,0 +1,289 @@
+//! Lidar points in the lidar's own fixed point units
+//!
+//! The RPLIDAR C1 reports each point as a quality byte, an angle in 1/64° and a distance in 1/4mm.
+//! Scan carries them as f32s, which nearly doubles their size on the wire and has the firmware
+//! convert every point to floats. PackedScan carries them as measured instead, optionally with
+//! each angle as the change from the previous one: consecutive points are under a degree apart, so
+//! the change usually fits in a single byte. Hosts convert them back with PackedScan::to_scan.
+
+use alloc::vec::Vec;
+
+use libm::{floorf, roundf};
+
+use crate::Error;
+use crate::messages::mote_to_host::{Message, PackedScan, Point, PointEncoding, Scan};
+
+/// Units of RawPoint::angle in a degree
+pub const ANGLE_UNITS_PER_DEGREE: f32 = 64.0;
+/// Units of RawPoint::distance in a millimeter
+pub const DISTANCE_UNITS_PER_MM: f32 = 4.0;
+
+/// A lidar point as the lidar measures it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RawPoint {
+    pub quality: u8,
+    /// Clockwise from forward, in 1/64°
+    pub angle: u16,
+    /// In 1/4mm, 0 if nothing was hit
+    pub distance: u16,
+}
+
+impl From<RawPoint> for Point {
+    fn from(point: RawPoint) -> Self {
+        Point {
+            quality: point.quality,
+            angle_rad: (point.angle as f32 / ANGLE_UNITS_PER_DEGREE).to_radians(),
+            distance_mm: point.distance as f32 / DISTANCE_UNITS_PER_MM,
+        }
+    }
+}
+
+impl From<&Point> for RawPoint {
+    /// Quantise a point to the lidar's resolution, e.g. to simulate one
+    fn from(point: &Point) -> Self {
+        let degrees = point.angle_rad.to_degrees();
+        let wrapped = degrees - 360.0 * floorf(degrees / 360.0);
+        let full_turn = (360.0 * ANGLE_UNITS_PER_DEGREE) as u32;
+        RawPoint {
+            quality: point.quality,
+            angle: (roundf(wrapped * ANGLE_UNITS_PER_DEGREE) as u32 % full_turn) as u16,
+            // Float to int casts saturate, so out of range distances clamp
+            distance: roundf(point.distance_mm * DISTANCE_UNITS_PER_MM) as u16,
+        }
+    }
+}
+
+impl PackedScan {
+    /// Pack `points`, measured up to `timestamp_us`
+    pub fn new(
+        timestamp_us: u64,
+        encoding: PointEncoding,
+        points: impl IntoIterator<Item = RawPoint>,
+    ) -> Self {
+        let mut data = Vec::new();
+        let mut last_angle = 0;
+        for point in points {
+            data.push(point.quality);
+            match encoding {
+                PointEncoding::Raw => data.extend_from_slice(&point.angle.to_le_bytes()),
+                PointEncoding::DeltaAngle => {
+                    // Wrapping, so the jump back to 0 each revolution is small too
+                    let delta = point.angle.wrapping_sub(last_angle) as i16;
+                    write_varint(&mut data, zigzag(delta));
+                    last_angle = point.angle;
+                }
+            }
+            data.extend_from_slice(&point.distance.to_le_bytes());
+        }
+        Self {
+            timestamp_us,
+            encoding,
+            data,
+        }
+    }
+
+    /// The points as the lidar measured them
+    pub fn raw_points(&self) -> Result<Vec<RawPoint>, Error> {
+        let mut points = Vec::new();
+        let mut data = self.data.as_slice();
+        let mut last_angle: u16 = 0;
+        while let Some((&quality, rest)) = data.split_first() {
+            data = rest;
+            let angle = match self.encoding {
+                PointEncoding::Raw => read_u16(&mut data)?,
+                PointEncoding::DeltaAngle => {
+                    let delta = unzigzag(read_varint(&mut data)?);
+                    last_angle = last_angle.wrapping_add(delta as u16);
+                    last_angle
+                }
+            };
+            let distance = read_u16(&mut data)?;
+            points.push(RawPoint {
+                quality,
+                angle,
+                distance,
+            });
+        }
+        Ok(points)
+    }
+
+    /// The equivalent Scan, with angles in radians and distances in millimeters
+    pub fn to_scan(&self) -> Result<Scan, Error> {
+        Ok(Scan {
+            timestamp_us: self.timestamp_us,
+            points: self.raw_points()?.into_iter().map(Point::from).collect(),
+        })
+    }
+}
+
+impl Message {
+    /// This message with a PackedScan replaced by the equivalent Scan, for hosts which only handle
+    /// Scan. Other messages are returned as they are.
+    pub fn unpacked(self) -> Result<Self, Error> {
+        match self {
+            Message::PackedScan(packed) => Ok(Message::Scan(packed.to_scan()?)),
+            message => Ok(message),
+        }
+    }
+}
+
+fn zigzag(value: i16) -> u16 {
+    ((value << 1) ^ (value >> 15)) as u16
+}
+
+fn unzigzag(value: u16) -> i16 {
+    ((value >> 1) as i16) ^ -((value & 1) as i16)
+}
+
+/// Append `value` 7 bits at a time, least significant first, with the top bit of each byte set
+/// if more follow
+fn write_varint(data: &mut Vec<u8>, mut value: u16) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u16, Error> {
+    let mut value: u16 = 0;
+    // A u16 takes at most 3 bytes
+    for shift in [0, 7, 14] {
+        let (&byte, rest) = data.split_first().ok_or(Error::MalformedScan)?;
+        *data = rest;
+        value |= ((byte & 0x7F) as u16) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::MalformedScan)
+}
+
+fn read_u16(data: &mut &[u8]) -> Result<u16, Error> {
+    let (bytes, rest) = data.split_first_chunk().ok_or(Error::MalformedScan)?;
+    *data = rest;
+    Ok(u16::from_le_bytes(*bytes))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use crate::messages::mote_to_host;
+
+    /// `count` points as the lidar measures them, about 0.72° apart from `start` in 1/64°, and
+    /// wrapping around past 360°
+    pub(crate) fn raw_lidar_points(start: u16, count: u16) -> impl Iterator<Item = RawPoint> {
+        (0..count).map(move |i| RawPoint {
+            quality: (i % 64) as u8,
+            angle: ((start as u32 + i as u32 * 46 + (i as u32 % 3)) % 23_040) as u16,
+            distance: 4 * (200 + i * 37 % 11_000),
+        })
+    }
+
+    #[test]
+    fn test_packed_scan_round_trip() {
+        // Crossing 0°, where the angle wraps around
+        let points: Vec<_> = raw_lidar_points(22_000, 100).collect();
+        for encoding in [
+            mote_to_host::PointEncoding::Raw,
+            mote_to_host::PointEncoding::DeltaAngle,
+        ] {
+            let packed = mote_to_host::PackedScan::new(42, encoding, points.iter().copied());
+            assert_eq!(packed.raw_points().unwrap(), points);
+
+            let scan = packed.to_scan().unwrap();
+            assert_eq!(scan.timestamp_us, 42);
+            assert_eq!(scan.points.len(), points.len());
+            for (point, raw) in scan.points.iter().zip(&points) {
+                assert_eq!(point.quality, raw.quality);
+                assert!((point.angle_rad.to_degrees() - raw.angle as f32 / 64.0).abs() < 1e-3);
+                assert_eq!(point.distance_mm, raw.distance as f32 / 4.0);
+                assert_eq!(RawPoint::from(point), *raw);
+            }
+        }
+
+        let message = mote_to_host::Message::PackedScan(mote_to_host::PackedScan::new(
+            7,
+            mote_to_host::PointEncoding::DeltaAngle,
+            points.iter().copied(),
+        ));
+        let mote_to_host::Message::Scan(scan) = message.unpacked().unwrap() else {
+            panic!("Expected a Scan");
+        };
+        assert_eq!(scan.points.len(), 100);
+        assert_eq!(
+            mote_to_host::Message::Pong.unpacked().unwrap(),
+            mote_to_host::Message::Pong
+        );
+    }
+
+    #[test]
+    fn test_packed_scan_quantises_points() {
+        let point = mote_to_host::Point {
+            quality: 9,
+            angle_rad: -core::f32::consts::FRAC_PI_2,
+            distance_mm: 1234.56,
+        };
+        assert_eq!(
+            RawPoint::from(&point),
+            RawPoint {
+                quality: 9,
+                angle: 270 * 64,
+                distance: 4938,
+            }
+        );
+        // Just under 360° rounds to 0°, and distances beyond the lidar's range saturate
+        let point = mote_to_host::Point {
+            quality: 0,
+            angle_rad: core::f32::consts::TAU - 1e-6,
+            distance_mm: 1e9,
+        };
+        let raw = RawPoint::from(&point);
+        assert_eq!((raw.angle, raw.distance), (0, u16::MAX));
+    }
+
+    #[test]
+    fn test_packed_scan_halves_bandwidth() {
+        let points: Vec<_> = raw_lidar_points(0, 100).collect();
+        let scan = mote_to_host::Message::Scan(mote_to_host::Scan {
+            timestamp_us: 12_345_678,
+            points: points
+                .iter()
+                .copied()
+                .map(mote_to_host::Point::from)
+                .collect(),
+        });
+        let size = |encoding| {
+            let packed =
+                mote_to_host::PackedScan::new(12_345_678, encoding, points.iter().copied());
+            postcard::to_allocvec(&mote_to_host::Message::PackedScan(packed))
+                .unwrap()
+                .len()
+        };
+        let scan_size = postcard::to_allocvec(&scan).unwrap().len();
+        assert!(size(mote_to_host::PointEncoding::Raw) * 10 < scan_size * 6);
+        assert!(size(mote_to_host::PointEncoding::DeltaAngle) * 2 < scan_size);
+    }
+
+    #[test]
+    fn test_packed_scan_rejects_malformed_data() {
+        let mut packed = mote_to_host::PackedScan::new(
+            0,
+            mote_to_host::PointEncoding::DeltaAngle,
+            raw_lidar_points(0, 3),
+        );
+        packed.data.pop();
+        assert!(matches!(packed.raw_points(), Err(Error::MalformedScan)));
+
+        // A varint longer than a u16
+        packed.data = vec![1, 0xFF, 0xFF, 0xFF, 0x01, 0, 0];
+        assert!(matches!(packed.to_scan(), Err(Error::MalformedScan)));
+
+        packed.encoding = mote_to_host::PointEncoding::Raw;
+        packed.data = vec![1, 2, 3, 4];
+        let message = mote_to_host::Message::PackedScan(packed);
+        assert!(matches!(message.unpacked(), Err(Error::MalformedScan)));
+    }
+}
@@ -0,0 +1,256 @@
+//! Assembling lidar scans into whole revolutions
+//!
+//! Mote sends lidar points in `Scan` messages as they are measured, up to 100 at a time, so each
+//! message covers an arbitrary arc. ScanAssembler collects them into sweeps of one revolution
+//! each, splitting where the angle wraps around past zero, and filters out unwanted points.
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use core::f32::consts::PI;
+
+use libm::{cosf, sinf};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "schemars")]
+use schemars::JsonSchema;
+
+use crate::messages::mote_to_host::Point;
+
+/// A drop in angle of more than this between consecutive points is the lidar starting a new
+/// revolution. Smaller drops are jitter in the measured angles.
+const WRAP_THRESHOLD_RAD: f32 = PI;
+
+/// Position of a lidar point in Mote's frame, in meters with +x forward and +y to the left
+///
+/// The lidar measures angles clockwise from straight ahead, and is taken to be at Mote's centre.
+pub fn point_position(point: &Point) -> (f32, f32) {
+    let distance_m = point.distance_mm / 1000.0;
+    (
+        distance_m * cosf(point.angle_rad),
+        -distance_m * sinf(point.angle_rad),
+    )
+}
+
+/// Which lidar points to keep
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScanFilter {
+    /// Points measured with a lower quality are dropped
+    pub min_quality: u8,
+    /// Points closer than this are dropped, e.g. to ignore Mote's own chassis
+    pub min_distance_mm: f32,
+    /// Points further than this are dropped
+    pub max_distance_mm: f32,
+    /// Drop points with a distance of zero, which the lidar reports when nothing reflected
+    pub drop_zero_distance: bool,
+}
+
+impl ScanFilter {
+    /// Whether `point` passes the filter
+    pub fn accepts(&self, point: &Point) -> bool {
+        if self.drop_zero_distance && point.distance_mm == 0.0 {
+            return false;
+        }
+        point.quality >= self.min_quality
+            && point.distance_mm >= self.min_distance_mm
+            && point.distance_mm <= self.max_distance_mm
+    }
+
+    /// Remove the points which don't pass the filter
+    pub fn apply(&self, points: &mut Vec<Point>) {
+        points.retain(|point| self.accepts(point));
+    }
+}
+
+/// Keeps every point except those without a return
+impl Default for ScanFilter {
+    fn default() -> Self {
+        Self {
+            min_quality: 0,
+            min_distance_mm: 0.0,
+            max_distance_mm: f32::MAX,
+            drop_zero_distance: true,
+        }
+    }
+}
+
+/// One revolution of the lidar
+#[cfg_attr(feature = "schemars", derive(JsonSchema))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sweep {
+    /// Points that passed the filter, in the order they were measured
+    pub points: Vec<Point>,
+    /// Time given with the scan holding the sweep's first point, in µs
+    pub start_us: u64,
+    /// Time given with the scan holding the sweep's last point, in µs
+    pub end_us: u64,
+}
+
+/// Assembles `Scan` messages into sweeps, see the module documentation
+///
+/// Push every scan received, in order, then poll for completed sweeps. Points before the first
+/// wrap around belong to a revolution that started before the first scan, so are discarded.
+#[derive(Debug, Clone, Default)]
+pub struct ScanAssembler {
+    filter: ScanFilter,
+    /// Points of the sweep in progress, once the first wrap around has been seen
+    current: Option<Vec<Point>>,
+    start_us: u64,
+    last_us: u64,
+    last_angle: Option<f32>,
+    sweeps: VecDeque<Sweep>,
+}
+
+impl ScanAssembler {
+    pub fn new(filter: ScanFilter) -> Self {
+        Self {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    pub fn filter(&self) -> &ScanFilter {
+        &self.filter
+    }
+
+    /// Add the points of a scan measured at `time_us`, on any clock counting in µs, e.g. the
+    /// scan's `timestamp_us`
+    pub fn push(&mut self, points: &[Point], time_us: u64) {
+        for point in points {
+            let wrapped = self
+                .last_angle
+                .is_some_and(|last| point.angle_rad < last - WRAP_THRESHOLD_RAD);
+            if wrapped {
+                if let Some(points) = self.current.replace(Vec::new()) {
+                    self.sweeps.push_back(Sweep {
+                        points,
+                        start_us: self.start_us,
+                        end_us: self.last_us,
+                    });
+                }
+                self.start_us = time_us;
+            }
+            self.last_angle = Some(point.angle_rad);
+            self.last_us = time_us;
+
+            if let Some(current) = &mut self.current
+                && self.filter.accepts(point)
+            {
+                current.push(point.clone());
+            }
+        }
+    }
+
+    /// The oldest completed sweep not yet polled
+    pub fn poll_sweep(&mut self) -> Option<Sweep> {
+        self.sweeps.pop_front()
+    }
+
+    /// Forget the sweep in progress, e.g. after a gap in the scans, and wait for the next wrap
+    /// around
+    pub fn reset(&mut self) {
+        self.current = None;
+        self.last_angle = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use crate::messages::mote_to_host;
+
+    // `count` evenly spaced points per revolution, starting at `start` points in, all 1m away
+    fn lidar_points(start: usize, count: usize, revolutions: usize) -> Vec<mote_to_host::Point> {
+        (start..start + count * revolutions)
+            .map(|i| mote_to_host::Point {
+                quality: 40,
+                angle_rad: (i % count) as f32 * core::f32::consts::TAU / count as f32,
+                distance_mm: 1000.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_assembler_splits_revolutions() {
+        let mut assembler = ScanAssembler::default();
+        // Two and a half revolutions of 360 points, starting a quarter of the way round, in
+        // chunks of 100 like Mote's
+        let points = lidar_points(90, 360, 3);
+        for (i, chunk) in points[..900].chunks(100).enumerate() {
+            assembler.push(chunk, i as u64 * 1000);
+        }
+
+        // The partial first revolution is discarded, and the second is complete
+        let sweep = assembler.poll_sweep().unwrap();
+        assert_eq!(sweep.points.len(), 360);
+        assert_eq!(sweep.points[0].angle_rad, 0.0);
+        assert!(
+            sweep
+                .points
+                .windows(2)
+                .all(|pair| pair[0].angle_rad < pair[1].angle_rad)
+        );
+        // The first point is in the third chunk, and the last in the seventh
+        assert_eq!((sweep.start_us, sweep.end_us), (2000, 6000));
+        // The third is still in progress
+        assert!(assembler.poll_sweep().is_none());
+
+        assembler.push(&points[900..], 9000);
+        assert_eq!(assembler.poll_sweep().unwrap().start_us, 6000);
+    }
+
+    #[test]
+    fn test_scan_assembler_tolerates_jitter() {
+        let mut assembler = ScanAssembler::default();
+        let mut points = lidar_points(0, 100, 3);
+        // Angles measured slightly out of order aren't a new revolution
+        points.swap(150, 151);
+        assembler.push(&points, 0);
+        assert_eq!(assembler.poll_sweep().unwrap().points.len(), 100);
+        assert!(assembler.poll_sweep().is_none());
+
+        // After a reset, the next wrap around starts over
+        assembler.reset();
+        assembler.push(&lidar_points(50, 100, 2), 0);
+        assert_eq!(assembler.poll_sweep().unwrap().points.len(), 100);
+        assert!(assembler.poll_sweep().is_none());
+    }
+
+    #[test]
+    fn test_scan_filter() {
+        let point = |quality, distance_mm| mote_to_host::Point {
+            quality,
+            angle_rad: 0.0,
+            distance_mm,
+        };
+        let default = ScanFilter::default();
+        assert!(default.accepts(&point(0, 12000.0)));
+        assert!(!default.accepts(&point(0, 0.0)));
+
+        let filter = ScanFilter {
+            min_quality: 10,
+            min_distance_mm: 100.0,
+            max_distance_mm: 6000.0,
+            drop_zero_distance: false,
+        };
+        assert!(filter.accepts(&point(10, 100.0)));
+        assert!(filter.accepts(&point(255, 6000.0)));
+        assert!(!filter.accepts(&point(9, 1000.0)));
+        assert!(!filter.accepts(&point(10, 99.0)));
+        assert!(!filter.accepts(&point(10, 6001.0)));
+
+        let mut points = vec![point(20, 500.0), point(5, 500.0), point(20, 0.0)];
+        filter.apply(&mut points);
+        assert_eq!(points, vec![point(20, 500.0)]);
+
+        // The assembler only keeps points which pass its filter
+        let mut assembler = ScanAssembler::new(filter);
+        let mut scan = lidar_points(0, 100, 2);
+        scan[110].quality = 0;
+        scan[120].distance_mm = 7000.0;
+        assembler.push(&scan, 0);
+        assembler.push(&lidar_points(0, 100, 1)[..1], 0);
+        assert_eq!(assembler.poll_sweep().unwrap().points.len(), 98);
+    }
+}
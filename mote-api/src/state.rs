@@ -0,0 +1,147 @@
+//! Sequencing, duplicate suppression and handshake state shared by MoteComms and StaticMoteComms
+
+use serde::Deserialize;
+
+use crate::frame::FragmentHeader;
+use crate::{Error, LinkStats, PROTOCOL_VERSION, ProtocolMessage};
+
+/// Frames this far behind the newest received sequence number are counted as out of order. Frames
+/// even further behind are assumed to come from a restarted peer, and resynchronise the receiver.
+const SEQUENCE_REORDER_WINDOW: i16 = 64;
+
+/// Number of delivered reliable sequence numbers remembered to suppress duplicates
+const DELIVERED_HISTORY: usize = 32;
+
+/// Everything a link knows about its peer, other than the bytes in flight
+pub(crate) struct LinkState {
+    pub now_ms: u64,
+    pub peer_protocol_version: Option<u16>,
+    pub stats: LinkStats,
+
+    next_transmit_sequence: u16,
+    expected_receive_sequence: Option<u16>,
+    recently_delivered: heapless::Deque<u16, DELIVERED_HISTORY>,
+}
+
+impl LinkState {
+    pub const fn new() -> Self {
+        Self {
+            now_ms: 0,
+            peer_protocol_version: None,
+            stats: LinkStats {
+                frames_sent: 0,
+                frames_received: 0,
+                frames_dropped: 0,
+                frames_corrupt: 0,
+                frames_out_of_order: 0,
+                fragments_expired: 0,
+                retransmissions: 0,
+                delivery_failures: 0,
+                duplicates_suppressed: 0,
+                frames_discarded: 0,
+                receive_overflows: 0,
+            },
+            next_transmit_sequence: 0,
+            expected_receive_sequence: None,
+            recently_delivered: heapless::Deque::new(),
+        }
+    }
+
+    /// Sequence number of the next frame to be queued
+    pub fn transmit_sequence(&self) -> u16 {
+        self.next_transmit_sequence
+    }
+
+    /// Record that the frame carrying transmit_sequence has been queued
+    pub fn advance_transmit_sequence(&mut self) {
+        self.next_transmit_sequence = self.next_transmit_sequence.wrapping_add(1);
+        self.stats.frames_sent = self.stats.frames_sent.wrapping_add(1);
+    }
+
+    /// Whether a completed frame is a reliable frame which has already been delivered. The peer
+    /// must have missed our acknowledgement, so it should be sent again.
+    pub fn is_duplicate(&mut self, header: FragmentHeader) -> bool {
+        let duplicate = header.is_reliable()
+            && self
+                .recently_delivered
+                .iter()
+                .any(|&sequence| sequence == header.sequence);
+        if duplicate {
+            self.stats.duplicates_suppressed = self.stats.duplicates_suppressed.wrapping_add(1);
+        }
+        duplicate
+    }
+
+    /// Deserialize the payload of a completed frame, and record its delivery
+    pub fn decode<'de, I>(&mut self, header: FragmentHeader, payload: &'de [u8]) -> Result<I, Error>
+    where
+        I: Deserialize<'de> + ProtocolMessage,
+    {
+        let msg = match postcard::from_bytes::<I>(payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                self.stats.frames_corrupt = self.stats.frames_corrupt.wrapping_add(1);
+                return Err(err.into());
+            }
+        };
+        self.stats.frames_received = self.stats.frames_received.wrapping_add(1);
+        self.track_sequence(header.sequence);
+
+        if msg.protocol_version().is_some() {
+            // A handshake starts a new session, so sequence numbers may be reused
+            self.recently_delivered.clear();
+        }
+        if header.is_reliable() {
+            if self.recently_delivered.is_full() {
+                self.recently_delivered.pop_front();
+            }
+            let _ = self.recently_delivered.push_back(header.sequence);
+        }
+
+        Ok(msg)
+    }
+
+    /// Hand a decoded message to the application, unless the peer speaks another protocol version
+    ///
+    /// Handshake messages are always handed over, so that they can be answered and inspected.
+    pub fn accept<I>(&mut self, msg: I) -> Result<Option<I>, Error>
+    where
+        I: ProtocolMessage,
+    {
+        if let Some(version) = msg.protocol_version() {
+            self.peer_protocol_version = Some(version);
+            return Ok(Some(msg));
+        }
+        match self.peer_protocol_version {
+            Some(peer) if peer != PROTOCOL_VERSION => Err(Error::IncompatibleProtocol {
+                local: PROTOCOL_VERSION,
+                peer,
+            }),
+            _ => Ok(Some(msg)),
+        }
+    }
+
+    /// Update the loss and reordering counters given the sequence number of a received frame
+    fn track_sequence(&mut self, sequence: u16) {
+        let Some(expected) = self.expected_receive_sequence else {
+            // First frame from this peer
+            self.expected_receive_sequence = Some(sequence.wrapping_add(1));
+            return;
+        };
+
+        let gap = sequence.wrapping_sub(expected) as i16;
+        if gap >= 0 {
+            // Every frame we skipped over is presumed lost
+            self.stats.frames_dropped = self.stats.frames_dropped.wrapping_add(gap as u32);
+            self.expected_receive_sequence = Some(sequence.wrapping_add(1));
+        } else if gap >= -SEQUENCE_REORDER_WINDOW {
+            // A late frame, which we previously presumed lost
+            self.stats.frames_out_of_order = self.stats.frames_out_of_order.wrapping_add(1);
+            self.stats.frames_dropped = self.stats.frames_dropped.saturating_sub(1);
+        } else {
+            // Far outside the window, the peer has likely restarted
+            self.expected_receive_sequence = Some(sequence.wrapping_add(1));
+            self.recently_delivered.clear();
+        }
+    }
+}
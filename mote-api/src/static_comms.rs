@@ -0,0 +1,657 @@
+//! Fixed capacity variant of MoteComms, for firmware which can't afford to allocate per message
+
+use core::marker::PhantomData;
+
+use heapless::Deque;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::frame::{
+    FLAG_RELIABLE, FRAGMENT_HEADER_LENGTH, FRAME_CRC_LENGTH, FragmentHeader, fragment_capacity,
+    fragment_count, read_fragment, write_fragment,
+};
+use crate::messages::{host_to_mote, mote_to_host};
+use crate::state::LinkState;
+use crate::{
+    Error, LinkStats, MAX_PARTIAL_FRAMES, MAX_RETRANSMISSIONS, Priority, ProtocolMessage,
+    REASSEMBLY_TIMEOUT_MS, RETRANSMIT_TIMEOUT_MS,
+};
+
+/// Number of telemetry messages which may be queued at once. When full, the oldest is dropped.
+const MAX_QUEUED_TELEMETRY: usize = 32;
+
+/// Number of reliable frames which may await acknowledgement at once. Each keeps a copy of its
+/// message, so this is smaller than MoteComms allows. When full, the oldest is given up on.
+const MAX_UNACKNOWLEDGED: usize = 4;
+
+/// A frame which has received some, but not all, of its fragments
+struct StaticPartialFrame<const MAX_MESSAGE: usize> {
+    sequence: u16,
+    fragment_count: u8,
+    /// Bitmap of the fragment indices received so far
+    received: [u32; 8],
+    fragments_received: u8,
+    length: usize,
+    started_ms: u64,
+    payload: [u8; MAX_MESSAGE],
+}
+
+impl<const MAX_MESSAGE: usize> StaticPartialFrame<MAX_MESSAGE> {
+    fn has_fragment(&self, index: u8) -> bool {
+        self.received[index as usize / 32] & (1 << (index % 32)) != 0
+    }
+
+    fn mark_fragment(&mut self, index: u8) {
+        self.received[index as usize / 32] |= 1 << (index % 32);
+        self.fragments_received += 1;
+    }
+}
+
+/// A reliable frame which has been sent, but not yet acknowledged
+struct StaticUnacknowledged<const MAX_MESSAGE: usize> {
+    sequence: u16,
+    length: usize,
+    last_sent_ms: u64,
+    retransmissions: u8,
+    payload: [u8; MAX_MESSAGE],
+}
+
+/// Bidirectional SansIO communication link between mote and the host, which never allocates.
+///
+/// Behaves like MoteComms, speaking the same wire protocol, but every buffer has a fixed capacity:
+/// MAX_MESSAGE bytes per serialized message, RX_BUFFER bytes of received data awaiting
+/// poll_receive and TX_BUFFER bytes of encoded packets awaiting poll_transmit in each priority
+/// class. Decoding a message
+/// which owns Strings or Vecs still allocates them, but the link itself doesn't.
+///
+/// Reliable messages are retransmitted until acknowledged, as with MoteComms, but at most
+/// MAX_UNACKNOWLEDGED may be awaiting acknowledgement at once, since each holds a copy of its
+/// serialized message.
+///
+/// You probably do not want to directly construct this. Instead, use the type aliases:
+/// StaticMoteLink
+/// StaticHostLink (use on mote)
+/// StaticMoteConfigLink
+/// StaticHostConfigLink (use on mote)
+pub struct StaticMoteComms<
+    const MTU: usize,
+    const MAX_MESSAGE: usize,
+    const RX_BUFFER: usize,
+    const TX_BUFFER: usize,
+    I,
+    O,
+> where
+    I: DeserializeOwned, // Input type
+    O: Serialize,        // Output type
+{
+    /// Encoded control packets, each ending in its zero delimiter
+    control_buffer: Deque<u8, TX_BUFFER>,
+    /// Encoded telemetry packets, each ending in its zero delimiter
+    telemetry_buffer: Deque<u8, TX_BUFFER>,
+    /// Bytes of each telemetry message still in telemetry_buffer, oldest first
+    telemetry_lengths: Deque<usize, MAX_QUEUED_TELEMETRY>,
+    deserialization_buffer: Deque<u8, RX_BUFFER>,
+    /// Dropping received bytes up to the next zero delimiter, after the buffer overflowed
+    resynchronising: bool,
+    partial_frames: heapless::Vec<StaticPartialFrame<MAX_MESSAGE>, MAX_PARTIAL_FRAMES>,
+    unacknowledged: heapless::Vec<StaticUnacknowledged<MAX_MESSAGE>, MAX_UNACKNOWLEDGED>,
+    state: LinkState,
+
+    // Scratch space, kept here rather than on the stack
+    payload: [u8; MAX_MESSAGE],
+    fragment: [u8; MTU],
+    packet: [u8; MTU],
+
+    in_type: PhantomData<I>,
+    out_type: PhantomData<O>,
+}
+
+impl<
+    const MTU: usize,
+    const MAX_MESSAGE: usize,
+    const RX_BUFFER: usize,
+    const TX_BUFFER: usize,
+    I,
+    O,
+> Default for StaticMoteComms<MTU, MAX_MESSAGE, RX_BUFFER, TX_BUFFER, I, O>
+where
+    I: DeserializeOwned + ProtocolMessage, // Input type
+    O: Serialize + ProtocolMessage,        // Output type
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+    const MTU: usize,
+    const MAX_MESSAGE: usize,
+    const RX_BUFFER: usize,
+    const TX_BUFFER: usize,
+    I,
+    O,
+> StaticMoteComms<MTU, MAX_MESSAGE, RX_BUFFER, TX_BUFFER, I, O>
+where
+    I: DeserializeOwned + ProtocolMessage, // Input type
+    O: Serialize + ProtocolMessage,        // Output type
+{
+    /// Generate a new link
+    pub const fn new() -> Self {
+        Self {
+            control_buffer: Deque::new(),
+            telemetry_buffer: Deque::new(),
+            telemetry_lengths: Deque::new(),
+            deserialization_buffer: Deque::new(),
+            resynchronising: false,
+            partial_frames: heapless::Vec::new(),
+            unacknowledged: heapless::Vec::new(),
+            state: LinkState::new(),
+            payload: [0; MAX_MESSAGE],
+            fragment: [0; MTU],
+            packet: [0; MTU],
+            in_type: PhantomData,
+            out_type: PhantomData,
+        }
+    }
+
+    /// Queue a message to be sent
+    ///
+    /// Messages which serialize to more than MAX_MESSAGE bytes are refused with
+    /// `Error::MessageTooLarge`. Messages are sent reliably if ProtocolMessage::reliable says so,
+    /// otherwise best-effort, and are queued by ProtocolMessage::priority, like MoteComms::send:
+    /// telemetry makes room by dropping the oldest queued telemetry, while a control message which
+    /// doesn't fit is refused with `Error::TransmitQueueFull`.
+    pub fn send(&mut self, message: O) -> Result<(), Error> {
+        let reliable = message.reliable();
+        self.queue(&message, reliable)
+    }
+
+    /// Queue a message to be sent reliably, regardless of ProtocolMessage::reliable
+    ///
+    /// Behaves like MoteComms::send_reliable. Retransmission is timed by handle_time, so it must be
+    /// called periodically for reliable messages to be retried.
+    pub fn send_reliable(&mut self, message: O) -> Result<(), Error> {
+        self.queue(&message, true)
+    }
+
+    fn queue(&mut self, message: &O, reliable: bool) -> Result<(), Error> {
+        let priority = if reliable {
+            Priority::Control
+        } else {
+            message.priority()
+        };
+        let length = match postcard::to_slice(message, &mut self.payload) {
+            Ok(payload) => payload.len(),
+            Err(postcard::Error::SerializeBufferFull) => return Err(Error::MessageTooLarge),
+            Err(err) => return Err(err.into()),
+        };
+
+        // Make room for the longest the packets could encode to
+        let encoded_bound = encoded_bound(length, MTU)?;
+        if encoded_bound > TX_BUFFER {
+            return Err(Error::TransmitQueueFull);
+        }
+        let queue = match priority {
+            Priority::Control => {
+                if TX_BUFFER - self.control_buffer.len() < encoded_bound {
+                    return Err(Error::TransmitQueueFull);
+                }
+                &mut self.control_buffer
+            }
+            Priority::Telemetry => {
+                while TX_BUFFER - self.telemetry_buffer.len() < encoded_bound
+                    || self.telemetry_lengths.is_full()
+                {
+                    let Some(oldest) = self.telemetry_lengths.pop_front() else {
+                        break;
+                    };
+                    for _ in 0..oldest {
+                        self.telemetry_buffer.pop_front();
+                    }
+                    self.state.stats.frames_discarded =
+                        self.state.stats.frames_discarded.wrapping_add(1);
+                }
+                &mut self.telemetry_buffer
+            }
+        };
+
+        let sequence = self.state.transmit_sequence();
+        let flags = if reliable { FLAG_RELIABLE } else { 0 };
+        let queued = queue.len();
+        push_frame::<MTU, TX_BUFFER>(
+            sequence,
+            flags,
+            &self.payload[..length],
+            &mut self.fragment,
+            &mut self.packet,
+            queue,
+        )?;
+        if priority == Priority::Telemetry {
+            let _ = self.telemetry_lengths.push_back(queue.len() - queued);
+        }
+        self.state.advance_transmit_sequence();
+
+        if reliable {
+            if self.unacknowledged.is_full() {
+                self.unacknowledged.remove(0);
+                self.state.stats.delivery_failures =
+                    self.state.stats.delivery_failures.wrapping_add(1);
+            }
+            let mut pending = StaticUnacknowledged {
+                sequence,
+                length,
+                last_sent_ms: self.state.now_ms,
+                retransmissions: 0,
+                payload: [0; MAX_MESSAGE],
+            };
+            pending.payload[..length].copy_from_slice(&self.payload[..length]);
+            let _ = self.unacknowledged.push(pending);
+        }
+
+        Ok(())
+    }
+
+    /// Get the next packet to be sent
+    ///
+    /// Control packets are sent before any telemetry. Once all control traffic has been sent,
+    /// reliable messages whose acknowledgement is overdue are queued again, ahead of telemetry.
+    pub fn poll_transmit(&mut self) -> Option<heapless::Vec<u8, MTU>> {
+        if self.control_buffer.is_empty() {
+            self.queue_retransmissions();
+        }
+        if let Some(packet) = pop_packet(&mut self.control_buffer) {
+            return Some(packet);
+        }
+        let packet = pop_packet(&mut self.telemetry_buffer)?;
+        if let Some(remaining) = self.telemetry_lengths.front_mut() {
+            *remaining = remaining.saturating_sub(packet.len());
+            if *remaining == 0 {
+                self.telemetry_lengths.pop_front();
+            }
+        }
+        Some(packet)
+    }
+
+    fn queue_retransmissions(&mut self) {
+        let now_ms = self.state.now_ms;
+        let stats = &mut self.state.stats;
+        let control_buffer = &mut self.control_buffer;
+        let fragment = &mut self.fragment;
+        let packet = &mut self.packet;
+        let mut full = false;
+        self.unacknowledged.retain_mut(|pending| {
+            if full || now_ms.saturating_sub(pending.last_sent_ms) < RETRANSMIT_TIMEOUT_MS {
+                return true;
+            }
+            if pending.retransmissions >= MAX_RETRANSMISSIONS {
+                stats.delivery_failures = stats.delivery_failures.wrapping_add(1);
+                return false;
+            }
+            // Several overdue messages may not fit at once, so leave the rest for a later poll
+            // rather than queueing part of a frame
+            let free = TX_BUFFER - control_buffer.len();
+            if !encoded_bound(pending.length, MTU).is_ok_and(|bound| bound <= free) {
+                full = true;
+                return true;
+            }
+            let _ = push_frame::<MTU, TX_BUFFER>(
+                pending.sequence,
+                FLAG_RELIABLE,
+                &pending.payload[..pending.length],
+                fragment,
+                packet,
+                control_buffer,
+            );
+            pending.retransmissions += 1;
+            pending.last_sent_ms = now_ms;
+            stats.retransmissions = stats.retransmissions.wrapping_add(1);
+            true
+        });
+    }
+
+    /// Number of reliable messages which have not been acknowledged by the peer yet
+    pub fn unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    /// Receive a message from raw bytes
+    ///
+    /// If the RX_BUFFER bytes of buffer overflow, the oldest frame is discarded, or the frame being
+    /// received if it is the only one, and LinkStats::receive_overflows is incremented.
+    pub fn handle_receive(&mut self, packet: &[u8]) {
+        for &byte in packet {
+            if self.resynchronising {
+                // Skip the rest of the discarded frame
+                self.resynchronising = byte != 0;
+                continue;
+            }
+            if self.deserialization_buffer.is_full() {
+                self.state.stats.receive_overflows =
+                    self.state.stats.receive_overflows.wrapping_add(1);
+                // Drop bytes up to and including the first delimiter, so the buffer starts on a
+                // frame boundary again
+                let mut found_delimiter = false;
+                while let Some(dropped) = self.deserialization_buffer.pop_front() {
+                    if dropped == 0 {
+                        found_delimiter = true;
+                        break;
+                    }
+                }
+                if !found_delimiter {
+                    self.resynchronising = byte != 0;
+                    continue;
+                }
+            }
+            let _ = self.deserialization_buffer.push_back(byte);
+        }
+    }
+
+    /// Advance the link's clock, in milliseconds since any fixed point.
+    ///
+    /// Frames which are still missing fragments REASSEMBLY_TIMEOUT_MS after their first fragment
+    /// arrived are discarded. Links which are never given the time never retransmit reliable
+    /// messages.
+    pub fn handle_time(&mut self, now_ms: u64) {
+        self.state.now_ms = now_ms;
+
+        let stats = &mut self.state.stats;
+        self.partial_frames.retain(|partial| {
+            let expired = now_ms.saturating_sub(partial.started_ms) > REASSEMBLY_TIMEOUT_MS;
+            if expired {
+                stats.fragments_expired = stats
+                    .fragments_expired
+                    .wrapping_add(partial.fragments_received as u32);
+            }
+            !expired
+        });
+    }
+
+    /// Poll for new messages in the recv buffer
+    ///
+    /// Behaves like MoteComms::poll_receive. Packets longer than the MTU can't have been sent by
+    /// a compatible peer, and are dropped and reported as `Error::FrameTooLong`.
+    pub fn poll_receive(&mut self) -> Result<Option<I>, Error> {
+        // Keep decoding fragments until one completes a frame
+        while let Some(end) = self.deserialization_buffer.iter().position(|&x| x == 0) {
+            let length = end + 1;
+            if length > MTU {
+                for _ in 0..length {
+                    self.deserialization_buffer.pop_front();
+                }
+                self.state.stats.frames_corrupt = self.state.stats.frames_corrupt.wrapping_add(1);
+                return Err(Error::FrameTooLong);
+            }
+            for byte in &mut self.packet[..length] {
+                *byte = self.deserialization_buffer.pop_front().unwrap_or_default();
+            }
+
+            let fragment = corncobs::decode_in_place(&mut self.packet[..length])
+                .map_err(Error::from)
+                .and_then(|decoded_size| read_fragment(&self.packet[..decoded_size]));
+            let (header, chunk) = match fragment {
+                Ok(fragment) => fragment,
+                Err(Error::CobsError(corncobs::CobsError::Truncated)) => {
+                    // We checked for the end byte above, so it shouldn't happen.
+                    // But it isn't an error.
+                    continue;
+                }
+                Err(err) => {
+                    self.state.stats.frames_corrupt =
+                        self.state.stats.frames_corrupt.wrapping_add(1);
+                    return Err(err);
+                }
+            };
+
+            if header.is_ack() {
+                self.unacknowledged
+                    .retain(|pending| pending.sequence != header.sequence);
+                continue;
+            }
+
+            let mut completed = None;
+            let payload = if header.fragment_count == 1 {
+                chunk
+            } else {
+                match reassemble::<MTU, MAX_MESSAGE>(
+                    &mut self.partial_frames,
+                    &mut self.state,
+                    header,
+                    chunk,
+                ) {
+                    Some(position) => {
+                        completed = Some(position);
+                        let partial = &self.partial_frames[position];
+                        &partial.payload[..partial.length]
+                    }
+                    None => continue,
+                }
+            };
+            let decoded = if self.state.is_duplicate(header) {
+                None
+            } else {
+                Some(self.state.decode::<I>(header, payload))
+            };
+            if let Some(position) = completed {
+                self.partial_frames.remove(position);
+            }
+
+            let Some(decoded) = decoded else {
+                // Our acknowledgement was lost, so send it again
+                self.queue_ack(header.sequence);
+                continue;
+            };
+            let msg = decoded?;
+            if header.is_reliable() {
+                self.queue_ack(header.sequence);
+            }
+            return self.state.accept(msg);
+        }
+
+        // No end byte = no message
+        Ok(None)
+    }
+
+    /// Queue an acknowledgement of the reliable frame with the given sequence number
+    ///
+    /// If the transmit buffer is full the acknowledgement is skipped, and the peer will retransmit.
+    fn queue_ack(&mut self, sequence: u16) {
+        let _ = push_fragment(
+            FragmentHeader::ack(sequence),
+            &[],
+            &mut self.fragment,
+            &mut self.packet,
+            &mut self.control_buffer,
+        );
+    }
+
+    /// Link quality counters
+    pub fn stats(&self) -> LinkStats {
+        self.state.stats
+    }
+
+    /// Protocol version advertised by the peer during the handshake, if one has been received
+    pub fn peer_protocol_version(&self) -> Option<u16> {
+        self.state.peer_protocol_version
+    }
+}
+
+/// Store a received fragment, returning the position of its frame in partial_frames once
+/// every fragment has arrived
+fn reassemble<const MTU: usize, const MAX_MESSAGE: usize>(
+    partial_frames: &mut heapless::Vec<StaticPartialFrame<MAX_MESSAGE>, MAX_PARTIAL_FRAMES>,
+    state: &mut LinkState,
+    header: FragmentHeader,
+    chunk: &[u8],
+) -> Option<usize> {
+    let position = partial_frames.iter().position(|partial| {
+        partial.sequence == header.sequence && partial.fragment_count == header.fragment_count
+    });
+    let position = match position {
+        Some(position) => position,
+        None => {
+            if partial_frames.is_full() {
+                let oldest = partial_frames.remove(0);
+                state.stats.fragments_expired = state
+                    .stats
+                    .fragments_expired
+                    .wrapping_add(oldest.fragments_received as u32);
+            }
+            let _ = partial_frames.push(StaticPartialFrame {
+                sequence: header.sequence,
+                fragment_count: header.fragment_count,
+                received: [0; 8],
+                fragments_received: 0,
+                length: 0,
+                started_ms: state.now_ms,
+                payload: [0; MAX_MESSAGE],
+            });
+            partial_frames.len() - 1
+        }
+    };
+
+    let partial = &mut partial_frames[position];
+    if partial.has_fragment(header.fragment_index) {
+        // Duplicate fragment
+        return None;
+    }
+
+    // Every fragment but the last is filled to capacity, so each chunk has a fixed place
+    let capacity = fragment_capacity(MTU);
+    let is_last = header.fragment_index + 1 == header.fragment_count;
+    let start = header.fragment_index as usize * capacity;
+    let end = start + chunk.len();
+    let consistent = chunk.len() == capacity || (is_last && chunk.len() <= capacity);
+    if !consistent || end > MAX_MESSAGE {
+        // Larger than we would ever accept, or cut by a peer with another MTU
+        let oversized = partial_frames.remove(position);
+        state.stats.frames_corrupt = state.stats.frames_corrupt.wrapping_add(1);
+        state.stats.fragments_expired = state
+            .stats
+            .fragments_expired
+            .wrapping_add(oversized.fragments_received as u32);
+        return None;
+    }
+    partial.payload[start..end].copy_from_slice(chunk);
+    partial.length += chunk.len();
+    partial.mark_fragment(header.fragment_index);
+
+    (partial.fragments_received == partial.fragment_count).then_some(position)
+}
+
+/// Longest the packets of a `length` byte message could encode to
+fn encoded_bound(length: usize, mtu: usize) -> Result<usize, Error> {
+    let capacity = fragment_capacity(mtu);
+    let fragment_count = fragment_count(length, mtu)?;
+    Ok((0..fragment_count as usize)
+        .map(|fragment_index| {
+            let chunk_length = length
+                .saturating_sub(fragment_index * capacity)
+                .min(capacity);
+            corncobs::max_encoded_len(FRAGMENT_HEADER_LENGTH + chunk_length + FRAME_CRC_LENGTH)
+        })
+        .sum())
+}
+
+/// Split a serialized message into fragments, appending each to the transmit buffer
+fn push_frame<const MTU: usize, const TX_BUFFER: usize>(
+    sequence: u16,
+    flags: u8,
+    payload: &[u8],
+    fragment: &mut [u8],
+    packet: &mut [u8],
+    transmit_buffer: &mut Deque<u8, TX_BUFFER>,
+) -> Result<(), Error> {
+    let fragment_count = fragment_count(payload.len(), MTU)?;
+    let capacity = fragment_capacity(MTU);
+    for fragment_index in 0..fragment_count {
+        let start = (fragment_index as usize * capacity).min(payload.len());
+        let end = (start + capacity).min(payload.len());
+        let header = FragmentHeader {
+            sequence,
+            fragment_index,
+            fragment_count,
+            flags,
+        };
+        push_fragment(
+            header,
+            &payload[start..end],
+            fragment,
+            packet,
+            transmit_buffer,
+        )?;
+    }
+    Ok(())
+}
+
+/// Lay out and COBS encode a fragment, appending it to the transmit buffer if there is room
+fn push_fragment<const TX_BUFFER: usize>(
+    header: FragmentHeader,
+    chunk: &[u8],
+    fragment: &mut [u8],
+    packet: &mut [u8],
+    transmit_buffer: &mut Deque<u8, TX_BUFFER>,
+) -> Result<(), Error> {
+    let fragment_length = write_fragment(header, chunk, fragment);
+    let packet_length = corncobs::encode_buf(&fragment[..fragment_length], packet);
+    if transmit_buffer.capacity() - transmit_buffer.len() < packet_length {
+        return Err(Error::TransmitQueueFull);
+    }
+    for &byte in &packet[..packet_length] {
+        let _ = transmit_buffer.push_back(byte);
+    }
+    Ok(())
+}
+
+/// Take the next encoded packet from a transmit buffer
+fn pop_packet<const MTU: usize, const TX_BUFFER: usize>(
+    transmit_buffer: &mut Deque<u8, TX_BUFFER>,
+) -> Option<heapless::Vec<u8, MTU>> {
+    let mut packet = heapless::Vec::new();
+    while let Some(byte) = transmit_buffer.pop_front() {
+        // Packets are never encoded longer than the MTU
+        let _ = packet.push(byte);
+        if byte == 0 {
+            return Some(packet);
+        }
+    }
+    None
+}
+
+/// Used by the host to send commands to and receive data from Mote, without allocating
+pub type StaticMoteLink = StaticMoteComms<
+    1400, // UDP MTU(ish)
+    2048, // Max message
+    4096, // Receive buffer
+    2048, // Transmit buffer, per priority
+    mote_to_host::Message,
+    host_to_mote::Message,
+>;
+
+/// Used by Mote to send data to and receive commands from the host, without allocating
+pub type StaticHostLink = StaticMoteComms<
+    1400, // UDP MTU(ish)
+    2048, // Max message
+    4096, // Receive buffer
+    4096, // Transmit buffer, per priority
+    host_to_mote::Message,
+    mote_to_host::Message,
+>;
+
+/// Used by the host to send commands to and receive data from Mote, without allocating
+pub type StaticMoteConfigLink = StaticMoteComms<
+    64,   // Serial MTU
+    2048, // Max message
+    1024, // Receive buffer
+    1024, // Transmit buffer, per priority
+    mote_to_host::Message,
+    host_to_mote::Message,
+>;
+
+/// Used by Mote to send data to and receive commands from the host, without allocating
+pub type StaticHostConfigLink = StaticMoteComms<
+    64,   // Serial MTU
+    2048, // Max message
+    1024, // Receive buffer
+    2048, // Transmit buffer, per priority
+    host_to_mote::Message,
+    mote_to_host::Message,
+>;
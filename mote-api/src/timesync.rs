@@ -0,0 +1,339 @@
+//! Mapping Mote's clock onto the host's
+//!
+//! Telemetry is timestamped in µs since Mote booted. To fuse it with data timestamped on the host,
+//! the host estimates the offset between the clocks with NTP-style exchanges: it sends a
+//! TimeSyncRequest stamped with its clock (t0), Mote notes when it received the request (t1) and
+//! sent its TimeSyncReply (t2), and the host notes when the reply arrived (t3). If the request and
+//! the reply took equally long, Mote's clock was ahead by ((t1 - t0) + (t2 - t3)) / 2.
+//!
+//! They rarely take equally long over Wi-Fi, and the offset is then off by half the difference.
+//! The difference can't exceed the round trip delay, so quick exchanges are the most trustworthy:
+//! ClockSync keeps a window of recent exchanges, rejects those delayed much longer than the
+//! quickest, and fits a line through the rest to track the clocks drifting apart.
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+
+use libm::round;
+
+use crate::messages::mote_to_host::TimeSyncReply;
+
+/// One time sync exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSyncSample {
+    /// Host time halfway through the exchange, in µs
+    pub host_us: u64,
+    /// How far Mote's clock was ahead of the host's, in µs
+    pub offset_us: i64,
+    /// Round trip delay, less the time Mote took to reply, in µs
+    pub delay_us: u64,
+}
+
+impl TimeSyncSample {
+    /// The exchange answered by `reply`, which arrived at `host_t3` on the host's clock
+    ///
+    /// None if the timestamps are inconsistent, e.g. the reply answers a request sent after t3.
+    pub fn new(reply: &TimeSyncReply, host_t3: u64) -> Option<Self> {
+        let round_trip = host_t3.checked_sub(reply.host_t0)?;
+        let processing = reply.device_t2.checked_sub(reply.device_t1)?;
+        let delay_us = round_trip.checked_sub(processing)?;
+        let outbound = reply.device_t1 as i64 - reply.host_t0 as i64;
+        let inbound = reply.device_t2 as i64 - host_t3 as i64;
+        Some(Self {
+            host_us: reply.host_t0 + round_trip / 2,
+            offset_us: (outbound + inbound) / 2,
+            delay_us,
+        })
+    }
+}
+
+/// How many exchanges to keep, and which to trust
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockSyncConfig {
+    /// Number of recent exchanges the estimate is based on
+    pub window: usize,
+    /// Exchanges delayed longer than the quickest in the window by more than this are rejected
+    pub max_excess_delay_us: u64,
+    /// Drift is only estimated once the trusted exchanges span this long, as jitter dominates
+    /// over shorter spans
+    pub min_drift_span_us: u64,
+}
+
+impl Default for ClockSyncConfig {
+    fn default() -> Self {
+        Self {
+            window: 32,
+            max_excess_delay_us: 2_000,
+            min_drift_span_us: 10_000_000,
+        }
+    }
+}
+
+/// The line fitted through the trusted exchanges
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Estimate {
+    /// Host time the line is centred on, in µs
+    host_us: u64,
+    /// Offset at host_us, in µs
+    offset_us: f64,
+    /// Change in offset per µs of host time
+    drift: f64,
+}
+
+/// Estimates the offset and drift between Mote's clock and the host's, see the module
+/// documentation
+///
+/// Send a TimeSyncRequest every second or so, passing each reply to handle_reply along with when
+/// it arrived, on the same clock as the request's host_t0.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    config: ClockSyncConfig,
+    samples: VecDeque<TimeSyncSample>,
+    estimate: Option<Estimate>,
+}
+
+impl ClockSync {
+    pub fn new(config: ClockSyncConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &ClockSyncConfig {
+        &self.config
+    }
+
+    /// Add the exchange answered by `reply`, which arrived at `host_t3`. Returns whether it is
+    /// trusted, i.e. not rejected for inconsistent timestamps or a long delay.
+    pub fn handle_reply(&mut self, reply: &TimeSyncReply, host_t3: u64) -> bool {
+        match TimeSyncSample::new(reply, host_t3) {
+            Some(sample) => self.add_sample(sample),
+            None => false,
+        }
+    }
+
+    /// Add an exchange, returning whether it is trusted
+    pub fn add_sample(&mut self, sample: TimeSyncSample) -> bool {
+        while self.samples.len() >= self.config.window.max(1) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        let trusted = self.trusted();
+        self.estimate = self.fit(&trusted);
+        trusted.contains(&sample)
+    }
+
+    /// Forget every exchange, e.g. after Mote rebooted
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.estimate = None;
+    }
+
+    pub fn is_synchronised(&self) -> bool {
+        self.estimate.is_some()
+    }
+
+    /// How far Mote's clock is ahead of the host's at `host_us`, in µs
+    pub fn offset_us(&self, host_us: u64) -> Option<i64> {
+        let estimate = self.estimate?;
+        let elapsed = host_us as i64 - estimate.host_us as i64;
+        Some(round(estimate.offset_us + estimate.drift * elapsed as f64) as i64)
+    }
+
+    /// How much faster Mote's clock runs than the host's, in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.estimate.map(|estimate| estimate.drift * 1e6)
+    }
+
+    /// The host time when Mote's clock read `device_us`, e.g. a telemetry timestamp
+    pub fn device_to_host(&self, device_us: u64) -> Option<u64> {
+        let estimate = self.estimate?;
+        // device = host + offset + drift * (host - centre), solved for host
+        let from_centre = device_us as i64 - estimate.host_us as i64;
+        let elapsed = (from_centre as f64 - estimate.offset_us) / (1.0 + estimate.drift);
+        u64::try_from(estimate.host_us as i64 + round(elapsed) as i64).ok()
+    }
+
+    /// What Mote's clock reads at `host_us`
+    pub fn host_to_device(&self, host_us: u64) -> Option<u64> {
+        u64::try_from(host_us as i64 + self.offset_us(host_us)?).ok()
+    }
+
+    fn trusted(&self) -> Vec<TimeSyncSample> {
+        let Some(quickest) = self.samples.iter().map(|sample| sample.delay_us).min() else {
+            return Vec::new();
+        };
+        let limit = quickest.saturating_add(self.config.max_excess_delay_us);
+        self.samples
+            .iter()
+            .filter(|sample| sample.delay_us <= limit)
+            .copied()
+            .collect()
+    }
+
+    /// Least squares line through the offsets of `samples`
+    fn fit(&self, samples: &[TimeSyncSample]) -> Option<Estimate> {
+        let count = samples.len() as f64;
+        // Replies can arrive out of order, so the samples needn't be sorted by time
+        let earliest = samples.iter().map(|sample| sample.host_us).min()?;
+        let latest = samples.iter().map(|sample| sample.host_us).max()?;
+        // Centred on the middle of the samples' span, keeping the numbers small
+        let centre = earliest + (latest - earliest) / 2;
+        let elapsed = |sample: &TimeSyncSample| sample.host_us as f64 - centre as f64;
+        let mean_elapsed = samples.iter().map(elapsed).sum::<f64>() / count;
+        let mean_offset = samples.iter().map(|s| s.offset_us as f64).sum::<f64>() / count;
+
+        let mut drift = 0.0;
+        if latest - earliest >= self.config.min_drift_span_us {
+            let (mut covariance, mut variance) = (0.0, 0.0);
+            for sample in samples {
+                let dx = elapsed(sample) - mean_elapsed;
+                covariance += dx * (sample.offset_us as f64 - mean_offset);
+                variance += dx * dx;
+            }
+            if variance > 0.0 {
+                drift = covariance / variance;
+            }
+        }
+        Some(Estimate {
+            host_us: centre,
+            offset_us: mean_offset - drift * mean_elapsed,
+            drift,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mote's clock in the simulated exchanges: booted 5s before the host's clock started, and
+    /// running 50ppm fast
+    fn device_clock(host_us: u64) -> u64 {
+        5_000_000 + host_us + host_us / 20_000
+    }
+
+    /// The reply to a request sent at `host_t0`, and when it arrives, given how long the request
+    /// and reply spend in flight
+    fn exchange(host_t0: u64, outbound_us: u64, inbound_us: u64) -> (TimeSyncReply, u64) {
+        let received = host_t0 + outbound_us;
+        let replied = received + 150;
+        let reply = TimeSyncReply {
+            host_t0,
+            device_t1: device_clock(received),
+            device_t2: device_clock(replied),
+        };
+        (reply, replied + inbound_us)
+    }
+
+    /// Deterministic jitter of up to 200µs
+    fn jitter(seed: &mut u32) -> u64 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as u64 % 200
+    }
+
+    #[test]
+    fn test_time_sync_sample() {
+        let (reply, host_t3) = exchange(1_000_000, 1_000, 1_000);
+        let sample = TimeSyncSample::new(&reply, host_t3).unwrap();
+        assert_eq!(sample.delay_us, 2_000);
+        assert_eq!(sample.host_us, 1_001_075);
+        let true_offset = device_clock(sample.host_us) as i64 - sample.host_us as i64;
+        assert!((sample.offset_us - true_offset).abs() <= 1);
+
+        // Half the difference between the directions ends up in the offset
+        let (reply, host_t3) = exchange(1_000_000, 500, 4_500);
+        let sample = TimeSyncSample::new(&reply, host_t3).unwrap();
+        let true_offset = device_clock(sample.host_us) as i64 - sample.host_us as i64;
+        assert!((sample.offset_us - true_offset + 2_000).abs() <= 1);
+
+        // A reply arriving before its request was sent
+        assert!(TimeSyncSample::new(&reply, 999_000).is_none());
+    }
+
+    #[test]
+    fn test_clock_sync_rejects_delayed_exchanges() {
+        let mut sync = ClockSync::default();
+        assert!(!sync.is_synchronised());
+        assert!(sync.offset_us(0).is_none());
+
+        let mut seed = 1;
+        let mut naive_total = 0;
+        let mut last_host_us = 0;
+        for i in 0..40u64 {
+            let host_t0 = 1_000_000 + i * 1_000_000;
+            let mut outbound = 1_500 + jitter(&mut seed);
+            let mut inbound = 1_500 + jitter(&mut seed);
+            // Every fourth exchange one direction is held up, e.g. by a retransmission
+            let delayed = i % 4 == 3;
+            if delayed && i % 8 == 3 {
+                inbound += 40_000;
+            } else if delayed {
+                outbound += 10_000;
+            }
+            let (reply, host_t3) = exchange(host_t0, outbound, inbound);
+            naive_total += TimeSyncSample::new(&reply, host_t3).unwrap().offset_us;
+            assert_eq!(sync.handle_reply(&reply, host_t3), !delayed, "exchange {i}");
+            last_host_us = host_t3;
+        }
+        assert!(sync.is_synchronised());
+
+        let true_offset = device_clock(last_host_us) as i64 - last_host_us as i64;
+        let offset = sync.offset_us(last_host_us).unwrap();
+        assert!(
+            (offset - true_offset).abs() < 150,
+            "{offset} isn't {true_offset}"
+        );
+        let drift = sync.drift_ppm().unwrap();
+        assert!((drift - 50.0).abs() < 5.0, "{drift}ppm");
+
+        // Averaging every exchange would be milliseconds out
+        let naive_offset = naive_total / 40;
+        let middle = 20_500_000;
+        let middle_offset = device_clock(middle) as i64 - middle as i64;
+        assert!((naive_offset - middle_offset).abs() > 1_000);
+
+        // Mapping timestamps both ways
+        let host_us = last_host_us + 2_000_000;
+        let device_us = sync.host_to_device(host_us).unwrap();
+        assert!((device_us as i64 - device_clock(host_us) as i64).abs() < 200);
+        assert_eq!(sync.device_to_host(device_us), Some(host_us));
+
+        sync.reset();
+        assert!(!sync.is_synchronised());
+    }
+
+    #[test]
+    fn test_clock_sync_drift_needs_span() {
+        // A burst of exchanges can't tell drift from jitter
+        let mut sync = ClockSync::default();
+        let mut seed = 7;
+        for i in 0..10 {
+            let (reply, host_t3) = exchange(i * 10_000, 1_000 + jitter(&mut seed), 1_000);
+            assert!(sync.handle_reply(&reply, host_t3));
+        }
+        assert_eq!(sync.drift_ppm(), Some(0.0));
+        let offset = sync.offset_us(50_000).unwrap();
+        assert!((offset - 5_000_002).abs() < 150, "{offset}");
+    }
+
+    #[test]
+    fn test_clock_sync_reordered_replies() {
+        // The reply to the later request overtakes the earlier one
+        let mut sync = ClockSync::default();
+        let earlier = exchange(1_000_000, 1_000, 1_000);
+        let later = exchange(13_000_000, 1_000, 1_000);
+        assert!(sync.handle_reply(&later.0, later.1));
+        assert!(sync.handle_reply(&earlier.0, earlier.1));
+
+        let drift = sync.drift_ppm().unwrap();
+        assert!((drift - 50.0).abs() < 1.0, "{drift}ppm");
+        let true_offset = device_clock(7_000_000) as i64 - 7_000_000;
+        let offset = sync.offset_us(7_000_000).unwrap();
+        assert!(
+            (offset - true_offset).abs() < 10,
+            "{offset} isn't {true_offset}"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Async Stream/Sink adapters, which drive a link over an async transport
+//!
+//! MoteComms and StaticMoteComms are SansIO: the application moves bytes between the link and a
+//! socket or serial port, and keeps the link's clock ticking. AsyncLink does that pumping, so
+//! applications just read messages from a Stream and write messages to a Sink.
+//!
+//! Enable the `tokio` feature for tokio sockets and byte streams (serial ports), or the `embassy`
+//! feature for embassy-net sockets.
+
+use core::ops::Deref;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{Error, MoteComms, ProtocolMessage, RETRANSMIT_TIMEOUT_MS, StaticMoteComms};
+
+#[cfg(feature = "embassy")]
+mod embassy;
+#[cfg(feature = "tokio")]
+mod tokio;
+
+#[cfg(feature = "embassy")]
+pub use self::embassy::*;
+#[cfg(feature = "tokio")]
+pub use self::tokio::*;
+
+/// How often AsyncLink advances the link's clock while idle, so that reliable messages are
+/// retransmitted and stale fragments expire even when no packets arrive
+pub const TICK_INTERVAL_MS: u64 = RETRANSMIT_TIMEOUT_MS / 5;
+
+/// The SansIO interface shared by MoteComms and StaticMoteComms
+pub trait Link {
+    /// Messages received from the peer
+    type Input;
+    /// Messages sent to the peer
+    type Output;
+    /// Encoded packet returned by poll_transmit
+    type Packet: Deref<Target = [u8]>;
+
+    fn send(&mut self, message: Self::Output) -> Result<(), Error>;
+    fn poll_transmit(&mut self) -> Option<Self::Packet>;
+    fn handle_receive(&mut self, bytes: &[u8]);
+    fn handle_time(&mut self, now_ms: u64);
+    fn poll_receive(&mut self) -> Result<Option<Self::Input>, Error>;
+}
+
+impl<const MTU: usize, const MAX_MESSAGE: usize, I, O> Link for MoteComms<MTU, MAX_MESSAGE, I, O>
+where
+    I: for<'de> Deserialize<'de> + ProtocolMessage,
+    O: Serialize + ProtocolMessage,
+{
+    type Input = I;
+    type Output = O;
+    type Packet = alloc::vec::Vec<u8>;
+
+    fn send(&mut self, message: O) -> Result<(), Error> {
+        MoteComms::send(self, message)
+    }
+
+    fn poll_transmit(&mut self) -> Option<Self::Packet> {
+        MoteComms::poll_transmit(self)
+    }
+
+    fn handle_receive(&mut self, bytes: &[u8]) {
+        MoteComms::handle_receive(self, bytes)
+    }
+
+    fn handle_time(&mut self, now_ms: u64) {
+        MoteComms::handle_time(self, now_ms)
+    }
+
+    fn poll_receive(&mut self) -> Result<Option<I>, Error> {
+        MoteComms::poll_receive(self)
+    }
+}
+
+impl<
+    const MTU: usize,
+    const MAX_MESSAGE: usize,
+    const RX_BUFFER: usize,
+    const TX_BUFFER: usize,
+    I,
+    O,
+> Link for StaticMoteComms<MTU, MAX_MESSAGE, RX_BUFFER, TX_BUFFER, I, O>
+where
+    I: DeserializeOwned + ProtocolMessage,
+    O: Serialize + ProtocolMessage,
+{
+    type Input = I;
+    type Output = O;
+    type Packet = heapless::Vec<u8, MTU>;
+
+    fn send(&mut self, message: O) -> Result<(), Error> {
+        StaticMoteComms::send(self, message)
+    }
+
+    fn poll_transmit(&mut self) -> Option<Self::Packet> {
+        StaticMoteComms::poll_transmit(self)
+    }
+
+    fn handle_receive(&mut self, bytes: &[u8]) {
+        StaticMoteComms::handle_receive(self, bytes)
+    }
+
+    fn handle_time(&mut self, now_ms: u64) {
+        StaticMoteComms::handle_time(self, now_ms)
+    }
+
+    fn poll_receive(&mut self) -> Result<Option<I>, Error> {
+        StaticMoteComms::poll_receive(self)
+    }
+}
+
+/// An async packet transport, such as a UDP socket or a serial port
+pub trait Transport {
+    type Error;
+
+    /// Send a whole packet. After returning Pending, this is called again with the same packet.
+    fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<Result<(), Self::Error>>;
+
+    /// Receive some bytes into `buffer`, returning how many. Returning 0 ends the stream.
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>>;
+}
+
+/// The time source of an AsyncLink
+pub trait Clock {
+    /// Milliseconds since any fixed point
+    fn now_ms(&self) -> u64;
+
+    /// Ready once every TICK_INTERVAL_MS, and Pending in between
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+/// Errors from an AsyncLink
+#[derive(thiserror::Error, Debug)]
+pub enum TransportError<E> {
+    #[error(transparent)]
+    Link(#[from] Error),
+    #[error("Transport failed: {0:?}")]
+    Transport(E),
+}
+
+/// A link driven over an async transport, see the module documentation
+///
+/// Received messages are read from the Stream. Items which fail to decode are yielded as errors,
+/// and the stream carries on afterwards; it only ends once the transport closes.
+///
+/// Messages are written to the Sink. It is always ready, since the link queues messages by
+/// priority, see MoteComms::send. Flushing waits until the transport has taken every queued packet.
+///
+/// Acknowledgements and retransmissions are sent whenever the stream or sink is polled, so keep
+/// polling the stream even when not expecting any messages.
+///
+/// READ_BUFFER must hold the largest packet the transport delivers at once.
+pub struct AsyncLink<L, T, C, const READ_BUFFER: usize = 2048>
+where
+    L: Link,
+{
+    link: L,
+    transport: T,
+    clock: C,
+    /// Packet taken from the link which the transport hasn't accepted yet
+    pending: Option<L::Packet>,
+    read_buffer: [u8; READ_BUFFER],
+}
+
+impl<L, T, C, const READ_BUFFER: usize> AsyncLink<L, T, C, READ_BUFFER>
+where
+    L: Link,
+    T: Transport,
+    C: Clock,
+{
+    pub fn new(link: L, transport: T, clock: C) -> Self {
+        Self {
+            link,
+            transport,
+            clock,
+            pending: None,
+            read_buffer: [0; READ_BUFFER],
+        }
+    }
+
+    /// The link being driven, e.g. to read its stats
+    pub fn link(&self) -> &L {
+        &self.link
+    }
+
+    pub fn link_mut(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Advance the link's clock, making sure we're woken up again for the next tick
+    fn poll_time(&mut self, cx: &mut Context<'_>) {
+        while self.clock.poll_tick(cx).is_ready() {}
+        self.link.handle_time(self.clock.now_ms());
+    }
+
+    /// Send queued packets until the link has none left, or the transport is busy
+    fn poll_send_queued(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        loop {
+            let Some(packet) = self.pending.take().or_else(|| self.link.poll_transmit()) else {
+                return Poll::Ready(Ok(()));
+            };
+            match self.transport.poll_send(cx, &packet) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => {
+                    self.pending = Some(packet);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<L, T, C, const READ_BUFFER: usize> Stream for AsyncLink<L, T, C, READ_BUFFER>
+where
+    L: Link + Unpin,
+    L::Packet: Unpin,
+    T: Transport + Unpin,
+    C: Clock + Unpin,
+{
+    type Item = Result<L::Input, TransportError<T::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.poll_time(cx);
+        loop {
+            if let Poll::Ready(Err(err)) = this.poll_send_queued(cx) {
+                return Poll::Ready(Some(Err(TransportError::Transport(err))));
+            }
+
+            match this.link.poll_receive() {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Some(Err(err.into()))),
+            }
+
+            match this.transport.poll_recv(cx, &mut this.read_buffer) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(None),
+                Poll::Ready(Ok(bytes_read)) => {
+                    this.link.handle_time(this.clock.now_ms());
+                    this.link.handle_receive(&this.read_buffer[..bytes_read]);
+                }
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Some(Err(TransportError::Transport(err))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<L, T, C, const READ_BUFFER: usize> Sink<L::Output> for AsyncLink<L, T, C, READ_BUFFER>
+where
+    L: Link + Unpin,
+    L::Packet: Unpin,
+    T: Transport + Unpin,
+    C: Clock + Unpin,
+{
+    type Error = TransportError<T::Error>;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: L::Output) -> Result<(), Self::Error> {
+        Ok(self.get_mut().link.send(message)?)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.poll_time(cx);
+        this.poll_send_queued(cx).map_err(TransportError::Transport)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,113 @@
+//! Transport and clock for embassy
+
+use core::pin::Pin;
+use core::task::{Context, Poll, ready};
+
+use embassy_net::udp::{RecvError, SendError, UdpMetadata, UdpSocket};
+use embassy_time::{Duration, Instant, Ticker};
+use futures_core::Stream;
+use thiserror::Error;
+
+use super::{Clock, TICK_INTERVAL_MS, Transport};
+
+/// A Transport over a bound embassy-net UDP socket
+///
+/// Packets are sent to whoever the last packet was received from, or to the peer given to
+/// with_peer until then. Packets sent before there is a peer are dropped.
+pub struct EmbassyUdp<'a> {
+    socket: UdpSocket<'a>,
+    peer: Option<UdpMetadata>,
+}
+
+impl<'a> EmbassyUdp<'a> {
+    pub fn new(socket: UdpSocket<'a>) -> Self {
+        Self { socket, peer: None }
+    }
+
+    pub fn with_peer(socket: UdpSocket<'a>, peer: impl Into<UdpMetadata>) -> Self {
+        Self {
+            socket,
+            peer: Some(peer.into()),
+        }
+    }
+
+    /// Where packets are currently sent
+    pub fn peer(&self) -> Option<UdpMetadata> {
+        self.peer
+    }
+
+    pub fn socket(&self) -> &UdpSocket<'a> {
+        &self.socket
+    }
+}
+
+/// Errors from an EmbassyUdp transport
+#[derive(Error, Debug)]
+pub enum UdpError {
+    #[error("UDP receive failed: {0:?}")]
+    Recv(RecvError),
+    #[error("UDP send failed: {0:?}")]
+    Send(SendError),
+}
+
+impl Transport for EmbassyUdp<'_> {
+    type Error = UdpError;
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<Result<(), UdpError>> {
+        let Some(peer) = self.peer else {
+            // Nobody to send to yet
+            return Poll::Ready(Ok(()));
+        };
+        self.socket
+            .poll_send_to(packet, peer, cx)
+            .map_err(UdpError::Send)
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<Result<usize, UdpError>> {
+        loop {
+            let (bytes_read, peer) =
+                ready!(self.socket.poll_recv_from(buffer, cx)).map_err(UdpError::Recv)?;
+            self.peer = Some(peer);
+            // An empty datagram doesn't mean the socket has closed
+            if bytes_read > 0 {
+                return Poll::Ready(Ok(bytes_read));
+            }
+        }
+    }
+}
+
+/// Clock for embassy, using embassy-time
+pub struct EmbassyClock {
+    ticker: Ticker,
+}
+
+impl EmbassyClock {
+    pub fn new() -> Self {
+        Self {
+            ticker: Ticker::every(Duration::from_millis(TICK_INTERVAL_MS)),
+        }
+    }
+}
+
+impl Default for EmbassyClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for EmbassyClock {
+    fn now_ms(&self) -> u64 {
+        Instant::now().as_millis()
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.ticker).poll_next(cx).map(|_| ())
+    }
+}
+
+/// AsyncLink over an embassy-net UDP socket
+pub type EmbassyLink<'a, L> = super::AsyncLink<L, EmbassyUdp<'a>, EmbassyClock>;
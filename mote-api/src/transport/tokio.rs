@@ -0,0 +1,126 @@
+//! Transports and clock for the tokio runtime
+
+use core::pin::Pin;
+use core::task::{Context, Poll, ready};
+use core::time::Duration;
+use std::io;
+
+use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use ::tokio::net::UdpSocket;
+use ::tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use super::{Clock, TICK_INTERVAL_MS, Transport};
+
+/// Sends to and receives from the peer the socket is connected to, see UdpSocket::connect
+impl Transport for UdpSocket {
+    type Error = io::Error;
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<()>> {
+        UdpSocket::poll_send(self, cx, packet).map_ok(|_| ())
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut read = ReadBuf::new(buffer);
+            ready!(UdpSocket::poll_recv(self, cx, &mut read))?;
+            // An empty datagram doesn't mean the socket has closed
+            if !read.filled().is_empty() {
+                return Poll::Ready(Ok(read.filled().len()));
+            }
+        }
+    }
+}
+
+/// A Transport over a byte stream, such as a serial port
+pub struct ByteStream<S> {
+    inner: S,
+    /// Bytes of the packet being sent which have already been written
+    written: usize,
+}
+
+impl<S> ByteStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Transport for ByteStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<()>> {
+        while self.written < packet.len() {
+            let written = Pin::new(&mut self.inner).poll_write(cx, &packet[self.written..]);
+            match ready!(written) {
+                Ok(0) => {
+                    self.written = 0;
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Ok(length) => self.written += length,
+                Err(err) => {
+                    self.written = 0;
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+        let flushed = ready!(Pin::new(&mut self.inner).poll_flush(cx));
+        self.written = 0;
+        Poll::Ready(flushed)
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut read = ReadBuf::new(buffer);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read))?;
+        Poll::Ready(Ok(read.filled().len()))
+    }
+}
+
+/// Clock for the tokio runtime. Must be created from within a runtime.
+pub struct TokioClock {
+    start: Instant,
+    ticks: Interval,
+}
+
+impl TokioClock {
+    pub fn new() -> Self {
+        let mut ticks = ::tokio::time::interval(Duration::from_millis(TICK_INTERVAL_MS));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            start: Instant::now(),
+            ticks,
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.ticks.poll_tick(cx).map(|_| ())
+    }
+}
+
+/// AsyncLink for the tokio runtime, e.g. over a UdpSocket or a ByteStream
+pub type TokioLink<L, T> = super::AsyncLink<L, T, TokioClock>;
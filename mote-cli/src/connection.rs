@@ -0,0 +1,272 @@
+//! Talking to Mote over UDP or USB serial
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, anyhow, bail};
+use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::{MoteConfigLink, PROTOCOL_VERSION};
+use mote_client::{Address, MoteClient};
+use serialport::SerialPort;
+
+/// How long to wait for Mote to answer a handshake or request
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Baud rate of the USB CDC serial device, which it ignores
+const SERIAL_BAUD_RATE: u32 = 115_200;
+
+/// How long a serial read waits for bytes before the link is serviced again
+const SERIAL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A connection to Mote over either transport
+pub trait Connection {
+    fn send(&mut self, message: host_to_mote::Message) -> anyhow::Result<()>;
+
+    /// Next message from Mote, or None if nothing arrives within `timeout`
+    fn recv_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<mote_to_host::Message>>;
+}
+
+/// Over Wi-Fi, using mote-client to keep the connection alive
+pub struct UdpConnection {
+    client: MoteClient,
+    messages: Receiver<mote_to_host::Message>,
+}
+
+impl UdpConnection {
+    pub fn open(address: impl Into<Address>) -> anyhow::Result<Self> {
+        let client = MoteClient::connect(address)?;
+        let messages = client.subscribe();
+        Ok(Self { client, messages })
+    }
+}
+
+impl Connection for UdpConnection {
+    fn send(&mut self, message: host_to_mote::Message) -> anyhow::Result<()> {
+        Ok(self.client.send(message)?)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<mote_to_host::Message>> {
+        match self.messages.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("Connection to Mote closed"),
+        }
+    }
+}
+
+/// Over the USB CDC serial device, which carries configuration commands and State
+pub struct SerialConnection {
+    port: Box<dyn SerialPort>,
+    link: MoteConfigLink,
+    start: Instant,
+}
+
+impl SerialConnection {
+    /// Open the serial device at `path` and exchange protocol versions with Mote
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let port = serialport::new(path, SERIAL_BAUD_RATE)
+            .timeout(SERIAL_POLL_INTERVAL)
+            .open()
+            .with_context(|| format!("Could not open {path}"))?;
+        let mut connection = Self {
+            port,
+            link: MoteConfigLink::new(),
+            start: Instant::now(),
+        };
+        connection.handshake()?;
+        Ok(connection)
+    }
+
+    fn handshake(&mut self) -> anyhow::Result<()> {
+        self.send(host_to_mote::Message::Hello(host_to_mote::Hello::default()))?;
+        let deadline = Instant::now() + ANSWER_TIMEOUT;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if let Some(mote_to_host::Message::HelloAck(ack)) = self.recv_timeout(remaining)? {
+                if ack.protocol_version != PROTOCOL_VERSION {
+                    bail!(mote_api::Error::IncompatibleProtocol {
+                        local: PROTOCOL_VERSION,
+                        peer: ack.protocol_version,
+                    });
+                }
+                return Ok(());
+            }
+        }
+        bail!("Mote did not answer the handshake over serial")
+    }
+
+    /// Send every queued packet, including acknowledgements and retransmissions
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.link
+            .handle_time(self.start.elapsed().as_millis() as u64);
+        while let Some(packet) = self.link.poll_transmit() {
+            self.port.write_all(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection for SerialConnection {
+    fn send(&mut self, message: host_to_mote::Message) -> anyhow::Result<()> {
+        self.link.send(message)?;
+        self.flush()
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<mote_to_host::Message>> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; 64];
+        // Read the port at least once, so a zero timeout still picks up waiting bytes
+        let mut read_port = false;
+        loop {
+            match self.link.poll_receive() {
+                Ok(Some(message)) => {
+                    self.flush()?;
+                    return Ok(Some(message));
+                }
+                Ok(None) => {}
+                Err(err @ mote_api::Error::IncompatibleProtocol { .. }) => return Err(err.into()),
+                // Corrupt frames are counted by the link, and skipped
+                Err(_) => {}
+            }
+            if read_port && Instant::now() >= deadline {
+                return Ok(None);
+            }
+            read_port = true;
+            match self.port.read(&mut buffer) {
+                Ok(bytes_read) => self.link.handle_receive(&buffer[..bytes_read]),
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                Err(err) => return Err(err.into()),
+            }
+            self.flush()?;
+        }
+    }
+}
+
+/// Connect over serial if a device path was given, otherwise over UDP. Without an address, Mote is
+/// found with mDNS.
+pub fn open(serial: Option<&str>, address: Option<&str>) -> anyhow::Result<Box<dyn Connection>> {
+    if let Some(path) = serial {
+        return Ok(Box::new(SerialConnection::open(path)?));
+    }
+    let address = match address {
+        Some(address) => Address::from(address),
+        None => discover_one()?,
+    };
+    Ok(Box::new(UdpConnection::open(address)?))
+}
+
+/// Find the only Mote on the network
+fn discover_one() -> anyhow::Result<Address> {
+    let motes = mote_client::discover(mote_client::DISCOVERY_TIMEOUT)?;
+    match motes.as_slice() {
+        [] => bail!("No Motes found on the network, pass --address or --serial"),
+        [mote] => {
+            eprintln!("Connecting to {} at {}", mote.uid, mote.ip);
+            Ok(mote.clone().into())
+        }
+        _ => {
+            let found: Vec<String> = motes
+                .iter()
+                .map(|mote| format!("{} ({})", mote.uid, mote.ip))
+                .collect();
+            Err(anyhow!(
+                "Found several Motes, pick one with --address: {}",
+                found.join(", ")
+            ))
+        }
+    }
+}
+
+/// Send a command as a request, and wait for Mote to carry it out
+///
+/// Returns the command's reply, if it has one. Fails if Mote refuses it or doesn't answer.
+pub fn request(
+    connection: &mut dyn Connection,
+    id: u32,
+    message: host_to_mote::Message,
+) -> anyhow::Result<Option<mote_to_host::Message>> {
+    connection.send(host_to_mote::Message::Request(host_to_mote::Request {
+        id,
+        message: Box::new(message),
+    }))?;
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match connection.recv_timeout(remaining)? {
+            Some(mote_to_host::Message::Response(response)) if response.id == id => {
+                return Ok(response.result.map(|result| *result));
+            }
+            Some(mote_to_host::Message::Nack(nack)) if nack.id == id => {
+                bail!("Mote refused the command: {:?}", nack.reason);
+            }
+            _ => {}
+        }
+    }
+    bail!("Mote did not answer within {ANSWER_TIMEOUT:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Answers from a script instead of a Mote
+    #[derive(Default)]
+    struct Scripted {
+        sent: Vec<host_to_mote::Message>,
+        answers: VecDeque<mote_to_host::Message>,
+    }
+
+    impl Connection for Scripted {
+        fn send(&mut self, message: host_to_mote::Message) -> anyhow::Result<()> {
+            self.sent.push(message);
+            Ok(())
+        }
+
+        fn recv_timeout(
+            &mut self,
+            _timeout: Duration,
+        ) -> anyhow::Result<Option<mote_to_host::Message>> {
+            Ok(self.answers.pop_front())
+        }
+    }
+
+    #[test]
+    fn test_request_returns_matching_response() -> anyhow::Result<()> {
+        let mut connection = Scripted::default();
+        connection.answers.extend([
+            mote_to_host::Message::Scan(mote_to_host::Scan::default()),
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 6,
+                result: Some(Box::new(mote_to_host::Message::Ping)),
+            }),
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 7,
+                result: Some(Box::new(mote_to_host::Message::Pong)),
+            }),
+        ]);
+        let reply = request(&mut connection, 7, host_to_mote::Message::Ping)?;
+        assert_eq!(reply, Some(mote_to_host::Message::Pong));
+        assert!(matches!(
+            &connection.sent[..],
+            [host_to_mote::Message::Request(request)] if request.id == 7
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_fails_on_nack() {
+        let mut connection = Scripted::default();
+        connection
+            .answers
+            .push_back(mote_to_host::Message::Nack(mote_to_host::Nack {
+                id: 1,
+                reason: mote_to_host::NackReason::Unsupported,
+            }));
+        let result = request(
+            &mut connection,
+            1,
+            host_to_mote::Message::RequestNetworkScan,
+        );
+        assert!(result.unwrap_err().to_string().contains("Unsupported"));
+    }
+}
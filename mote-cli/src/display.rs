@@ -0,0 +1,102 @@
+//! Human readable output
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+use mote_api::messages::mote_to_host::{BITList, BITResult, NetworkConnection, State};
+
+/// State as an indented report, one field or test per line
+pub fn format_state(state: &State) -> String {
+    let mut out = String::new();
+    let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".into());
+    // Writing to a String can't fail
+    let _ = writeln!(out, "UID:      {}", state.uid);
+    let _ = writeln!(out, "IP:       {}", or_none(&state.ip));
+    let _ = writeln!(out, "MAC:      {}", or_none(&state.mac));
+    let _ = writeln!(
+        out,
+        "Network:  {}",
+        or_none(&state.current_network_connection)
+    );
+    out.push_str("Built-in tests:\n");
+    let bit = &state.built_in_test;
+    for (group, list) in [
+        ("Power", &bit.power),
+        ("Wi-Fi", &bit.wifi),
+        ("LiDAR", &bit.lidar),
+        ("IMU", &bit.imu),
+        ("Encoders", &bit.encoders),
+    ] {
+        format_bit_list(&mut out, group, list);
+    }
+    out
+}
+
+fn format_bit_list(out: &mut String, group: &str, list: &BITList) {
+    let _ = writeln!(out, "  {group}");
+    if list.is_empty() {
+        out.push_str("    (no tests)\n");
+    }
+    for test in list {
+        let result = match test.result {
+            BITResult::Waiting => "WAITING",
+            BITResult::Pass => "PASS",
+            BITResult::Fail => "FAIL",
+        };
+        let _ = writeln!(out, "    {result:<8}{}", test.name);
+    }
+}
+
+/// Networks from a scan, strongest first
+pub fn format_networks(networks: &[NetworkConnection]) -> String {
+    if networks.is_empty() {
+        return "No networks found\n".into();
+    }
+    let mut networks = networks.to_vec();
+    networks.sort_by_key(|network| Reverse(network.strength));
+    let mut out = String::new();
+    for network in networks {
+        let _ = writeln!(out, "{:>4}  {}", network.strength, network.ssid);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mote_api::messages::mote_to_host::BIT;
+
+    #[test]
+    fn test_format_state() {
+        let mut state = State {
+            uid: "mote-a".into(),
+            ip: Some("192.168.1.20".into()),
+            ..Default::default()
+        };
+        state.built_in_test.lidar.push(BIT {
+            name: "Spinning".into(),
+            result: BITResult::Fail,
+        });
+        let formatted = format_state(&state);
+        assert!(formatted.contains("UID:      mote-a\n"));
+        assert!(formatted.contains("IP:       192.168.1.20\n"));
+        assert!(formatted.contains("MAC:      -\n"));
+        assert!(formatted.contains("  LiDAR\n    FAIL    Spinning\n"));
+        assert!(formatted.contains("  IMU\n    (no tests)\n"));
+    }
+
+    #[test]
+    fn test_format_networks_strongest_first() {
+        let networks = [
+            NetworkConnection {
+                ssid: "weak".into(),
+                strength: 20,
+            },
+            NetworkConnection {
+                ssid: "strong".into(),
+                strength: 80,
+            },
+        ];
+        assert_eq!(format_networks(&networks), "  80  strong\n  20  weak\n");
+    }
+}
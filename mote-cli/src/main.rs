@@ -0,0 +1,463 @@
+//! `mote`, a command-line tool for driving and inspecting a Mote
+//!
+//! Commands go over UDP to the Mote at --address, or over the USB serial device at --serial. With
+//! neither, the only Mote advertising itself on the network is used.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use clap::{Parser, Subcommand, ValueEnum};
+use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::motion;
+use mote_api::odometry::OdometryConfig;
+
+mod connection;
+mod display;
+mod mapping;
+mod record;
+mod teleop;
+
+use crate::connection::{Connection, request};
+
+#[derive(Parser)]
+#[command(name = "mote", version, about = "Drive and inspect a Mote")]
+struct Cli {
+    /// IP address or UID of the Mote to connect to over UDP
+    #[arg(long, short, global = true, conflicts_with = "serial")]
+    address: Option<String>,
+
+    /// Path of Mote's USB serial device, e.g. /dev/ttyACM0
+    #[arg(long, short, global = true)]
+    serial: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the Motes advertising themselves on the network
+    Discover {
+        /// Seconds to listen for
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Measure the round trip time to Mote
+    Ping {
+        #[arg(long, short, default_value_t = 4)]
+        count: u32,
+    },
+    /// Print Mote's state and built-in test results (serial only)
+    State {
+        /// Seconds to wait for Mote to report its state
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Change Mote's UID, which is also its mDNS hostname (serial only)
+    SetUid { uid: String },
+    /// Scan for or join Wi-Fi networks (serial only)
+    Wifi {
+        #[command(subcommand)]
+        command: WifiCommand,
+    },
+    /// Choose which telemetry Mote sends, and how often, until it restarts
+    Telemetry {
+        /// Stop sending lidar scans
+        #[arg(long)]
+        no_scan: bool,
+        /// Only send one in this many lidar points
+        #[arg(long, default_value_t = 1)]
+        scan_decimation: u8,
+        /// Stop sending IMU measurements
+        #[arg(long)]
+        no_imu: bool,
+        /// Milliseconds between IMU measurements
+        #[arg(long, default_value_t = 20)]
+        imu_period: u32,
+        /// Stop sending drive base states
+        #[arg(long)]
+        no_drive: bool,
+        /// Milliseconds between drive base states
+        #[arg(long, default_value_t = 100)]
+        drive_period: u32,
+    },
+    /// Drive Mote with the keyboard
+    Drive {
+        /// Wheel speed in rad/s
+        #[arg(long, default_value_t = 5.0)]
+        speed: f32,
+    },
+    /// Drive a distance or turn on the spot, and wait for Mote to get there
+    Move {
+        /// Meters to drive forward, backwards if negative
+        #[arg(long, allow_hyphen_values = true, required_unless_present = "turn")]
+        distance: Option<f32>,
+        /// Degrees to turn counterclockwise, clockwise if negative
+        #[arg(long, allow_hyphen_values = true, conflicts_with = "distance")]
+        turn: Option<f32>,
+        /// Top speed of the wheels over the ground in m/s
+        #[arg(long, default_value_t = 0.2)]
+        speed: f32,
+        /// Acceleration of the wheels over the ground in m/s²
+        #[arg(long, default_value_t = 0.5)]
+        acceleration: f32,
+        #[arg(long, default_value_t = 0.033)]
+        wheel_radius: f32,
+        /// Distance between the wheels in meters
+        #[arg(long, default_value_t = 0.14)]
+        track_width: f32,
+    },
+    /// Print telemetry as JSON lines
+    Tail {
+        /// Only print this kind of message
+        #[arg(long, short)]
+        kind: Option<Kind>,
+        /// Stop after this many messages
+        #[arg(long, short)]
+        count: Option<usize>,
+    },
+    /// Record telemetry to a log, or to MCAP if the path ends in .mcap, until Enter is pressed
+    Record {
+        path: PathBuf,
+        /// Stop after this many seconds
+        #[arg(long)]
+        duration: Option<u64>,
+        /// UID written to the log, defaults to --address
+        #[arg(long)]
+        uid: Option<String>,
+    },
+    /// Convert a recorded log to MCAP, for Foxglove
+    Export { log: PathBuf, mcap: PathBuf },
+    /// Build an occupancy grid map from a recorded log, as a PNG if the path ends in .png and a
+    /// PGM otherwise, with map_server metadata in a .yaml file next to it
+    Map {
+        log: PathBuf,
+        output: PathBuf,
+        /// Size of a cell in meters
+        #[arg(long, default_value_t = 0.05)]
+        resolution: f32,
+        /// Width and height of the map in meters, centered where Mote started
+        #[arg(long, default_value_t = 20.0)]
+        size: f32,
+        #[arg(long, default_value_t = 0.033)]
+        wheel_radius: f32,
+        /// Distance between the wheels in meters
+        #[arg(long, default_value_t = 0.14)]
+        track_width: f32,
+        /// How much heading is taken from the gyro rather than the wheels, from 0 to 1
+        #[arg(long, default_value_t = 0.9)]
+        gyro_weight: f32,
+    },
+}
+
+#[derive(Subcommand)]
+enum WifiCommand {
+    /// List the networks Mote can see
+    Scan {
+        /// Seconds to wait for the scan to finish
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Connect Mote to a network
+    Join {
+        #[arg(long)]
+        ssid: String,
+        #[arg(long)]
+        password: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Kind {
+    Scan,
+    Imu,
+    Drive,
+    State,
+}
+
+impl Kind {
+    fn matches(self, message: &mote_to_host::Message) -> bool {
+        matches!(
+            (self, message),
+            (Kind::Scan, mote_to_host::Message::Scan(_))
+                | (Kind::Imu, mote_to_host::Message::IMUMeasurement(_))
+                | (Kind::Drive, mote_to_host::Message::DriveBaseState(_))
+                | (Kind::State, mote_to_host::Message::State(_))
+        )
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match &cli.command {
+        Command::Discover { timeout } => return discover(Duration::from_secs(*timeout)),
+        Command::Export { log, mcap } => return record::export(log, mcap),
+        Command::Map {
+            log,
+            output,
+            resolution,
+            size,
+            wheel_radius,
+            track_width,
+            gyro_weight,
+        } => {
+            let options = mapping::MapOptions {
+                resolution_m: *resolution,
+                size_m: *size,
+                wheel_radius_m: *wheel_radius,
+                track_width_m: *track_width,
+                gyro_weight: *gyro_weight,
+            };
+            return mapping::map(log, output, &options);
+        }
+        _ => {}
+    }
+
+    let mut connection = connection::open(cli.serial.as_deref(), cli.address.as_deref())?;
+    let connection = connection.as_mut();
+    match cli.command {
+        Command::Discover { .. } | Command::Export { .. } | Command::Map { .. } => {
+            unreachable!("handled without connecting")
+        }
+        Command::Ping { count } => ping(connection, count),
+        Command::State { timeout } => {
+            let state = next_state(connection, Duration::from_secs(timeout))?;
+            print!("{}", display::format_state(&state));
+            Ok(())
+        }
+        Command::SetUid { uid } => {
+            let set_uid = host_to_mote::SetUID { uid };
+            request(connection, 1, host_to_mote::Message::SetUID(set_uid))?;
+            println!("UID set");
+            Ok(())
+        }
+        Command::Wifi {
+            command: WifiCommand::Scan { timeout },
+        } => wifi_scan(connection, Duration::from_secs(timeout)),
+        Command::Wifi {
+            command: WifiCommand::Join { ssid, password },
+        } => {
+            let config = host_to_mote::SetNetworkConnectionConfig { ssid, password };
+            let message = host_to_mote::Message::SetNetworkConnectionConfig(config);
+            request(connection, 1, message)?;
+            println!("Network saved, Mote is connecting");
+            Ok(())
+        }
+        Command::Telemetry {
+            no_scan,
+            scan_decimation,
+            no_imu,
+            imu_period,
+            no_drive,
+            drive_period,
+        } => {
+            let config = host_to_mote::ConfigureTelemetry {
+                scan: host_to_mote::ScanTelemetry {
+                    enabled: !no_scan,
+                    decimation: scan_decimation,
+                },
+                imu: host_to_mote::PeriodicTelemetry {
+                    enabled: !no_imu,
+                    period_ms: imu_period,
+                },
+                drive_base: host_to_mote::PeriodicTelemetry {
+                    enabled: !no_drive,
+                    period_ms: drive_period,
+                },
+            };
+            request(
+                connection,
+                1,
+                host_to_mote::Message::ConfigureTelemetry(config),
+            )?;
+            println!("Telemetry configured");
+            Ok(())
+        }
+        Command::Drive { speed } => teleop::drive(connection, speed),
+        Command::Move {
+            distance,
+            turn,
+            speed,
+            acceleration,
+            wheel_radius,
+            track_width,
+        } => {
+            let config = OdometryConfig::new(wheel_radius, track_width);
+            let command = match (distance, turn) {
+                (Some(distance), _) => host_to_mote::MoveWheels::drive_distance(
+                    1,
+                    &config,
+                    distance,
+                    speed,
+                    acceleration,
+                ),
+                (None, Some(turn)) => {
+                    // Angular limits giving the wheels the requested speed over the ground
+                    let radius = track_width / 2.0;
+                    host_to_mote::MoveWheels::rotate_angle(
+                        1,
+                        &config,
+                        turn.to_radians(),
+                        speed / radius,
+                        acceleration / radius,
+                    )
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            move_wheels(connection, command)
+        }
+        Command::Tail { kind, count } => tail(connection, kind, count),
+        Command::Record {
+            path,
+            duration,
+            uid,
+        } => {
+            let uid = uid.or(cli.address).unwrap_or_else(|| "unknown".into());
+            record::record(connection, &path, &uid, duration.map(Duration::from_secs))
+        }
+    }
+}
+
+fn discover(timeout: Duration) -> anyhow::Result<()> {
+    let motes = mote_client::discover(timeout)?;
+    if motes.is_empty() {
+        bail!("No Motes found");
+    }
+    for mote in motes {
+        println!("{}\t{}", mote.uid, mote.ip);
+    }
+    Ok(())
+}
+
+fn ping(connection: &mut dyn Connection, count: u32) -> anyhow::Result<()> {
+    for id in 1..=count {
+        let sent = Instant::now();
+        match request(connection, id, host_to_mote::Message::Ping)? {
+            Some(mote_to_host::Message::Pong) => {
+                println!("Pong {id}: {:.1} ms", sent.elapsed().as_secs_f64() * 1000.0);
+            }
+            other => bail!("Expected a Pong, got {other:?}"),
+        }
+        if id < count {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+    Ok(())
+}
+
+/// Wait for Mote to report its state, which it does periodically over serial
+fn next_state(
+    connection: &mut dyn Connection,
+    timeout: Duration,
+) -> anyhow::Result<mote_to_host::State> {
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if let Some(mote_to_host::Message::State(state)) = connection.recv_timeout(remaining)? {
+            return Ok(*state);
+        }
+    }
+    bail!("Mote didn't report its state within {timeout:?}, it only does so over --serial")
+}
+
+fn wifi_scan(connection: &mut dyn Connection, timeout: Duration) -> anyhow::Result<()> {
+    request(connection, 1, host_to_mote::Message::RequestNetworkScan)?;
+    // Mote reports what it found in its state once the scan finishes
+    let deadline = Instant::now() + timeout;
+    let mut state = next_state(connection, timeout)?;
+    while state.available_network_connections.is_empty() {
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            break;
+        };
+        match next_state(connection, remaining) {
+            Ok(next) => state = next,
+            Err(_) => break,
+        }
+    }
+    print!(
+        "{}",
+        display::format_networks(&state.available_network_connections)
+    );
+    Ok(())
+}
+
+/// Carry out a move, and wait for Mote to report how it ended
+fn move_wheels(
+    connection: &mut dyn Connection,
+    command: host_to_mote::MoveWheels,
+) -> anyhow::Result<()> {
+    let Some(profile) = command.profile() else {
+        bail!(
+            "Speed and acceleration must be positive, and the move take at most {}s",
+            motion::MAX_MOVE_S
+        );
+    };
+    let id = command.id;
+    request(connection, id, host_to_mote::Message::MoveWheels(command))?;
+
+    // Mote gives up on the wheels settling after SETTLE_TIMEOUT_S, allow a little more for the
+    // report to arrive
+    let timeout = Duration::from_secs_f32(profile.duration_s() + motion::SETTLE_TIMEOUT_S + 1.0);
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let finished = match connection.recv_timeout(remaining)? {
+            Some(mote_to_host::Message::MoveFinished(finished)) if finished.id == id => finished,
+            _ => continue,
+        };
+        let errors = format!(
+            "wheels {:.3} and {:.3} rad from their targets",
+            finished.left_error_rad, finished.right_error_rad
+        );
+        return match finished.outcome {
+            mote_to_host::MoveOutcome::Completed => {
+                println!("Arrived, {errors}");
+                Ok(())
+            }
+            outcome => bail!("Move ended {outcome:?}, {errors}"),
+        };
+    }
+    bail!("Mote didn't finish the move within {timeout:?}")
+}
+
+fn tail(
+    connection: &mut dyn Connection,
+    kind: Option<Kind>,
+    count: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut printed = 0;
+    while count.is_none_or(|count| printed < count) {
+        let Some(message) = connection.recv_timeout(Duration::from_secs(1))? else {
+            continue;
+        };
+        let telemetry = matches!(
+            message,
+            mote_to_host::Message::Scan(_)
+                | mote_to_host::Message::IMUMeasurement(_)
+                | mote_to_host::Message::DriveBaseState(_)
+                | mote_to_host::Message::State(_)
+        );
+        if telemetry && kind.is_none_or(|kind| kind.matches(&message)) {
+            println!("{}", serde_json::to_string(&message)?);
+            printed += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_kind_matches() {
+        let scan = mote_to_host::Message::Scan(mote_to_host::Scan::default());
+        assert!(Kind::Scan.matches(&scan));
+        assert!(!Kind::Imu.matches(&scan));
+    }
+}
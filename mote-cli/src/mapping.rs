@@ -0,0 +1,202 @@
+//! Building occupancy grid maps from recordings
+
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Context;
+use mote_api::mapping::{GridConfig, OccupancyGrid};
+use mote_api::messages::mote_to_host;
+use mote_api::odometry::{Odometry, OdometryConfig};
+use mote_api::scan::ScanAssembler;
+use mote_client::LogReader;
+
+/// How to turn a recording into a map
+pub struct MapOptions {
+    pub resolution_m: f32,
+    /// Width and height of the map, centered where Mote started
+    pub size_m: f32,
+    pub wheel_radius_m: f32,
+    pub track_width_m: f32,
+    /// See OdometryConfig::gyro_weight
+    pub gyro_weight: f32,
+}
+
+/// Replay a recording through wheel odometry, inserting each lidar sweep at the pose Mote had
+/// reached when the sweep completed
+///
+/// Times come from Mote's own timestamps, which unlike the recording's aren't skewed by network
+/// jitter.
+pub fn build_map<R: Read>(
+    log: LogReader<R>,
+    options: &MapOptions,
+) -> anyhow::Result<OccupancyGrid> {
+    let mut config = OdometryConfig::new(options.wheel_radius_m, options.track_width_m);
+    config.gyro_weight = options.gyro_weight;
+    let mut odometry = Odometry::new(config);
+    let mut assembler = ScanAssembler::default();
+    let mut grid = OccupancyGrid::new(GridConfig::new(
+        options.size_m,
+        options.size_m,
+        options.resolution_m,
+    ));
+
+    let mut last_imu_us: Option<u64> = None;
+    // A sweep is only complete once the next one starts, by when Mote may have moved on, so
+    // remember where it was at the previous scan
+    let mut last_scan_pose = odometry.pose();
+    for record in log {
+        let record = record?;
+        match record.message {
+            mote_to_host::Message::DriveBaseState(state) => odometry.update_drive_base(&state),
+            mote_to_host::Message::IMUMeasurement(measurement) => {
+                if let Some(last_us) = last_imu_us {
+                    let dt_s = measurement.timestamp_us.saturating_sub(last_us) as f32 / 1e6;
+                    odometry.update_imu(&measurement, dt_s);
+                }
+                last_imu_us = Some(measurement.timestamp_us);
+            }
+            mote_to_host::Message::Scan(scan) => {
+                assembler.push(&scan.points, scan.timestamp_us);
+                while let Some(sweep) = assembler.poll_sweep() {
+                    let pose = if sweep.end_us == scan.timestamp_us {
+                        odometry.pose()
+                    } else {
+                        last_scan_pose
+                    };
+                    grid.insert_scan(&sweep.points, pose);
+                }
+                last_scan_pose = odometry.pose();
+            }
+            _ => {}
+        }
+    }
+    Ok(grid)
+}
+
+/// Map the recording at `log`, writing the map to `output` as a PNG if it ends in .png and a PGM
+/// otherwise, with its metadata next to it in a .yaml file
+pub fn map(log: &Path, output: &Path, options: &MapOptions) -> anyhow::Result<()> {
+    let reader =
+        LogReader::open(log).with_context(|| format!("Could not read {}", log.display()))?;
+    let grid = build_map(reader, options)?;
+
+    let image = if output
+        .extension()
+        .is_some_and(|extension| extension == "png")
+    {
+        grid.to_png()
+    } else {
+        grid.to_pgm()
+    };
+    std::fs::write(output, image)
+        .with_context(|| format!("Could not write {}", output.display()))?;
+
+    let metadata = output.with_extension("yaml");
+    let image_name = output
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    std::fs::write(&metadata, grid.to_yaml(&image_name))
+        .with_context(|| format!("Could not write {}", metadata.display()))?;
+    eprintln!("Wrote {} and {}", output.display(), metadata.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+    use std::time::Duration;
+
+    use mote_client::LogWriter;
+
+    use super::*;
+
+    fn options() -> MapOptions {
+        MapOptions {
+            resolution_m: 0.05,
+            size_m: 4.0,
+            wheel_radius_m: 0.03,
+            track_width_m: 0.15,
+            gyro_weight: 0.0,
+        }
+    }
+
+    fn wheels_at(position: f32, timestamp_us: u64) -> mote_to_host::Message {
+        let wheel = mote_to_host::WheelJointState {
+            effort_percent: 0.0,
+            velocity_rad_per_s: 0.0,
+            postition_rad: position,
+        };
+        mote_to_host::Message::DriveBaseState(mote_to_host::DriveBaseState {
+            timestamp_us,
+            left: wheel.clone(),
+            right: wheel,
+        })
+    }
+
+    /// Walls of the test room are this far from its middle, which is in the middle of a cell
+    const WALL_M: f32 = 1.025;
+
+    /// A revolution of 400 points from `offset_m` behind the middle of the test room, in scans
+    /// of 100 points measured 25ms apart from `start_us`
+    fn sweep(offset_m: f32, start_us: u64) -> Vec<mote_to_host::Message> {
+        let points: Vec<mote_to_host::Point> = (0..400)
+            .map(|i| {
+                let angle_rad = i as f32 * TAU / 400.0;
+                // Clockwise from forward, which is +x
+                let (dx, dy) = (angle_rad.cos(), -angle_rad.sin());
+                let x_wall = if dx > 0.0 {
+                    WALL_M + offset_m
+                } else {
+                    WALL_M - offset_m
+                };
+                let distance_m = (x_wall / dx.abs()).min(WALL_M / dy.abs());
+                mote_to_host::Point {
+                    quality: 40,
+                    angle_rad,
+                    distance_mm: distance_m * 1000.0,
+                }
+            })
+            .collect();
+        points
+            .chunks(100)
+            .zip((start_us..).step_by(25_000))
+            .map(|(chunk, timestamp_us)| {
+                mote_to_host::Message::Scan(mote_to_host::Scan {
+                    timestamp_us,
+                    points: chunk.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_map_follows_odometry() {
+        let mut writer = LogWriter::new(Vec::new(), "mote-a").unwrap();
+        let mut messages = vec![wheels_at(0.0, 0)];
+        // Scans from the start, then after driving 0.3m forward into the room
+        messages.extend(sweep(0.0, 100_000));
+        messages.extend(sweep(0.0, 200_000));
+        messages.push(wheels_at(0.3 / 0.03, 300_000));
+        messages.extend(sweep(-0.3, 400_000));
+        messages.extend(sweep(-0.3, 500_000));
+        for (i, message) in messages.iter().enumerate() {
+            writer
+                .write_at(Duration::from_millis(i as u64 * 20), message)
+                .unwrap();
+        }
+        let log = writer.into_inner();
+
+        let grid = build_map(LogReader::new(log.as_slice()).unwrap(), &options()).unwrap();
+        let probability = |x, y| {
+            let (column, row) = grid.cell(x, y).unwrap();
+            grid.probability(column, row)
+        };
+        // Both sweeps put the walls in the same place
+        assert!(probability(WALL_M, 0.0) > 0.65);
+        assert!(probability(-WALL_M, 0.5) > 0.65);
+        assert!(probability(0.0, WALL_M) > 0.65);
+        assert!(probability(0.5, 0.0) < 0.2);
+        assert!(probability(-1.5, 0.0) == 0.5);
+    }
+}
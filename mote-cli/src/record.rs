@@ -0,0 +1,100 @@
+//! Recording telemetry, and exporting recordings for Foxglove
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use mote_api::messages::mote_to_host;
+use mote_client::mcap::{self, McapWriter};
+use mote_client::{LogReader, LogWriter};
+
+use crate::connection::Connection;
+
+/// Where received messages are written
+enum Output {
+    Log(LogWriter<BufWriter<File>>),
+    Mcap(McapWriter<BufWriter<File>>),
+}
+
+impl Output {
+    fn create(path: &Path, uid: &str) -> anyhow::Result<Self> {
+        let output = if path
+            .extension()
+            .is_some_and(|extension| extension == "mcap")
+        {
+            Output::Mcap(McapWriter::create(path)?)
+        } else {
+            Output::Log(LogWriter::create(path, uid)?)
+        };
+        Ok(output)
+    }
+
+    fn write(&mut self, message: &mote_to_host::Message) -> anyhow::Result<()> {
+        match self {
+            Output::Log(log) => log.write(message)?,
+            Output::Mcap(mcap) => mcap.write(SystemTime::now(), message)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Output::Log(mut log) => log.flush()?,
+            Output::Mcap(mcap) => {
+                mcap.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Record everything Mote sends until Enter is pressed, or `duration` passes
+pub fn record(
+    connection: &mut dyn Connection,
+    path: &Path,
+    uid: &str,
+    duration: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut output = Output::create(path, uid)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            // Without a terminal, e.g. in a script, stdin ends straight away and only --duration
+            // stops the recording
+            if let Ok(1..) = std::io::stdin().lock().read_line(&mut String::new()) {
+                stop.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+    eprintln!("Recording to {}, press Enter to stop", path.display());
+
+    let start = Instant::now();
+    let mut recorded = 0;
+    while !stop.load(Ordering::Relaxed)
+        && duration.is_none_or(|duration| start.elapsed() < duration)
+    {
+        if let Some(message) = connection.recv_timeout(Duration::from_millis(100))? {
+            output.write(&message)?;
+            recorded += 1;
+        }
+    }
+    output.finish()?;
+    eprintln!("Recorded {recorded} messages");
+    Ok(())
+}
+
+/// Convert the log at `log` to an MCAP file at `output`
+pub fn export(log: &Path, output: &Path) -> anyhow::Result<()> {
+    let log = LogReader::open(log).with_context(|| format!("Could not read {}", log.display()))?;
+    let mcap = McapWriter::create(output)
+        .with_context(|| format!("Could not create {}", output.display()))?;
+    mcap::export_log(log, mcap)?;
+    Ok(())
+}
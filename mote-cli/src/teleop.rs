@@ -0,0 +1,112 @@
+//! Keyboard teleoperation
+
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal;
+use mote_api::messages::host_to_mote::{Message, SetDriveBaseVelocity};
+
+use crate::connection::Connection;
+
+/// How often the drive command is resent, so a dropped command is soon replaced
+const COMMAND_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Stop,
+    Forward,
+    Backward,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn from_key(key: KeyCode) -> Option<Self> {
+        match key {
+            KeyCode::Char('w') | KeyCode::Up => Some(Direction::Forward),
+            KeyCode::Char('s') | KeyCode::Down => Some(Direction::Backward),
+            KeyCode::Char('a') | KeyCode::Left => Some(Direction::Left),
+            KeyCode::Char('d') | KeyCode::Right => Some(Direction::Right),
+            KeyCode::Char(' ') => Some(Direction::Stop),
+            _ => None,
+        }
+    }
+
+    /// Wheel velocities driving in this direction, turning on the spot
+    pub fn command(self, speed_rad: f32) -> SetDriveBaseVelocity {
+        let (left, right) = match self {
+            Direction::Stop => (0.0, 0.0),
+            Direction::Forward => (1.0, 1.0),
+            Direction::Backward => (-1.0, -1.0),
+            Direction::Left => (-1.0, 1.0),
+            Direction::Right => (1.0, -1.0),
+        };
+        SetDriveBaseVelocity {
+            left_velocity_rad: left * speed_rad,
+            right_velocity_rad: right * speed_rad,
+        }
+    }
+}
+
+/// Restores the terminal when teleop ends, including on error
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> std::io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Drive Mote from the keyboard until q or Esc is pressed
+///
+/// Directions are sticky: Mote keeps driving until another direction or space is pressed.
+pub fn drive(connection: &mut dyn Connection, speed_rad: f32) -> anyhow::Result<()> {
+    println!("w/a/s/d or arrows to drive, space to stop, q to quit");
+    let raw_mode = RawMode::enable()?;
+    let mut direction = Direction::Stop;
+    let mut next_command = Instant::now();
+    let result = loop {
+        if Instant::now() >= next_command {
+            let command = Message::DriveBaseCommand(direction.command(speed_rad));
+            if let Err(err) = connection.send(command) {
+                break Err(err);
+            }
+            next_command += COMMAND_INTERVAL;
+        }
+
+        // Telemetry isn't shown, but is drained so it doesn't pile up
+        while let Ok(Some(_)) = connection.recv_timeout(Duration::ZERO) {}
+
+        let wait = next_command.saturating_duration_since(Instant::now());
+        match event::poll(wait) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(err) => break Err(err.into()),
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+                code => {
+                    if let Some(pressed) = Direction::from_key(code) {
+                        direction = pressed;
+                    }
+                }
+            },
+            Ok(_) => {}
+            Err(err) => break Err(err.into()),
+        }
+    };
+    drop(raw_mode);
+
+    connection.send(Message::DriveBaseCommand(
+        Direction::Stop.command(speed_rad),
+    ))?;
+    result
+}
@@ -0,0 +1,62 @@
+//! mDNS discovery of Motes on the local network
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures_util::{StreamExt, pin_mut};
+use mdns::RecordKind;
+
+use crate::{Address, Error, UDP_PORT};
+
+/// Service Mote advertises over mDNS
+pub const MDNS_SERVICE: &str = "_mote-api._udp.local";
+
+/// How long connecting by UID listens for Motes, when the OS can't resolve `<uid>.local`
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the mDNS query is repeated while discovering
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A Mote found by discover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredMote {
+    pub uid: String,
+    pub ip: Ipv4Addr,
+}
+
+impl From<DiscoveredMote> for Address {
+    fn from(mote: DiscoveredMote) -> Self {
+        Address::Ip(SocketAddr::new(mote.ip.into(), UDP_PORT))
+    }
+}
+
+/// Listen for Motes advertising themselves on the local network for `duration`
+pub fn discover(duration: Duration) -> Result<Vec<DiscoveredMote>, Error> {
+    async_std::task::block_on(async {
+        let stream = mdns::discover::all(MDNS_SERVICE, QUERY_INTERVAL)
+            .map_err(|err| Error::Discovery(err.to_string()))?
+            .listen();
+        pin_mut!(stream);
+
+        let mut found = Vec::new();
+        let _ = async_std::future::timeout(duration, async {
+            while let Some(response) = stream.next().await {
+                let Ok(response) = response else {
+                    continue;
+                };
+                // Mote's hostname is its UID
+                for record in response.records() {
+                    if let RecordKind::A(ip) = record.kind {
+                        let uid = record.name.trim_end_matches(".local").to_owned();
+                        let mote = DiscoveredMote { uid, ip };
+                        if !found.contains(&mote) {
+                            found.push(mote);
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+        Ok(found)
+    })
+}
@@ -0,0 +1,974 @@
+//! Blocking host client for Mote
+//!
+//! MoteClient connects to a Mote over UDP and drives the link from a background thread: it pings
+//! Mote to keep the connection alive, reconnects when Mote goes quiet, and hands received
+//! messages to typed subscriptions. Lidar points sent as a PackedScan are handed on as a Scan.
+//!
+//! Received telemetry can be recorded to a log with LogWriter, and played back later with Replay.
+//! With the `mcap` feature, it can also be exported for Foxglove, see the mcap module.
+
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::{LinkStats, MoteLink, PROTOCOL_VERSION};
+use thiserror::Error;
+
+#[cfg(feature = "discovery")]
+mod discovery;
+#[cfg(feature = "mcap")]
+pub mod mcap;
+mod recording;
+
+#[cfg(feature = "discovery")]
+pub use crate::discovery::*;
+pub use crate::recording::*;
+
+/// UDP port Mote listens on
+pub const UDP_PORT: u16 = 7475;
+
+/// How long the background thread waits for a packet before checking on the connection
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Link(#[from] mote_api::Error),
+    #[error(
+        "Could not find a Mote with UID {0}, check the UID and that mDNS works on this network"
+    )]
+    UnknownUid(String),
+    #[error("mDNS discovery failed: {0}")]
+    Discovery(String),
+    #[error("Mote did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error("Not a Mote telemetry log")]
+    NotALog,
+    #[error("Log format version {0} is not supported")]
+    UnsupportedLogFormat(u16),
+    #[error("Log is corrupt")]
+    CorruptLog,
+    #[error("Replay speed must be positive and finite, not {0}")]
+    InvalidReplaySpeed(f64),
+    #[cfg(feature = "mcap")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Where to find a Mote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Ip(SocketAddr),
+    /// Resolved as `<uid>.local` every time the client (re)connects
+    Uid(String),
+}
+
+impl Address {
+    fn resolve(&self) -> Result<SocketAddr, Error> {
+        match self {
+            Address::Ip(addr) => Ok(*addr),
+            Address::Uid(uid) => resolve_uid(uid),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Ip(addr)
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        Address::Ip(SocketAddr::new(ip, UDP_PORT))
+    }
+}
+
+impl From<Ipv4Addr> for Address {
+    fn from(ip: Ipv4Addr) -> Self {
+        IpAddr::V4(ip).into()
+    }
+}
+
+/// An IP address, or otherwise a UID
+impl From<&str> for Address {
+    fn from(address: &str) -> Self {
+        match address.parse::<IpAddr>() {
+            Ok(ip) => ip.into(),
+            Err(_) => Address::Uid(address.to_owned()),
+        }
+    }
+}
+
+fn resolve_uid(uid: &str) -> Result<SocketAddr, Error> {
+    // Mote answers mDNS queries for its UID as a hostname
+    let resolved = (format!("{uid}.local"), UDP_PORT)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4));
+    if let Some(addr) = resolved {
+        return Ok(addr);
+    }
+
+    // Not every OS resolves .local names, so look for it ourselves
+    #[cfg(feature = "discovery")]
+    if let Some(mote) = discover(DISCOVERY_TIMEOUT)?
+        .into_iter()
+        .find(|mote| mote.uid == uid)
+    {
+        return Ok(SocketAddr::new(mote.ip.into(), UDP_PORT));
+    }
+
+    Err(Error::UnknownUid(uid.to_owned()))
+}
+
+/// Connection settings, see MoteClient::connect_with
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// How often Mote is pinged, so that it keeps sending to us and we notice if it goes away
+    pub keepalive_interval: Duration,
+    /// Mote is considered gone if nothing is received for this long, and the client reconnects
+    pub connection_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(1),
+            connection_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Open a socket talking only to the Mote at `address`
+fn open_socket(address: &Address) -> Result<UdpSocket, Error> {
+    let peer = address.resolve()?;
+    let local: IpAddr = match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(peer)?;
+    socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))?;
+    Ok(socket)
+}
+
+/// Everything about the current connection, shared with the background thread
+struct Connection {
+    socket: UdpSocket,
+    link: MoteLink,
+    start: Instant,
+    /// Mote's answer to our handshake, cleared once Mote goes quiet
+    hello_ack: Option<mote_to_host::HelloAck>,
+    last_received: Instant,
+    last_keepalive: Instant,
+    /// When the current socket was opened
+    connected_at: Instant,
+}
+
+impl Connection {
+    fn new(socket: UdpSocket) -> Self {
+        let now = Instant::now();
+        let mut connection = Self {
+            socket,
+            link: MoteLink::new(),
+            start: now,
+            hello_ack: None,
+            last_received: now,
+            last_keepalive: now,
+            connected_at: now,
+        };
+        connection.send_hello();
+        connection
+    }
+
+    /// Start over with a new socket and link, after Mote went quiet
+    fn reopen(&mut self, socket: UdpSocket) {
+        *self = Self {
+            start: self.start,
+            ..Self::new(socket)
+        };
+    }
+
+    fn send_hello(&mut self) {
+        let hello = host_to_mote::Message::Hello(host_to_mote::Hello::default());
+        // The link was just created, so there is room for it
+        let _ = self.link.send(hello);
+        self.last_keepalive = Instant::now();
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    /// Send every queued packet
+    fn flush(&mut self) {
+        while let Some(packet) = self.link.poll_transmit() {
+            // Packets are lost while Mote is unreachable, the keepalive notices
+            let _ = self.socket.send(&packet);
+        }
+    }
+}
+
+/// Senders for each subscription, see MoteClient::subscribe
+#[derive(Default)]
+struct Subscribers {
+    messages: Vec<Sender<mote_to_host::Message>>,
+    scans: Vec<Sender<mote_to_host::Scan>>,
+    drive_base_states: Vec<Sender<mote_to_host::DriveBaseState>>,
+    imu_measurements: Vec<Sender<mote_to_host::IMUMeasurement>>,
+    states: Vec<Sender<mote_to_host::State>>,
+    moves_finished: Vec<Sender<mote_to_host::MoveFinished>>,
+}
+
+impl Subscribers {
+    fn publish(&mut self, message: &mote_to_host::Message) {
+        publish(&mut self.messages, message);
+        match message {
+            mote_to_host::Message::Scan(scan) => publish(&mut self.scans, scan),
+            mote_to_host::Message::DriveBaseState(state) => {
+                publish(&mut self.drive_base_states, state)
+            }
+            mote_to_host::Message::IMUMeasurement(measurement) => {
+                publish(&mut self.imu_measurements, measurement)
+            }
+            mote_to_host::Message::State(state) => publish(&mut self.states, &**state),
+            mote_to_host::Message::MoveFinished(finished) => {
+                publish(&mut self.moves_finished, finished)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Send a copy of `value` to every subscriber, forgetting those which have hung up
+fn publish<T: Clone>(senders: &mut Vec<Sender<T>>, value: &T) {
+    senders.retain(|sender| sender.send(value.clone()).is_ok());
+}
+
+struct Shared {
+    connection: Mutex<Connection>,
+    /// Notified whenever Mote answers the handshake
+    handshake: Condvar,
+    subscribers: Mutex<Subscribers>,
+    running: AtomicBool,
+}
+
+impl Shared {
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Subscribers> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A connection to a Mote, driven by a background thread
+///
+/// Subscriptions receive every matching message from the moment they are made. Receivers which
+/// are dropped are unsubscribed; receivers which are never read from buffer without bound.
+pub struct MoteClient {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MoteClient {
+    /// Connect to a Mote by IP address or UID, with the default ClientConfig
+    pub fn connect(address: impl Into<Address>) -> Result<Self, Error> {
+        Self::connect_with(address, ClientConfig::default())
+    }
+
+    /// Connect to a Mote, waiting up to `config.connection_timeout` for it to answer the
+    /// version handshake
+    ///
+    /// Fails with `mote_api::Error::IncompatibleProtocol` if Mote speaks another protocol version.
+    pub fn connect_with(address: impl Into<Address>, config: ClientConfig) -> Result<Self, Error> {
+        let address = address.into();
+        let socket = open_socket(&address)?;
+        let reader = socket.try_clone()?;
+        let shared = Arc::new(Shared {
+            connection: Mutex::new(Connection::new(socket)),
+            handshake: Condvar::new(),
+            subscribers: Mutex::new(Subscribers::default()),
+            running: AtomicBool::new(true),
+        });
+        let thread = {
+            let shared = shared.clone();
+            let config = config.clone();
+            thread::spawn(move || run(&shared, &address, &config, reader))
+        };
+        let client = Self {
+            shared,
+            thread: Some(thread),
+        };
+
+        let handshake = {
+            let (connection, _) = client
+                .shared
+                .handshake
+                .wait_timeout_while(
+                    client.shared.connection(),
+                    config.connection_timeout,
+                    |connection| connection.hello_ack.is_none(),
+                )
+                .unwrap_or_else(PoisonError::into_inner);
+            match &connection.hello_ack {
+                None => Err(Error::Timeout(config.connection_timeout)),
+                Some(ack) if ack.protocol_version != PROTOCOL_VERSION => {
+                    Err(mote_api::Error::IncompatibleProtocol {
+                        local: PROTOCOL_VERSION,
+                        peer: ack.protocol_version,
+                    }
+                    .into())
+                }
+                Some(_) => Ok(()),
+            }
+        };
+        handshake.map(|()| client)
+    }
+
+    /// Send a message to Mote
+    pub fn send(&self, message: host_to_mote::Message) -> Result<(), Error> {
+        let mut connection = self.shared.connection();
+        connection.link.send(message)?;
+        connection.flush();
+        Ok(())
+    }
+
+    /// Whether Mote has answered recently
+    pub fn is_connected(&self) -> bool {
+        self.shared.connection().hello_ack.is_some()
+    }
+
+    /// Mote's answer to the most recent handshake, describing its firmware
+    pub fn hello_ack(&self) -> Option<mote_to_host::HelloAck> {
+        self.shared.connection().hello_ack.clone()
+    }
+
+    /// Link quality counters for the current connection
+    pub fn stats(&self) -> LinkStats {
+        self.shared.connection().link.stats()
+    }
+
+    /// Every message received from Mote
+    pub fn subscribe(&self) -> Receiver<mote_to_host::Message> {
+        subscribe(&mut self.shared.subscribers().messages)
+    }
+
+    pub fn subscribe_scans(&self) -> Receiver<mote_to_host::Scan> {
+        subscribe(&mut self.shared.subscribers().scans)
+    }
+
+    pub fn subscribe_drive_base_states(&self) -> Receiver<mote_to_host::DriveBaseState> {
+        subscribe(&mut self.shared.subscribers().drive_base_states)
+    }
+
+    pub fn subscribe_imu_measurements(&self) -> Receiver<mote_to_host::IMUMeasurement> {
+        subscribe(&mut self.shared.subscribers().imu_measurements)
+    }
+
+    pub fn subscribe_states(&self) -> Receiver<mote_to_host::State> {
+        subscribe(&mut self.shared.subscribers().states)
+    }
+
+    /// Reports of host_to_mote::MoveWheels ending, subscribe before sending the move so as not to
+    /// miss its report
+    pub fn subscribe_moves_finished(&self) -> Receiver<mote_to_host::MoveFinished> {
+        subscribe(&mut self.shared.subscribers().moves_finished)
+    }
+}
+
+impl Drop for MoteClient {
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn subscribe<T>(senders: &mut Vec<Sender<T>>) -> Receiver<T> {
+    let (sender, receiver) = mpsc::channel();
+    senders.push(sender);
+    receiver
+}
+
+/// Body of the background thread: receive, keep the connection alive and publish messages
+fn run(shared: &Shared, address: &Address, config: &ClientConfig, mut reader: UdpSocket) {
+    let mut buffer = [0; 2048];
+    let mut received = Vec::new();
+    while shared.running.load(Ordering::Relaxed) {
+        let bytes_read = match reader.recv(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            // Timeouts, or Mote being unreachable, which the keepalive notices
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => 0,
+        };
+
+        let mut connection = shared.connection();
+        let now_ms = connection.now_ms();
+        connection.link.handle_time(now_ms);
+        connection.link.handle_receive(&buffer[..bytes_read]);
+        loop {
+            match connection.link.poll_receive() {
+                Ok(Some(message)) => {
+                    connection.last_received = Instant::now();
+                    match &message {
+                        mote_to_host::Message::Ping => {
+                            let _ = connection.link.send(host_to_mote::Message::Pong);
+                        }
+                        mote_to_host::Message::HelloAck(ack) => {
+                            connection.hello_ack = Some(ack.clone());
+                            shared.handshake.notify_all();
+                        }
+                        _ => {}
+                    }
+                    // Subscribers only ever see Scan, however Mote packed the points
+                    if let Ok(message) = message.unpacked() {
+                        received.push(message);
+                    }
+                }
+                Ok(None) => break,
+                // Corrupt frames and the like, the link keeps count
+                Err(_) => {}
+            }
+        }
+
+        let quiet = if connection.hello_ack.is_some() {
+            connection.last_received.elapsed()
+        } else {
+            connection.connected_at.elapsed()
+        };
+        if quiet > config.connection_timeout {
+            // Mote may have restarted or changed address, so start over. If it can't be found,
+            // try again after another timeout.
+            connection.hello_ack = None;
+            connection.connected_at = Instant::now();
+            if let Ok(socket) = open_socket(address)
+                && let Ok(new_reader) = socket.try_clone()
+            {
+                connection.reopen(socket);
+                reader = new_reader;
+            }
+        } else if connection.last_keepalive.elapsed() >= config.keepalive_interval {
+            if connection.hello_ack.is_some() {
+                let _ = connection.link.send(host_to_mote::Message::Ping);
+                connection.last_keepalive = Instant::now();
+            } else {
+                connection.send_hello();
+            }
+        }
+        connection.flush();
+        drop(connection);
+
+        if !received.is_empty() {
+            let mut subscribers = shared.subscribers();
+            for message in received.drain(..) {
+                subscribers.publish(&message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mote_api::HostLink;
+
+    // A local stand-in for the robot, answering like the firmware's UDP server
+    struct FakeMote {
+        addr: SocketAddr,
+        received: Receiver<host_to_mote::Message>,
+        // While false, everything sent to the fake is ignored, as if it had lost power
+        answering: Arc<AtomicBool>,
+        running: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl FakeMote {
+        fn spawn(protocol_version: u16) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(RECEIVE_POLL_INTERVAL))
+                .unwrap();
+            let addr = socket.local_addr().unwrap();
+            let (sender, received) = mpsc::channel();
+            let answering = Arc::new(AtomicBool::new(true));
+            let running = Arc::new(AtomicBool::new(true));
+            let thread = {
+                let answering = answering.clone();
+                let running = running.clone();
+                thread::spawn(move || {
+                    Self::run(socket, protocol_version, sender, &answering, &running)
+                })
+            };
+            Self {
+                addr,
+                received,
+                answering,
+                running,
+                thread: Some(thread),
+            }
+        }
+
+        fn run(
+            socket: UdpSocket,
+            protocol_version: u16,
+            sender: Sender<host_to_mote::Message>,
+            answering: &AtomicBool,
+            running: &AtomicBool,
+        ) {
+            let mut link = HostLink::new();
+            let mut client = None;
+            let mut buffer = [0; 2048];
+            while running.load(Ordering::Relaxed) {
+                let Ok((bytes_read, from)) = socket.recv_from(&mut buffer) else {
+                    // Stream telemetry between packets
+                    if answering.load(Ordering::Relaxed) && client.is_some() {
+                        let _ = link.send(mote_to_host::Message::PackedScan(packed_scan()));
+                    }
+                    flush(&socket, &mut link, client);
+                    continue;
+                };
+                if !answering.load(Ordering::Relaxed) {
+                    // Lost power, so the link starts over too
+                    link = HostLink::new();
+                    continue;
+                }
+                client = Some(from);
+                link.handle_receive(&buffer[..bytes_read]);
+                while let Ok(Some(message)) = link.poll_receive() {
+                    let reply = match &message {
+                        host_to_mote::Message::Hello(_) => {
+                            Some(mote_to_host::Message::HelloAck(mote_to_host::HelloAck {
+                                protocol_version,
+                                firmware_version: String::from("0.1.0"),
+                                git_hash: String::from("c8c9062"),
+                                capabilities: mote_to_host::capabilities::LIDAR,
+                            }))
+                        }
+                        host_to_mote::Message::Ping => Some(mote_to_host::Message::Pong),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        link.send(reply).unwrap();
+                    }
+                    let _ = sender.send(message);
+                }
+                flush(&socket, &mut link, client);
+            }
+        }
+    }
+
+    impl Drop for FakeMote {
+        fn drop(&mut self) {
+            self.running.store(false, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn flush(socket: &UdpSocket, link: &mut HostLink, client: Option<SocketAddr>) {
+        while let Some(packet) = link.poll_transmit() {
+            if let Some(client) = client {
+                let _ = socket.send_to(&packet, client);
+            }
+        }
+    }
+
+    // Points packed like the firmware's lidar task sends them
+    fn packed_scan() -> mote_to_host::PackedScan {
+        let point = mote_api::packed_scan::RawPoint {
+            quality: 15,
+            angle: 1833,
+            distance: 4800,
+        };
+        mote_to_host::PackedScan::new(4_000_000, mote_to_host::PointEncoding::DeltaAngle, [point])
+    }
+
+    fn scan() -> mote_to_host::Scan {
+        mote_to_host::Scan {
+            timestamp_us: 4_000_000,
+            points: vec![mote_to_host::Point {
+                quality: 15,
+                angle_rad: 0.5,
+                distance_mm: 1200.0,
+            }],
+        }
+    }
+
+    fn fast_config() -> ClientConfig {
+        ClientConfig {
+            keepalive_interval: Duration::from_millis(50),
+            connection_timeout: Duration::from_millis(300),
+        }
+    }
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    // Wait for the fake to receive a message matching `predicate`
+    fn wait_for(mote: &FakeMote, predicate: impl Fn(&host_to_mote::Message) -> bool) -> bool {
+        let deadline = Instant::now() + WAIT;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match mote.received.recv_timeout(remaining) {
+                Ok(message) if predicate(&message) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_address_from_str() {
+        assert_eq!(
+            Address::from("192.168.4.20"),
+            Address::Ip(SocketAddr::from(([192, 168, 4, 20], UDP_PORT)))
+        );
+        assert_eq!(
+            Address::from("mote-a"),
+            Address::Uid(String::from("mote-a"))
+        );
+    }
+
+    #[test]
+    fn test_connect_handshakes() -> Result<(), Error> {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION);
+        let client = MoteClient::connect_with(mote.addr, fast_config())?;
+        assert!(client.is_connected());
+        assert_eq!(
+            client.hello_ack().map(|ack| ack.protocol_version),
+            Some(PROTOCOL_VERSION)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_times_out() {
+        // Bound, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let result = MoteClient::connect_with(silent.local_addr().unwrap(), fast_config());
+        assert!(matches!(result, Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn test_connect_refuses_incompatible_protocol() {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION + 1);
+        let result = MoteClient::connect_with(mote.addr, fast_config());
+        assert!(matches!(
+            result,
+            Err(Error::Link(mote_api::Error::IncompatibleProtocol { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_typed_subscriptions() -> Result<(), Error> {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION);
+        let client = MoteClient::connect_with(mote.addr, fast_config())?;
+        let scans = client.subscribe_scans();
+        let states = client.subscribe_states();
+        let messages = client.subscribe();
+
+        // Unpacked on the way in
+        assert_eq!(
+            scans.recv_timeout(WAIT).unwrap(),
+            packed_scan().to_scan().unwrap()
+        );
+        assert!(matches!(
+            messages.recv_timeout(WAIT).unwrap(),
+            mote_to_host::Message::Scan(_) | mote_to_host::Message::Pong
+        ));
+        // Nothing else is delivered to other kinds
+        assert!(states.try_recv().is_err());
+
+        // Dropped receivers are unsubscribed
+        drop(scans);
+        thread::sleep(Duration::from_millis(100));
+        assert!(client.shared.subscribers().scans.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_send() -> Result<(), Error> {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION);
+        let client = MoteClient::connect_with(mote.addr, fast_config())?;
+        let uid = host_to_mote::SetUID {
+            uid: String::from("mote-a"),
+        };
+        client.send(host_to_mote::Message::SetUID(uid.clone()))?;
+        assert!(wait_for(&mote, |message| {
+            *message == host_to_mote::Message::SetUID(uid.clone())
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keepalive_pings() -> Result<(), Error> {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION);
+        let _client = MoteClient::connect_with(mote.addr, fast_config())?;
+        assert!(wait_for(&mote, |message| {
+            *message == host_to_mote::Message::Ping
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconnects_after_mote_goes_quiet() -> Result<(), Error> {
+        let mote = FakeMote::spawn(PROTOCOL_VERSION);
+        let config = fast_config();
+        let client = MoteClient::connect_with(mote.addr, config.clone())?;
+
+        mote.answering.store(false, Ordering::Relaxed);
+        thread::sleep(config.connection_timeout * 2);
+        assert!(!client.is_connected());
+
+        // Drain everything received so far, so that only the new handshake matches
+        while mote.received.try_recv().is_ok() {}
+        mote.answering.store(true, Ordering::Relaxed);
+        assert!(wait_for(&mote, |message| {
+            matches!(message, host_to_mote::Message::Hello(_))
+        }));
+        let deadline = Instant::now() + WAIT;
+        while !client.is_connected() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.is_connected());
+        Ok(())
+    }
+
+    // One of every message variant
+    fn every_message() -> Vec<mote_to_host::Message> {
+        let wheel = mote_to_host::WheelJointState {
+            effort_percent: 12.5,
+            velocity_rad_per_s: -1.0,
+            postition_rad: 3.0,
+        };
+        let axes = mote_to_host::IMUAxisTriple {
+            x: 0.1,
+            y: -0.2,
+            z: 9.8,
+        };
+        let state = mote_to_host::State {
+            uid: "mote-a".into(),
+            ip: Some("192.168.1.20".into()),
+            mac: None,
+            current_network_connection: Some("lab".into()),
+            available_network_connections: vec![mote_to_host::NetworkConnection {
+                ssid: "lab".into(),
+                strength: 70,
+            }],
+            built_in_test: mote_to_host::BITCollection {
+                lidar: vec![mote_to_host::BIT {
+                    name: "Spinning".into(),
+                    result: mote_to_host::BITResult::Pass,
+                }],
+                ..Default::default()
+            },
+        };
+        vec![
+            mote_to_host::Message::Ping,
+            mote_to_host::Message::Pong,
+            mote_to_host::Message::Scan(scan()),
+            mote_to_host::Message::DriveBaseState(mote_to_host::DriveBaseState {
+                timestamp_us: 4_100_000,
+                left: wheel.clone(),
+                right: wheel,
+            }),
+            mote_to_host::Message::IMUMeasurement(mote_to_host::IMUMeasurement {
+                timestamp_us: 4_120_000,
+                accel: axes.clone(),
+                gyro: axes,
+            }),
+            mote_to_host::Message::State(Box::new(state)),
+            mote_to_host::Message::HelloAck(mote_to_host::HelloAck {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: "1.2.3".into(),
+                git_hash: "abc123".into(),
+                capabilities: mote_to_host::capabilities::LIDAR,
+            }),
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 3,
+                result: Some(Box::new(mote_to_host::Message::Pong)),
+            }),
+            mote_to_host::Message::Nack(mote_to_host::Nack {
+                id: 4,
+                reason: mote_to_host::NackReason::Unsupported,
+            }),
+            mote_to_host::Message::TimeSyncReply(mote_to_host::TimeSyncReply {
+                host_t0: 1_000_000,
+                device_t1: 4_200_000,
+                device_t2: 4_200_150,
+            }),
+            mote_to_host::Message::PackedScan(packed_scan()),
+            mote_to_host::Message::MoveFinished(mote_to_host::MoveFinished {
+                id: 5,
+                timestamp_us: 4_300_000,
+                outcome: mote_to_host::MoveOutcome::Completed,
+                left_error_rad: 0.01,
+                right_error_rad: -0.02,
+            }),
+        ]
+    }
+
+    fn record(messages: &[mote_to_host::Message]) -> Result<Vec<u8>, Error> {
+        let mut writer = LogWriter::new(Vec::new(), "mote-a")?;
+        for (i, message) in messages.iter().enumerate() {
+            writer.write_at(Duration::from_millis(i as u64 * 10), message)?;
+        }
+        Ok(writer.into_inner())
+    }
+
+    #[test]
+    fn test_log_round_trip() -> Result<(), Error> {
+        let messages = every_message();
+        let log = record(&messages)?;
+
+        let mut reader = LogReader::new(log.as_slice())?;
+        assert_eq!(reader.header().uid, "mote-a");
+        assert_eq!(reader.header().protocol_version, PROTOCOL_VERSION);
+        let records = reader.by_ref().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(records.len(), messages.len());
+        for (i, (record, message)) in records.iter().zip(&messages).enumerate() {
+            assert_eq!(record.timestamp, Duration::from_millis(i as u64 * 10));
+            assert_eq!(&record.message, message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_rejects_other_files() {
+        assert!(matches!(
+            LogReader::new(&b"not a log"[..]),
+            Err(Error::NotALog)
+        ));
+
+        let mut log = record(&[]).unwrap();
+        // Recorded with another protocol version
+        log[10..12].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            LogReader::new(log.as_slice()),
+            Err(Error::Link(mote_api::Error::IncompatibleProtocol { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_log_errors_after_last_record() -> Result<(), Error> {
+        let mut log = record(&every_message()[..2])?;
+        log.pop();
+        let mut reader = LogReader::new(log.as_slice())?;
+        assert_eq!(reader.read()?.unwrap().message, mote_to_host::Message::Ping);
+        assert!(reader.read().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_into_link() -> Result<(), Error> {
+        let messages = every_message();
+        let log = record(&messages)?;
+        let mut replay = Replay::unpaced(LogReader::new(log.as_slice())?);
+        let mut link = MoteLink::new();
+        let mut replayed = Vec::new();
+        while replay.step(&mut link)? {
+            while let Some(message) = link.poll_receive()? {
+                replayed.push(message);
+            }
+        }
+        assert_eq!(replayed, messages);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_keeps_recorded_timing() -> Result<(), Error> {
+        let log = record(&every_message()[..3])?;
+        // Records are 10ms apart, so 20ms at real time and 10ms at double speed
+        for (speed, expected) in [(1.0, 20), (2.0, 10)] {
+            let mut replay = Replay::new(LogReader::new(log.as_slice())?, speed)?;
+            let mut link = MoteLink::new();
+            let start = Instant::now();
+            while replay.step(&mut link)? {}
+            assert!(start.elapsed() >= Duration::from_millis(expected));
+        }
+
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                Replay::new(LogReader::new(log.as_slice())?, speed),
+                Err(Error::InvalidReplaySpeed(_))
+            ));
+        }
+        Ok(())
+    }
+
+    // Splits an MCAP file into its records' opcodes and contents
+    #[cfg(feature = "mcap")]
+    fn mcap_records(file: &[u8]) -> Vec<(u8, &[u8])> {
+        const MAGIC: &[u8] = b"\x89MCAP0\r\n";
+        assert!(file.starts_with(MAGIC) && file.ends_with(MAGIC));
+        let mut rest = &file[MAGIC.len()..file.len() - MAGIC.len()];
+        let mut records = Vec::new();
+        while let [opcode, tail @ ..] = rest {
+            let (length, tail) = tail.split_at(8);
+            let length = u64::from_le_bytes(length.try_into().unwrap()) as usize;
+            records.push((*opcode, &tail[..length]));
+            rest = &tail[length..];
+        }
+        records
+    }
+
+    #[cfg(feature = "mcap")]
+    #[test]
+    fn test_export_log_to_mcap() -> Result<(), Error> {
+        let log = record(&every_message())?;
+        let file = mcap::export_log(
+            LogReader::new(log.as_slice())?,
+            mcap::McapWriter::new(Vec::new())?,
+        )?;
+        let records = mcap_records(&file);
+
+        let opcodes: Vec<u8> = records.iter().map(|(opcode, _)| *opcode).collect();
+        // Header, a schema and channel each for scans, IMU and joint states, their messages, then
+        // the end of the data and the footer
+        assert_eq!(opcodes, [1, 3, 4, 3, 4, 3, 4, 5, 5, 5, 5, 0x0F, 2]);
+
+        let topics: Vec<&[u8]> = records
+            .iter()
+            .filter(|(opcode, _)| *opcode == 4)
+            .map(|(_, channel)| {
+                let length = u32::from_le_bytes(channel[4..8].try_into().unwrap()) as usize;
+                &channel[8..8 + length]
+            })
+            .collect();
+        assert_eq!(topics, [&b"/scan"[..], b"/imu", b"/joint_states"]);
+
+        let messages: Vec<(u16, serde_json::Value)> = records
+            .iter()
+            .filter(|(opcode, _)| *opcode == 5)
+            .map(|(_, message)| {
+                let channel = u16::from_le_bytes(message[..2].try_into().unwrap());
+                (channel, serde_json::from_slice(&message[22..]).unwrap())
+            })
+            .collect();
+        let [(1, scan), (3, joint_state), (2, imu), (1, packed_scan)] = &messages[..] else {
+            panic!("Messages on unexpected channels: {messages:?}");
+        };
+        assert_eq!(scan["ranges"], serde_json::json!([1.2]));
+        assert_eq!(scan["start_angle"], serde_json::json!(0.5));
+        assert_eq!(scan["frame_id"], mcap::LIDAR_FRAME);
+        assert_eq!(packed_scan["ranges"], serde_json::json!([1.2]));
+        assert_eq!(imu["accel"]["z"], serde_json::json!(9.8));
+        assert_eq!(joint_state["velocity"], serde_json::json!([-1.0, -1.0]));
+        Ok(())
+    }
+}
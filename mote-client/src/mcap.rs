@@ -0,0 +1,317 @@
+//! Exporting telemetry to MCAP, for viewing in Foxglove
+//!
+//! Messages are written as JSON, on channels whose JSON schemas are generated with schemars:
+//!
+//! | Topic           | Schema                     | From                          |
+//! |-----------------|----------------------------|-------------------------------|
+//! | `/scan`         | `foxglove.LaserScan`       | `mote_to_host::Scan`          |
+//! | `/imu`          | `mote.IMUMeasurement`      | `mote_to_host::IMUMeasurement`|
+//! | `/joint_states` | `mote.JointState`          | `mote_to_host::DriveBaseState`|
+//!
+//! Other messages aren't exported. Files are written without chunks or a summary section, which
+//! readers handle by scanning the whole file.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mote_api::messages::mote_to_host;
+use schemars::{JsonSchema, Schema, schema_for};
+use serde::Serialize;
+
+use crate::{Error, LogReader};
+
+/// Start and end of every MCAP file
+const MAGIC: &[u8] = b"\x89MCAP0\r\n";
+
+// Record opcodes
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_DATA_END: u8 = 0x0F;
+
+// Each channel has the schema with the same id
+const SCAN_CHANNEL: u16 = 1;
+const IMU_CHANNEL: u16 = 2;
+const JOINT_STATE_CHANNEL: u16 = 3;
+
+/// Lidar frame name used in exported laser scans
+pub const LIDAR_FRAME: &str = "lidar";
+
+/// Writes telemetry to an MCAP file, see the module documentation
+///
+/// Call finish once done, or the file is left without its footer.
+pub struct McapWriter<W: Write> {
+    writer: W,
+    sequence: u32,
+}
+
+impl McapWriter<BufWriter<File>> {
+    /// Create an MCAP file at `path`, replacing any existing file
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> McapWriter<W> {
+    /// Start an MCAP file, writing its header and channels straight away
+    pub fn new(writer: W) -> Result<Self, Error> {
+        let mut mcap = Self {
+            writer,
+            sequence: 0,
+        };
+        mcap.writer.write_all(MAGIC)?;
+        let mut header = Vec::new();
+        put_str(&mut header, ""); // profile
+        put_str(
+            &mut header,
+            concat!("mote-client ", env!("CARGO_PKG_VERSION")),
+        );
+        mcap.record(OP_HEADER, &header)?;
+
+        let channels = [
+            (
+                SCAN_CHANNEL,
+                "foxglove.LaserScan",
+                schema_for!(LaserScan),
+                "/scan",
+            ),
+            (
+                IMU_CHANNEL,
+                "mote.IMUMeasurement",
+                schema_for!(mote_to_host::IMUMeasurement),
+                "/imu",
+            ),
+            (
+                JOINT_STATE_CHANNEL,
+                "mote.JointState",
+                schema_for!(JointState),
+                "/joint_states",
+            ),
+        ];
+        for (id, name, schema, topic) in channels {
+            mcap.write_channel(id, name, &schema, topic)?;
+        }
+        Ok(mcap)
+    }
+
+    fn write_channel(
+        &mut self,
+        id: u16,
+        name: &str,
+        schema: &Schema,
+        topic: &str,
+    ) -> Result<(), Error> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&id.to_le_bytes());
+        put_str(&mut record, name);
+        put_str(&mut record, "jsonschema");
+        put_bytes(&mut record, &serde_json::to_vec(schema)?);
+        self.record(OP_SCHEMA, &record)?;
+
+        record.clear();
+        record.extend_from_slice(&id.to_le_bytes());
+        record.extend_from_slice(&id.to_le_bytes()); // schema id
+        put_str(&mut record, topic);
+        put_str(&mut record, "json");
+        record.extend_from_slice(&0u32.to_le_bytes()); // empty metadata
+        self.record(OP_CHANNEL, &record)
+    }
+
+    /// Export a message received at `time`. PackedScans are exported like Scans, and messages which
+    /// aren't exported are skipped.
+    pub fn write(
+        &mut self,
+        time: SystemTime,
+        message: &mote_to_host::Message,
+    ) -> Result<(), Error> {
+        let (channel, data) = match message {
+            mote_to_host::Message::Scan(scan) => (
+                SCAN_CHANNEL,
+                serde_json::to_vec(&LaserScan::new(time, &scan.points))?,
+            ),
+            mote_to_host::Message::PackedScan(packed) => (
+                SCAN_CHANNEL,
+                serde_json::to_vec(&LaserScan::new(time, &packed.to_scan()?.points))?,
+            ),
+            mote_to_host::Message::IMUMeasurement(measurement) => {
+                (IMU_CHANNEL, serde_json::to_vec(measurement)?)
+            }
+            mote_to_host::Message::DriveBaseState(state) => (
+                JOINT_STATE_CHANNEL,
+                serde_json::to_vec(&JointState::new(time, state))?,
+            ),
+            _ => return Ok(()),
+        };
+        let nanos = (time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64)
+            .to_le_bytes();
+        let mut record = Vec::with_capacity(22 + data.len());
+        record.extend_from_slice(&channel.to_le_bytes());
+        record.extend_from_slice(&self.sequence.to_le_bytes());
+        record.extend_from_slice(&nanos); // log time
+        record.extend_from_slice(&nanos); // publish time
+        record.extend_from_slice(&data);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.record(OP_MESSAGE, &record)
+    }
+
+    /// Write the footer, completing the file
+    pub fn finish(mut self) -> Result<W, Error> {
+        // CRCs of zero mean they weren't calculated
+        self.record(OP_DATA_END, &0u32.to_le_bytes())?;
+        let mut footer = Vec::new();
+        footer.extend_from_slice(&0u64.to_le_bytes()); // no summary section
+        footer.extend_from_slice(&0u64.to_le_bytes()); // no summary offsets
+        footer.extend_from_slice(&0u32.to_le_bytes()); // summary CRC
+        self.record(OP_FOOTER, &footer)?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn record(&mut self, opcode: u8, content: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(&[opcode])?;
+        self.writer
+            .write_all(&(content.len() as u64).to_le_bytes())?;
+        self.writer.write_all(content)?;
+        Ok(())
+    }
+}
+
+/// Export a recorded session to MCAP, returning the finished writer
+pub fn export_log<R: Read, W: Write>(
+    log: LogReader<R>,
+    mut mcap: McapWriter<W>,
+) -> Result<W, Error> {
+    let started_at = log.header().started_at;
+    for record in log {
+        let record = record?;
+        mcap.write(started_at + record.timestamp, &record.message)?;
+    }
+    mcap.finish()
+}
+
+fn put_str(buffer: &mut Vec<u8>, value: &str) {
+    put_bytes(buffer, value.as_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(value);
+}
+
+/// foxglove.Time
+#[derive(Serialize, JsonSchema)]
+struct Time {
+    sec: u32,
+    nsec: u32,
+}
+
+impl From<SystemTime> for Time {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        Self {
+            sec: since_epoch.as_secs() as u32,
+            nsec: since_epoch.subsec_nanos(),
+        }
+    }
+}
+
+/// foxglove.Vector3
+#[derive(Serialize, JsonSchema, Default)]
+struct Vector3 {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+/// foxglove.Quaternion
+#[derive(Serialize, JsonSchema)]
+struct Quaternion {
+    x: f64,
+    y: f64,
+    z: f64,
+    w: f64,
+}
+
+/// foxglove.Pose
+#[derive(Serialize, JsonSchema)]
+struct Pose {
+    position: Vector3,
+    orientation: Quaternion,
+}
+
+/// foxglove.LaserScan, which Foxglove's 3D panel draws
+#[derive(Serialize, JsonSchema)]
+struct LaserScan {
+    timestamp: Time,
+    frame_id: String,
+    /// Origin of the scan relative to frame_id
+    pose: Pose,
+    start_angle: f64,
+    end_angle: f64,
+    /// In meters
+    ranges: Vec<f64>,
+    intensities: Vec<f64>,
+}
+
+impl LaserScan {
+    /// Sort the points by angle, and take them to be evenly spaced between the first and last
+    /// angle. The lidar samples at a near constant rate, so this is close.
+    fn new(time: SystemTime, points: &[mote_to_host::Point]) -> Self {
+        let mut points: Vec<&mote_to_host::Point> = points.iter().collect();
+        points.sort_by(|a, b| a.angle_rad.total_cmp(&b.angle_rad));
+        Self {
+            timestamp: time.into(),
+            frame_id: LIDAR_FRAME.into(),
+            pose: Pose {
+                position: Vector3::default(),
+                orientation: Quaternion {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                    w: 1.0,
+                },
+            },
+            start_angle: points.first().map_or(0.0, |point| point.angle_rad.into()),
+            end_angle: points.last().map_or(0.0, |point| point.angle_rad.into()),
+            ranges: points
+                .iter()
+                .map(|point| f64::from(point.distance_mm) / 1000.0)
+                .collect(),
+            intensities: points.iter().map(|point| point.quality.into()).collect(),
+        }
+    }
+}
+
+/// The drive base's wheels, laid out like ROS's sensor_msgs/JointState
+#[derive(Serialize, JsonSchema)]
+struct JointState {
+    timestamp: Time,
+    name: [&'static str; 2],
+    /// In rad
+    position: [f32; 2],
+    /// In rad/s
+    velocity: [f32; 2],
+    /// In percent
+    effort: [f32; 2],
+}
+
+impl JointState {
+    fn new(time: SystemTime, state: &mote_to_host::DriveBaseState) -> Self {
+        let wheels = [&state.left, &state.right];
+        Self {
+            timestamp: time.into(),
+            name: ["left_wheel", "right_wheel"],
+            position: wheels.map(|wheel| wheel.postition_rad),
+            velocity: wheels.map(|wheel| wheel.velocity_rad_per_s),
+            effort: wheels.map(|wheel| wheel.effort_percent),
+        }
+    }
+}
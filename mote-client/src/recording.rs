@@ -0,0 +1,290 @@
+//! Recording telemetry to a log file, and replaying it later
+//!
+//! A log starts with a header:
+//!
+//! | Bytes | Field                                        |
+//! |-------|----------------------------------------------|
+//! | 8     | `MOTELOG\0`                                  |
+//! | 2     | Log format version, see LOG_FORMAT_VERSION   |
+//! | 2     | Protocol version the messages are encoded in |
+//! | 8     | Unix time the recording started, in ms       |
+//! | 2     | Length of the UID                            |
+//! | n     | UID of the recorded Mote, UTF-8              |
+//!
+//! followed by one record per message:
+//!
+//! | Bytes | Field                                           |
+//! |-------|-------------------------------------------------|
+//! | 8     | Time since the recording started, in µs         |
+//! | 4     | Length of the message                           |
+//! | n     | postcard encoded `mote_to_host::Message`        |
+//!
+//! Integers are little endian. Since messages are encoded as on the wire, a log can only be read
+//! by code speaking the protocol version it was recorded with.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mote_api::messages::mote_to_host;
+use mote_api::{HostLink, MoteLink, PROTOCOL_VERSION};
+
+use crate::Error;
+
+/// Start of every log file
+const MAGIC: [u8; 8] = *b"MOTELOG\0";
+
+/// Version of the log layout described in the module documentation
+pub const LOG_FORMAT_VERSION: u16 = 1;
+
+/// Records longer than this are taken to be corruption, rather than allocated
+const MAX_RECORD_LENGTH: usize = 1 << 20;
+
+/// Describes a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogHeader {
+    /// Protocol version the messages were encoded in
+    pub protocol_version: u16,
+    /// UID of the recorded Mote
+    pub uid: String,
+    pub started_at: SystemTime,
+}
+
+/// A message and when it was received
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Time since the recording started
+    pub timestamp: Duration,
+    pub message: mote_to_host::Message,
+}
+
+/// Writes messages to a log, see the module documentation
+pub struct LogWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl LogWriter<BufWriter<File>> {
+    /// Create a log file at `path`, replacing any existing file
+    pub fn create(path: impl AsRef<Path>, uid: &str) -> Result<Self, Error> {
+        Self::new(BufWriter::new(File::create(path)?), uid)
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    /// Start a recording of the Mote with `uid`, writing the header straight away
+    pub fn new(mut writer: W, uid: &str) -> Result<Self, Error> {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let uid_length = u16::try_from(uid.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "UID is too long"))?;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&LOG_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+        writer.write_all(&started_at.to_le_bytes())?;
+        writer.write_all(&uid_length.to_le_bytes())?;
+        writer.write_all(uid.as_bytes())?;
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record a message received just now
+    pub fn write(&mut self, message: &mote_to_host::Message) -> Result<(), Error> {
+        self.write_at(self.start.elapsed(), message)
+    }
+
+    /// Record a message received `timestamp` after the recording started
+    pub fn write_at(
+        &mut self,
+        timestamp: Duration,
+        message: &mote_to_host::Message,
+    ) -> Result<(), Error> {
+        let encoded = postcard::to_allocvec(message).map_err(mote_api::Error::from)?;
+        let timestamp = timestamp.as_micros() as u64;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer
+            .write_all(&(encoded.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&encoded)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads the messages in a log, see the module documentation
+///
+/// Iterating yields each record in turn. A log cut short, e.g. because the recording crashed,
+/// ends with an error after its last whole record.
+pub struct LogReader<R: Read> {
+    reader: R,
+    header: LogHeader,
+    buffer: Vec<u8>,
+}
+
+impl LogReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    /// Read the header of a log
+    ///
+    /// Fails with `mote_api::Error::IncompatibleProtocol` if the log was recorded with another
+    /// protocol version, since its messages can't be decoded.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => Error::NotALog,
+                _ => err.into(),
+            })?;
+        if magic != MAGIC {
+            return Err(Error::NotALog);
+        }
+        let format_version = u16::from_le_bytes(read_array(&mut reader)?);
+        if format_version != LOG_FORMAT_VERSION {
+            return Err(Error::UnsupportedLogFormat(format_version));
+        }
+        let protocol_version = u16::from_le_bytes(read_array(&mut reader)?);
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(mote_api::Error::IncompatibleProtocol {
+                local: PROTOCOL_VERSION,
+                peer: protocol_version,
+            }
+            .into());
+        }
+        let started_at = u64::from_le_bytes(read_array(&mut reader)?);
+        let uid_length = u16::from_le_bytes(read_array(&mut reader)?);
+        let mut uid = vec![0; uid_length as usize];
+        reader.read_exact(&mut uid)?;
+        let header = LogHeader {
+            protocol_version,
+            uid: String::from_utf8(uid).map_err(|_| Error::CorruptLog)?,
+            started_at: UNIX_EPOCH + Duration::from_millis(started_at),
+        };
+        Ok(Self {
+            reader,
+            header,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &LogHeader {
+        &self.header
+    }
+
+    /// The next record, or None at the end of the log
+    pub fn read(&mut self) -> Result<Option<LogRecord>, Error> {
+        let mut timestamp = [0; 8];
+        // Only the end of the file before a record is a clean end of the log
+        match self.reader.read(&mut timestamp[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut timestamp[1..])?,
+        }
+        let length = u32::from_le_bytes(read_array(&mut self.reader)?) as usize;
+        if length > MAX_RECORD_LENGTH {
+            return Err(Error::CorruptLog);
+        }
+        self.buffer.resize(length, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+        let message = postcard::from_bytes(&self.buffer).map_err(mote_api::Error::from)?;
+        Ok(Some(LogRecord {
+            timestamp: Duration::from_micros(u64::from_le_bytes(timestamp)),
+            message,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<LogRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Plays a log back into a MoteLink, as if a Mote were sending it
+///
+/// Messages are sent over one HostLink kept for the whole replay, and their packets handed to the
+/// receiving link, so they arrive as they would have from a Mote. Poll the receiving link for
+/// messages after each step.
+pub struct Replay<R: Read> {
+    reader: LogReader<R>,
+    /// Times the recorded rate, or None to deliver records without waiting
+    speed: Option<f64>,
+    mote: HostLink,
+    /// When the first record was delivered, and its timestamp
+    start: Option<(Instant, Duration)>,
+}
+
+impl<R: Read> Replay<R> {
+    /// Replay at `speed` times the recorded rate, e.g. 1.0 for real time. Speeds which aren't
+    /// positive and finite are refused with `Error::InvalidReplaySpeed`.
+    pub fn new(reader: LogReader<R>, speed: f64) -> Result<Self, Error> {
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(Error::InvalidReplaySpeed(speed));
+        }
+        Ok(Self::with_speed(reader, Some(speed)))
+    }
+
+    /// Replay delivering records as fast as they are stepped through, without waiting
+    pub fn unpaced(reader: LogReader<R>) -> Self {
+        Self::with_speed(reader, None)
+    }
+
+    fn with_speed(reader: LogReader<R>, speed: Option<f64>) -> Self {
+        Self {
+            reader,
+            speed,
+            mote: HostLink::new(),
+            start: None,
+        }
+    }
+
+    pub fn header(&self) -> &LogHeader {
+        self.reader.header()
+    }
+
+    /// Wait until the next record is due, then deliver it to `link`. Returns false once the log
+    /// has ended.
+    pub fn step(&mut self, link: &mut MoteLink) -> Result<bool, Error> {
+        let Some(record) = self.reader.read()? else {
+            return Ok(false);
+        };
+        let (start, first) = *self.start.get_or_insert((Instant::now(), record.timestamp));
+        if let Some(speed) = self.speed {
+            let offset = record.timestamp.saturating_sub(first).as_secs_f64() / speed;
+            if let Some(wait) =
+                (start + Duration::from_secs_f64(offset)).checked_duration_since(Instant::now())
+            {
+                thread::sleep(wait);
+            }
+        }
+
+        self.mote.send(record.message)?;
+        while let Some(packet) = self.mote.poll_transmit() {
+            link.handle_receive(&packet);
+        }
+        Ok(true)
+    }
+}
@@ -64,6 +64,7 @@ async function readLoop(telemetry_recv: (data: PollReceiveResult) => void) {
 
         // Parse message
         let message = Array.from(new TextEncoder().encode(value));
+        link.handle_time(performance.now());
         link.handle_receive(message);
 
         // Check if one or more messages completed by the packet
@@ -73,6 +74,9 @@ async function readLoop(telemetry_recv: (data: PollReceiveResult) => void) {
             telemetry_recv(data);
             data = link.poll_receive() as PollReceiveResult;
         }
+
+        // Send acknowledgements, and retry configuration writes Mote hasn't acknowledged
+        await flush();
     }
 }
 
@@ -82,15 +86,22 @@ async function write() {
         return;
     }
 
-    let data = link.poll_transmit() as number[] | null;
-    if (!data) {
+    if (!(await flush())) {
         console.log("[serial] poll_transmit called but no data was returned.");
     }
-    while (data) {
+}
+
+// Send every packet queued by the link, returning how many were sent
+async function flush(): Promise<number> {
+    let sent = 0;
+    let data = link.poll_transmit() as number[] | null;
+    while (data && outputStream) {
         await outputStream.write(new TextDecoder().decode(new Uint8Array(data)));
         console.log("[serial] [TX] message sent");
+        sent += 1;
         data = link.poll_transmit() as number[] | null;
     }
+    return sent;
 }
 
 // UI event handlers
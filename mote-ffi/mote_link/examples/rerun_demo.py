@@ -13,8 +13,10 @@ from mote_link.link import (
     Ping,
     Pong,
     Scan,
+    ScanAssembler,
     SetDriveBaseVelocity,
     State,
+    Sweep,
 )
 
 
@@ -37,16 +39,16 @@ def _log_imu_measurement(imu: IMUMeasurement):
     rr.log("imu/gyro/z", rr.Scalars(imu.gyro.z))
 
 
-def _log_scan(scan: Scan):
+def _log_sweep(sweep: Sweep):
     positions = [
         [
             math.cos(p.angle_rad) * p.distance_mm,
             math.sin(p.angle_rad) * p.distance_mm,
         ]
-        for p in scan.points
+        for p in sweep.points
     ]
     colors = []
-    for p in scan.points:
+    for p in sweep.points:
         h = (p.distance_mm / (20.0 * 360.0)) % 1.0
         r, g, b = colorsys.hsv_to_rgb(h, 1.0, 1.0)
         colors.append([int(r * 255), int(g * 255), int(b * 255)])
@@ -146,6 +148,9 @@ async def run_main():
         print("Pinging Mote")
         await client.send(Ping())
 
+        # Scans arrive in arcs of up to 100 points, so collect them into whole revolutions
+        assembler = ScanAssembler()
+
         async def recv_loop():
             while True:
                 message = await client.recv()
@@ -156,7 +161,9 @@ async def run_main():
                     print("Mote pinged host.")
                     await client.send(Pong())
                 elif isinstance(message, Scan):
-                    _log_scan(message)
+                    assembler.push(message)
+                    while (sweep := assembler.poll_sweep()) is not None:
+                        _log_sweep(sweep)
                 elif isinstance(message, DriveBaseState):
                     _log_drive_base_state(message)
                 elif isinstance(message, IMUMeasurement):
@@ -5,10 +5,12 @@ from __future__ import annotations
 import mote_link.mote_ffi as mote_ffi  # ty:ignore[unresolved-import]
 
 import asyncio
+import collections
 import ipaddress
 import json
 import socket
-from dataclasses import dataclass
+import time
+from dataclasses import asdict, dataclass, field
 from enum import Enum
 from typing import Union
 
@@ -20,6 +22,31 @@ class MoteConnectionError(Exception):
     """Raised when a connection attempt to Mote fails."""
 
 
+class MoteRequestError(Exception):
+    """Raised when Mote refuses a request."""
+
+    def __init__(self, reason: NackReason):
+        super().__init__(f"Mote refused the request: {reason.value}")
+        self.reason = reason
+
+
+@dataclass
+class LinkStats:
+    """Link quality counters, mirrors mote_api::LinkStats."""
+
+    frames_sent: int
+    frames_received: int
+    frames_dropped: int
+    frames_corrupt: int
+    frames_out_of_order: int
+    fragments_expired: int
+    retransmissions: int
+    delivery_failures: int
+    duplicates_suppressed: int
+    frames_discarded: int
+    receive_overflows: int
+
+
 # Message types
 @dataclass
 class LidarPoint:
@@ -74,6 +101,19 @@ class Pong:
     pass
 
 
+@dataclass
+class Hello:
+    protocol_version: int
+
+
+@dataclass
+class HelloAck:
+    protocol_version: int
+    firmware_version: str
+    git_hash: str
+    capabilities: int
+
+
 @dataclass
 class RequestNetworkScan:
     pass
@@ -90,6 +130,29 @@ class SetUID:
     uid: str
 
 
+@dataclass
+class ScanTelemetry:
+    enabled: bool = True
+    decimation: int = 1  # only one in this many lidar points is sent
+
+
+@dataclass
+class PeriodicTelemetry:
+    enabled: bool
+    period_ms: int
+
+
+@dataclass
+class ConfigureTelemetry:
+    """Which telemetry streams Mote sends, and how often, until it restarts."""
+
+    scan: ScanTelemetry = field(default_factory=ScanTelemetry)
+    imu: PeriodicTelemetry = field(default_factory=lambda: PeriodicTelemetry(True, 20))
+    drive_base: PeriodicTelemetry = field(
+        default_factory=lambda: PeriodicTelemetry(True, 100)
+    )
+
+
 @dataclass
 class WheelJointState:
     effort_percent: float
@@ -99,6 +162,7 @@ class WheelJointState:
 
 @dataclass
 class DriveBaseState:
+    timestamp_us: int  # when the wheels were sampled, in µs since Mote booted
     left: WheelJointState
     right: WheelJointState
 
@@ -112,6 +176,7 @@ class IMUAxisTriple:
 
 @dataclass
 class IMUMeasurement:
+    timestamp_us: int  # when the IMU was read, in µs since Mote booted
     accel: IMUAxisTriple
     gyro: IMUAxisTriple
 
@@ -122,11 +187,74 @@ class SetDriveBaseVelocity:
     right_velocity_rad: float
 
 
+@dataclass
+class MoveWheels:
+    """Turn each wheel through an angle, Mote answers with MoveFinished once it's done."""
+
+    id: int
+    left_rad: float
+    right_rad: float
+    max_velocity_rad_per_s: float
+    max_acceleration_rad_per_s2: float
+
+
+class MoveOutcome(Enum):
+    Completed = "Completed"
+    TimedOut = "TimedOut"
+    Interrupted = "Interrupted"
+
+
+@dataclass
+class MoveFinished:
+    id: int
+    timestamp_us: int  # when the move ended, in µs since Mote booted
+    outcome: MoveOutcome
+    left_error_rad: float  # how far the wheel stopped short of its target
+    right_error_rad: float
+
+
 @dataclass
 class Scan:
+    timestamp_us: int  # when the last point was received, in µs since Mote booted
     points: list[LidarPoint]
 
 
+@dataclass
+class TimeSyncRequest:
+    host_t0: int  # when the request was sent, in µs on the host's clock
+
+
+@dataclass
+class TimeSyncReply:
+    host_t0: int
+    device_t1: int  # when Mote received the request, in µs since Mote booted
+    device_t2: int  # when Mote sent the reply, in µs since Mote booted
+
+
+@dataclass
+class Request:
+    id: int
+    message: HostMessage
+
+
+@dataclass
+class Response:
+    id: int
+    result: MoteMessage | None
+
+
+class NackReason(Enum):
+    Unsupported = "Unsupported"
+    InvalidRequest = "InvalidRequest"
+    InvalidArgument = "InvalidArgument"
+
+
+@dataclass
+class Nack:
+    id: int
+    reason: NackReason
+
+
 @dataclass
 class State:
     data: MoteState
@@ -140,10 +268,27 @@ HostMessage = Union[
     SetNetworkConnectionConfig,
     SetUID,
     SetDriveBaseVelocity,
+    Hello,
+    Request,
+    TimeSyncRequest,
+    ConfigureTelemetry,
+    MoveWheels,
 ]
 
 # Union of all messages Mote can send to the host
-MoteMessage = Union[Ping, Pong, Scan, DriveBaseState, IMUMeasurement, State]
+MoteMessage = Union[
+    Ping,
+    Pong,
+    Scan,
+    DriveBaseState,
+    IMUMeasurement,
+    State,
+    HelloAck,
+    Response,
+    Nack,
+    TimeSyncReply,
+    MoveFinished,
+]
 
 
 # Converts mote_ffi json based messages into Python native types
@@ -169,6 +314,23 @@ def _serialize_host_message(msg: HostMessage) -> str:
                 }
             }
         )
+    if isinstance(msg, Hello):
+        return json.dumps({"Hello": {"protocol_version": msg.protocol_version}})
+    if isinstance(msg, Request):
+        return json.dumps(
+            {
+                "Request": {
+                    "id": msg.id,
+                    "message": json.loads(_serialize_host_message(msg.message)),
+                }
+            }
+        )
+    if isinstance(msg, ConfigureTelemetry):
+        return json.dumps({"ConfigureTelemetry": asdict(msg)})
+    if isinstance(msg, TimeSyncRequest):
+        return json.dumps({"TimeSyncRequest": {"host_t0": msg.host_t0}})
+    if isinstance(msg, MoveWheels):
+        return json.dumps({"MoveWheels": asdict(msg)})
     raise TypeError(f"Unknown host message type: {type(msg)}")
 
 
@@ -179,20 +341,47 @@ def _deserialize_mote_message(data) -> MoteMessage:
     if data == "Pong":
         return Pong()
     if isinstance(data, dict):
+        if "PackedScan" in data:
+            # Mote sends points in the lidar's units, convert them like a Scan
+            scan = mote_ffi.unpack_scan(json.dumps(data["PackedScan"]))
+            return _deserialize_mote_message({"Scan": json.loads(scan)})
         if "Scan" in data:
-            return Scan(points=[LidarPoint(**p) for p in data["Scan"]])
+            d = data["Scan"]
+            return Scan(
+                timestamp_us=d["timestamp_us"],
+                points=[LidarPoint(**p) for p in d["points"]],
+            )
         if "DriveBaseState" in data:
             d = data["DriveBaseState"]
             return DriveBaseState(
+                timestamp_us=d["timestamp_us"],
                 left=WheelJointState(**d["left"]),
                 right=WheelJointState(**d["right"]),
             )
         if "IMUMeasurement" in data:
             d = data["IMUMeasurement"]
             return IMUMeasurement(
+                timestamp_us=d["timestamp_us"],
                 accel=IMUAxisTriple(**d["accel"]),
                 gyro=IMUAxisTriple(**d["gyro"]),
             )
+        if "HelloAck" in data:
+            return HelloAck(**data["HelloAck"])
+        if "Response" in data:
+            d = data["Response"]
+            result = d["result"]
+            return Response(
+                id=d["id"],
+                result=None if result is None else _deserialize_mote_message(result),
+            )
+        if "Nack" in data:
+            d = data["Nack"]
+            return Nack(id=d["id"], reason=NackReason(d["reason"]))
+        if "TimeSyncReply" in data:
+            return TimeSyncReply(**data["TimeSyncReply"])
+        if "MoveFinished" in data:
+            d = data["MoveFinished"]
+            return MoveFinished(**{**d, "outcome": MoveOutcome(d["outcome"])})
         if "State" in data:
             s = data["State"]
             return State(
@@ -218,6 +407,71 @@ def _deserialize_mote_message(data) -> MoteMessage:
     raise ValueError(f"Unknown mote message: {data!r}")
 
 
+@dataclass
+class ScanFilter:
+    """Which lidar points a ScanAssembler keeps, mirrors mote_api::scan::ScanFilter."""
+
+    min_quality: int = 0
+    min_distance_mm: float = 0.0
+    # f32::MAX, the largest distance Mote can report
+    max_distance_mm: float = 3.4028234663852886e38
+    drop_zero_distance: bool = True
+
+
+@dataclass
+class Sweep:
+    """One revolution of the lidar, times are as given to ScanAssembler.push."""
+
+    points: list[LidarPoint]
+    start_us: int
+    end_us: int
+
+
+def _deserialize_sweep(data) -> Sweep:
+    return Sweep(
+        points=[LidarPoint(**p) for p in data["points"]],
+        start_us=data["start_us"],
+        end_us=data["end_us"],
+    )
+
+
+class ScanAssembler:
+    """
+    Collects Scan messages into whole revolutions of the lidar.
+
+    Mote sends lidar points as they are measured, up to 100 at a time, so each Scan covers an
+    arbitrary arc. Push every Scan received, then poll for completed sweeps.
+    """
+
+    def __init__(self, scan_filter: ScanFilter | None = None):
+        scan_filter = scan_filter or ScanFilter()
+        self._assembler = mote_ffi.ScanAssembler(json.dumps(scan_filter.__dict__))
+
+    def push(self, scan: Scan, time_us: int | None = None):
+        """
+        Add the points of a scan measured at `time_us`, which defaults to the scan's own
+        timestamp on Mote's clock.
+        """
+        if time_us is None:
+            time_us = scan.timestamp_us
+        self._assembler.push(json.dumps([p.__dict__ for p in scan.points]), time_us)
+
+    def poll_sweep(self) -> Sweep | None:
+        """
+        The oldest completed sweep not yet polled.
+        """
+        sweep_json = self._assembler.poll_sweep()
+        if sweep_json is None:
+            return None
+        return _deserialize_sweep(json.loads(sweep_json))
+
+    def reset(self):
+        """
+        Forget the sweep in progress, e.g. after a gap in the scans.
+        """
+        self._assembler.reset()
+
+
 # Prompt the client to chose a robot from all devices advertising on the provided service.
 # Scans for `service_name` via mDNS for 3 seconds, then either auto-connects if only one
 # device is found, or presents a selection prompt if multiple devices are found.
@@ -300,6 +554,9 @@ class MoteClient:
         self.ip = None
         self._protocol: _MoteProtocol | None = None
         self._link: mote_ffi.Link | None = None
+        self._next_request_id = 0
+        # Messages received while waiting for an answer, returned by recv before anything new
+        self._backlog: collections.deque[MoteMessage] = collections.deque()
 
     async def __aenter__(self):
         return self
@@ -365,6 +622,10 @@ class MoteClient:
     async def send(self, message: HostMessage):
         """
         Send a message to Mote.
+
+        Configuration commands (SetUID, SetNetworkConnectionConfig, RequestNetworkScan,
+        ConfigureTelemetry) and MoveWheels are retransmitted until Mote acknowledges them, which
+        happens while recv is being called.
         """
         assert self._link is not None and self._protocol is not None, (
             "Not connected, try calling MoteClient.connect"
@@ -372,12 +633,88 @@ class MoteClient:
         assert self._protocol.transport is not None
 
         self._link.send(_serialize_host_message(message))
+        self._flush()
+
+    def _flush(self):
+        """
+        Send every packet the link has queued, including acknowledgements and retransmissions.
+        """
+        assert self._link is not None and self._protocol is not None
+        assert self._protocol.transport is not None
+
         while True:
             transmit_json = self._link.poll_transmit()
             if transmit_json is None:
                 break
             self._protocol.transport.sendto(bytes(json.loads(transmit_json)))
 
+    async def handshake(self, timeout: float = 3.0) -> HelloAck:
+        """
+        Exchange protocol versions with Mote.
+
+        Returns Mote's firmware version information.
+        Raises MoteConnectionError if Mote does not answer, or speaks an incompatible protocol version.
+        """
+        local_version = mote_ffi.protocol_version()
+        await self.send(Hello(protocol_version=local_version))
+
+        async def _wait_for_ack() -> HelloAck:
+            while True:
+                message = await self.recv()
+                if isinstance(message, HelloAck):
+                    return message
+
+        try:
+            ack = await asyncio.wait_for(_wait_for_ack(), timeout)
+        except asyncio.TimeoutError as e:
+            raise MoteConnectionError(
+                "Mote did not answer the version handshake. "
+                "Its firmware may predate the handshake, try updating it."
+            ) from e
+
+        if ack.protocol_version != local_version:
+            raise MoteConnectionError(
+                f"Mote speaks protocol version {ack.protocol_version} "
+                f"(firmware {ack.firmware_version}, {ack.git_hash}), "
+                f"but this library speaks version {local_version}. "
+                "Update the firmware or this library so that they match."
+            )
+        return ack
+
+    async def request(
+        self, message: HostMessage, timeout: float = 3.0
+    ) -> MoteMessage | None:
+        """
+        Send a message to Mote as a request, and wait for Mote to answer it.
+
+        Returns the command's reply (e.g. Pong for Ping), or None if it has no reply.
+        Raises MoteRequestError if Mote refuses the request, and TimeoutError if it is not
+        answered within `timeout` seconds. Messages received while waiting are kept for recv.
+        """
+        request_id = self._next_request_id
+        self._next_request_id = (request_id + 1) % 2**32
+        await self.send(Request(id=request_id, message=message))
+
+        def _is_answer(message: MoteMessage) -> bool:
+            return isinstance(message, (Response, Nack)) and message.id == request_id
+
+        async def _wait_for_answer() -> Response | Nack:
+            # Another request may have already received our answer
+            for message in self._backlog:
+                if _is_answer(message):
+                    self._backlog.remove(message)
+                    return message
+            while True:
+                message = await self._receive()
+                if _is_answer(message):
+                    return message
+                self._backlog.append(message)
+
+        answer = await asyncio.wait_for(_wait_for_answer(), timeout)
+        if isinstance(answer, Nack):
+            raise MoteRequestError(answer.reason)
+        return answer.result
+
     async def recv(self) -> MoteMessage:
         """
         Receive one message from Mote.
@@ -385,17 +722,31 @@ class MoteClient:
         Suspends until a complete message is decoded, yielding control to the
         event loop between packets.
         """
+        if self._backlog:
+            return self._backlog.popleft()
+        return await self._receive()
+
+    async def _receive(self) -> MoteMessage:
         assert self._link is not None and self._protocol is not None, (
             "Not connected, try calling MoteClient.connect"
         )
 
         while True:
             data = await self._protocol._queue.get()
+            self._link.handle_time(int(time.monotonic() * 1000))
             self._link.handle_receive(json.dumps(list(data)))
             message_json = self._link.poll_receive()
+            self._flush()
             if message_json is not None:
                 return _deserialize_mote_message(json.loads(message_json))
 
+    def stats(self) -> LinkStats:
+        """
+        Link quality counters for the connection to Mote.
+        """
+        assert self._link is not None, "Not connected, try calling MoteClient.connect"
+        return LinkStats(**json.loads(self._link.stats()))
+
     async def __aexit__(self, exc_type, exc_val, exc_tb):
         if self._protocol is not None:
             assert self._protocol.transport is not None
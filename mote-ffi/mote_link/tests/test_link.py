@@ -3,15 +3,33 @@ import json
 import pytest
 
 from mote_link.link import (
+    ConfigureTelemetry,
     DriveBaseState,
+    Hello,
+    HelloAck,
     IMUMeasurement,
+    LidarPoint,
+    MoveFinished,
+    MoveOutcome,
+    MoveWheels,
+    Nack,
+    NackReason,
     Ping,
+    PeriodicTelemetry,
     Pong,
+    Request,
     RequestNetworkScan,
+    Response,
     Scan,
+    ScanAssembler,
+    ScanFilter,
+    ScanTelemetry,
     SetDriveBaseVelocity,
     SetNetworkConnectionConfig,
     SetUID,
+    Sweep,
+    TimeSyncReply,
+    TimeSyncRequest,
     _deserialize_mote_message,
     _serialize_host_message,
 )
@@ -48,6 +66,51 @@ class TestSerializeHostMessage:
             "DriveBaseCommand": {"left_velocity_rad": 1.5, "right_velocity_rad": -0.5}
         }
 
+    def test_hello(self):
+        data = json.loads(_serialize_host_message(Hello(protocol_version=1)))
+        assert data == {"Hello": {"protocol_version": 1}}
+
+    def test_request(self):
+        msg = Request(id=3, message=SetUID(uid="mote-abc"))
+        assert json.loads(_serialize_host_message(msg)) == {
+            "Request": {"id": 3, "message": {"SetUID": {"uid": "mote-abc"}}}
+        }
+
+    def test_time_sync_request(self):
+        data = json.loads(_serialize_host_message(TimeSyncRequest(host_t0=1234)))
+        assert data == {"TimeSyncRequest": {"host_t0": 1234}}
+
+    def test_configure_telemetry(self):
+        msg = ConfigureTelemetry(
+            scan=ScanTelemetry(enabled=False),
+            imu=PeriodicTelemetry(enabled=True, period_ms=50),
+        )
+        assert json.loads(_serialize_host_message(msg)) == {
+            "ConfigureTelemetry": {
+                "scan": {"enabled": False, "decimation": 1},
+                "imu": {"enabled": True, "period_ms": 50},
+                "drive_base": {"enabled": True, "period_ms": 100},
+            }
+        }
+
+    def test_move_wheels(self):
+        msg = MoveWheels(
+            id=3,
+            left_rad=10.0,
+            right_rad=-10.0,
+            max_velocity_rad_per_s=8.0,
+            max_acceleration_rad_per_s2=20.0,
+        )
+        assert json.loads(_serialize_host_message(msg)) == {
+            "MoveWheels": {
+                "id": 3,
+                "left_rad": 10.0,
+                "right_rad": -10.0,
+                "max_velocity_rad_per_s": 8.0,
+                "max_acceleration_rad_per_s2": 20.0,
+            }
+        }
+
     def test_unknown_type_raises(self):
         with pytest.raises(TypeError):
             _serialize_host_message("not_a_message")  # type: ignore[arg-type]
@@ -71,14 +134,18 @@ class TestRoundTrip:
             {"quality": 1, "angle_rad": 0.1, "distance_mm": 10.0},
             {"quality": 2, "angle_rad": 0.2, "distance_mm": 20.0},
         ]
-        result = _deserialize_mote_message({"Scan": points})
+        result = _deserialize_mote_message(
+            {"Scan": {"timestamp_us": 1_500_000, "points": points}}
+        )
         assert isinstance(result, Scan)
+        assert result.timestamp_us == 1_500_000
         assert len(result.points) == 2
         assert result.points[1].distance_mm == 20.0
 
     def test_drive_base_state(self):
         data = {
             "DriveBaseState": {
+                "timestamp_us": 2_000_000,
                 "left": {
                     "effort_percent": 0.5,
                     "velocity_rad_per_s": 1.0,
@@ -93,17 +160,146 @@ class TestRoundTrip:
         }
         result = _deserialize_mote_message(data)
         assert isinstance(result, DriveBaseState)
+        assert result.timestamp_us == 2_000_000
         assert result.left.effort_percent == 0.5
         assert result.right.velocity_rad_per_s == 0.8
 
     def test_imu_measurement(self):
         data = {
             "IMUMeasurement": {
+                "timestamp_us": 2_020_000,
                 "accel": {"x": 0.1, "y": 0.2, "z": 9.8},
                 "gyro": {"x": 0.01, "y": 0.02, "z": 0.03},
             }
         }
         result = _deserialize_mote_message(data)
         assert isinstance(result, IMUMeasurement)
+        assert result.timestamp_us == 2_020_000
         assert result.accel.z == 9.8
         assert result.gyro.x == 0.01
+
+    def test_hello_ack(self):
+        data = {
+            "HelloAck": {
+                "protocol_version": 1,
+                "firmware_version": "0.1.0",
+                "git_hash": "c8c9062",
+                "capabilities": 15,
+            }
+        }
+        result = _deserialize_mote_message(data)
+        assert isinstance(result, HelloAck)
+        assert result.protocol_version == 1
+        assert result.git_hash == "c8c9062"
+
+    def test_response(self):
+        result = _deserialize_mote_message({"Response": {"id": 3, "result": "Pong"}})
+        assert result == Response(id=3, result=Pong())
+
+    def test_empty_response(self):
+        result = _deserialize_mote_message({"Response": {"id": 4, "result": None}})
+        assert result == Response(id=4, result=None)
+
+    def test_nack(self):
+        result = _deserialize_mote_message(
+            {"Nack": {"id": 5, "reason": "Unsupported"}}
+        )
+        assert result == Nack(id=5, reason=NackReason.Unsupported)
+
+    def test_time_sync_reply(self):
+        result = _deserialize_mote_message(
+            {"TimeSyncReply": {"host_t0": 1234, "device_t1": 50, "device_t2": 60}}
+        )
+        assert result == TimeSyncReply(host_t0=1234, device_t1=50, device_t2=60)
+
+    def test_move_finished(self):
+        result = _deserialize_mote_message(
+            {
+                "MoveFinished": {
+                    "id": 3,
+                    "timestamp_us": 9_000_000,
+                    "outcome": "TimedOut",
+                    "left_error_rad": 0.5,
+                    "right_error_rad": -0.25,
+                }
+            }
+        )
+        assert result == MoveFinished(
+            id=3,
+            timestamp_us=9_000_000,
+            outcome=MoveOutcome.TimedOut,
+            left_error_rad=0.5,
+            right_error_rad=-0.25,
+        )
+
+    def test_packed_scan_unpacked_by_ffi(self):
+        from mote_link.link import mote_ffi
+
+        packed = {
+            "timestamp_us": 7,
+            "encoding": "DeltaAngle",
+            "data": [3, 128, 1, 32, 3],
+        }
+        mote_ffi.unpack_scan.return_value = json.dumps(
+            {
+                "timestamp_us": 7,
+                "points": [{"quality": 3, "angle_rad": 0.5, "distance_mm": 200.0}],
+            }
+        )
+        result = _deserialize_mote_message({"PackedScan": packed})
+        (packed_json,) = mote_ffi.unpack_scan.call_args.args
+        assert json.loads(packed_json) == packed
+        assert result == Scan(
+            timestamp_us=7,
+            points=[LidarPoint(quality=3, angle_rad=0.5, distance_mm=200.0)],
+        )
+
+
+class TestScanAssembler:
+    def test_filter_passed_as_json(self):
+        assembler = ScanAssembler(ScanFilter(min_quality=10, max_distance_mm=6000.0))
+        from mote_link.link import mote_ffi
+
+        (filter_json,) = mote_ffi.ScanAssembler.call_args.args
+        assert json.loads(filter_json) == {
+            "min_quality": 10,
+            "min_distance_mm": 0.0,
+            "max_distance_mm": 6000.0,
+            "drop_zero_distance": True,
+        }
+
+    def test_push_serializes_points(self):
+        assembler = ScanAssembler()
+        scan = Scan(
+            timestamp_us=7,
+            points=[LidarPoint(quality=3, angle_rad=1.5, distance_mm=200.0)],
+        )
+        assembler.push(scan, time_us=42)
+        points_json, time_us = assembler._assembler.push.call_args.args
+        assert json.loads(points_json) == [
+            {"quality": 3, "angle_rad": 1.5, "distance_mm": 200.0}
+        ]
+        assert time_us == 42
+
+        # Mote's timestamp is used by default
+        assembler.push(scan)
+        _, time_us = assembler._assembler.push.call_args.args
+        assert time_us == 7
+
+    def test_poll_sweep(self):
+        assembler = ScanAssembler()
+        assembler._assembler.poll_sweep.return_value = json.dumps(
+            {
+                "points": [{"quality": 3, "angle_rad": 0.5, "distance_mm": 100.0}],
+                "start_us": 10,
+                "end_us": 20,
+            }
+        )
+        assert assembler.poll_sweep() == Sweep(
+            points=[LidarPoint(quality=3, angle_rad=0.5, distance_mm=100.0)],
+            start_us=10,
+            end_us=20,
+        )
+
+        assembler._assembler.poll_sweep.return_value = None
+        assert assembler.poll_sweep() is None
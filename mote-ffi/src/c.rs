@@ -9,6 +9,12 @@ pub struct MoteLinkHandle {
     inner: MoteLink,
 }
 
+/// Version of the wire protocol spoken by this library.
+#[unsafe(no_mangle)]
+pub extern "C" fn mote_link_protocol_version() -> u16 {
+    mote_api::PROTOCOL_VERSION
+}
+
 /// Create a new MoteLink handle. The returned pointer must be freed with `mote_link_free`.
 #[unsafe(no_mangle)]
 pub extern "C" fn mote_link_new() -> *mut MoteLinkHandle {
@@ -98,6 +104,18 @@ pub unsafe extern "C" fn mote_link_handle_receive(
     0
 }
 
+/// Advance the link's clock, in milliseconds since any fixed point.
+///
+/// Call this before `mote_link_handle_receive` so that incomplete messages time out.
+///
+/// # Safety
+/// `handle` must be a valid non-null pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mote_link_handle_time(handle: *mut MoteLinkHandle, now_ms: u64) {
+    let handle = unsafe { &mut *handle };
+    handle.inner.handle_time(now_ms);
+}
+
 /// Copy the next decoded mote-to-host message as a null-terminated JSON string into `buf`.
 ///
 /// Returns the number of bytes written including the null terminator, 0 if no message is ready,
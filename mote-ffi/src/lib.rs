@@ -4,7 +4,9 @@ use std::marker::PhantomData;
 use std::string::String;
 use std::vec::Vec;
 
-use mote_api::{Error as MoteCommsError, MoteComms};
+use mote_api::messages::mote_to_host;
+use mote_api::scan::{ScanAssembler, ScanFilter};
+use mote_api::{Error as MoteCommsError, MoteComms, ProtocolMessage};
 
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use thiserror::Error;
@@ -27,7 +29,7 @@ pub enum Error {
     SerdeJson(#[from] serde_json::Error),
 }
 
-pub struct MoteCommsFFI<const MTU: usize, I, O>
+pub struct MoteCommsFFI<const MTU: usize, const MAX_MESSAGE: usize, I, O>
 where
     I: DeserializeOwned, // Input type
     O: Serialize,        // Output type
@@ -35,15 +37,16 @@ where
     in_type: PhantomData<I>,
     out_type: PhantomData<O>,
 
-    link: MoteComms<MTU, I, O>,
+    link: MoteComms<MTU, MAX_MESSAGE, I, O>,
 }
 
-impl<const MTU: usize, I, O> From<MoteComms<MTU, I, O>> for MoteCommsFFI<MTU, I, O>
+impl<const MTU: usize, const MAX_MESSAGE: usize, I, O> From<MoteComms<MTU, MAX_MESSAGE, I, O>>
+    for MoteCommsFFI<MTU, MAX_MESSAGE, I, O>
 where
     I: DeserializeOwned, // Input type
     O: Serialize,        // Output type
 {
-    fn from(link: MoteComms<MTU, I, O>) -> Self {
+    fn from(link: MoteComms<MTU, MAX_MESSAGE, I, O>) -> Self {
         Self {
             link,
             in_type: PhantomData,
@@ -57,12 +60,12 @@ where
 // This makes FFI implementation easier, as they don't need to worry about converting complex native type.
 // JSON schemas are generated at build time, from which foreign language implementations may use to generate native type information.
 #[allow(dead_code)]
-impl<const MTU: usize, I, O> MoteCommsFFI<MTU, I, O>
+impl<const MTU: usize, const MAX_MESSAGE: usize, I, O> MoteCommsFFI<MTU, MAX_MESSAGE, I, O>
 where
-    I: Serialize + for<'de> Deserialize<'de>, // Input type
-    O: Serialize + for<'de> Deserialize<'de>, // Output type
+    I: Serialize + for<'de> Deserialize<'de> + ProtocolMessage, // Input type
+    O: Serialize + for<'de> Deserialize<'de> + ProtocolMessage, // Output type
 {
-    fn new(link: MoteComms<MTU, I, O>) -> Self {
+    fn new(link: MoteComms<MTU, MAX_MESSAGE, I, O>) -> Self {
         Self {
             link,
             in_type: PhantomData,
@@ -90,6 +93,10 @@ where
         Ok(())
     }
 
+    fn handle_time(&mut self, now_ms: u64) {
+        self.link.handle_time(now_ms);
+    }
+
     fn poll_receive(&mut self) -> Result<Option<String>, Error> {
         if let Some(v) = self.link.poll_receive()? {
             Ok(Some(serde_json::to_string(&v)?))
@@ -97,6 +104,50 @@ where
             Ok(None)
         }
     }
+
+    fn stats(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(&self.link.stats())?)
+    }
+}
+
+/// JSON shim for ScanAssembler, taking a ScanFilter and Scan points and returning Sweeps as JSON
+pub struct ScanAssemblerFFI {
+    assembler: ScanAssembler,
+}
+
+#[allow(dead_code)]
+impl ScanAssemblerFFI {
+    fn new(filter_json: &str) -> Result<Self, Error> {
+        let filter: ScanFilter = serde_json::from_str(filter_json)?;
+        Ok(Self {
+            assembler: ScanAssembler::new(filter),
+        })
+    }
+
+    fn push(&mut self, points_json: &str, time_us: u64) -> Result<(), Error> {
+        let points: Vec<mote_to_host::Point> = serde_json::from_str(points_json)?;
+        self.assembler.push(&points, time_us);
+        Ok(())
+    }
+
+    fn poll_sweep(&mut self) -> Result<Option<String>, Error> {
+        self.assembler
+            .poll_sweep()
+            .map(|sweep| serde_json::to_string(&sweep))
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    fn reset(&mut self) {
+        self.assembler.reset();
+    }
+}
+
+/// JSON shim for PackedScan::to_scan, taking a PackedScan and returning the equivalent Scan
+#[allow(dead_code)]
+fn unpack_scan(packed_json: &str) -> Result<String, Error> {
+    let packed: mote_to_host::PackedScan = serde_json::from_str(packed_json)?;
+    Ok(serde_json::to_string(&packed.to_scan()?)?)
 }
 
 #[cfg(test)]
@@ -107,7 +158,7 @@ mod tests {
         messages::{host_to_mote, mote_to_host},
     };
 
-    type HostFFI = MoteCommsFFI<1400, mote_to_host::Message, host_to_mote::Message>;
+    type HostFFI = MoteCommsFFI<1400, 5000, mote_to_host::Message, host_to_mote::Message>;
 
     fn make_host_ffi() -> HostFFI {
         MoteCommsFFI::from(MoteLink::new())
@@ -191,6 +242,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ffi_stats() {
+        let mut mote = HostLink::new();
+        let mut host_ffi = make_host_ffi();
+
+        mote.send(mote_to_host::Message::Pong).unwrap();
+        let payload = mote.poll_transmit().unwrap();
+        host_ffi
+            .handle_receive(&serde_json::to_string(&payload).unwrap())
+            .unwrap();
+        host_ffi.poll_receive().unwrap();
+
+        let stats: mote_api::LinkStats = serde_json::from_str(&host_ffi.stats().unwrap()).unwrap();
+        assert_eq!(stats.frames_received, 1);
+        assert_eq!(stats.frames_dropped, 0);
+    }
+
+    #[test]
+    fn test_ffi_handle_time_expires_fragments() {
+        let mut mote = HostLink::new();
+        let mut host_ffi = make_host_ffi();
+
+        let points = (0..400)
+            .map(|i| mote_to_host::Point {
+                quality: 0,
+                angle_rad: i as f32,
+                distance_mm: 0.0,
+            })
+            .collect();
+        let scan = mote_to_host::Scan {
+            timestamp_us: 0,
+            points,
+        };
+        mote.send(mote_to_host::Message::Scan(scan)).unwrap();
+        let first = mote.poll_transmit().unwrap();
+        assert!(mote.poll_transmit().is_some(), "expected fragmentation");
+
+        host_ffi.handle_time(0);
+        host_ffi
+            .handle_receive(&serde_json::to_string(&first).unwrap())
+            .unwrap();
+        assert!(host_ffi.poll_receive().unwrap().is_none());
+        host_ffi.handle_time(mote_api::REASSEMBLY_TIMEOUT_MS + 1);
+
+        let stats: mote_api::LinkStats = serde_json::from_str(&host_ffi.stats().unwrap()).unwrap();
+        assert_eq!(stats.fragments_expired, 1);
+    }
+
+    #[test]
+    fn test_ffi_request_round_trip() {
+        let mut host_ffi = make_host_ffi();
+        let mut mote = HostLink::new();
+
+        host_ffi
+            .send(r#"{"Request":{"id":42,"message":"Ping"}}"#)
+            .unwrap();
+        let packet_json = host_ffi.poll_transmit().unwrap().unwrap();
+        mote.handle_receive(&extract_payload(&packet_json));
+        assert_eq!(
+            mote.poll_receive().unwrap().unwrap(),
+            host_to_mote::Message::Request(host_to_mote::Request {
+                id: 42,
+                message: Box::new(host_to_mote::Message::Ping),
+            })
+        );
+
+        mote.send(mote_to_host::Message::Response(mote_to_host::Response {
+            id: 42,
+            result: Some(Box::new(mote_to_host::Message::Pong)),
+        }))
+        .unwrap();
+        let payload = mote.poll_transmit().unwrap();
+        host_ffi
+            .handle_receive(&serde_json::to_string(&payload).unwrap())
+            .unwrap();
+        let received: serde_json::Value =
+            serde_json::from_str(&host_ffi.poll_receive().unwrap().unwrap()).unwrap();
+        assert_eq!(
+            received,
+            serde_json::json!({"Response": {"id": 42, "result": "Pong"}})
+        );
+    }
+
     #[test]
     fn test_ffi_set_uid_round_trip() {
         let mut host_ffi = make_host_ffi();
@@ -231,4 +365,50 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_ffi_scan_assembler() {
+        let filter = r#"{"min_quality":10,"min_distance_mm":0.0,"max_distance_mm":5000.0,"drop_zero_distance":true}"#;
+        let mut assembler = ScanAssemblerFFI::new(filter).unwrap();
+        assert!(ScanAssemblerFFI::new("{}").is_err());
+
+        let points = |angles: &[f32]| {
+            serde_json::to_string(
+                &angles
+                    .iter()
+                    .map(|&angle_rad| mote_to_host::Point {
+                        quality: 20,
+                        angle_rad,
+                        distance_mm: 1000.0,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+        assembler.push(&points(&[6.0, 0.0, 2.0]), 10).unwrap();
+        assert!(assembler.poll_sweep().unwrap().is_none());
+        assembler.push(&points(&[4.0, 6.0, 0.1]), 20).unwrap();
+
+        let sweep: serde_json::Value =
+            serde_json::from_str(&assembler.poll_sweep().unwrap().unwrap()).unwrap();
+        assert_eq!(sweep["start_us"], 10);
+        assert_eq!(sweep["end_us"], 20);
+        assert_eq!(sweep["points"].as_array().unwrap().len(), 4);
+        assert!(assembler.poll_sweep().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ffi_unpack_scan() {
+        let packed = r#"{"timestamp_us":5,"encoding":"DeltaAngle","data":[30,128,1,160,15]}"#;
+        let scan: mote_to_host::Scan = serde_json::from_str(&unpack_scan(packed).unwrap()).unwrap();
+        assert_eq!(scan.timestamp_us, 5);
+        assert_eq!(scan.points.len(), 1);
+        assert_eq!(scan.points[0].quality, 30);
+        assert!((scan.points[0].angle_rad.to_degrees() - 1.0).abs() < 1e-4);
+        assert_eq!(scan.points[0].distance_mm, 1000.0);
+
+        // Truncated
+        let packed = r#"{"timestamp_us":5,"encoding":"Raw","data":[30,64]}"#;
+        assert!(unpack_scan(packed).is_err());
+    }
 }
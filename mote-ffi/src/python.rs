@@ -5,7 +5,7 @@ mod mote_ffi {
     use pyo3::{exceptions::PyIOError, prelude::*};
     use std::string::{String, ToString};
 
-    use crate::{Error, MoteCommsFFI};
+    use crate::{Error, MoteCommsFFI, ScanAssemblerFFI};
     use mote_api::{
         MoteLink,
         messages::{host_to_mote, mote_to_host},
@@ -17,9 +17,21 @@ mod mote_ffi {
         }
     }
 
+    /// Version of the wire protocol spoken by this library
+    #[pyfunction]
+    fn protocol_version() -> u16 {
+        mote_api::PROTOCOL_VERSION
+    }
+
+    /// Convert a PackedScan's points to radians and millimeters, returning the equivalent Scan
+    #[pyfunction]
+    fn unpack_scan(packed: String) -> Result<String, Error> {
+        crate::unpack_scan(&packed)
+    }
+
     #[pyclass]
     struct Link {
-        link: MoteCommsFFI<1400, mote_to_host::Message, host_to_mote::Message>,
+        link: MoteCommsFFI<1400, 5000, mote_to_host::Message, host_to_mote::Message>,
     }
 
     #[pymethods]
@@ -44,8 +56,44 @@ mod mote_ffi {
             self.link.handle_receive(&packet)
         }
 
+        fn handle_time(&mut self, now_ms: u64) {
+            self.link.handle_time(now_ms)
+        }
+
         fn poll_receive(&mut self) -> Result<Option<String>, Error> {
             self.link.poll_receive()
         }
+
+        fn stats(&self) -> Result<String, Error> {
+            self.link.stats()
+        }
+    }
+
+    /// Collects Scan points into whole revolutions, see mote_api::scan
+    #[pyclass]
+    struct ScanAssembler {
+        assembler: ScanAssemblerFFI,
+    }
+
+    #[pymethods]
+    impl ScanAssembler {
+        #[new]
+        fn new(filter: String) -> Result<Self, Error> {
+            Ok(Self {
+                assembler: ScanAssemblerFFI::new(&filter)?,
+            })
+        }
+
+        fn push(&mut self, points: String, time_us: u64) -> Result<(), Error> {
+            self.assembler.push(&points, time_us)
+        }
+
+        fn poll_sweep(&mut self) -> Result<Option<String>, Error> {
+            self.assembler.poll_sweep()
+        }
+
+        fn reset(&mut self) {
+            self.assembler.reset()
+        }
     }
 }
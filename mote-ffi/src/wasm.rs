@@ -55,6 +55,11 @@ impl Link {
         self.link.handle_receive(&bytes);
     }
 
+    /// Milliseconds since any fixed point, e.g. performance.now()
+    pub fn handle_time(&mut self, now_ms: f64) {
+        self.link.handle_time(now_ms as u64);
+    }
+
     pub fn poll_receive(&mut self) -> JsValue {
         let message: Result<Option<mote_to_host::Message>, _> = self.link.poll_receive();
         console_log!("[RX] Configuration link unpacked: {:?}", message);
@@ -1,5 +1,6 @@
 use defmt::error;
-use mote_api::messages::mote_to_host::{BITList, BITResult};
+use mote_api::device;
+use mote_api::messages::mote_to_host::{BITList, BITResult, HelloAck, capabilities};
 
 pub fn update_bit_result(collection: &mut BITList, name: &'static str, result: BITResult) {
     if let Some(bit) = collection.iter_mut().find(|i| i.name == name) {
@@ -8,3 +9,12 @@ pub fn update_bit_result(collection: &mut BITList, name: &'static str, result: B
         error!("Failed to update BIT result for {}", name);
     }
 }
+
+/// Answer to a host's version handshake
+pub fn hello_ack() -> HelloAck {
+    device::hello_ack(
+        env!("CARGO_PKG_VERSION"),
+        env!("MOTE_GIT_HASH"),
+        capabilities::LIDAR | capabilities::IMU | capabilities::DRIVE_BASE | capabilities::WIFI,
+    )
+}
@@ -1,16 +1,19 @@
-use defmt::{error, info};
+use defmt::{Debug2Format, error, info, warn};
 use embassy_executor::Spawner;
 use embassy_futures::select::select4;
 use embassy_rp::pio::{Instance, Pio};
 use embassy_rp::pwm::SetDutyCycle;
 use embassy_rp::{gpio, pwm};
+use embassy_sync::channel::TrySendError;
 use embassy_time::{Duration, Instant, Ticker, Timer};
-use mote_api::messages::mote_to_host::{DriveBaseState, Message, WheelJointState};
+use mote_api::messages::host_to_mote::{ConfigureTelemetry, MoveWheels, SetDriveBaseVelocity};
+use mote_api::messages::mote_to_host::{DriveBaseState, Message, MoveFinished, MoveOutcome, WheelJointState};
+use mote_api::motion::{SETTLE_TIMEOUT_S, WheelMove};
 use pid::Pid;
 
 use crate::tasks::drive_base::encoder::PioEncoder;
 use crate::tasks::drive_base::hbridge::PwmBridge;
-use crate::tasks::wifi::{DATA_OFFLOAD_CHANNEL, MOTOR_COMMAND_CHANNEL};
+use crate::tasks::wifi::{DATA_OFFLOAD_CHANNEL, MOTOR_COMMAND_CHANNEL, TELEMETRY_CONFIG_WATCH};
 use crate::tasks::{
     DRV8833Resources, EncoderDriverResources, Irqs, LeftEncoderResources, RightEncoderResources, power_gate,
 };
@@ -30,11 +33,31 @@ const MOTOR_DEADBAND_PERCENT: u8 = 52;
 const CONTROL_DEADBAND_PERCENT: f32 = 2.;
 /// ms per iteration of the PID control loop.
 const PID_CONTROL_LOOP_PERIOD_MS: u64 = 20;
-/// ms per joint state telemetry value.
-const TELEMETRY_LOOP_PERIOD_MS: u64 = 100;
 /// Seconds of not receiving a command before deactivating the drive base.
 const WATCH_DOG_TIMEOUT: u64 = 1;
 
+/// Commands for the drive base from the host
+pub enum DriveBaseCommand {
+    /// Drive at a velocity until the next command, or the watchdog stops the drive base
+    Velocity(SetDriveBaseVelocity),
+    /// Turn the wheels through an angle, see mote_api::motion
+    Move(MoveWheels),
+}
+
+/// A move the drive base is carrying out
+struct ActiveMove {
+    wheel_move: WheelMove,
+    started_at: Instant,
+}
+
+/// Ticks every `period_ms`, as requested by the host, but no faster than the joint states are
+/// updated
+fn new_telemetry_ticker(period_ms: u32) -> Ticker {
+    Ticker::every(Duration::from_millis(
+        (period_ms as u64).max(PID_CONTROL_LOOP_PERIOD_MS),
+    ))
+}
+
 /// Convert encoder pulses into radians
 fn encoder_pulses_to_rad(pulses: i32) -> f32 {
     (pulses as f32 / ENCODER_PULSES_PER_ROTATION as f32) * 2. * core::f32::consts::PI
@@ -68,6 +91,11 @@ impl<'d, T: SetDutyCycle, P: Instance, const SM: usize> Motor<'d, T, P, SM> {
         }
     }
 
+    /// Position in radians, as of the last PID step
+    fn position_rad(&self) -> f32 {
+        self.joint_state.postition_rad
+    }
+
     /// Set the target velocity in radians/second
     fn set_setpoint_rad_per_s(&mut self, setpoint: f32) {
         self.pid.setpoint(setpoint);
@@ -182,9 +210,17 @@ async fn motor_task(
     let mut sleep = gpio::Output::new(motor_driver_r.sleep, gpio::Level::High);
 
     // PID, telem and watchdog timers
+    let mut telemetry_config = TELEMETRY_CONFIG_WATCH.receiver().unwrap();
+    let mut telemetry = ConfigureTelemetry::default().drive_base;
     let mut pid_ticker = Ticker::every(Duration::from_millis(PID_CONTROL_LOOP_PERIOD_MS));
-    let mut telemetry_ticker = Ticker::every(Duration::from_millis(TELEMETRY_LOOP_PERIOD_MS));
+    let mut telemetry_ticker = new_telemetry_ticker(telemetry.period_ms);
     let mut watchdog_deadline = Instant::now() + Duration::from_secs(WATCH_DOG_TIMEOUT);
+    // When the joint states were last updated
+    let mut sampled_at = Instant::now();
+    // The move being carried out, which the watchdog only interrupts once it should have ended
+    let mut active_move: Option<ActiveMove> = None;
+    // Report of a finished move still waiting for room in DATA_OFFLOAD_CHANNEL
+    let mut unsent_report: Option<MoveFinished> = None;
 
     // Motors start with 0 velocity
     left_motor.set_setpoint_rad_per_s(0.0);
@@ -203,16 +239,59 @@ async fn motor_task(
                 // Run PID update
                 left_motor.step(PID_CONTROL_LOOP_PERIOD_MS).await;
                 right_motor.step(PID_CONTROL_LOOP_PERIOD_MS).await;
+                sampled_at = Instant::now();
+                retry_move_report(&mut unsent_report);
+
+                // Follow the move's profile, finishing it once the wheels arrive or give up
+                if let Some(current) = &active_move {
+                    let elapsed_s = (sampled_at - current.started_at).as_micros() as f32 / 1e6;
+                    let (left, right) = (left_motor.position_rad(), right_motor.position_rad());
+                    if let Some(outcome) = current.wheel_move.outcome(elapsed_s, left, right) {
+                        info!("Move {} finished: {}", current.wheel_move.id(), Debug2Format(&outcome));
+                        let report = current
+                            .wheel_move
+                            .finished(outcome, sampled_at.as_micros(), left, right);
+                        report_move(&mut unsent_report, report);
+                        active_move = None;
+                        left_motor.set_setpoint_rad_per_s(0.0);
+                        right_motor.set_setpoint_rad_per_s(0.0);
+                        watchdog_deadline = Instant::now() + Duration::from_secs(WATCH_DOG_TIMEOUT);
+                    } else {
+                        let (left_setpoint, right_setpoint) = current.wheel_move.setpoints(elapsed_s, left, right);
+                        left_motor.set_setpoint_rad_per_s(left_setpoint);
+                        right_motor.set_setpoint_rad_per_s(right_setpoint);
+                    }
+                }
+
+                // Pick up telemetry settings from the host
+                if let Some(config) = telemetry_config.try_changed() {
+                    if config.drive_base.period_ms != telemetry.period_ms {
+                        telemetry_ticker = new_telemetry_ticker(config.drive_base.period_ms);
+                    }
+                    telemetry = config.drive_base;
+                }
             }
             embassy_futures::select::Either4::Second(_) => {
                 // Send a value to the data offload link
-                let _ = DATA_OFFLOAD_CHANNEL.try_send(Message::DriveBaseState(DriveBaseState {
-                    left: left_motor.joint_state.clone(),
-                    right: right_motor.joint_state.clone(),
-                }));
+                if telemetry.enabled {
+                    let _ = DATA_OFFLOAD_CHANNEL.try_send(Message::DriveBaseState(DriveBaseState {
+                        timestamp_us: sampled_at.as_micros(),
+                        left: left_motor.joint_state.clone(),
+                        right: right_motor.joint_state.clone(),
+                    }));
+                }
             }
             embassy_futures::select::Either4::Third(_) => {
                 // Watchdog timeout, stop the motors and sleep the drive base
+                if let Some(current) = active_move.take() {
+                    let (left, right) = (left_motor.position_rad(), right_motor.position_rad());
+                    error!("Move {} overran, stopping", current.wheel_move.id());
+                    let outcome = MoveOutcome::TimedOut;
+                    let report = current
+                        .wheel_move
+                        .finished(outcome, Instant::now().as_micros(), left, right);
+                    report_move(&mut unsent_report, report);
+                }
                 sleep.set_low();
                 left_motor.set_setpoint_rad_per_s(0.0);
                 right_motor.set_setpoint_rad_per_s(0.0);
@@ -220,17 +299,75 @@ async fn motor_task(
                 watchdog_deadline = Instant::now() + Duration::from_secs(10000);
             }
             embassy_futures::select::Either4::Fourth(command) => {
-                // Command received, feed the watchdog
-                watchdog_deadline = Instant::now() + Duration::from_secs(WATCH_DOG_TIMEOUT);
+                let (left, right) = (left_motor.position_rad(), right_motor.position_rad());
+                // Any command replaces the move in progress
+                if let Some(current) = active_move.take() {
+                    let outcome = MoveOutcome::Interrupted;
+                    let report = current
+                        .wheel_move
+                        .finished(outcome, Instant::now().as_micros(), left, right);
+                    report_move(&mut unsent_report, report);
+                }
                 // Handle the command
                 sleep.set_high();
-                left_motor.set_setpoint_rad_per_s(command.left_velocity_rad);
-                right_motor.set_setpoint_rad_per_s(command.right_velocity_rad);
+                match command {
+                    DriveBaseCommand::Velocity(command) => {
+                        // Command received, feed the watchdog
+                        watchdog_deadline = Instant::now() + Duration::from_secs(WATCH_DOG_TIMEOUT);
+                        left_motor.set_setpoint_rad_per_s(command.left_velocity_rad);
+                        right_motor.set_setpoint_rad_per_s(command.right_velocity_rad);
+                    }
+                    DriveBaseCommand::Move(command) => {
+                        // Validated by the UDP server
+                        let Some(wheel_move) = WheelMove::new(&command, left, right) else {
+                            error!("Invalid move {} reached the drive base", command.id);
+                            left_motor.set_setpoint_rad_per_s(0.0);
+                            right_motor.set_setpoint_rad_per_s(0.0);
+                            continue;
+                        };
+                        info!("Starting move {}", command.id);
+                        // Moves end on their own, but stop them if they overrun
+                        let limit_s = wheel_move.profile().duration_s() + SETTLE_TIMEOUT_S;
+                        watchdog_deadline = Instant::now() + Duration::from_micros((limit_s * 1e6) as u64);
+                        active_move = Some(ActiveMove {
+                            wheel_move,
+                            started_at: Instant::now(),
+                        });
+                    }
+                }
             }
         }
     }
 }
 
+/// Report a finished move to the host without blocking
+///
+/// The UDP server may be waiting to hand this task a command rather than draining
+/// DATA_OFFLOAD_CHANNEL, so waiting for room here could deadlock both tasks. A report which doesn't
+/// fit is kept in `unsent` and retried every PID tick.
+fn report_move(unsent: &mut Option<MoveFinished>, report: MoveFinished) {
+    retry_move_report(unsent);
+    if let Some(dropped) = unsent.take() {
+        warn!("Dropping the report of move {}, the host link is backed up", dropped.id);
+    }
+    *unsent = try_send_move_report(report);
+}
+
+/// Retry a report which didn't fit in DATA_OFFLOAD_CHANNEL, see report_move
+fn retry_move_report(unsent: &mut Option<MoveFinished>) {
+    if let Some(report) = unsent.take() {
+        *unsent = try_send_move_report(report);
+    }
+}
+
+/// Returns the report if DATA_OFFLOAD_CHANNEL is full
+fn try_send_move_report(report: MoveFinished) -> Option<MoveFinished> {
+    match DATA_OFFLOAD_CHANNEL.try_send(Message::MoveFinished(report)) {
+        Err(TrySendError::Full(Message::MoveFinished(report))) => Some(report),
+        _ => None,
+    }
+}
+
 pub async fn init(
     spawner: Spawner,
     motor_driver_r: DRV8833Resources,
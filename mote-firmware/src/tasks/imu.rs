@@ -3,25 +3,29 @@ pub mod regs;
 use embassy_executor::Spawner;
 use embassy_rp::i2c::{Config, I2c};
 use embassy_rp::peripherals::I2C1;
+use embassy_time::Instant;
 pub use lib::Lsm6ds3TRC;
-use mote_api::messages::mote_to_host;
 use mote_api::messages::mote_to_host::{BIT, BITResult, IMUAxisTriple, IMUMeasurement};
+use mote_api::messages::{host_to_mote, mote_to_host};
 
 use super::{ImuResources, Irqs};
 use crate::helpers::update_bit_result;
 use crate::tasks::CONFIGURATION_STATE;
-use crate::wifi::DATA_OFFLOAD_CHANNEL;
+use crate::wifi::{DATA_OFFLOAD_CHANNEL, TELEMETRY_CONFIG_WATCH};
 
 // NUMBER OF MISSED IMU READS IN A ROW BEFORE WE FLAG A BIT FAILURE
 const MISSED_READ_THRESHOLD: u8 = 10;
 const INVALID_TEMPERATURE: f32 = 25.0; // value returned by get_sensor_data on read failure 
 // (MUST be 25.0 since imu.read_all() may not return an error but just return 0
 // for all values, in this case the temp is read as 25)
+/// ms between reads at the fastest, the IMU's output data rate is 104Hz
+const MIN_READ_PERIOD_MS: u32 = 10;
 
 // returns temperature and (accel, gyro) IMU measurement
 pub async fn get_sensor_data(
     imu: &mut Lsm6ds3TRC<I2c<'static, I2C1, embassy_rp::i2c::Async>>,
 ) -> (f32, IMUMeasurement) {
+    let timestamp_us = Instant::now().as_micros();
     match imu.read_all().await {
         Ok((temperature, gyro_tuple, accel_tuple)) => {
             // Map the accelerometer tuple (f32, f32, f32) to IMUAxisTriple
@@ -39,13 +43,21 @@ pub async fn get_sensor_data(
             };
 
             // Return the temperature and the combined measurement
-            (temperature, IMUMeasurement { accel, gyro })
+            (
+                temperature,
+                IMUMeasurement {
+                    timestamp_us,
+                    accel,
+                    gyro,
+                },
+            )
         }
         Err(_) => {
             // Default error case
             (
                 INVALID_TEMPERATURE, // invalid temperature to indicate error
                 IMUMeasurement {
+                    timestamp_us,
                     accel: IMUAxisTriple { x: 0.0, y: 0.0, z: 0.0 },
                     gyro: IMUAxisTriple { x: 0.0, y: 0.0, z: 0.0 },
                 },
@@ -59,11 +71,20 @@ async fn imu_task(r: ImuResources) {
     let i2c = I2c::new_async(r.i2c, r.scl, r.sda, Irqs, Config::default());
     let mut imu = reset_imu(i2c).await;
     let mut missed_read_count: u8 = 0;
+    let mut telemetry_config = TELEMETRY_CONFIG_WATCH.receiver().unwrap();
+    let mut telemetry = host_to_mote::ConfigureTelemetry::default().imu;
 
     // Sensor Reading loop
     loop {
+        if let Some(config) = telemetry_config.try_changed() {
+            telemetry = config.imu;
+        }
+
+        // Keep reading while the stream is disabled, so the BIT still reflects the IMU's health
         let (temp, measurement) = get_sensor_data(&mut imu).await;
-        let _ = DATA_OFFLOAD_CHANNEL.try_send(mote_to_host::Message::IMUMeasurement(measurement));
+        if telemetry.enabled {
+            let _ = DATA_OFFLOAD_CHANNEL.try_send(mote_to_host::Message::IMUMeasurement(measurement));
+        }
 
         // get sensor data errored, update BIT and log, and missed read count
         if temp == INVALID_TEMPERATURE {
@@ -93,7 +114,7 @@ async fn imu_task(r: ImuResources) {
             missed_read_count = 0; // reset missed read count on successful read
         }
 
-        embassy_time::Timer::after_millis(20).await;
+        embassy_time::Timer::after_millis(telemetry.period_ms.max(MIN_READ_PERIOD_MS) as u64).await;
     }
 }
 
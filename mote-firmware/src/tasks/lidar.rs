@@ -3,25 +3,27 @@ mod rp_c1_driver;
 use defmt::info;
 use embassy_executor::Spawner;
 use embassy_rp::uart::{BufferedUart, Config, DataBits, Parity, StopBits};
-use mote_api::messages::mote_to_host;
-use mote_api::messages::mote_to_host::{BIT, BITResult};
+use embassy_time::Instant;
+use mote_api::messages::mote_to_host::{BIT, BITResult, PackedScan, PointEncoding};
+use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::packed_scan::RawPoint;
 use static_cell::StaticCell;
 
 use super::{Irqs, RplidarC1Resources};
 use crate::helpers::update_bit_result;
 use crate::tasks::lidar::rp_c1_driver::{LidarState, Point, RPLidarC1};
 use crate::tasks::{CONFIGURATION_STATE, power_gate};
-use crate::wifi::DATA_OFFLOAD_CHANNEL;
+use crate::wifi::{DATA_OFFLOAD_CHANNEL, TELEMETRY_CONFIG_WATCH};
 
 const MAX_POINTS_PER_SCAN_MESSAGE: usize = 100;
 
-impl From<Point> for mote_to_host::Point {
+impl From<Point> for RawPoint {
     fn from(value: Point) -> Self {
-        mote_to_host::Point {
+        // Sent in the lidar's own units, the host converts them
+        RawPoint {
             quality: value.quality,
-            // Feels expensive, but the RP2354 has a FPU so probably fine
-            angle_rad: (value.angle as f32 / 64.0).to_radians(),
-            distance_mm: value.distance as f32 / 4.0,
+            angle: value.angle,
+            distance: value.distance,
         }
     }
 }
@@ -64,6 +66,9 @@ async fn lidar_state_machine_task(r: RplidarC1Resources) {
 
     let mut point_buf: [rp_c1_driver::Point; MAX_POINTS_PER_SCAN_MESSAGE] = [rp_c1_driver::Point::default(); _];
     let mut valid_points = 0;
+    let mut sampled_at = Instant::now();
+    let mut telemetry_config = TELEMETRY_CONFIG_WATCH.receiver().unwrap();
+    let mut telemetry = host_to_mote::ConfigureTelemetry::default().scan;
 
     let mut driver = RPLidarC1::new(uart);
 
@@ -104,6 +109,7 @@ async fn lidar_state_machine_task(r: RplidarC1Resources) {
                             LidarState::CheckHealth
                         } else {
                             valid_points = count;
+                            sampled_at = Instant::now();
                             LidarState::ProcessSample
                         }
                     }
@@ -114,11 +120,21 @@ async fn lidar_state_machine_task(r: RplidarC1Resources) {
                 }
             }
             LidarState::ProcessSample => {
+                if let Some(config) = telemetry_config.try_changed() {
+                    telemetry = config.scan;
+                }
                 // We don't care if these packets get lost, so don't block if the channel is
                 // full
-                let _ = DATA_OFFLOAD_CHANNEL.try_send(mote_to_host::Message::Scan(
-                    point_buf[..valid_points].iter().map(|&point| point.into()).collect(),
-                ));
+                if telemetry.enabled {
+                    let decimation = telemetry.decimation.max(1) as usize;
+                    let points = point_buf[..valid_points].iter().step_by(decimation);
+                    let scan = PackedScan::new(
+                        sampled_at.as_micros(),
+                        PointEncoding::DeltaAngle,
+                        points.map(|&point| point.into()),
+                    );
+                    let _ = DATA_OFFLOAD_CHANNEL.try_send(mote_to_host::Message::PackedScan(scan));
+                }
 
                 LidarState::ReceiveSample
             }
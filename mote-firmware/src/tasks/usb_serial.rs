@@ -1,20 +1,23 @@
 use alloc::boxed::Box;
 
-use defmt::{info, trace};
+use defmt::{Display2Format, info, trace, warn};
 use embassy_executor::Spawner;
 use embassy_futures::select::{Either, select};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver as UsbDriver, Instance as UsbInstance};
-use embassy_time::{Duration, Ticker, with_timeout};
+use embassy_time::{Duration, Instant, Ticker, with_timeout};
 use embassy_usb::UsbDevice;
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
 use embassy_usb::driver::EndpointError;
-use mote_api::HostConfigLink;
+use mote_api::device::{reply, unwrap_request};
+use mote_api::messages::mote_to_host::NackReason;
 use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::{PROTOCOL_VERSION, ProtocolMessage, StaticHostConfigLink};
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
 use super::{Irqs, UsbSerialResources};
+use crate::helpers::hello_ack;
 use crate::tasks::CONFIGURATION_STATE;
 use crate::tasks::flash_manager::{FLASH_SAVE_CHANNEL, FlashSaveRequest};
 use crate::tasks::wifi::connection_manager::{WIFI_REQUEST_CONNECT, WIFI_REQUEST_RESCAN};
@@ -35,10 +38,29 @@ impl From<EndpointError> for Disconnected {
     }
 }
 
-async fn handle_host_message(msg: host_to_mote::Message) {
+async fn handle_host_message(msg: host_to_mote::Message, link: &mut StaticHostConfigLink) {
+    let reliable = msg.reliable();
+    let (id, command) = unwrap_request(msg);
+    let outcome = execute_host_message(command).await;
+    if let Some(answer) = reply(id, outcome) {
+        // Answer reliable requests reliably, see udp_server
+        let queued = if reliable {
+            link.send_reliable(answer)
+        } else {
+            link.send(answer)
+        };
+        if let Err(err) = queued {
+            warn!("Failed to queue reply: {}", Display2Format(&err));
+        }
+    }
+}
+
+/// Carry out a configuration command from the host, returning its reply if it has one
+async fn execute_host_message(msg: host_to_mote::Message) -> Result<Option<mote_to_host::Message>, NackReason> {
     match msg {
         host_to_mote::Message::SetNetworkConnectionConfig(set_network_connection_config) => {
             WIFI_REQUEST_CONNECT.send(set_network_connection_config).await;
+            Ok(None)
         }
         host_to_mote::Message::SetUID(set_uid) => {
             CONFIGURATION_STATE.lock().await.uid = set_uid.uid.clone();
@@ -46,12 +68,30 @@ async fn handle_host_message(msg: host_to_mote::Message) {
                 .send(FlashSaveRequest::Uid(set_uid.uid.clone()))
                 .await;
             info!("Set UID: {}", set_uid.uid.as_str());
+            Ok(None)
         }
         host_to_mote::Message::RequestNetworkScan => {
             WIFI_REQUEST_RESCAN.signal(());
             info!("Requesting network scan");
+            Ok(None)
+        }
+        host_to_mote::Message::Hello(hello) => {
+            if hello.protocol_version != PROTOCOL_VERSION {
+                warn!(
+                    "Host speaks protocol version {}, but firmware speaks {}",
+                    hello.protocol_version, PROTOCOL_VERSION
+                );
+            }
+            Ok(Some(mote_to_host::Message::HelloAck(hello_ack())))
+        }
+        host_to_mote::Message::Request(_) => {
+            warn!("Received a nested request");
+            Err(NackReason::InvalidRequest)
+        }
+        _ => {
+            warn!("Received message not handled over USB serial");
+            Err(NackReason::Unsupported)
         }
-        _ => todo!(),
     }
 }
 
@@ -65,17 +105,18 @@ async fn handle_serial<'d, T: UsbInstance + 'd>(
     let mut ticker = Ticker::every(Duration::from_millis(500));
 
     // Link to the host
-    let mut link = HostConfigLink::new();
+    let mut link = StaticHostConfigLink::new();
 
     loop {
         match select(class.read_packet(&mut serial_buffer), ticker.next()).await {
             Either::First(Ok(bytes_read)) => {
+                link.handle_time(Instant::now().as_millis());
                 link.handle_receive(&serial_buffer[..bytes_read]);
 
                 trace!("USB Serial got: {:x}", serial_buffer[..bytes_read]);
 
                 while let Ok(Some(message)) = link.poll_receive() {
-                    handle_host_message(message).await;
+                    handle_host_message(message, &mut link).await;
                 }
                 Ok(())
             }
@@ -86,7 +127,9 @@ async fn handle_serial<'d, T: UsbInstance + 'd>(
                 {
                     let message = mote_to_host::Message::State(Box::new(configuration_state.clone()));
 
-                    link.send(message).unwrap();
+                    if let Err(err) = link.send(message) {
+                        warn!("Dropping state telemetry: {}", Display2Format(&err));
+                    }
                 }
                 Ok(())
             }
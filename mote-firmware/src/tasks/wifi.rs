@@ -14,6 +14,7 @@ use embassy_rp::peripherals::PIO0;
 use embassy_rp::pio::Pio;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
+use embassy_sync::watch::Watch;
 use mote_api::messages::mote_to_host::{BIT, BITResult};
 use mote_api::messages::{host_to_mote, mote_to_host};
 use static_cell::StaticCell;
@@ -22,10 +23,13 @@ use {defmt_rtt as _, panic_probe as _};
 use super::{Cyw43Resources, Irqs};
 use crate::helpers::update_bit_result;
 use crate::tasks::CONFIGURATION_STATE;
+use crate::tasks::drive_base::DriveBaseCommand;
 
 pub static DATA_OFFLOAD_CHANNEL: Channel<CriticalSectionRawMutex, mote_to_host::Message, 32> = Channel::new();
-pub static MOTOR_COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, host_to_mote::SetDriveBaseVelocity, 5> =
-    Channel::new();
+pub static MOTOR_COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, DriveBaseCommand, 5> = Channel::new();
+/// Telemetry settings from the host, watched by the lidar, IMU and drive base tasks. Until the host
+/// sends any they use ConfigureTelemetry::default().
+pub static TELEMETRY_CONFIG_WATCH: Watch<CriticalSectionRawMutex, host_to_mote::ConfigureTelemetry, 3> = Watch::new();
 
 #[embassy_executor::task]
 async fn cyw43_task(runner: cyw43::Runner<'static, SpiBus<Output<'static>, PioSpi<'static, PIO0, 0>>>) -> ! {
@@ -1,31 +1,94 @@
-use defmt::{error, info, warn};
+use defmt::{Debug2Format, Display2Format, error, info, warn};
 use embassy_futures::select::{Either, select};
 use embassy_net::Stack;
 use embassy_net::udp::{PacketMetadata, UdpMetadata, UdpSocket};
-use mote_api::HostLink;
-use mote_api::messages::mote_to_host::BITResult;
+use embassy_time::Instant;
+use mote_api::device::{reply, unwrap_request};
+use mote_api::messages::mote_to_host::{BITResult, NackReason};
 use mote_api::messages::{host_to_mote, mote_to_host};
+use mote_api::{PROTOCOL_VERSION, ProtocolMessage, StaticHostLink};
 
-use crate::helpers::update_bit_result;
+use crate::helpers::{hello_ack, update_bit_result};
 use crate::tasks::CONFIGURATION_STATE;
-use crate::tasks::wifi::{DATA_OFFLOAD_CHANNEL, MOTOR_COMMAND_CHANNEL};
+use crate::tasks::drive_base::DriveBaseCommand;
+use crate::tasks::wifi::{DATA_OFFLOAD_CHANNEL, MOTOR_COMMAND_CHANNEL, TELEMETRY_CONFIG_WATCH};
 
 pub const UDP_SERVER_PORT: u16 = 7475;
 
-async fn handle_command(rx_message: host_to_mote::Message, link: &mut HostLink) {
-    match rx_message {
+async fn handle_command(rx_message: host_to_mote::Message, link: &mut StaticHostLink, received_at: Instant) {
+    let reliable = rx_message.reliable();
+    let (id, command) = unwrap_request(rx_message);
+    let outcome = execute_command(command, received_at).await;
+    if let Some(answer) = reply(id, outcome) {
+        // The host's retransmission of a reliable request whose answer was lost is suppressed as a
+        // duplicate, so answer it reliably or it may never be answered
+        let queued = if reliable {
+            link.send_reliable(answer)
+        } else {
+            link.send(answer)
+        };
+        if let Err(err) = queued {
+            warn!("Failed to queue reply: {}", Display2Format(&err));
+        }
+    }
+}
+
+/// Carry out a command from the host, returning its reply if it has one. `received_at` is when the
+/// packet carrying it arrived.
+async fn execute_command(
+    command: host_to_mote::Message,
+    received_at: Instant,
+) -> Result<Option<mote_to_host::Message>, NackReason> {
+    match command {
         host_to_mote::Message::Ping => {
             info!("Parsed ping request, responding.");
-            let _ = link.send(mote_to_host::Message::Pong);
+            Ok(Some(mote_to_host::Message::Pong))
         }
         host_to_mote::Message::Pong => {
             info!("Received ping response from host.");
+            Ok(None)
         }
         host_to_mote::Message::DriveBaseCommand(cmd) => {
-            MOTOR_COMMAND_CHANNEL.send(cmd).await;
+            MOTOR_COMMAND_CHANNEL.send(DriveBaseCommand::Velocity(cmd)).await;
+            Ok(None)
+        }
+        host_to_mote::Message::MoveWheels(command) => {
+            if command.profile().is_none() {
+                warn!("Refusing move {} with invalid limits or taking too long", command.id);
+                return Err(NackReason::InvalidArgument);
+            }
+            MOTOR_COMMAND_CHANNEL.send(DriveBaseCommand::Move(command)).await;
+            Ok(None)
+        }
+        host_to_mote::Message::Hello(hello) => {
+            if hello.protocol_version != PROTOCOL_VERSION {
+                warn!(
+                    "Host speaks protocol version {}, but firmware speaks {}",
+                    hello.protocol_version, PROTOCOL_VERSION
+                );
+            }
+            Ok(Some(mote_to_host::Message::HelloAck(hello_ack())))
+        }
+        host_to_mote::Message::Request(_) => {
+            error!("Received a nested request");
+            Err(NackReason::InvalidRequest)
+        }
+        host_to_mote::Message::ConfigureTelemetry(config) => {
+            info!("Telemetry configured: {}", Debug2Format(&config));
+            TELEMETRY_CONFIG_WATCH.sender().send(config);
+            Ok(None)
+        }
+        host_to_mote::Message::TimeSyncRequest(request) => {
+            let reply = mote_to_host::TimeSyncReply {
+                host_t0: request.host_t0,
+                device_t1: received_at.as_micros(),
+                device_t2: Instant::now().as_micros(),
+            };
+            Ok(Some(mote_to_host::Message::TimeSyncReply(reply)))
         }
         _ => {
             error!("Received unhandled message type");
+            Err(NackReason::Unsupported)
         }
     }
 }
@@ -46,13 +109,14 @@ pub async fn udp_server_task(stack: Stack<'static>) -> ! {
         warn!("bind error: {:?}", e);
     }
 
-    let mut link = HostLink::new();
+    let mut link = StaticHostLink::new();
     let mut message_buffer = [0; 4096];
     let mut client: Option<UdpMetadata> = None;
 
     loop {
         match select(socket.recv_from(&mut message_buffer), DATA_OFFLOAD_CHANNEL.receive()).await {
             Either::First(Ok((bytes_read, ep))) => {
+                let received_at = Instant::now();
                 let new_client = match client {
                     None => {
                         info!("Client connected: {}", ep);
@@ -74,9 +138,10 @@ pub async fn udp_server_task(stack: Stack<'static>) -> ! {
                     client = Some(ep);
                 }
 
+                link.handle_time(received_at.as_millis());
                 link.handle_receive(&message_buffer[..bytes_read]);
                 while let Ok(Some(message)) = link.poll_receive() {
-                    handle_command(message, &mut link).await;
+                    handle_command(message, &mut link, received_at).await;
                 }
 
                 while let Some(payload) = link.poll_transmit() {
@@ -90,7 +155,11 @@ pub async fn udp_server_task(stack: Stack<'static>) -> ! {
             }
             Either::Second(message) => {
                 if let Some(ep) = client {
-                    link.send(message).unwrap();
+                    // Telemetry flows steadily, so this also times retransmission of reliable messages
+                    link.handle_time(Instant::now().as_millis());
+                    if let Err(err) = link.send(message) {
+                        warn!("Dropping telemetry: {}", Display2Format(&err));
+                    }
 
                     while let Some(payload) = link.poll_transmit() {
                         if let Err(err) = socket.send_to(&payload, ep).await {
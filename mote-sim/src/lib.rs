@@ -0,0 +1,604 @@
+//! Software-in-the-loop simulator for Mote
+//!
+//! Simulator stands in for a Mote on the network: it serves the Mote API over UDP with a HostLink,
+//! driving it just like the firmware's UDP server, so clients can't tell it from real hardware.
+//! Drive base commands move a simulated robot around a 2D map, and its drive base, IMU and lidar
+//! stream telemetry at the firmware's rates.
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use mote_api::device::{hello_ack, reply, unwrap_request};
+use mote_api::messages::host_to_mote::{self, ConfigureTelemetry};
+use mote_api::messages::mote_to_host::{self, NackReason, PackedScan, PointEncoding, capabilities};
+use mote_api::packed_scan::RawPoint;
+use mote_api::{HostLink, ProtocolMessage};
+use thiserror::Error;
+
+pub mod map;
+pub mod robot;
+
+use crate::map::Map;
+use crate::robot::{Robot, RobotConfig};
+
+/// UDP port Mote listens on
+pub const UDP_PORT: u16 = 7475;
+
+/// How long step waits for a packet from the host
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// How fast the firmware samples each kind of telemetry. The host picks how often it is sent, see
+// ConfigureTelemetry.
+const MIN_DRIVE_BASE_STATE_INTERVAL: Duration = Duration::from_millis(20);
+const MIN_IMU_INTERVAL: Duration = Duration::from_millis(10);
+const SCAN_INTERVAL: Duration = Duration::from_millis(20);
+/// Points per scan message, like the firmware's lidar task
+const POINTS_PER_SCAN_MESSAGE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// When a kind of telemetry is next due
+struct Schedule {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Schedule {
+    fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            next: now,
+        }
+    }
+
+    /// Whether it is due at `now`, scheduling the next one if so. Missed ones are skipped rather
+    /// than sent in a burst.
+    fn due(&mut self, now: Instant) -> bool {
+        if now < self.next {
+            return false;
+        }
+        self.next = (self.next + self.interval).max(now);
+        true
+    }
+}
+
+/// A simulated Mote serving the Mote API, see the crate documentation
+pub struct Simulator {
+    socket: UdpSocket,
+    link: HostLink,
+    /// Where telemetry goes, the last host we heard from
+    client: Option<SocketAddr>,
+    map: Map,
+    robot: Robot,
+    start: Instant,
+    last_step: Instant,
+    telemetry: ConfigureTelemetry,
+    drive_base_state: Schedule,
+    imu: Schedule,
+    scan: Schedule,
+    buffer: Vec<u8>,
+}
+
+impl Simulator {
+    /// Serve on `address`, with a robot starting at the map's start cell
+    pub fn bind(address: impl ToSocketAddrs, map: Map, config: RobotConfig) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))?;
+        let now = Instant::now();
+        let mut simulator = Self {
+            socket,
+            link: HostLink::new(),
+            client: None,
+            robot: Robot::new(config, map.start()),
+            map,
+            start: now,
+            last_step: now,
+            telemetry: ConfigureTelemetry::default(),
+            drive_base_state: Schedule::new(MIN_DRIVE_BASE_STATE_INTERVAL, now),
+            imu: Schedule::new(MIN_IMU_INTERVAL, now),
+            scan: Schedule::new(SCAN_INTERVAL, now),
+            buffer: vec![0; 4096],
+        };
+        simulator.configure_telemetry(ConfigureTelemetry::default());
+        Ok(simulator)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// The host telemetry is sent to, if one has connected
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.client
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn robot(&self) -> &Robot {
+        &self.robot
+    }
+
+    /// Which telemetry is sent, and how often, as last configured by the host
+    pub fn telemetry(&self) -> &ConfigureTelemetry {
+        &self.telemetry
+    }
+
+    fn configure_telemetry(&mut self, telemetry: ConfigureTelemetry) {
+        let period = |period_ms| Duration::from_millis(period_ms as u64);
+        self.drive_base_state.interval =
+            period(telemetry.drive_base.period_ms).max(MIN_DRIVE_BASE_STATE_INTERVAL);
+        self.imu.interval = period(telemetry.imu.period_ms).max(MIN_IMU_INTERVAL);
+        self.telemetry = telemetry;
+    }
+
+    /// Handle a packet from the host if one arrives shortly, advance the simulation, and send any
+    /// telemetry that is due
+    pub fn step(&mut self) -> Result<(), Error> {
+        match self.socket.recv_from(&mut self.buffer) {
+            Ok((length, from)) => self.handle_packet(length, from)?,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            // On some platforms, an unreachable host shows up as an error on the next receive
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let now = Instant::now();
+        if let Some(finished) = self.robot.step(now - self.last_step, &self.map) {
+            self.send_move_finished(finished);
+        }
+        self.last_step = now;
+
+        let Some(client) = self.client else {
+            return Ok(());
+        };
+        // Telemetry flows steadily, so this also times retransmission of reliable messages, as on
+        // Mote. The host may be silent while it waits for one, e.g. a finished move.
+        self.link.handle_time((now - self.start).as_millis() as u64);
+        if self.drive_base_state.due(now) && self.telemetry.drive_base.enabled {
+            self.send_telemetry(mote_to_host::Message::DriveBaseState(
+                self.robot.drive_base_state(),
+            ));
+        }
+        if self.imu.due(now) && self.telemetry.imu.enabled {
+            self.send_telemetry(mote_to_host::Message::IMUMeasurement(
+                self.robot.imu_measurement(),
+            ));
+        }
+        // The lidar keeps spinning while its stream is disabled
+        if self.scan.due(now) {
+            let points = self.robot.scan(POINTS_PER_SCAN_MESSAGE, &self.map);
+            if self.telemetry.scan.enabled {
+                let decimation = self.telemetry.scan.decimation.max(1) as usize;
+                // Packed in the lidar's units, like the firmware sends them
+                let scan = PackedScan::new(
+                    self.robot.uptime().as_micros() as u64,
+                    PointEncoding::DeltaAngle,
+                    points.iter().step_by(decimation).map(RawPoint::from),
+                );
+                self.send_telemetry(mote_to_host::Message::PackedScan(scan));
+            }
+        }
+        self.flush(client)
+    }
+
+    /// Run until an error occurs
+    pub fn run(&mut self) -> Result<(), Error> {
+        loop {
+            self.step()?;
+        }
+    }
+
+    fn handle_packet(&mut self, length: usize, from: SocketAddr) -> Result<(), Error> {
+        // Telemetry follows whichever host spoke last
+        self.client = Some(from);
+
+        self.link
+            .handle_time(self.start.elapsed().as_millis() as u64);
+        self.link.handle_receive(&self.buffer[..length]);
+        while let Ok(Some(message)) = self.link.poll_receive() {
+            self.handle_command(message);
+        }
+        self.flush(from)
+    }
+
+    fn handle_command(&mut self, message: host_to_mote::Message) {
+        let reliable = message.reliable();
+        let (id, command) = unwrap_request(message);
+        let outcome = self.execute_command(command);
+        if let Some(answer) = reply(id, outcome) {
+            // A full transmit queue loses the reply, as on Mote. Reliable requests are answered
+            // reliably, since the host's retransmission of one would be suppressed as a duplicate.
+            let _ = if reliable {
+                self.link.send_reliable(answer)
+            } else {
+                self.link.send(answer)
+            };
+        }
+    }
+
+    /// Carry out a command from the host, returning its reply if it has one
+    fn execute_command(
+        &mut self,
+        command: host_to_mote::Message,
+    ) -> Result<Option<mote_to_host::Message>, NackReason> {
+        match command {
+            host_to_mote::Message::Ping => Ok(Some(mote_to_host::Message::Pong)),
+            host_to_mote::Message::Pong => Ok(None),
+            host_to_mote::Message::DriveBaseCommand(command) => {
+                if let Some(interrupted) = self.robot.command(&command) {
+                    self.send_move_finished(interrupted);
+                }
+                Ok(None)
+            }
+            host_to_mote::Message::MoveWheels(command) => {
+                if command.profile().is_none() {
+                    return Err(NackReason::InvalidArgument);
+                }
+                if let Some(interrupted) = self.robot.move_wheels(&command) {
+                    self.send_move_finished(interrupted);
+                }
+                Ok(None)
+            }
+            host_to_mote::Message::Hello(_) => {
+                Ok(Some(mote_to_host::Message::HelloAck(hello_ack(
+                    env!("CARGO_PKG_VERSION"),
+                    "simulator",
+                    capabilities::LIDAR | capabilities::IMU | capabilities::DRIVE_BASE,
+                ))))
+            }
+            host_to_mote::Message::ConfigureTelemetry(telemetry) => {
+                self.configure_telemetry(telemetry);
+                Ok(None)
+            }
+            host_to_mote::Message::Request(_) => Err(NackReason::InvalidRequest),
+            host_to_mote::Message::TimeSyncRequest(request) => {
+                // On the clock telemetry is stamped with, which stands still while packets are
+                // handled
+                let now_us = self.robot.uptime().as_micros() as u64;
+                Ok(Some(mote_to_host::Message::TimeSyncReply(
+                    mote_to_host::TimeSyncReply {
+                        host_t0: request.host_t0,
+                        device_t1: now_us,
+                        device_t2: now_us,
+                    },
+                )))
+            }
+            _ => Err(NackReason::Unsupported),
+        }
+    }
+
+    fn send_telemetry(&mut self, message: mote_to_host::Message) {
+        // Telemetry is dropped when the transmit queue is full, as on Mote
+        let _ = self.link.send(message);
+    }
+
+    fn send_move_finished(&mut self, finished: mote_to_host::MoveFinished) {
+        // A full transmit queue loses it, as on Mote
+        let _ = self
+            .link
+            .send(mote_to_host::Message::MoveFinished(finished));
+    }
+
+    fn flush(&mut self, to: SocketAddr) -> Result<(), Error> {
+        while let Some(payload) = self.link.poll_transmit() {
+            if let Err(err) = self.socket.send_to(&payload, to) {
+                // The host went away, wait for it, or another, to send something
+                if err.kind() == ErrorKind::ConnectionRefused {
+                    self.client = None;
+                    break;
+                }
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use mote_api::PROTOCOL_VERSION;
+    use mote_api::messages::host_to_mote::{MoveWheels, SetDriveBaseVelocity};
+    use mote_api::messages::mote_to_host::MoveOutcome;
+    use mote_api::odometry::OdometryConfig;
+    use mote_client::MoteClient;
+
+    use super::*;
+    use crate::map::{DEFAULT_RESOLUTION_M, Pose};
+
+    #[test]
+    fn test_raycast() {
+        let map = Map::parse("#####\n#S  #\n#####", 1.0).unwrap();
+        let start = map.start();
+        assert_eq!(
+            start,
+            Pose {
+                x: 1.5,
+                y: 1.5,
+                theta: 0.0
+            }
+        );
+
+        let east = map.raycast(start.x, start.y, 0.0, 10.0).unwrap();
+        assert!((east - 2.5).abs() < 1e-4, "{east}");
+        let north = map
+            .raycast(start.x, start.y, std::f32::consts::FRAC_PI_2, 10.0)
+            .unwrap();
+        assert!((north - 0.5).abs() < 1e-4, "{north}");
+        assert_eq!(map.raycast(start.x, start.y, 0.0, 2.0), None);
+        // Diagonally to the top right corner
+        let diagonal = map
+            .raycast(1.0, 1.0, std::f32::consts::FRAC_PI_4, 10.0)
+            .unwrap();
+        assert!((diagonal - 2f32.sqrt()).abs() < 1e-4, "{diagonal}");
+
+        assert!(matches!(Map::parse("", 1.0), Err(map::MapError::Empty)));
+        assert!(matches!(
+            Map::parse("SS", 1.0),
+            Err(map::MapError::SeveralStarts)
+        ));
+    }
+
+    #[test]
+    fn test_robot_drives_and_watchdog_stops_it() {
+        let map = Map::default();
+        let start = map.start();
+        let mut robot = Robot::new(RobotConfig::default(), start);
+        let dt = Duration::from_millis(10);
+
+        robot.command(&SetDriveBaseVelocity {
+            left_velocity_rad: 5.0,
+            right_velocity_rad: 5.0,
+        });
+        for _ in 0..50 {
+            robot.step(dt, &map);
+        }
+        let moved = robot.pose();
+        assert!(moved.x - start.x > 0.05, "{moved:?}");
+        assert!((moved.y - start.y).abs() < 1e-4);
+        let state = robot.drive_base_state();
+        assert_eq!(state.timestamp_us, 500_000);
+        assert_eq!(robot.imu_measurement().timestamp_us, 500_000);
+        assert!(state.left.velocity_rad_per_s > 4.5);
+        assert!(state.right.postition_rad > 1.0);
+        assert!(robot.imu_measurement().gyro.z.abs() < 1e-4);
+
+        // No command for over a second
+        for _ in 0..200 {
+            robot.step(dt, &map);
+        }
+        let stopped = robot.pose();
+        robot.step(dt, &map);
+        assert!((robot.pose().x - stopped.x).abs() < 1e-4);
+        assert!(robot.drive_base_state().left.velocity_rad_per_s.abs() < 0.01);
+
+        // Turning on the spot
+        robot.command(&SetDriveBaseVelocity {
+            left_velocity_rad: -5.0,
+            right_velocity_rad: 5.0,
+        });
+        for _ in 0..50 {
+            robot.step(dt, &map);
+        }
+        assert!(robot.imu_measurement().gyro.z > 1.0);
+        assert!(robot.pose().theta > 0.5);
+    }
+
+    #[test]
+    fn test_robot_moves_wheels() {
+        let map = Map::default();
+        let start = map.start();
+        let config = RobotConfig::default();
+        let odometry = OdometryConfig::new(config.wheel_radius_m, config.track_width_m);
+        let mut robot = Robot::new(config, start);
+        let dt = Duration::from_millis(10);
+        let run = |robot: &mut Robot| {
+            (0..500)
+                .find_map(|_| robot.step(dt, &map))
+                .expect("move didn't finish")
+        };
+
+        // The watchdog doesn't stop a move, even one taking longer than its timeout
+        let command = MoveWheels::drive_distance(1, &odometry, 0.3, 0.2, 0.5);
+        assert_eq!(robot.move_wheels(&command), None);
+        let finished = run(&mut robot);
+        assert_eq!(finished.id, 1);
+        assert_eq!(finished.outcome, MoveOutcome::Completed);
+        assert!(robot.uptime() > robot::WATCHDOG_TIMEOUT);
+        let moved = robot.pose();
+        assert!((moved.x - start.x - 0.3).abs() < 0.01, "{moved:?}");
+        assert!((moved.y - start.y).abs() < 1e-4);
+
+        let command = MoveWheels::rotate_angle(2, &odometry, std::f32::consts::FRAC_PI_2, 2.0, 4.0);
+        robot.move_wheels(&command);
+        assert_eq!(run(&mut robot).outcome, MoveOutcome::Completed);
+        let turned = robot.pose();
+        assert!(
+            (turned.theta - std::f32::consts::FRAC_PI_2).abs() < 0.05,
+            "{turned:?}"
+        );
+        assert!((turned.x - moved.x).abs() < 1e-3);
+
+        // A velocity command interrupts a move
+        robot.move_wheels(&command);
+        robot.step(dt, &map);
+        let interrupted = robot.command(&SetDriveBaseVelocity {
+            left_velocity_rad: 0.0,
+            right_velocity_rad: 0.0,
+        });
+        assert_eq!(interrupted.unwrap().outcome, MoveOutcome::Interrupted);
+    }
+
+    #[test]
+    fn test_scan_sees_walls() {
+        let map = Map::parse("#####\n#S  #\n#####", DEFAULT_RESOLUTION_M).unwrap();
+        let config = RobotConfig {
+            lidar_noise_mm: 0.0,
+            ..Default::default()
+        };
+        let mut robot = Robot::new(config, map.start());
+        let points = robot.scan(robot::LIDAR_POINTS_PER_REVOLUTION, &map);
+        assert_eq!(points.len(), robot::LIDAR_POINTS_PER_REVOLUTION);
+        // Straight ahead is 2.5 cells away, and 90° clockwise is to the right, half a cell away
+        assert!(
+            (points[0].distance_mm - 125.0).abs() < 0.1,
+            "{:?}",
+            points[0]
+        );
+        let right = &points[robot::LIDAR_POINTS_PER_REVOLUTION / 4];
+        assert!((right.angle_rad - std::f32::consts::FRAC_PI_2).abs() < 1e-4);
+        assert!((right.distance_mm - 25.0).abs() < 0.1, "{right:?}");
+        assert!(points.iter().all(|point| point.quality > 0));
+    }
+
+    #[test]
+    fn test_configure_telemetry() {
+        let mut simulator =
+            Simulator::bind("127.0.0.1:0", Map::default(), RobotConfig::default()).unwrap();
+        assert_eq!(simulator.imu.interval, Duration::from_millis(20));
+        assert_eq!(
+            simulator.drive_base_state.interval,
+            Duration::from_millis(100)
+        );
+
+        let mut telemetry = ConfigureTelemetry::default();
+        telemetry.scan.enabled = false;
+        telemetry.imu.period_ms = 1;
+        telemetry.drive_base.period_ms = 500;
+        let outcome =
+            simulator.execute_command(host_to_mote::Message::ConfigureTelemetry(telemetry.clone()));
+        assert_eq!(outcome, Ok(None));
+        assert_eq!(simulator.telemetry(), &telemetry);
+        // Raised to how fast the firmware samples the IMU
+        assert_eq!(simulator.imu.interval, MIN_IMU_INTERVAL);
+        assert_eq!(
+            simulator.drive_base_state.interval,
+            Duration::from_millis(500)
+        );
+
+        // Settings outlast the host reconnecting
+        simulator
+            .execute_command(host_to_mote::Message::Hello(host_to_mote::Hello::default()))
+            .unwrap();
+        assert_eq!(simulator.telemetry(), &telemetry);
+    }
+
+    #[test]
+    fn test_retransmits_to_a_silent_host() {
+        let mut simulator =
+            Simulator::bind("127.0.0.1:0", Map::default(), RobotConfig::default()).unwrap();
+        // A host which never acknowledges anything
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        simulator.client = Some(host.local_addr().unwrap());
+        simulator.send_move_finished(mote_to_host::MoveFinished {
+            id: 1,
+            timestamp_us: 0,
+            outcome: MoveOutcome::Completed,
+            left_error_rad: 0.0,
+            right_error_rad: 0.0,
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while simulator.link.stats().retransmissions == 0 {
+            assert!(Instant::now() < deadline, "never retransmitted");
+            simulator.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_client_cannot_tell_the_difference() {
+        let mut simulator =
+            Simulator::bind("127.0.0.1:0", Map::default(), RobotConfig::default()).unwrap();
+        let address = simulator.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    simulator.step().unwrap();
+                }
+                simulator
+            })
+        };
+
+        let client = MoteClient::connect(address).unwrap();
+        let messages = client.subscribe();
+        let scans = client.subscribe_scans();
+        let imu = client.subscribe_imu_measurements();
+        let states = client.subscribe_drive_base_states();
+
+        let ack = client.hello_ack().unwrap();
+        assert_eq!(ack.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(ack.git_hash, "simulator");
+
+        let timeout = Duration::from_secs(2);
+        client
+            .send(host_to_mote::Message::Request(host_to_mote::Request {
+                id: 7,
+                message: Box::new(host_to_mote::Message::Ping),
+            }))
+            .unwrap();
+        let response = messages
+            .iter()
+            .find(|message| matches!(message, mote_to_host::Message::Response(_)))
+            .unwrap();
+        assert_eq!(
+            response,
+            mote_to_host::Message::Response(mote_to_host::Response {
+                id: 7,
+                result: Some(Box::new(mote_to_host::Message::Pong)),
+            })
+        );
+
+        client
+            .send(host_to_mote::Message::TimeSyncRequest(
+                host_to_mote::TimeSyncRequest { host_t0: 42 },
+            ))
+            .unwrap();
+        let reply = messages
+            .iter()
+            .find_map(|message| match message {
+                mote_to_host::Message::TimeSyncReply(reply) => Some(reply),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(reply.host_t0, 42);
+        assert!(reply.device_t2 >= reply.device_t1);
+
+        assert_eq!(scans.recv_timeout(timeout).unwrap().points.len(), 100);
+        assert!((imu.recv_timeout(timeout).unwrap().accel.z - 9.80665).abs() < 1e-4);
+        states.recv_timeout(timeout).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            client
+                .send(host_to_mote::Message::DriveBaseCommand(
+                    SetDriveBaseVelocity {
+                        left_velocity_rad: 3.0,
+                        right_velocity_rad: 3.0,
+                    },
+                ))
+                .unwrap();
+            let state = states.recv_timeout(timeout).unwrap();
+            if state.left.velocity_rad_per_s > 2.0 {
+                break;
+            }
+            assert!(Instant::now() < deadline, "Drive base never moved");
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        let simulator = server.join().unwrap();
+        assert!(simulator.robot().pose().x > simulator.map().start().x);
+        assert!(simulator.client().is_some());
+    }
+}
@@ -0,0 +1,105 @@
+//! `mote-sim`, a simulated Mote on the local network
+//!
+//! Serves the Mote API on UDP port 7475 and advertises itself over mDNS like a real Mote, so the
+//! `mote` tool, mote-client and the Python bindings can all be pointed at it.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use mote_sim::map::{DEFAULT_MAP, DEFAULT_RESOLUTION_M, Map};
+use mote_sim::robot::RobotConfig;
+use mote_sim::{Simulator, UDP_PORT};
+
+#[derive(Parser)]
+#[command(
+    name = "mote-sim",
+    version,
+    about = "Simulate a Mote on the local network"
+)]
+struct Cli {
+    /// Text map to drive around: `#` is a wall, `S` is the start, anything else is free space.
+    /// Defaults to a room with a few obstacles.
+    #[arg(long)]
+    map: Option<PathBuf>,
+
+    /// Size of a map cell in meters
+    #[arg(long, default_value_t = DEFAULT_RESOLUTION_M)]
+    resolution: f32,
+
+    /// UID to advertise, which is also the mDNS hostname
+    #[arg(long, default_value = "mote-sim")]
+    uid: String,
+
+    #[arg(long, default_value_t = UDP_PORT)]
+    port: u16,
+
+    /// Don't advertise over mDNS, e.g. to run next to a real Mote
+    #[arg(long)]
+    no_mdns: bool,
+
+    /// Lidar distances are off by up to this many millimeters
+    #[arg(long, default_value_t = 5.0)]
+    noise_mm: f32,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let text = match &cli.map {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?,
+        None => DEFAULT_MAP.to_owned(),
+    };
+    let map = Map::parse(&text, cli.resolution)?;
+    let config = RobotConfig {
+        lidar_noise_mm: cli.noise_mm,
+        ..Default::default()
+    };
+
+    let mut simulator = Simulator::bind(
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, cli.port)),
+        map,
+        config,
+    )
+    .with_context(|| format!("Could not listen on port {}", cli.port))?;
+
+    #[cfg(feature = "mdns")]
+    let _mdns = if cli.no_mdns {
+        None
+    } else {
+        Some(advertise(&cli.uid, cli.port)?)
+    };
+    #[cfg(not(feature = "mdns"))]
+    if !cli.no_mdns {
+        eprintln!("Built without the mdns feature, so not advertising");
+    }
+
+    let (width, height) = simulator.map().size_m();
+    eprintln!(
+        "Simulating Mote {} on port {} in a {width}m x {height}m map",
+        cli.uid, cli.port
+    );
+    simulator.run()?;
+    Ok(())
+}
+
+/// Advertise the Mote API service as Mote's firmware does, until the daemon is dropped
+#[cfg(feature = "mdns")]
+fn advertise(uid: &str, port: u16) -> anyhow::Result<mdns_sd::ServiceDaemon> {
+    use std::collections::HashMap;
+
+    let daemon = mdns_sd::ServiceDaemon::new().context("Could not start mDNS")?;
+    let service = mdns_sd::ServiceInfo::new(
+        "_mote-api._udp.local.",
+        "Mote Server",
+        &format!("{uid}.local."),
+        "",
+        port,
+        None::<HashMap<String, String>>,
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    Ok(daemon)
+}
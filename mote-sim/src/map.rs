@@ -0,0 +1,178 @@
+//! The 2D world the simulated Mote drives around
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// The map used when none is given, a room with a few obstacles
+pub const DEFAULT_MAP: &str = include_str!("../maps/room.txt");
+
+/// Size of a map cell when none is given, in meters
+pub const DEFAULT_RESOLUTION_M: f32 = 0.05;
+
+/// A pose in the map frame: meters, and radians counterclockwise from +x
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+}
+
+/// An occupancy grid, parsed from text
+///
+/// Each character is a cell: `#` is a wall, `S` is where Mote starts (facing +x), and anything else
+/// is free space. The first line is the top of the map. The map frame has its origin at the
+/// bottom left corner, with +x to the right and +y up. Everything outside the map is free space.
+#[derive(Clone, PartialEq)]
+pub struct Map {
+    width: usize,
+    height: usize,
+    resolution_m: f32,
+    /// Row major, starting at the bottom row
+    occupied: Vec<bool>,
+    start: Option<(usize, usize)>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MapError {
+    #[error("Map has no cells")]
+    Empty,
+    #[error("Map has more than one start cell (S)")]
+    SeveralStarts,
+}
+
+impl Map {
+    /// Parse a map whose cells are `resolution_m` meters wide
+    pub fn parse(text: &str, resolution_m: f32) -> Result<Self, MapError> {
+        let rows: Vec<&str> = text.lines().collect();
+        let width = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        let height = rows.len();
+        if width == 0 {
+            return Err(MapError::Empty);
+        }
+
+        let mut occupied = vec![false; width * height];
+        let mut start = None;
+        // The first line is the top row
+        for (y, row) in rows.iter().rev().enumerate() {
+            for (x, cell) in row.chars().enumerate() {
+                match cell {
+                    '#' => occupied[y * width + x] = true,
+                    'S' if start.is_some() => return Err(MapError::SeveralStarts),
+                    'S' => start = Some((x, y)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            resolution_m,
+            occupied,
+            start,
+        })
+    }
+
+    /// Size of the map in meters
+    pub fn size_m(&self) -> (f32, f32) {
+        (
+            self.width as f32 * self.resolution_m,
+            self.height as f32 * self.resolution_m,
+        )
+    }
+
+    pub fn resolution_m(&self) -> f32 {
+        self.resolution_m
+    }
+
+    /// Centre of the start cell, or the centre of the map if it has none
+    pub fn start(&self) -> Pose {
+        let (x, y) = match self.start {
+            Some((x, y)) => (x as f32 + 0.5, y as f32 + 0.5),
+            None => (self.width as f32 / 2.0, self.height as f32 / 2.0),
+        };
+        Pose {
+            x: x * self.resolution_m,
+            y: y * self.resolution_m,
+            theta: 0.0,
+        }
+    }
+
+    fn cell_occupied(&self, x: i64, y: i64) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return false;
+        }
+        self.occupied[y as usize * self.width + x as usize]
+    }
+
+    /// Whether the point (in meters) lies in a wall
+    pub fn occupied(&self, x: f32, y: f32) -> bool {
+        self.cell_occupied(
+            (x / self.resolution_m).floor() as i64,
+            (y / self.resolution_m).floor() as i64,
+        )
+    }
+
+    /// Distance from (x, y) along `angle` to the first wall, if there is one within `max_range`
+    pub fn raycast(&self, x: f32, y: f32, angle: f32, max_range: f32) -> Option<f32> {
+        // Walk the cells the ray passes through, see Amanatides & Woo, "A Fast Voxel Traversal
+        // Algorithm for Ray Tracing"
+        let (dx, dy) = (angle.cos(), angle.sin());
+        let resolution = self.resolution_m;
+        let mut cell_x = (x / resolution).floor() as i64;
+        let mut cell_y = (y / resolution).floor() as i64;
+        let axis = |position: f32, cell: i64, direction: f32| {
+            if direction > 0.0 {
+                (
+                    1,
+                    ((cell + 1) as f32 * resolution - position) / direction,
+                    resolution / direction,
+                )
+            } else if direction < 0.0 {
+                (
+                    -1,
+                    (cell as f32 * resolution - position) / direction,
+                    -resolution / direction,
+                )
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (step_x, mut next_x, delta_x) = axis(x, cell_x, dx);
+        let (step_y, mut next_y, delta_y) = axis(y, cell_y, dy);
+
+        let mut distance = 0.0;
+        while distance <= max_range {
+            if self.cell_occupied(cell_x, cell_y) {
+                return Some(distance);
+            }
+            if next_x < next_y {
+                distance = next_x;
+                next_x += delta_x;
+                cell_x += step_x;
+            } else {
+                distance = next_y;
+                next_y += delta_y;
+                cell_y += step_y;
+            }
+        }
+        None
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self::parse(DEFAULT_MAP, DEFAULT_RESOLUTION_M).expect("The default map is valid")
+    }
+}
+
+impl fmt::Debug for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (width, height) = self.size_m();
+        write!(f, "Map({width}m x {height}m)")
+    }
+}
@@ -0,0 +1,290 @@
+//! Models of Mote's drive base and sensors
+
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use mote_api::messages::host_to_mote::{MoveWheels, SetDriveBaseVelocity};
+use mote_api::messages::mote_to_host::{
+    DriveBaseState, IMUAxisTriple, IMUMeasurement, MoveFinished, MoveOutcome, Point,
+    WheelJointState,
+};
+use mote_api::motion::WheelMove;
+
+use crate::map::{Map, Pose};
+
+/// Standard gravity, in m/s²
+const GRAVITY: f32 = 9.80665;
+
+/// The drive base stops if no command arrives for this long, like the firmware's watchdog
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Physical parameters of the simulated robot
+#[derive(Debug, Clone)]
+pub struct RobotConfig {
+    pub wheel_radius_m: f32,
+    /// Distance between the wheels
+    pub track_width_m: f32,
+    /// Wheels can't spin faster than this, in rad/s
+    pub max_wheel_velocity_rad_per_s: f32,
+    /// How quickly the wheels reach their commanded velocity, as the time constant of a first
+    /// order lag
+    pub wheel_time_constant: Duration,
+    /// Mote can't drive closer than this to a wall
+    pub body_radius_m: f32,
+    /// Range of the lidar, beyond which there is no return
+    pub lidar_range_m: f32,
+    /// Lidar distances are off by up to this much
+    pub lidar_noise_mm: f32,
+}
+
+impl Default for RobotConfig {
+    fn default() -> Self {
+        Self {
+            wheel_radius_m: 0.033,
+            track_width_m: 0.14,
+            max_wheel_velocity_rad_per_s: 15.0,
+            wheel_time_constant: Duration::from_millis(100),
+            body_radius_m: 0.08,
+            lidar_range_m: 12.0,
+            lidar_noise_mm: 5.0,
+        }
+    }
+}
+
+/// The simulated robot: a differential drive base carrying an IMU and a lidar
+pub struct Robot {
+    config: RobotConfig,
+    pose: Pose,
+    /// Commanded wheel velocities
+    setpoint: (f32, f32),
+    left: WheelJointState,
+    right: WheelJointState,
+    since_command: Duration,
+    /// The move being carried out, and the uptime it started at. The watchdog doesn't stop it.
+    wheel_move: Option<(WheelMove, Duration)>,
+    /// Forward velocity and acceleration, for the IMU
+    velocity: f32,
+    acceleration: f32,
+    angular_velocity: f32,
+    /// Lidar bearing of the next point
+    lidar_bearing: f32,
+    rng: XorShift,
+    /// Simulated time since the robot was created, which stamps its telemetry
+    uptime: Duration,
+}
+
+/// Lidar samples per revolution
+pub const LIDAR_POINTS_PER_REVOLUTION: usize = 500;
+
+impl Robot {
+    pub fn new(config: RobotConfig, pose: Pose) -> Self {
+        let wheel = WheelJointState {
+            effort_percent: 0.0,
+            velocity_rad_per_s: 0.0,
+            postition_rad: 0.0,
+        };
+        Self {
+            config,
+            pose,
+            setpoint: (0.0, 0.0),
+            left: wheel.clone(),
+            right: wheel,
+            since_command: WATCHDOG_TIMEOUT,
+            wheel_move: None,
+            velocity: 0.0,
+            acceleration: 0.0,
+            angular_velocity: 0.0,
+            lidar_bearing: 0.0,
+            rng: XorShift(0x2545_f491_4f6c_dd1d),
+            uptime: Duration::ZERO,
+        }
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    /// Simulated time since the robot was created, like the time since Mote booted
+    pub fn uptime(&self) -> Duration {
+        self.uptime
+    }
+
+    /// Drive the wheels at the commanded velocities until the next command, or the watchdog
+    /// stops them. Returns the move this interrupts, if any.
+    pub fn command(&mut self, command: &SetDriveBaseVelocity) -> Option<MoveFinished> {
+        let interrupted = self.interrupt_move();
+        self.set_setpoint(command.left_velocity_rad, command.right_velocity_rad);
+        self.since_command = Duration::ZERO;
+        interrupted
+    }
+
+    /// Turn the wheels through the commanded angles, like the firmware, see mote_api::motion.
+    /// Returns the move this interrupts, if any. Invalid moves stop the wheels.
+    pub fn move_wheels(&mut self, command: &MoveWheels) -> Option<MoveFinished> {
+        let interrupted = self.interrupt_move();
+        let (left, right) = (self.left.postition_rad, self.right.postition_rad);
+        self.wheel_move =
+            WheelMove::new(command, left, right).map(|wheel_move| (wheel_move, self.uptime));
+        self.set_setpoint(0.0, 0.0);
+        self.since_command = Duration::ZERO;
+        interrupted
+    }
+
+    fn interrupt_move(&mut self) -> Option<MoveFinished> {
+        let (wheel_move, _) = self.wheel_move.take()?;
+        Some(self.finished(&wheel_move, MoveOutcome::Interrupted))
+    }
+
+    fn finished(&self, wheel_move: &WheelMove, outcome: MoveOutcome) -> MoveFinished {
+        wheel_move.finished(
+            outcome,
+            self.uptime.as_micros() as u64,
+            self.left.postition_rad,
+            self.right.postition_rad,
+        )
+    }
+
+    fn set_setpoint(&mut self, left: f32, right: f32) {
+        let limit = self.config.max_wheel_velocity_rad_per_s;
+        self.setpoint = (left.clamp(-limit, limit), right.clamp(-limit, limit));
+    }
+
+    /// Advance the simulation by `dt`, returning the move which finished, if any
+    pub fn step(&mut self, dt: Duration, map: &Map) -> Option<MoveFinished> {
+        self.uptime += dt;
+        self.since_command += dt;
+        let mut finished = None;
+        if let Some((wheel_move, started)) = self.wheel_move.take() {
+            let elapsed_s = (self.uptime - started).as_secs_f32();
+            let (left, right) = (self.left.postition_rad, self.right.postition_rad);
+            match wheel_move.outcome(elapsed_s, left, right) {
+                Some(outcome) => {
+                    finished = Some(self.finished(&wheel_move, outcome));
+                    self.set_setpoint(0.0, 0.0);
+                    // The watchdog takes over again from here
+                    self.since_command = Duration::ZERO;
+                }
+                None => {
+                    let (left, right) = wheel_move.setpoints(elapsed_s, left, right);
+                    self.set_setpoint(left, right);
+                    self.wheel_move = Some((wheel_move, started));
+                }
+            }
+        } else if self.since_command >= WATCHDOG_TIMEOUT {
+            self.setpoint = (0.0, 0.0);
+        }
+
+        let seconds = dt.as_secs_f32();
+        let blend = 1.0 - (-seconds / self.config.wheel_time_constant.as_secs_f32()).exp();
+        let limit = self.config.max_wheel_velocity_rad_per_s;
+        for (wheel, setpoint) in [
+            (&mut self.left, self.setpoint.0),
+            (&mut self.right, self.setpoint.1),
+        ] {
+            wheel.velocity_rad_per_s += (setpoint - wheel.velocity_rad_per_s) * blend;
+            wheel.postition_rad += wheel.velocity_rad_per_s * seconds;
+            wheel.effort_percent = wheel.velocity_rad_per_s / limit * 100.0;
+        }
+
+        let radius = self.config.wheel_radius_m;
+        let left = self.left.velocity_rad_per_s * radius;
+        let right = self.right.velocity_rad_per_s * radius;
+        let velocity = (left + right) / 2.0;
+        self.angular_velocity = (right - left) / self.config.track_width_m;
+        if seconds > 0.0 {
+            self.acceleration = (velocity - self.velocity) / seconds;
+        }
+        self.velocity = velocity;
+
+        // Integrate along the arc's mean heading
+        let heading = self.pose.theta + self.angular_velocity * seconds / 2.0;
+        let next = Pose {
+            x: self.pose.x + velocity * seconds * heading.cos(),
+            y: self.pose.y + velocity * seconds * heading.sin(),
+            theta: (self.pose.theta + self.angular_velocity * seconds).rem_euclid(TAU),
+        };
+        // Walls stop Mote, but the wheels keep turning
+        if self.collides(&next, map) {
+            self.pose.theta = next.theta;
+        } else {
+            self.pose = next;
+        }
+        finished
+    }
+
+    fn collides(&self, pose: &Pose, map: &Map) -> bool {
+        (0..8).any(|i| {
+            let angle = i as f32 * TAU / 8.0;
+            let radius = self.config.body_radius_m;
+            map.occupied(pose.x + radius * angle.cos(), pose.y + radius * angle.sin())
+        })
+    }
+
+    pub fn drive_base_state(&self) -> DriveBaseState {
+        DriveBaseState {
+            timestamp_us: self.uptime.as_micros() as u64,
+            left: self.left.clone(),
+            right: self.right.clone(),
+        }
+    }
+
+    /// IMU reading, with x forward, y left and z up, in m/s² and rad/s
+    pub fn imu_measurement(&self) -> IMUMeasurement {
+        IMUMeasurement {
+            timestamp_us: self.uptime.as_micros() as u64,
+            accel: IMUAxisTriple {
+                x: self.acceleration,
+                // Centripetal acceleration
+                y: self.velocity * self.angular_velocity,
+                z: GRAVITY,
+            },
+            gyro: IMUAxisTriple {
+                x: 0.0,
+                y: 0.0,
+                z: self.angular_velocity,
+            },
+        }
+    }
+
+    /// The next `count` lidar points of the sweep
+    ///
+    /// Like the RPLIDAR C1, bearings increase clockwise from straight ahead, distances are in mm,
+    /// and points with no return have a distance and quality of 0.
+    pub fn scan(&mut self, count: usize, map: &Map) -> Vec<Point> {
+        let step = TAU / LIDAR_POINTS_PER_REVOLUTION as f32;
+        (0..count)
+            .map(|_| {
+                let bearing = self.lidar_bearing;
+                self.lidar_bearing = (bearing + step) % TAU;
+                let angle = self.pose.theta - bearing;
+                match map.raycast(self.pose.x, self.pose.y, angle, self.config.lidar_range_m) {
+                    Some(distance) => Point {
+                        quality: 47,
+                        angle_rad: bearing,
+                        distance_mm: (distance * 1000.0
+                            + self.rng.uniform() * self.config.lidar_noise_mm)
+                            .max(0.0),
+                    },
+                    None => Point {
+                        quality: 0,
+                        angle_rad: bearing,
+                        distance_mm: 0.0,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Small, deterministic noise source
+struct XorShift(u64);
+
+impl XorShift {
+    /// Uniformly distributed in [-1, 1)
+    fn uniform(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}